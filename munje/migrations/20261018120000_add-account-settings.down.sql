@@ -0,0 +1,2 @@
+alter table questions alter column author_id set not null;
+drop table handle_redirects;
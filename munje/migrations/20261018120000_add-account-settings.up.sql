@@ -0,0 +1,9 @@
+create table handle_redirects (
+  created_at timestamp with time zone not null default now(),
+  old_handle varchar(30) primary key,
+  user_id bigint not null references users on delete cascade
+);
+
+-- Questions outlive the accounts that wrote them, so that other people's queues and answers
+-- continue to work after an author deletes their account.
+alter table questions alter column author_id drop not null;
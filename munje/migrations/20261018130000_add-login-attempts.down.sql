@@ -0,0 +1,2 @@
+drop table login_attempts;
+alter table users drop column is_admin;
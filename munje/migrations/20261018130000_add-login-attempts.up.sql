@@ -0,0 +1,12 @@
+alter table users add column is_admin boolean not null default false;
+
+create table login_attempts (
+  created_at timestamp with time zone not null default now(),
+  handle varchar(30) not null,
+  id bigserial primary key,
+  ip_address text not null,
+  succeeded boolean not null
+);
+
+create index login_attempts_handle_created_at_idx on login_attempts (handle, created_at);
+create index login_attempts_ip_address_created_at_idx on login_attempts (ip_address, created_at);
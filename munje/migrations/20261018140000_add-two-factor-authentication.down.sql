@@ -0,0 +1,4 @@
+drop table recovery_codes;
+alter table users drop column totp_last_used_step;
+alter table users drop column totp_enabled;
+alter table users drop column totp_secret;
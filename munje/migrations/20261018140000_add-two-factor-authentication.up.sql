@@ -0,0 +1,13 @@
+alter table users add column totp_secret text;
+alter table users add column totp_enabled boolean not null default false;
+alter table users add column totp_last_used_step bigint;
+
+create table recovery_codes (
+  created_at timestamp with time zone not null default now(),
+  hashed_code text not null,
+  id bigserial primary key,
+  used_at timestamp with time zone,
+  user_id bigint not null references users on delete cascade
+);
+
+create index recovery_codes_user_id_idx on recovery_codes (user_id);
@@ -0,0 +1,10 @@
+create table user_identities (
+  created_at timestamp with time zone not null default now(),
+  id bigserial primary key,
+  issuer text not null,
+  subject text not null,
+  user_id bigint not null references users on delete cascade,
+  unique (issuer, subject)
+);
+
+create index user_identities_user_id_idx on user_identities (user_id);
@@ -0,0 +1,13 @@
+create table invites (
+  code varchar(30) not null unique,
+  created_at timestamp with time zone not null default now(),
+  created_by_id bigint not null references users on delete cascade,
+  expires_at timestamp with time zone,
+  id bigserial primary key,
+  max_uses integer not null default 1,
+  use_count integer not null default 0
+);
+
+create index invites_created_by_id_idx on invites (created_by_id);
+
+alter table users add column invite_id bigint references invites on delete set null;
@@ -0,0 +1,2 @@
+drop index handle_redirects_lower_old_handle_idx;
+drop index users_lower_handle_idx;
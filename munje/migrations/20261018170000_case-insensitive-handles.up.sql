@@ -0,0 +1,55 @@
+-- Handles are unique without regard to case from now on, and some handles are reserved because
+-- they would collide with the application's own routes (see RESERVED_HANDLES in src/routes.rs).
+-- Existing accounts that break either rule have to be renamed by hand before this migration can
+-- run, so they are listed in the error rather than being changed here.
+do $$
+declare
+  collisions text;
+begin
+  select string_agg(handles, '; ') into collisions
+  from (
+    select string_agg(handle, ', ' order by handle) handles
+    from (
+      select handle, id user_id from users
+      union all
+      select old_handle, user_id from handle_redirects
+    ) h
+    group by lower(handle)
+    having count(distinct user_id) > 1
+  ) c;
+
+  if collisions is not null then
+    raise exception 'Handles that differ only in case: %', collisions;
+  end if;
+
+  select string_agg(handle, ', ' order by handle) into collisions
+  from (
+    select handle from users
+    union all
+    select old_handle from handle_redirects
+  ) h
+  where lower(handle) in (
+    'about', 'admin', 'api', 'assets', 'favicon.ico', 'help', 'login', 'logout', 'overview',
+    'questions', 'queues', 'robots.txt', 'settings', 'signup', 'static', 'users'
+  );
+
+  if collisions is not null then
+    raise exception 'Handles that collide with application routes: %', collisions;
+  end if;
+end
+$$;
+
+-- Redirects that differ from the same user's handle, or from one another, only in case are no
+-- longer needed now that lookups ignore case.
+delete from handle_redirects hr
+using users u
+where hr.user_id = u.id and lower(hr.old_handle) = lower(u.handle);
+
+delete from handle_redirects a
+using handle_redirects b
+where a.user_id = b.user_id
+  and lower(a.old_handle) = lower(b.old_handle)
+  and a.old_handle > b.old_handle;
+
+create unique index users_lower_handle_idx on users (lower(handle));
+create unique index handle_redirects_lower_old_handle_idx on handle_redirects (lower(old_handle));
@@ -0,0 +1,5 @@
+-- The confusable skeleton of each handle (see src/users/handle.rs), so that handles that only
+-- look alike cannot both be registered.  Skeletons are computed by the application, which fills
+-- them in for existing accounts when it starts.
+alter table users add column handle_skeleton varchar(120);
+create unique index users_handle_skeleton_idx on users (handle_skeleton);
@@ -0,0 +1,5 @@
+delete from last_answers where user_id in (select id from users where is_temporary);
+delete from answers where user_id in (select id from users where is_temporary);
+delete from queues where user_id in (select id from users where is_temporary);
+delete from users where is_temporary;
+alter table users drop column is_temporary;
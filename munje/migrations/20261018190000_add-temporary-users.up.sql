@@ -0,0 +1,4 @@
+-- Guests who start a queue get a temporary account of their own, kept in their cookie, so that
+-- their answers can be recorded.  Signing up moves the queues and answers to the new account and
+-- deletes the temporary one.
+alter table users add column is_temporary boolean not null default false;
@@ -0,0 +1,30 @@
+-- A record of security-relevant changes and changes to content.  Events refer to users and other
+-- records by id without foreign keys, since they outlive what they refer to, and the actor's
+-- handle at the time is kept alongside the id.
+create table audit_events (
+  id bigserial primary key,
+  created_at timestamptz not null default now(),
+  action varchar(60) not null,
+  actor_id bigint,
+  actor_handle varchar(30),
+  target_type varchar(30) not null,
+  target_id varchar(60) not null,
+  ip_address varchar(60),
+  user_agent text,
+  changes jsonb not null default '{}'
+);
+
+create index audit_events_created_at_idx on audit_events (created_at);
+create index audit_events_actor_id_idx on audit_events (actor_id);
+create index audit_events_target_idx on audit_events (target_type, target_id);
+
+-- Events are only ever added.
+create function reject_audit_event_change() returns trigger as $$
+begin
+  raise exception 'audit_events is append-only';
+end;
+$$ language plpgsql;
+
+create trigger audit_events_append_only
+  before update or delete on audit_events
+  for each row execute function reject_audit_event_change();
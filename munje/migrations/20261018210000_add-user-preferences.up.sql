@@ -0,0 +1,16 @@
+-- Settings that each user can change.  A user without a row gets the defaults below.
+create table user_preferences (
+  user_id bigint primary key references users (id) on delete cascade,
+  timezone varchar(60) not null default 'UTC',
+  grading_scale varchar(20) not null default 'three-point'
+    check (grading_scale in ('three-point', 'two-point')),
+  daily_new_limit integer not null default 20,
+  daily_review_limit integer not null default 200,
+  queue_strategy varchar(30) not null default 'spaced-repetition'
+    check (queue_strategy in ('spaced-repetition', 'random')),
+  email_digest boolean not null default false,
+  theme varchar(20) not null default 'system'
+    check (theme in ('system', 'light', 'dark')),
+  created_at timestamptz not null default now(),
+  updated_at timestamptz not null default now()
+);
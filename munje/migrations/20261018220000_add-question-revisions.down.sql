@@ -0,0 +1 @@
+drop table question_revisions;
@@ -0,0 +1,19 @@
+-- Every version of a question, numbered from 1.  The latest revision is the same as the
+-- question, which is updated in place so that answers keep pointing at it.
+create table question_revisions (
+  id bigserial primary key,
+  question_id bigint not null references questions (id) on delete cascade,
+  number integer not null,
+  editor_id bigint references users (id) on delete set null,
+  title text not null,
+  text text not null,
+  link text,
+  link_logo text,
+  created_at timestamptz not null default now(),
+  unique (question_id, number)
+);
+
+insert into question_revisions
+  (question_id, number, editor_id, title, text, link, link_logo, created_at)
+  select id, 1, author_id, title, text, link, link_logo, updated_at
+  from questions;
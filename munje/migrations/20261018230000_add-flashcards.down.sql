@@ -0,0 +1,6 @@
+alter table answers drop column revealed_at;
+alter table answers drop column shown_at;
+alter table question_revisions drop column back;
+alter table questions drop constraint questions_kind_check;
+alter table questions drop column back;
+alter table questions drop column kind;
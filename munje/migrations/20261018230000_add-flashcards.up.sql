@@ -0,0 +1,11 @@
+-- Questions come in kinds.  A flashcard has a front, which is the text, and a back that is hidden
+-- until the person answering asks to see it.
+alter table questions add column kind varchar(30) not null default 'link';
+alter table questions add column back text;
+alter table questions add constraint questions_kind_check check (kind in ('link', 'flashcard'));
+
+alter table question_revisions add column back text;
+
+-- When the question was shown and when its answer was revealed, to see how long recall took.
+alter table answers add column shown_at timestamptz;
+alter table answers add column revealed_at timestamptz;
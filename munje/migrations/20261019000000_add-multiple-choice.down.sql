@@ -0,0 +1,5 @@
+alter table answers drop column selected_options;
+drop table question_options;
+alter table questions drop column is_multi_select;
+alter table questions drop constraint questions_kind_check;
+alter table questions add constraint questions_kind_check check (kind in ('link', 'flashcard'));
@@ -0,0 +1,17 @@
+-- Multiple-choice questions have options, one or more of which are correct.  Answers to them are
+-- graded from the options that were chosen.
+alter table questions drop constraint questions_kind_check;
+alter table questions add constraint questions_kind_check
+  check (kind in ('link', 'flashcard', 'multiple-choice'));
+alter table questions add column is_multi_select boolean not null default false;
+
+create table question_options (
+  id bigserial primary key,
+  question_id bigint not null references questions (id) on delete cascade,
+  position integer not null,
+  text text not null,
+  is_correct boolean not null default false,
+  unique (question_id, position)
+);
+
+alter table answers add column selected_options bigint[];
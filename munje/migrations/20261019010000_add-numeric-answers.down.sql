@@ -0,0 +1,5 @@
+alter table answers drop column submitted_value;
+drop table question_numeric_answers;
+alter table questions drop constraint questions_kind_check;
+alter table questions add constraint questions_kind_check
+  check (kind in ('link', 'flashcard', 'multiple-choice'));
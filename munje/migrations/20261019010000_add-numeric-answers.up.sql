@@ -0,0 +1,18 @@
+-- Numeric questions are answered with a number, optionally in a unit, which is graded against
+-- the expected value within a tolerance.
+alter table questions drop constraint questions_kind_check;
+alter table questions add constraint questions_kind_check
+  check (kind in ('link', 'flashcard', 'multiple-choice', 'numeric'));
+
+create table question_numeric_answers (
+  question_id bigint primary key references questions (id) on delete cascade,
+  value double precision not null,
+  tolerance double precision not null default 0 check (tolerance >= 0),
+  tolerance_kind varchar(10) not null default 'absolute'
+    check (tolerance_kind in ('absolute', 'relative')),
+  unit varchar(30),
+  significant_figures integer check (significant_figures > 0)
+);
+
+-- What was typed, as in "0.5 L".
+alter table answers add column submitted_value text;
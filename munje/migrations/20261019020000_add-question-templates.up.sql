@@ -0,0 +1,18 @@
+-- Templates are numeric questions whose numbers are drawn afresh for each review.  The seed that
+-- the numbers were drawn with is kept on the answer, so that the attempt can be shown again.
+alter table questions drop constraint questions_kind_check;
+alter table questions add constraint questions_kind_check
+  check (kind in ('link', 'flashcard', 'multiple-choice', 'numeric', 'template'));
+
+create table question_templates (
+  question_id bigint primary key references questions (id) on delete cascade,
+  variables text not null,
+  answer text not null,
+  tolerance double precision not null default 0 check (tolerance >= 0),
+  tolerance_kind varchar(10) not null default 'absolute'
+    check (tolerance_kind in ('absolute', 'relative')),
+  unit varchar(30),
+  significant_figures integer check (significant_figures > 0)
+);
+
+alter table answers add column seed bigint;
@@ -0,0 +1,12 @@
+delete from last_answers where cloze <> 0;
+alter table last_answers drop constraint last_answers_user_id_question_id_queue_id_cloze_key;
+alter table last_answers add constraint last_answers_user_id_question_id_queue_id_key
+  unique (user_id, question_id, queue_id);
+alter table last_answers drop column cloze;
+
+alter table answers drop column cloze;
+
+alter table questions drop column cloze_numbers;
+alter table questions drop constraint questions_kind_check;
+alter table questions add constraint questions_kind_check
+  check (kind in ('link', 'flashcard', 'multiple-choice', 'numeric', 'template'));
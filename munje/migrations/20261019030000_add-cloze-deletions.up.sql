@@ -0,0 +1,13 @@
+-- A cloze question is reviewed as one item for each cloze number in its text, and each item is
+-- scheduled on its own.  Answers to other kinds of question are for item 0, the whole question.
+alter table questions drop constraint questions_kind_check;
+alter table questions add constraint questions_kind_check
+  check (kind in ('link', 'flashcard', 'multiple-choice', 'numeric', 'template', 'cloze'));
+alter table questions add column cloze_numbers integer[] not null default '{}';
+
+alter table answers add column cloze integer not null default 0;
+
+alter table last_answers add column cloze integer not null default 0;
+alter table last_answers drop constraint last_answers_user_id_question_id_queue_id_key;
+alter table last_answers add constraint last_answers_user_id_question_id_queue_id_cloze_key
+  unique (user_id, question_id, queue_id, cloze);
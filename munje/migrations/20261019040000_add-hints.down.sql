@@ -0,0 +1,8 @@
+alter table last_answers drop column answer_solution_revealed;
+alter table last_answers drop column answer_hints_revealed;
+
+alter table answers drop column solution_revealed;
+alter table answers drop column hints_revealed;
+
+alter table questions drop column solution;
+alter table questions drop column hints;
@@ -0,0 +1,10 @@
+-- Hints are revealed one at a time, in order, and the worked solution after them.  Answers keep
+-- how much help was shown, so that a correct answer given with help can count for less.
+alter table questions add column hints text[] not null default '{}';
+alter table questions add column solution text;
+
+alter table answers add column hints_revealed integer not null default 0;
+alter table answers add column solution_revealed boolean not null default false;
+
+alter table last_answers add column answer_hints_revealed integer not null default 0;
+alter table last_answers add column answer_solution_revealed boolean not null default false;
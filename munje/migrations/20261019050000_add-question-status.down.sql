@@ -0,0 +1,2 @@
+alter table questions drop constraint questions_status_check;
+alter table questions drop column status;
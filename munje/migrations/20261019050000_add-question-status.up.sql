@@ -0,0 +1,6 @@
+-- Only published questions are listed and put to people in queues.  A question that has been
+-- answered is archived rather than deleted, so that its answers keep their question, and one that a
+-- queue starts from is marked as deleted for the same reason.
+alter table questions add column status text not null default 'published';
+alter table questions add constraint questions_status_check
+  check (status in ('draft', 'published', 'archived', 'deleted'));
@@ -0,0 +1 @@
+-- Nothing to undo: the sequences are only moved past ids that are already taken.
@@ -0,0 +1,4 @@
+-- The seed user and question were inserted with explicit ids, which left their sequences behind,
+-- so the first account or question added to a new database collided with them.
+select setval('users_id_seq', coalesce((select max(id) from users), 0) + 1, false);
+select setval('questions_id_seq', coalesce((select max(id) from questions), 0) + 1, false);
@@ -0,0 +1,3 @@
+-- Accounts without a password confirm sensitive changes by logging in with the identity provider
+-- again.  The time of that login is kept for a few minutes, until it is used.
+alter table users add column reauthenticated_at timestamp with time zone;
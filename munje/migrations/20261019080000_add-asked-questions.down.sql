@@ -0,0 +1 @@
+drop table asked_questions;
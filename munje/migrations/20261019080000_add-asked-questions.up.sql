@@ -0,0 +1,12 @@
+-- The question that is being put to the owner of a queue, kept until it is answered.  The answer
+-- is graded against the variant of a template question that was issued here rather than against
+-- whatever comes back from the page.
+create table asked_questions (
+  cloze integer not null default 0,
+  created_at timestamp with time zone not null default now(),
+  id bigserial primary key,
+  question_id bigint not null references questions on delete cascade,
+  queue_id bigint not null references queues on delete cascade,
+  seed bigint,
+  unique (queue_id, question_id, cloze)
+);
@@ -0,0 +1,5 @@
+alter table asked_questions drop column solution_revealed;
+alter table asked_questions drop column hints_revealed;
+
+alter table question_revisions drop column solution;
+alter table question_revisions drop column hints;
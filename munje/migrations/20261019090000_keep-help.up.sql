@@ -0,0 +1,15 @@
+-- Revisions keep the hints and the worked solution, so that reverting puts them back too.  Only
+-- the latest revision is known to have the hints that the question has now.
+alter table question_revisions add column hints text[] not null default '{}';
+alter table question_revisions add column solution text;
+
+update question_revisions r
+  set hints = q.hints, solution = q.solution
+  from questions q
+  where r.question_id = q.id
+    and r.number = (select max(number) from question_revisions where question_id = q.id);
+
+-- How much help has been shown while a question is being asked, which the answer is recorded
+-- with rather than what comes back from the page.
+alter table asked_questions add column hints_revealed integer not null default 0;
+alter table asked_questions add column solution_revealed boolean not null default false;
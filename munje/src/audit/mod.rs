@@ -0,0 +1,3 @@
+mod models;
+pub mod routes;
+pub use models::*;
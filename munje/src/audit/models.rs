@@ -0,0 +1,245 @@
+use actix_web::{
+    http::header::{USER_AGENT, X_FORWARDED_FOR},
+    web::Data,
+    HttpRequest,
+};
+use chrono;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::net::IpAddr;
+
+use crate::{
+    prelude::*,
+    types::{AppState, DateTime},
+};
+
+/// Something that someone did, such as logging in or creating a question.  Events are only ever
+/// added, never changed or deleted.
+#[derive(Serialize, Debug, Clone)]
+pub struct AuditEvent {
+    pub action: String,
+    pub actor_handle: Option<String>,
+    pub actor_id: Option<i64>,
+    pub changes: Value,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub id: i64,
+    pub ip_address: Option<String>,
+    pub target_id: String,
+    pub target_type: String,
+    pub user_agent: Option<String>,
+}
+
+/// Where a request came from, for the events that it leads to.
+#[derive(Debug, Clone, Default)]
+pub struct Context {
+    pub ip_address: String,
+    pub user_agent: Option<String>,
+}
+
+/// An event to record.  Only what is needed to follow what happened goes in `changes`, never
+/// passwords or codes.
+#[derive(Debug, Default)]
+pub struct CreateAuditEvent<'a> {
+    pub action: &'a str,
+    pub actor: Option<&'a User>,
+    pub changes: Option<Value>,
+    pub target_id: String,
+    pub target_type: &'a str,
+}
+
+/// Narrows down the events shown to admins.  `target` is a target type and id separated by a
+/// colon, as in "question:AbC123", or just an id.
+#[derive(Debug, Default)]
+pub struct EventFilter {
+    pub actor: Option<String>,
+    pub target: Option<String>,
+}
+
+impl Context {
+    pub fn from_request(request: &HttpRequest) -> Self {
+        let trusted_proxies = request
+            .app_data::<Data<AppState>>()
+            .map(|state| state.trusted_proxies.as_slice())
+            .unwrap_or_default();
+        Self {
+            ip_address: client_address(request, trusted_proxies)
+                .map_or_else(|| "unknown".to_string(), |ip| ip.to_string()),
+            user_agent: request
+                .headers()
+                .get(USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string),
+        }
+    }
+}
+
+// Anyone can send an X-Forwarded-For header, so it is only believed when the request comes from
+// one of the trusted proxies.  Each proxy adds the address it was reached from to the end, so the
+// client is the last address that is not one of the proxies.
+fn client_address(request: &HttpRequest, trusted_proxies: &[IpAddr]) -> Option<IpAddr> {
+    let peer = request.peer_addr()?.ip();
+    if !trusted_proxies.contains(&peer) {
+        return Some(peer);
+    }
+
+    let forwarded = request
+        .headers()
+        .get_all(X_FORWARDED_FOR)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|address| address.trim().parse::<IpAddr>().ok())
+        .collect::<Vec<_>>();
+    for address in forwarded.into_iter().rev() {
+        match address {
+            Some(ip) if trusted_proxies.contains(&ip) => continue,
+            Some(ip) => return Some(ip),
+            None => break,
+        }
+    }
+    Some(peer)
+}
+
+impl EventFilter {
+    // Splits the target into a type and an id.
+    fn target(&self) -> (Option<&str>, Option<&str>) {
+        match self.target.as_deref().map(str::trim) {
+            None | Some("") => (None, None),
+            Some(target) => match target.split_once(':') {
+                Some((kind, id)) => (Some(kind), Some(id)),
+                None => (None, Some(target)),
+            },
+        }
+    }
+
+    fn actor(&self) -> Option<&str> {
+        self.actor
+            .as_deref()
+            .map(str::trim)
+            .filter(|actor| !actor.is_empty())
+    }
+}
+
+impl AuditEvent {
+    pub async fn create<'e, E>(
+        event: CreateAuditEvent<'_>,
+        context: &Context,
+        db: E,
+    ) -> Result<(), Error>
+    where
+        E: sqlx::PgExecutor<'e>,
+    {
+        sqlx::query!(
+            "insert into audit_events
+                (action, actor_id, actor_handle, target_type, target_id, ip_address, user_agent,
+                 changes)
+             values ($1, $2, $3, $4, $5, $6, $7, $8)",
+            event.action,
+            event.actor.map(|user| user.id),
+            event.actor.map(|user| user.handle.clone()),
+            event.target_type,
+            event.target_id,
+            context.ip_address,
+            context.user_agent,
+            event.changes.unwrap_or_else(|| json!({})),
+        )
+        .execute(db)
+        .await?;
+        Ok(())
+    }
+
+    // The actor is matched on the handle at the time of the event as well as on the account that
+    // has the handle now, so that events from before a change of handle are found either way.
+    pub async fn find(filter: &EventFilter, db: &Pool) -> Result<Vec<Self>, Error> {
+        let (target_type, target_id) = filter.target();
+        let events = sqlx::query_as!(
+            Self,
+            "select * from audit_events
+             where ($1::text is null
+                    or lower(actor_handle) = lower($1)
+                    or actor_id = (select id from users where lower(handle) = lower($1)))
+               and ($2::text is null or target_type = $2)
+               and ($3::text is null or target_id = $3)
+             order by created_at desc, id desc
+             limit 200",
+            filter.actor(),
+            target_type,
+            target_id,
+        )
+        .fetch_all(db)
+        .await?;
+        Ok(events)
+    }
+
+    pub fn happened_at(&self) -> String {
+        self.created_at().humanize()
+    }
+
+    pub fn created_at(&self) -> DateTime {
+        DateTime(self.created_at)
+    }
+
+    pub fn target(&self) -> String {
+        format!("{}:{}", self.target_type, self.target_id)
+    }
+
+    pub fn changes_text(&self) -> String {
+        match &self.changes {
+            Value::Object(map) if map.is_empty() => String::new(),
+            changes => changes.to_string(),
+        }
+    }
+}
+
+/// The fields that differ between two JSON objects, as `{"field": {"from": ..., "to": ...}}`.
+pub fn diff(before: &Value, after: &Value) -> Value {
+    let empty = Map::new();
+    let before = before.as_object().unwrap_or(&empty);
+    let after = after.as_object().unwrap_or(&empty);
+
+    let mut changes = Map::new();
+    for key in before.keys().chain(after.keys()) {
+        let (from, to) = (
+            before.get(key).unwrap_or(&Value::Null),
+            after.get(key).unwrap_or(&Value::Null),
+        );
+        if from != to && !changes.contains_key(key) {
+            changes.insert(key.clone(), json!({ "from": from, "to": to }));
+        }
+    }
+    Value::Object(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_of_changed_fields() {
+        let before = json!({"handle": "frotz", "is_admin": false});
+        let after = json!({"handle": "gnusto", "is_admin": false, "title": "Sorting"});
+        assert_eq!(
+            json!({
+                "handle": {"from": "frotz", "to": "gnusto"},
+                "title": {"from": null, "to": "Sorting"},
+            }),
+            diff(&before, &after)
+        );
+        assert_eq!(json!({}), diff(&before, &before));
+    }
+
+    #[test]
+    fn filter_targets() {
+        let filter = EventFilter {
+            actor: Some(" ".to_string()),
+            target: Some("question:AbC:1".to_string()),
+        };
+        assert_eq!(None, filter.actor());
+        assert_eq!((Some("question"), Some("AbC:1")), filter.target());
+
+        let filter = EventFilter {
+            actor: None,
+            target: Some("42".to_string()),
+        };
+        assert_eq!((None, Some("42")), filter.target());
+    }
+}
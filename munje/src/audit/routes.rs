@@ -0,0 +1,57 @@
+use actix_identity::Identity;
+use actix_web::{get, web};
+use askama::Template;
+use serde::Deserialize;
+
+use crate::{
+    audit::{AuditEvent, EventFilter},
+    prelude::*,
+    types::{CurrentPage, Message},
+};
+
+pub fn register(cfg: &mut web::ServiceConfig) {
+    cfg.service(list);
+}
+
+#[derive(Deserialize)]
+struct ListQuery {
+    actor: Option<String>,
+    target: Option<String>,
+}
+
+#[derive(Template)]
+#[template(path = "audit/events.jinja")]
+struct List {
+    messages: Vec<Message>,
+    events: Vec<AuditEvent>,
+    actor: String,
+    target: String,
+    page: CurrentPage,
+}
+
+#[get("/admin/audit-events")]
+async fn list(
+    query: web::Query<ListQuery>,
+    request: HttpRequest,
+    id: Identity,
+) -> Result<HttpResponse, Error> {
+    let db = request.db()?;
+    let user = auth::admin(&id, db).await?;
+    let query = query.into_inner();
+    let filter = EventFilter {
+        actor: query.actor,
+        target: query.target,
+    };
+    let events = AuditEvent::find(&filter, db).await?;
+
+    let s = List {
+        messages: Message::none(),
+        events,
+        actor: filter.actor.unwrap_or_default(),
+        target: filter.target.unwrap_or_default(),
+        page: CurrentPage::new(&request, "/admin/audit-events", user),
+    }
+    .render()
+    .unwrap();
+    Ok(HttpResponse::Ok().content_type("text/html").body(s))
+}
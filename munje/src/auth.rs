@@ -1,19 +1,121 @@
 use actix_identity::Identity;
+use serde::{Deserialize, Serialize};
 
 use crate::prelude::*;
 
+/// Remembered in place of the user between the password and second-factor steps of logging in.
+/// Anyone holding a pending login is treated as a guest until the second step succeeds.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PendingLogin {
+    pub pending_user_id: i64,
+    pub started_at: DateTime,
+    // Whether "Remember me" was checked at the first step.
+    #[serde(default)]
+    pub remember_me: bool,
+}
+
+impl PendingLogin {
+    pub fn new(user: &User, remember_me: bool) -> Self {
+        Self {
+            pending_user_id: user.id,
+            started_at: DateTime::now(),
+            remember_me,
+        }
+    }
+
+    pub fn is_expired(&self, now: DateTime) -> bool {
+        now - self.started_at > chrono::Duration::minutes(5)
+    }
+}
+
+fn is_pending(string: &str) -> bool {
+    serde_json::from_str::<PendingLogin>(string).is_ok()
+}
+
 pub fn user(id: &Identity) -> Result<User, Error> {
+    let user = user_or_temporary(id)?;
+    if user.is_temporary {
+        return Err(Error::Unauthorized);
+    }
+    Ok(user)
+}
+
+/// The current user, or the temporary user of a guest who has started practicing.
+pub fn user_or_temporary(id: &Identity) -> Result<User, Error> {
     let string = id.identity().ok_or(Error::Unauthorized)?;
+    if is_pending(&string) {
+        return Err(Error::Unauthorized);
+    }
+
     serde_json::from_str(&string).map_err(|e| {
         error!("Unable to deserialize user: {:?}", e);
         Error::Unauthorized
     })
 }
 
+/// Like `user_or_temporary`, except that a guest without a temporary user is given one.
+pub async fn user_or_new_temporary(id: &Identity, db: &Pool) -> Result<User, Error> {
+    if let Ok(user) = user_or_temporary(id) {
+        return Ok(user);
+    }
+
+    let user = User::create_temporary(db).await?;
+    id.remember(serde_json::to_string(&user)?);
+    Ok(user)
+}
+
+/// The session only says who the user was when they logged in, so whether they are still an admin
+/// is looked up again.
+pub async fn admin(id: &Identity, db: &Pool) -> Result<User, Error> {
+    let user = user(id)?;
+    let user = User::find_by_id(user.id, db)
+        .await
+        .map_err(|_| Error::Unauthorized)?;
+    if !user.is_admin {
+        return Err(Error::Unauthorized);
+    }
+    Ok(user)
+}
+
 pub fn user_or_guest(id: &Identity) -> Result<User, Error> {
     let user = match id.identity() {
-        Some(string) => serde_json::from_str(&string)?,
-        None => User::guest(),
+        Some(string) if !is_pending(&string) => serde_json::from_str(&string)?,
+        _ => User::guest(),
     };
     Ok(user)
 }
+
+pub fn is_authenticated(id: &Identity) -> bool {
+    user(id).is_ok()
+}
+
+pub fn pending_login(id: &Identity) -> Result<PendingLogin, Error> {
+    let string = id.identity().ok_or(Error::Unauthorized)?;
+    let pending: PendingLogin = serde_json::from_str(&string).map_err(|_| Error::Unauthorized)?;
+    if pending.is_expired(DateTime::now()) {
+        return Err(Error::Unauthorized);
+    }
+    Ok(pending)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pending_login_expires() {
+        let pending = PendingLogin::new(&User::default(), false);
+        let now = pending.started_at;
+        assert!(!pending.is_expired(now + chrono::Duration::minutes(4)));
+        assert!(pending.is_expired(now + chrono::Duration::minutes(6)));
+    }
+
+    #[test]
+    fn user_is_not_pending() {
+        let string = serde_json::to_string(&User::default()).unwrap();
+        assert!(!is_pending(&string));
+
+        let string = serde_json::to_string(&PendingLogin::new(&User::default(), false)).unwrap();
+        assert!(is_pending(&string));
+    }
+}
@@ -0,0 +1,249 @@
+use actix_http::h1;
+use actix_web::{
+    body::EitherBody,
+    cookie::{Cookie, CookieJar, Key, SameSite},
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::Method,
+    web, HttpMessage, HttpRequest,
+};
+use std::{
+    future::{ready, Future, Ready},
+    pin::Pin,
+    rc::Rc,
+};
+
+use crate::error::Error;
+
+/// Protects against cross-site request forgery using the double-submit pattern.  Each browser is
+/// given a random token in an encrypted cookie, and every POST must send the same token back,
+/// either in a `csrf_token` form field or in an `X-CSRF-Token` header.  A page on another site can
+/// make the browser send the cookie, but it cannot read the token to put in the form.
+///
+/// Templates embed the token with `{{ page.csrf_field()|safe }}`.
+///
+/// The cookie is encrypted with the first of the session keys and can be opened with any of them,
+/// as with `SessionPolicy`.  A cookie opened with an older key is encrypted again with the first.
+#[derive(Clone)]
+pub struct CsrfProtection {
+    keys: Vec<Key>,
+}
+
+/// The token for the current request, for use in templates.
+#[derive(Clone, Debug)]
+struct CsrfToken(String);
+
+pub struct CsrfMiddleware<S> {
+    service: Rc<S>,
+    keys: Vec<Key>,
+}
+
+impl CsrfProtection {
+    pub const COOKIE_NAME: &'static str = "csrf-token";
+    pub const FIELD_NAME: &'static str = "csrf_token";
+    pub const HEADER_NAME: &'static str = "x-csrf-token";
+
+    pub fn new(session_keys: &[impl AsRef<[u8]>]) -> Self {
+        assert!(
+            !session_keys.is_empty(),
+            "At least one session key is needed"
+        );
+        Self {
+            keys: session_keys
+                .iter()
+                .map(|key| Key::derive_from(key.as_ref()))
+                .collect(),
+        }
+    }
+
+    /// Returns a new token along with the cookie that goes with it.
+    pub fn issue(&self) -> (String, Cookie<'static>) {
+        let token = generate_token();
+        let cookie = seal(&self.keys[0], &token);
+        (token, cookie)
+    }
+}
+
+/// The token that forms on the current page should include.  Empty when the middleware is not
+/// installed, in which case the form will be rejected.
+pub fn token(request: &HttpRequest) -> String {
+    request
+        .extensions()
+        .get::<CsrfToken>()
+        .map(|token| token.0.clone())
+        .unwrap_or_default()
+}
+
+impl<S, B> Transform<S, ServiceRequest> for CsrfProtection
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = CsrfMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(CsrfMiddleware {
+            service: Rc::new(service),
+            keys: self.keys.clone(),
+        }))
+    }
+}
+
+impl<S, B> Service<ServiceRequest> for CsrfMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut request: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let keys = self.keys.clone();
+
+        Box::pin(async move {
+            // Along with the position of the key that opened the cookie.
+            let opened = request
+                .cookie(CsrfProtection::COOKIE_NAME)
+                .and_then(|cookie| open(&keys, cookie));
+            let existing = opened.as_ref().map(|(token, _)| token.clone());
+
+            if requires_token(request.method()) {
+                let submitted = submitted_token(&mut request).await?;
+                let valid = matches!(
+                    (&existing, &submitted),
+                    (Some(existing), Some(submitted)) if existing == submitted
+                );
+                if !valid {
+                    warn!(
+                        "Rejecting {} {} without a valid CSRF token",
+                        request.method(),
+                        request.path()
+                    );
+                    return Ok(request
+                        .error_response(Error::InvalidCsrfToken)
+                        .map_into_right_body());
+                }
+            }
+
+            let token = existing.clone().unwrap_or_else(generate_token);
+            request.extensions_mut().insert(CsrfToken(token.clone()));
+
+            let mut response = service.call(request).await?;
+            if !matches!(opened, Some((_, 0))) {
+                response
+                    .response_mut()
+                    .add_cookie(&seal(&keys[0], &token))?;
+            }
+            Ok(response.map_into_left_body())
+        })
+    }
+}
+
+fn requires_token(method: &Method) -> bool {
+    !matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
+
+// The body has to be read to find the form field, so it is put back afterwards for the handler.
+async fn submitted_token(request: &mut ServiceRequest) -> Result<Option<String>, actix_web::Error> {
+    if let Some(value) = request.headers().get(CsrfProtection::HEADER_NAME) {
+        return Ok(value.to_str().ok().map(str::to_string));
+    }
+
+    if request.content_type() != "application/x-www-form-urlencoded" {
+        return Ok(None);
+    }
+
+    let body = request.extract::<web::Bytes>().await?;
+    let token = url::form_urlencoded::parse(&body)
+        .find(|(name, _)| name == CsrfProtection::FIELD_NAME)
+        .map(|(_, value)| value.into_owned());
+
+    let (_, mut payload) = h1::Payload::create(true);
+    payload.unread_data(body);
+    request.set_payload(payload.into());
+
+    Ok(token)
+}
+
+fn generate_token() -> String {
+    use rand::Rng;
+    let bytes: [u8; 32] = rand::thread_rng().gen();
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// The cookie is encrypted so that a token planted by a sibling subdomain, which can set cookies
+// but does not know the key, is not accepted.
+fn seal(key: &Key, token: &str) -> Cookie<'static> {
+    let mut jar = CookieJar::new();
+    jar.private_mut(key).add(
+        Cookie::build(CsrfProtection::COOKIE_NAME, token.to_string())
+            .path("/")
+            .same_site(SameSite::Strict)
+            .http_only(true)
+            .secure(true)
+            .finish(),
+    );
+    jar.get(CsrfProtection::COOKIE_NAME).unwrap().clone()
+}
+
+fn open(keys: &[Key], cookie: Cookie<'static>) -> Option<(String, usize)> {
+    let mut jar = CookieJar::new();
+    jar.add_original(cookie);
+    keys.iter().enumerate().find_map(|(i, key)| {
+        let cookie = jar.private(key).get(CsrfProtection::COOKIE_NAME)?;
+        Some((cookie.value().to_string(), i))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SESSION_KEY: &[u8] = b"0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn cookie_round_trip() {
+        let csrf = CsrfProtection::new(&[SESSION_KEY]);
+        let (token, cookie) = csrf.issue();
+        assert_eq!(64, token.len());
+        assert!(!cookie.value().contains(&token));
+        assert_eq!(Some((token, 0)), open(&csrf.keys, cookie));
+    }
+
+    #[test]
+    fn cookie_from_another_key() {
+        let (_, cookie) = CsrfProtection::new(&[SESSION_KEY]).issue();
+        let other = CsrfProtection::new(&[[b'x'; 64]]);
+        assert_eq!(None, open(&other.keys, cookie));
+    }
+
+    #[test]
+    fn cookie_from_older_key() {
+        let (token, cookie) = CsrfProtection::new(&[SESSION_KEY]).issue();
+        let rotated = CsrfProtection::new(&[&[b'x'; 64][..], SESSION_KEY]);
+        assert_eq!(Some((token, 1)), open(&rotated.keys, cookie));
+    }
+
+    #[test]
+    fn unencrypted_cookie() {
+        let csrf = CsrfProtection::new(&[SESSION_KEY]);
+        let cookie = Cookie::new(CsrfProtection::COOKIE_NAME, generate_token());
+        assert_eq!(None, open(&csrf.keys, cookie));
+    }
+
+    #[test]
+    fn safe_methods() {
+        assert!(!requires_token(&Method::GET));
+        assert!(!requires_token(&Method::HEAD));
+        assert!(requires_token(&Method::POST));
+        assert!(requires_token(&Method::DELETE));
+    }
+}
@@ -13,11 +13,20 @@ pub enum Error {
     Database(sqlx::Error),
     FetchPageError(reqwest::Error),
     Generic(String),
+    HandleTaken,
     HashPasswordError(argon2::Error),
+    IdTokenError(jsonwebtoken::errors::Error),
+    InvalidCode,
+    InvalidCsrfToken,
+    InvalidInvite,
     InvalidPassword,
+    InvalidRevision(String),
     Json(serde_json::error::Error),
     MigrationError(sqlx::migrate::MigrateError),
+    NotFound,
+    OidcError(String),
     ParseUrlError(url::ParseError),
+    TooManyLoginAttempts(DateTime),
     Unauthorized,
 }
 
@@ -36,11 +45,20 @@ impl error::Error for Error {
             Self::Database(e) => Some(e),
             Self::FetchPageError(e) => Some(e),
             Self::Generic(_) => None,
+            Self::HandleTaken => None,
             Self::HashPasswordError(e) => Some(e),
+            Self::IdTokenError(e) => Some(e),
+            Self::InvalidCode => None,
+            Self::InvalidCsrfToken => None,
+            Self::InvalidInvite => None,
             Self::InvalidPassword => None,
+            Self::InvalidRevision(_) => None,
             Self::Json(e) => Some(e),
             Self::MigrationError(e) => Some(e),
+            Self::NotFound => None,
+            Self::OidcError(_) => None,
             Self::ParseUrlError(e) => Some(e),
+            Self::TooManyLoginAttempts(_) => None,
             Self::Unauthorized => None,
         }
     }
@@ -58,6 +76,12 @@ impl From<actix_web::Error> for Error {
     }
 }
 
+impl From<actix_web::error::BlockingError> for Error {
+    fn from(e: actix_web::error::BlockingError) -> Self {
+        Self::ActixWeb(e.into())
+    }
+}
+
 impl From<sqlx::Error> for Error {
     fn from(e: sqlx::Error) -> Self {
         Self::Database(e)
@@ -88,6 +112,12 @@ impl From<sqlx::migrate::MigrateError> for Error {
     }
 }
 
+impl From<jsonwebtoken::errors::Error> for Error {
+    fn from(e: jsonwebtoken::errors::Error) -> Self {
+        Self::IdTokenError(e)
+    }
+}
+
 impl From<url::ParseError> for Error {
     fn from(e: url::ParseError) -> Self {
         Self::ParseUrlError(e)
@@ -111,7 +141,7 @@ struct NotFound {
 impl ResponseError for Error {
     fn error_response(&self) -> HttpResponse {
         match self {
-            Self::Database(sqlx::Error::RowNotFound) => {
+            Self::Database(sqlx::Error::RowNotFound) | Self::NotFound => {
                 let s = NotFound {
                     messages: Vec::new(),
                     page: CurrentPage::from("/", User::guest()),
@@ -123,6 +153,18 @@ impl ResponseError for Error {
                     .body(s)
             }
 
+            Self::InvalidCsrfToken => HttpResponse::Forbidden()
+                .content_type("text/html; charset=utf-8")
+                .body("The form has expired.  Go back, reload the page and try again."),
+
+            Self::TooManyLoginAttempts(_) => HttpResponse::TooManyRequests()
+                .content_type("text/html; charset=utf-8")
+                .body("Too many failed login attempts"),
+
+            Self::InvalidRevision(message) => HttpResponse::BadRequest()
+                .content_type("text/html; charset=utf-8")
+                .body(format!("The revision cannot be put back: {}", message)),
+
             Self::Unauthorized => HttpResponse::Unauthorized()
                 .content_type("text/html; charset=utf-8")
                 .body("You don't have the necessary privileges"),
@@ -1,4 +1,5 @@
 mod password;
+pub mod strength;
 mod text;
 use crate::types::Message;
 pub use password::PasswordField;
@@ -1,19 +1,45 @@
 use serde::{Deserialize, Deserializer, Serialize};
 
-use crate::{forms::Validate, types::Message};
+use crate::{
+    forms::{strength, strength::Strength, Validate},
+    types::Message,
+};
 
 #[derive(Debug, Default, Serialize)]
 pub struct PasswordField {
     pub value: String,
     pub errors: Vec<String>,
+    #[serde(skip)]
+    pub strength: Option<Strength>,
 }
 
 impl PasswordField {
+    // "Fair" on the strength meter.
+    pub const MIN_SCORE: u8 = 2;
+
     pub fn new(value: &str) -> Self {
         Self {
             value: value.to_string(),
             errors: Vec::new(),
+            strength: None,
+        }
+    }
+
+    /// For new passwords, on top of `validate`.  Passwords that would be guessed quickly are
+    /// rejected, and the estimate is kept so that suggestions can be shown along with the form.
+    /// `user_inputs` are things like the handle, which should not appear in the password.
+    pub fn validate_strength(&mut self, user_inputs: &[&str]) -> bool {
+        let strength = strength::estimate(&self.value, user_inputs);
+        let valid = strength.score >= Self::MIN_SCORE;
+        if !valid {
+            let error = strength
+                .warning
+                .clone()
+                .unwrap_or_else(|| "Password is too easy to guess".to_string());
+            self.errors.push(error);
         }
+        self.strength = Some(strength);
+        valid
     }
 }
 
@@ -38,12 +64,15 @@ impl Validate for PasswordField {
     }
 
     fn messages(&self) -> Vec<Message> {
+        let suggestions = self
+            .strength
+            .iter()
+            .flat_map(|strength| strength.suggestions.iter());
+
         self.errors
             .iter()
-            .map(|string| Message {
-                content: string.clone(),
-                level: "error".to_string(),
-            })
+            .map(|string| Message::new(string, "error"))
+            .chain(suggestions.map(|string| Message::new(string, "warning")))
             .collect()
     }
 }
@@ -53,6 +82,7 @@ impl Clone for PasswordField {
         Self {
             value: self.value.clone(),
             errors: self.errors.clone(),
+            strength: self.strength.clone(),
         }
     }
 }
@@ -65,6 +95,7 @@ impl<'de> Deserialize<'de> for PasswordField {
         Deserialize::deserialize(deserializer).map(|t| PasswordField {
             value: t,
             errors: Vec::new(),
+            strength: None,
         })
     }
 }
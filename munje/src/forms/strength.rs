@@ -0,0 +1,309 @@
+use serde::Serialize;
+
+// A few hundred of the passwords that turn up most often in leaked password lists.  Guessing
+// attacks try these first, whatever the rest of the password looks like.
+const COMMON_PASSWORDS: &str = include_str!("common-passwords.txt");
+
+const KEYBOARD_ROWS: &[&str] = &[
+    "`1234567890-=",
+    "qwertyuiop[]\\",
+    "asdfghjkl;'",
+    "zxcvbnm,./",
+];
+
+/// A rough estimate of how long a password would hold up against guessing, on a scale from 0
+/// (guessed almost at once) to 4 (not worth trying), along with feedback on how to improve it.
+/// It looks for common passwords, the user's own handle, and runs like "qwerty", "abcd" and
+/// "aaaa", and otherwise counts the bits it would take to guess the rest.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Strength {
+    pub score: u8,
+    pub warning: Option<String>,
+    pub suggestions: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Pattern {
+    Keyboard,
+    Repeat,
+    Sequence,
+}
+
+impl Strength {
+    pub const MAX_SCORE: u8 = 4;
+
+    pub fn label(&self) -> &'static str {
+        match self.score {
+            0 => "Very weak",
+            1 => "Weak",
+            2 => "Fair",
+            3 => "Strong",
+            _ => "Very strong",
+        }
+    }
+
+    // Bulma colour for the meter.
+    pub fn color(&self) -> &'static str {
+        match self.score {
+            0 | 1 => "is-danger",
+            2 => "is-warning",
+            _ => "is-success",
+        }
+    }
+}
+
+/// Estimates the strength of `password`.  `user_inputs` are words that someone trying to guess
+/// the password would know, such as the handle that goes with it.
+pub fn estimate(password: &str, user_inputs: &[&str]) -> Strength {
+    let lower = password.to_lowercase();
+    let plain: String = lower.chars().map(unleet).collect();
+    // Digits and symbols tacked onto either end, as in "Password1!", add very little.
+    let stripped: String = lower
+        .trim_matches(|c: char| !c.is_alphabetic())
+        .chars()
+        .map(unleet)
+        .collect();
+
+    let mut warning = None;
+    let mut suggestions = vec![];
+    let mut cap = Strength::MAX_SCORE;
+
+    if is_common(&lower) {
+        warning = Some("This is one of the most common passwords".to_string());
+        cap = 0;
+    } else if is_common(&plain) || (stripped.chars().count() >= 4 && is_common(&stripped)) {
+        warning = Some("This is very close to a common password".to_string());
+        cap = 0;
+    } else if contains_user_input(&plain, &stripped, user_inputs) {
+        warning = Some("Passwords with your username in them are easy to guess".to_string());
+        cap = 1;
+    }
+
+    let (bits, patterns) = entropy_bits(password);
+    if warning.is_none() {
+        warning = match patterns.first() {
+            Some(Pattern::Keyboard) => Some("Rows of keys like \"qwerty\" are easy to guess"),
+            Some(Pattern::Sequence) => {
+                Some("Sequences like \"abcd\" or \"6543\" are easy to guess")
+            }
+            Some(Pattern::Repeat) => Some("Repeated characters like \"aaaa\" are easy to guess"),
+            None => None,
+        }
+        .map(str::to_string);
+    }
+
+    let score = match bits {
+        b if b < 28.0 => 0,
+        b if b < 36.0 => 1,
+        b if b < 50.0 => 2,
+        b if b < 64.0 => 3,
+        _ => 4,
+    }
+    .min(cap);
+
+    if score < 3 {
+        if password.chars().count() < 12 {
+            suggestions.push("Use a longer password.  A few unrelated words work well".to_string());
+        }
+        if !patterns.is_empty() {
+            suggestions.push("Avoid sequences and rows of keys".to_string());
+        }
+        if cap < Strength::MAX_SCORE {
+            suggestions.push("Avoid common passwords and your own name".to_string());
+        }
+    }
+
+    Strength {
+        score,
+        warning,
+        suggestions,
+    }
+}
+
+fn is_common(password: &str) -> bool {
+    COMMON_PASSWORDS.lines().any(|line| line == password)
+}
+
+fn contains_user_input(password: &str, stripped: &str, user_inputs: &[&str]) -> bool {
+    user_inputs
+        .iter()
+        .map(|input| input.to_lowercase())
+        .filter(|input| input.chars().count() >= 3)
+        .any(|input| {
+            password.contains(&input) || (stripped.chars().count() >= 4 && input.contains(stripped))
+        })
+}
+
+// Undoes the usual letter-for-symbol swaps, so that "p@ssw0rd" is treated like "password".
+fn unleet(c: char) -> char {
+    match c {
+        '4' | '@' => 'a',
+        '8' => 'b',
+        '3' => 'e',
+        '6' | '9' => 'g',
+        '1' | '!' | '|' => 'i',
+        '0' => 'o',
+        '5' | '$' => 's',
+        '7' | '+' => 't',
+        '2' => 'z',
+        c => c,
+    }
+}
+
+// The number of bits needed to guess the password by brute force over the kinds of characters
+// that it uses, except that each character that continues a pattern only counts for one bit.
+// Returns the patterns that were found as well, most common first.
+fn entropy_bits(password: &str) -> (f64, Vec<Pattern>) {
+    let chars: Vec<char> = password.chars().collect();
+    let pool: f64 = [
+        (chars.iter().any(|c| c.is_ascii_lowercase()), 26.0),
+        (chars.iter().any(|c| c.is_ascii_uppercase()), 26.0),
+        (chars.iter().any(|c| c.is_ascii_digit()), 10.0),
+        (
+            chars
+                .iter()
+                .any(|c| c.is_ascii() && !c.is_ascii_alphanumeric()),
+            33.0,
+        ),
+        (chars.iter().any(|c| !c.is_ascii()), 100.0),
+    ]
+    .iter()
+    .filter(|(present, _)| *present)
+    .map(|(_, size)| size)
+    .sum();
+
+    if chars.is_empty() {
+        return (0.0, vec![]);
+    }
+
+    let mut patterns = vec![];
+    let mut bits = pool.log2();
+    let mut run: Option<(Pattern, i32)> = None;
+    let mut run_length = 1;
+
+    for pair in chars.windows(2) {
+        let step = continues(pair[0], pair[1]);
+        let continued = match (&run, &step) {
+            (Some((pattern, direction)), Some((next, next_direction))) => {
+                pattern == next && direction == next_direction
+            }
+            _ => false,
+        };
+
+        if continued {
+            run_length += 1;
+        } else {
+            run = step;
+            run_length = if run.is_some() { 2 } else { 1 };
+        }
+
+        // Two characters in a row could be chance.  From the third on it is a pattern, and the
+        // second character is marked down to one bit as well.
+        if run_length >= 3 {
+            bits += 1.0;
+            if run_length == 3 {
+                bits -= pool.log2() - 1.0;
+                let (pattern, _) = run.unwrap();
+                if !patterns.contains(&pattern) {
+                    patterns.push(pattern);
+                }
+            }
+        } else {
+            bits += pool.log2();
+        }
+    }
+
+    (bits.max(0.0), patterns)
+}
+
+// Whether `b` follows on from `a` as part of a pattern, and in which direction.
+fn continues(a: char, b: char) -> Option<(Pattern, i32)> {
+    let (a, b) = (a.to_ascii_lowercase(), b.to_ascii_lowercase());
+    if a == b {
+        return Some((Pattern::Repeat, 0));
+    }
+
+    let difference = b as i64 - a as i64;
+    if a.is_alphanumeric() && b.is_alphanumeric() && difference.abs() == 1 {
+        return Some((Pattern::Sequence, difference as i32));
+    }
+
+    for row in KEYBOARD_ROWS {
+        if let (Some(i), Some(j)) = (row.find(a), row.find(b)) {
+            let difference = j as i64 - i as i64;
+            if difference.abs() == 1 {
+                return Some((Pattern::Keyboard, difference as i32));
+            }
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn common_passwords() {
+        assert_eq!(0, estimate("password", &[]).score);
+        assert_eq!(0, estimate("Password1", &[]).score);
+        assert_eq!(0, estimate("P@ssw0rd!", &[]).score);
+        assert_eq!(
+            Some("This is one of the most common passwords".to_string()),
+            estimate("letmein", &[]).warning
+        );
+    }
+
+    #[test]
+    fn handle_in_password() {
+        let strength = estimate("gnusto1984!", &["gnusto"]);
+        assert!(strength.score <= 1);
+        assert_eq!(
+            Some("Passwords with your username in them are easy to guess".to_string()),
+            strength.warning
+        );
+        assert!(estimate("gnusto1984!", &[]).score >= 2);
+    }
+
+    #[test]
+    fn keyboard_patterns() {
+        let strength = estimate("qwertyuiop", &[]);
+        assert_eq!(0, strength.score);
+
+        let strength = estimate("zxcvbnmasdf", &[]);
+        assert!(strength.score <= 1);
+        assert_eq!(
+            Some("Rows of keys like \"qwerty\" are easy to guess".to_string()),
+            strength.warning
+        );
+    }
+
+    #[test]
+    fn sequences_and_repeats() {
+        assert_eq!(0, estimate("abcdefghij", &[]).score);
+        assert_eq!(0, estimate("98765432", &[]).score);
+        assert_eq!(0, estimate("aaaaaaaaaa", &[]).score);
+        assert_eq!(
+            Some("Repeated characters like \"aaaa\" are easy to guess".to_string()),
+            estimate("zzzzzzzzzz", &[]).warning
+        );
+    }
+
+    #[test]
+    fn strong_passwords() {
+        assert_eq!(4, estimate("correct horse battery staple", &[]).score);
+        assert_eq!(4, estimate("Tr0ub4dor&3x", &[]).score);
+        assert!(estimate("kj7Hq9zP", &[]).score >= 2);
+        assert!(estimate("kj7Hq9zP", &[]).suggestions.len() <= 1);
+    }
+
+    #[test]
+    fn labels() {
+        assert_eq!("Very weak", estimate("", &[]).label());
+        assert_eq!(
+            "Very strong",
+            estimate("plover lantern brass 58", &[]).label()
+        );
+    }
+}
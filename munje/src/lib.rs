@@ -2,7 +2,9 @@
 extern crate log;
 extern crate base64;
 
+pub mod audit;
 pub mod auth;
+pub mod csrf;
 pub mod error;
 pub mod forms;
 mod models;
@@ -13,5 +15,6 @@ pub mod questions;
 pub mod queues;
 mod requests;
 pub mod routes;
+pub mod session;
 pub mod types;
 pub mod users;
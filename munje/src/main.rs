@@ -1,42 +1,66 @@
 #[macro_use]
 extern crate log;
 
-use actix_identity::{CookieIdentityPolicy, IdentityService};
-use actix_web::{cookie::SameSite, middleware, web, App, HttpServer};
+use actix_identity::IdentityService;
+use actix_web::{middleware, web, App, HttpServer};
 use anyhow::Result;
 use sqlx::postgres::PgPoolOptions;
-use time::Duration;
 
 use munje::{
+    audit,
+    csrf::CsrfProtection,
     questions, queues, routes,
-    types::{AppState, Config},
-    users,
+    session::{self, SessionDurations, SessionPolicy},
+    types::{AppState, Clock, Config},
+    users::{self, oidc, HashParams, LoadPreferences},
 };
 
 #[actix_web::main]
 async fn main() -> Result<()> {
+    // `munje generate-key` prints a key to add to the front of SESSION_KEY.
+    if std::env::args().nth(1).as_deref() == Some("generate-key") {
+        println!("{}", session::generate_key());
+        return Ok(());
+    }
+
     let config = Config::load()?;
     env_logger::init();
 
     info!("Connecting to database: {}", &config.database_url);
     let db = PgPoolOptions::new().connect(&config.database_url).await?;
+    users::User::fill_handle_skeletons(&db)
+        .await
+        .map_err(|e| anyhow::anyhow!("Unable to fill in handle skeletons: {}", e))?;
+    let oidc = oidc::Client::from_config(&config);
+    let password_hashing = HashParams::from_config(&config);
+    let session_durations = SessionDurations::from_config(&config);
+    if let Some(client) = &oidc {
+        info!("Logging in with {} is enabled", client.provider_name);
+    }
 
     let server = HttpServer::new(move || {
         App::new()
-            .app_data(web::Data::new(AppState { db: db.clone() }))
+            .app_data(web::Data::new(AppState {
+                clock: Clock::System,
+                db: db.clone(),
+                invite_only: config.invite_only,
+                oidc: oidc.clone(),
+                password_hashing,
+                trusted_proxies: config.trusted_proxies(),
+            }))
             .wrap(middleware::Logger::default())
             .wrap(middleware::NormalizePath::trim())
-            .wrap(IdentityService::new(
-                CookieIdentityPolicy::new(config.session_key.as_bytes())
-                    .name("auth-cookie")
-                    .max_age(Duration::days(356))
-                    .same_site(SameSite::Strict)
-                    .secure(true),
-            ))
+            .wrap(LoadPreferences::new(&config.session_keys))
+            .wrap(CsrfProtection::new(&config.session_keys))
+            .wrap(IdentityService::new(SessionPolicy::new(
+                &config.session_keys,
+                session_durations,
+            )))
             .configure(routes::register)
             .configure(users::routes::register)
             .configure(questions::routes::register)
             .configure(queues::routes::register)
+            .configure(audit::routes::register)
     })
     .bind("0.0.0.0:8080")?;
 
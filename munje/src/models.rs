@@ -2,7 +2,7 @@ use anyhow::Result;
 use async_trait::async_trait;
 use sqlx::postgres::PgRow;
 
-use crate::types::{Id, Pool};
+use crate::types::Id;
 
 pub struct UpsertResult<T> {
     pub record: T,
@@ -11,7 +11,10 @@ pub struct UpsertResult<T> {
 
 #[async_trait]
 pub trait Creatable {
-    async fn next_id(sequence_name: &str, db: &Pool) -> Result<Id> {
+    async fn next_id<'e, E>(sequence_name: &str, db: E) -> Result<Id>
+    where
+        E: sqlx::PgExecutor<'e>,
+    {
         use sqlx::Row;
 
         let query = format!("select nextval('{}') id", sequence_name);
@@ -1,52 +1,112 @@
 use actix_identity::Identity;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use std::convert::identity;
 
 use crate::{
+    audit::{self, AuditEvent, CreateAuditEvent},
+    auth::PendingLogin,
     forms::{PasswordField, TextField, Validate},
-    models::UpsertResult,
     prelude::*,
-    questions::Question,
-    queues::{Answer, CreateAnswer, LastAnswer, Queue},
+    questions::{
+        kinds::{self, SubmittedAnswer},
+        parse_hints, CreateOption, CreateQuestion, NumericAnswer, Question, QuestionKind,
+        QuestionTemplate, UpdateQuestion,
+    },
+    queues::{Answer, AskedQuestion, CreateAnswer, LastAnswer, Queue},
+    routes,
+    types::Markdown,
+    users::{self, oidc::ExternalIdentity, HashParams, Invite, LoginAttempt, UserPreferences},
 };
 
+// The handle is normalized in place, so that what is checked here is what ends up stored.
+fn validate_handle(handle: &mut TextField) -> bool {
+    handle.value = users::handle::normalize(&handle.value);
+    let mut valid = vec![handle.validate()];
+
+    let length = handle.value.chars().count();
+    if length < 3 {
+        handle
+            .errors
+            .push("Username must have at least three characters".to_string());
+        valid.push(false);
+    } else if length > 30 {
+        handle
+            .errors
+            .push("Username cannot have more than 30 characters".to_string());
+        valid.push(false);
+    }
+
+    if handle.value.contains(char::is_whitespace) {
+        handle
+            .errors
+            .push("Username cannot have spaces".to_string());
+        valid.push(false);
+    } else if !users::handle::has_allowed_characters(&handle.value) {
+        handle.errors.push(
+            "Username can only have letters, numbers, dashes, underscores and periods".to_string(),
+        );
+        valid.push(false);
+    } else if !users::handle::is_single_script(&handle.value) {
+        handle
+            .errors
+            .push("Username cannot mix letters from different alphabets".to_string());
+        valid.push(false);
+    }
+
+    if routes::is_reserved_handle(&handle.value) {
+        handle.errors.push("Username is reserved".to_string());
+        valid.push(false);
+    }
+
+    valid.into_iter().all(identity)
+}
+
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub struct RegisterUser {
     pub handle: TextField,
+    #[serde(default)]
+    pub invite_code: TextField,
     pub password: PasswordField,
     pub password_confirmation: PasswordField,
     is_valid: Option<bool>,
 }
 
 impl RegisterUser {
-    pub async fn call(&self, id: &Identity, db: &Pool) -> Result<UpsertResult<User>, Error> {
+    // A taken handle is an error rather than a login, so that signing up with someone else's
+    // handle does not log you in as them.
+    pub async fn call(
+        &self,
+        invite_only: bool,
+        params: HashParams,
+        context: &audit::Context,
+        id: &Identity,
+        db: &Pool,
+    ) -> Result<User, Error> {
         debug_assert_eq!(Some(true), self.is_valid);
-
-        let result = match User::find_by_handle(&self.handle.value, db).await {
-            Ok(user) => UpsertResult {
-                record: user,
-                created: false,
+        let temporary = auth::user_or_temporary(id).ok();
+        let user = User::register(self, temporary.as_ref(), invite_only, params, db).await?;
+        AuditEvent::create(
+            CreateAuditEvent {
+                action: "user.register",
+                actor: Some(&user),
+                target_type: "user",
+                target_id: user.id.to_string(),
+                changes: temporary.map(|temporary| json!({ "claimed": temporary.handle })),
             },
-
-            Err(Error::Database(sqlx::Error::RowNotFound)) => {
-                let user = User::register(self, db).await?;
-                UpsertResult {
-                    record: user,
-                    created: true,
-                }
-            }
-
-            Err(error) => return Err(error),
-        };
-
-        let string = serde_json::to_string(&result.record)?;
+            context,
+            db,
+        )
+        .await?;
+        let string = serde_json::to_string(&user)?;
         id.remember(string);
-        Ok(result)
+        Ok(user)
     }
 
     pub fn new(handle: &str, password: &str, password_confirmation: &str) -> Self {
         Self {
             handle: TextField::new(handle),
+            invite_code: TextField::default(),
             password: PasswordField::new(password),
             password_confirmation: PasswordField::new(password_confirmation),
             is_valid: None,
@@ -59,32 +119,542 @@ impl RegisterUser {
         }
 
         let mut valid = vec![
-            self.handle.validate(),
-            self.password.validate(),
+            validate_handle(&mut self.handle),
+            self.password.validate() && self.password.validate_strength(&[&self.handle.value]),
             self.password_confirmation.validate(),
         ];
 
-        if self.handle.value.len() < 3 {
-            self.handle
+        if self.password.value != self.password_confirmation.value {
+            self.password_confirmation
                 .errors
-                .push("Username must have at least three characters".to_string());
+                .push("Passwords do not match".to_string());
             valid.push(false);
         }
 
-        if self.handle.value.contains(char::is_whitespace) {
+        let valid = valid.into_iter().all(identity);
+        self.is_valid = Some(valid);
+        valid
+    }
+}
+
+async fn complete_login(
+    user: &User,
+    method: &str,
+    context: &audit::Context,
+    id: &Identity,
+    db: &Pool,
+) -> Result<(), Error> {
+    LoginAttempt::create(&user.handle, &context.ip_address, true, db).await?;
+    User::update_last_login(user.id, db).await?;
+    AuditEvent::create(
+        CreateAuditEvent {
+            action: "user.login",
+            actor: Some(user),
+            target_type: "user",
+            target_id: user.id.to_string(),
+            changes: Some(json!({ "method": method })),
+        },
+        context,
+        db,
+    )
+    .await?;
+    let string = serde_json::to_string(user)?;
+    id.remember(string);
+    Ok(())
+}
+
+// The identity provider is trusted to have checked a second factor if one is needed, so a
+// two-factor code is not asked for here.
+pub async fn login_with_identity(
+    identity: &ExternalIdentity,
+    invite_code: Option<&str>,
+    invite_only: bool,
+    context: &audit::Context,
+    id: &Identity,
+    db: &Pool,
+) -> Result<User, Error> {
+    let user = match User::find_by_identity(identity, db).await? {
+        Some(user) => user,
+        None => {
+            let user = User::register_identity(identity, invite_code, invite_only, db).await?;
+            AuditEvent::create(
+                CreateAuditEvent {
+                    action: "user.register",
+                    actor: Some(&user),
+                    target_type: "user",
+                    target_id: user.id.to_string(),
+                    changes: Some(json!({ "method": "oidc" })),
+                },
+                context,
+                db,
+            )
+            .await?;
+            user
+        }
+    };
+    complete_login(&user, "oidc", context, id, db).await?;
+    Ok(user)
+}
+
+// Logging in with the identity provider again stands in for a password for the next few
+// minutes, but only if it is the identity that the account was created with or linked to.
+pub async fn reauthenticate_with_identity(
+    user_id: i64,
+    identity: &ExternalIdentity,
+    context: &audit::Context,
+    db: &Pool,
+) -> Result<(), Error> {
+    let user = match User::find_by_identity(identity, db).await? {
+        Some(user) if user.id == user_id => user,
+        _ => return Err(Error::Unauthorized),
+    };
+    user.reauthenticate(db).await?;
+    AuditEvent::create(
+        CreateAuditEvent {
+            action: "user.reauthenticate",
+            actor: Some(&user),
+            target_type: "user",
+            target_id: user.id.to_string(),
+            ..Default::default()
+        },
+        context,
+        db,
+    )
+    .await
+}
+
+// An identity that already belongs to another account is not moved.
+pub async fn link_identity(
+    user_id: i64,
+    identity: &ExternalIdentity,
+    context: &audit::Context,
+    db: &Pool,
+) -> Result<(), Error> {
+    match User::find_by_identity(identity, db).await? {
+        Some(user) if user.id == user_id => return Ok(()),
+        Some(_) => return Err(Error::Unauthorized),
+        None => {}
+    }
+
+    let user = User::find_by_id(user_id, db).await?;
+    user.link_identity(identity, db).await?;
+    AuditEvent::create(
+        CreateAuditEvent {
+            action: "user.identity_link",
+            actor: Some(&user),
+            target_type: "user",
+            target_id: user.id.to_string(),
+            changes: Some(json!({ "issuer": identity.issuer })),
+        },
+        context,
+        db,
+    )
+    .await
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum LoginStep {
+    Complete,
+    SecondFactor,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct AuthenticateUser {
+    pub handle: TextField,
+    pub password: PasswordField,
+    #[serde(default)]
+    pub remember_me: bool,
+    is_valid: Option<bool>,
+}
+
+impl AuthenticateUser {
+    #[allow(dead_code)]
+    pub fn new(handle: &str, password: &str) -> Self {
+        Self {
+            handle: TextField::new(handle),
+            password: PasswordField::new(password),
+            remember_me: false,
+            is_valid: None,
+        }
+    }
+
+    pub async fn call(
+        &self,
+        params: HashParams,
+        context: &audit::Context,
+        id: &Identity,
+        db: &Pool,
+    ) -> Result<LoginStep, Error> {
+        debug_assert_eq!(Some(true), self.is_valid);
+        let handle = &self.handle.value;
+        let ip_address = &context.ip_address;
+
+        if let Some(locked_until) = LoginAttempt::locked_until(handle, ip_address, db).await? {
+            warn!("Refusing login attempt for {} from {}", handle, ip_address);
+            return Err(Error::TooManyLoginAttempts(locked_until));
+        }
+
+        let user = match User::authenticate(self, params, db).await {
+            Ok(user) => user,
+            Err(error @ (Error::InvalidPassword | Error::Database(sqlx::Error::RowNotFound))) => {
+                LoginAttempt::create(handle, ip_address, false, db).await?;
+                return Err(error);
+            }
+            Err(error) => return Err(error),
+        };
+
+        // The attempt is not recorded as a success until the second factor has been checked, so
+        // that knowing the password does not reset the count of failed codes.
+        if user.totp_enabled {
+            let string = serde_json::to_string(&PendingLogin::new(&user, self.remember_me))?;
+            id.remember(string);
+            return Ok(LoginStep::SecondFactor);
+        }
+
+        complete_login(&user, "password", context, id, db).await?;
+        Ok(LoginStep::Complete)
+    }
+
+    pub fn validate(&mut self) -> bool {
+        if let Some(valid) = self.is_valid {
+            return valid;
+        }
+
+        let mut valid = vec![self.handle.validate(), self.password.validate()];
+
+        // Logins look handles up in their normalized form, and no handle is longer than this.
+        let handle = users::handle::normalize(&self.handle.value);
+        if handle.is_empty() {
+            self.handle
+                .errors
+                .push("Username cannot be empty".to_string());
+            valid.push(false);
+        } else if handle.chars().count() > 30 {
             self.handle
                 .errors
-                .push("Username cannot have spaces".to_string());
+                .push("Username cannot have more than 30 characters".to_string());
             valid.push(false);
         }
 
-        if !self.handle.value.is_ascii() {
-            self.handle
+        if self.password.value.is_empty() {
+            self.password
                 .errors
-                .push("Username cannot have special characters".to_string());
+                .push("Password cannot be empty".to_string());
             valid.push(false);
         }
 
+        let valid = valid.into_iter().all(identity);
+        self.is_valid = Some(valid);
+        valid
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct VerifyTwoFactorLogin {
+    pub code: TextField,
+    is_valid: Option<bool>,
+}
+
+impl VerifyTwoFactorLogin {
+    #[allow(dead_code)]
+    pub fn new(code: &str) -> Self {
+        Self {
+            code: TextField::new(code),
+            is_valid: None,
+        }
+    }
+
+    pub async fn call(
+        &self,
+        pending: &PendingLogin,
+        now: DateTime,
+        context: &audit::Context,
+        id: &Identity,
+        db: &Pool,
+    ) -> Result<(), Error> {
+        debug_assert_eq!(Some(true), self.is_valid);
+        let user = User::find_by_id(pending.pending_user_id, db).await?;
+        let ip_address = &context.ip_address;
+
+        if let Some(locked_until) = LoginAttempt::locked_until(&user.handle, ip_address, db).await?
+        {
+            warn!(
+                "Refusing login attempt for {} from {}",
+                user.handle, ip_address
+            );
+            return Err(Error::TooManyLoginAttempts(locked_until));
+        }
+
+        // Recovery codes have a dash in them and one-time passwords are all digits.
+        let code = &self.code.value;
+        let (verified, method) = if code.contains('-') {
+            (user.use_recovery_code(code, db).await?, "recovery_code")
+        } else {
+            (user.verify_totp(code, now, db).await?, "two_factor")
+        };
+
+        if !verified {
+            LoginAttempt::create(&user.handle, ip_address, false, db).await?;
+            return Err(Error::InvalidCode);
+        }
+
+        complete_login(&user, method, context, id, db).await
+    }
+
+    pub fn validate(&mut self) -> bool {
+        if let Some(valid) = self.is_valid {
+            return valid;
+        }
+
+        let valid = !self.code.value.trim().is_empty();
+        if !valid {
+            self.code.errors.push("Code cannot be empty".to_string());
+        }
+
+        self.is_valid = Some(valid);
+        valid
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CreateInvite {
+    pub max_uses: TextField,
+    pub expires_in_days: TextField,
+    is_valid: Option<bool>,
+}
+
+impl Default for CreateInvite {
+    fn default() -> Self {
+        Self {
+            max_uses: TextField::new("1"),
+            expires_in_days: TextField::new("7"),
+            is_valid: None,
+        }
+    }
+}
+
+impl CreateInvite {
+    const MAX_USES: i32 = 1000;
+    const MAX_DAYS: i64 = 365;
+
+    #[allow(dead_code)]
+    pub fn new(max_uses: &str, expires_in_days: &str) -> Self {
+        Self {
+            max_uses: TextField::new(max_uses),
+            expires_in_days: TextField::new(expires_in_days),
+            is_valid: None,
+        }
+    }
+
+    // Only admins can make invites that are good for more than one person.
+    pub async fn call(
+        &self,
+        user: &User,
+        context: &audit::Context,
+        db: &Pool,
+    ) -> Result<Invite, Error> {
+        debug_assert_eq!(Some(true), self.is_valid);
+        let max_uses = self.max_uses();
+        if max_uses > 1 && !user.is_admin {
+            return Err(Error::Unauthorized);
+        }
+
+        let expires_at = self
+            .expires_in_days()
+            .map(|days| DateTime::now() + chrono::Duration::days(days));
+        let invite = Invite::create(user, max_uses, expires_at, db).await?;
+        AuditEvent::create(
+            CreateAuditEvent {
+                action: "invite.create",
+                actor: Some(user),
+                target_type: "invite",
+                target_id: invite.id.to_string(),
+                changes: Some(json!({
+                    "max_uses": invite.max_uses,
+                    "expires_at": invite.expires_at,
+                })),
+            },
+            context,
+            db,
+        )
+        .await?;
+        Ok(invite)
+    }
+
+    fn max_uses(&self) -> i32 {
+        self.max_uses.value.trim().parse().unwrap_or(0)
+    }
+
+    // An empty field means that the invite does not expire.
+    fn expires_in_days(&self) -> Option<i64> {
+        self.expires_in_days.value.trim().parse().ok()
+    }
+
+    pub fn validate(&mut self) -> bool {
+        if let Some(valid) = self.is_valid {
+            return valid;
+        }
+
+        let mut valid = true;
+
+        if !(1..=Self::MAX_USES).contains(&self.max_uses()) {
+            self.max_uses.errors.push(format!(
+                "Number of uses must be between 1 and {}",
+                Self::MAX_USES
+            ));
+            valid = false;
+        }
+
+        if !self.expires_in_days.value.trim().is_empty()
+            && !self
+                .expires_in_days()
+                .is_some_and(|days| (1..=Self::MAX_DAYS).contains(&days))
+        {
+            self.expires_in_days.errors.push(format!(
+                "Days must be between 1 and {}, or left empty",
+                Self::MAX_DAYS
+            ));
+            valid = false;
+        }
+
+        self.is_valid = Some(valid);
+        valid
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct DestroyUserSession;
+
+impl DestroyUserSession {
+    pub async fn call(
+        &self,
+        context: &audit::Context,
+        id: &Identity,
+        db: &Pool,
+    ) -> Result<(), Error> {
+        if let Ok(user) = auth::user_or_temporary(id) {
+            AuditEvent::create(
+                CreateAuditEvent {
+                    action: "user.logout",
+                    actor: Some(&user),
+                    target_type: "user",
+                    target_id: user.id.to_string(),
+                    ..Default::default()
+                },
+                context,
+                db,
+            )
+            .await?;
+        }
+        id.forget();
+        Ok(())
+    }
+}
+
+/// How a sensitive change to an account is confirmed.  Accounts without a password confirm the
+/// change by logging in with the identity provider again, so the password is not asked for.
+#[derive(Debug, Default, Clone, Copy)]
+struct Confirmation {
+    without_password: bool,
+}
+
+impl Confirmation {
+    fn for_user(user: &User) -> Self {
+        Self {
+            without_password: !user.has_password(),
+        }
+    }
+
+    fn validate(&self, password: &mut PasswordField, error: &str) -> bool {
+        let valid = self.without_password || !password.value.is_empty();
+        if !valid {
+            password.errors.push(error.to_string());
+        }
+        valid
+    }
+
+    /// The user as they are now, once it is clear that they are the one asking for the change.
+    async fn confirm(user: &User, password: &PasswordField, db: &Pool) -> Result<User, Error> {
+        let user = User::find_by_id(user.id, db).await?;
+        if !user.confirm(&password.value, DateTime::now(), db).await? {
+            return Err(Error::InvalidPassword);
+        }
+        Ok(user)
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct ChangeUserPassword {
+    pub current_password: PasswordField,
+    pub password: PasswordField,
+    pub password_confirmation: PasswordField,
+    is_valid: Option<bool>,
+    #[serde(skip)]
+    confirmation: Confirmation,
+    // Kept out of the new password.
+    #[serde(skip)]
+    handle: String,
+}
+
+impl ChangeUserPassword {
+    #[allow(dead_code)]
+    pub fn new(current_password: &str, password: &str, password_confirmation: &str) -> Self {
+        Self {
+            current_password: PasswordField::new(current_password),
+            password: PasswordField::new(password),
+            password_confirmation: PasswordField::new(password_confirmation),
+            is_valid: None,
+            confirmation: Confirmation::default(),
+            handle: String::new(),
+        }
+    }
+
+    /// The new password is checked against the handle.
+    pub fn for_user(mut self, user: &User) -> Self {
+        self.confirmation = Confirmation::for_user(user);
+        self.handle = user.handle.clone();
+        self
+    }
+
+    pub async fn call(
+        &self,
+        user: &User,
+        params: HashParams,
+        context: &audit::Context,
+        db: &Pool,
+    ) -> Result<(), Error> {
+        debug_assert_eq!(Some(true), self.is_valid);
+        let user = Confirmation::confirm(user, &self.current_password, db).await?;
+        user.update_password(&self.password.value, params, db)
+            .await?;
+        AuditEvent::create(
+            CreateAuditEvent {
+                action: "user.password_change",
+                actor: Some(&user),
+                target_type: "user",
+                target_id: user.id.to_string(),
+                ..Default::default()
+            },
+            context,
+            db,
+        )
+        .await
+    }
+
+    pub fn validate(&mut self) -> bool {
+        if let Some(valid) = self.is_valid {
+            return valid;
+        }
+
+        let mut valid = vec![
+            self.password.validate() && self.password.validate_strength(&[&self.handle]),
+            self.password_confirmation.validate(),
+            self.confirmation.validate(
+                &mut self.current_password,
+                "Current password cannot be empty",
+            ),
+        ];
+
         if self.password.value != self.password_confirmation.value {
             self.password_confirmation
                 .errors
@@ -99,29 +669,53 @@ impl RegisterUser {
 }
 
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
-pub struct AuthenticateUser {
+pub struct ChangeUserHandle {
     pub handle: TextField,
-    pub password: PasswordField,
     is_valid: Option<bool>,
 }
 
-impl AuthenticateUser {
-    #[allow(dead_code)]
-    pub fn new(handle: &str, password: &str) -> Self {
+impl ChangeUserHandle {
+    pub fn new(handle: &str) -> Self {
         Self {
             handle: TextField::new(handle),
-            password: PasswordField::new(password),
             is_valid: None,
         }
     }
 
-    pub async fn call(&self, id: &Identity, db: &Pool) -> Result<(), Error> {
+    pub async fn call(
+        &self,
+        user: &User,
+        context: &audit::Context,
+        id: &Identity,
+        db: &Pool,
+    ) -> Result<User, Error> {
         debug_assert_eq!(Some(true), self.is_valid);
-        let user = User::authenticate(self, db).await?;
-        User::update_last_login(user.id, db).await?;
+        let user = User::find_by_id(user.id, db).await?;
+        if user.handle == self.handle.value {
+            return Ok(user);
+        }
+
+        if !user.handle_available(&self.handle.value, db).await? {
+            return Err(Error::HandleTaken);
+        }
+
+        let before = json!({ "handle": user.handle });
+        let user = user.update_handle(&self.handle.value, db).await?;
+        AuditEvent::create(
+            CreateAuditEvent {
+                action: "user.handle_change",
+                actor: Some(&user),
+                target_type: "user",
+                target_id: user.id.to_string(),
+                changes: Some(audit::diff(&before, &json!({ "handle": user.handle }))),
+            },
+            context,
+            db,
+        )
+        .await?;
         let string = serde_json::to_string(&user)?;
         id.remember(string);
-        Ok(())
+        Ok(user)
     }
 
     pub fn validate(&mut self) -> bool {
@@ -129,44 +723,1067 @@ impl AuthenticateUser {
             return valid;
         }
 
-        let mut valid = vec![self.handle.validate(), self.password.validate()];
+        let valid = validate_handle(&mut self.handle);
+        self.is_valid = Some(valid);
+        valid
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct DestroyUser {
+    pub password: PasswordField,
+    is_valid: Option<bool>,
+    #[serde(skip)]
+    confirmation: Confirmation,
+}
+
+impl DestroyUser {
+    #[allow(dead_code)]
+    pub fn new(password: &str) -> Self {
+        Self {
+            password: PasswordField::new(password),
+            is_valid: None,
+            confirmation: Confirmation::default(),
+        }
+    }
+
+    pub fn for_user(mut self, user: &User) -> Self {
+        self.confirmation = Confirmation::for_user(user);
+        self
+    }
+
+    pub async fn call(
+        &self,
+        user: &User,
+        context: &audit::Context,
+        id: &Identity,
+        db: &Pool,
+    ) -> Result<(), Error> {
+        debug_assert_eq!(Some(true), self.is_valid);
+        let user = Confirmation::confirm(user, &self.password, db).await?;
+        user.delete(db).await?;
+        AuditEvent::create(
+            CreateAuditEvent {
+                action: "user.delete",
+                actor: Some(&user),
+                target_type: "user",
+                target_id: user.id.to_string(),
+                ..Default::default()
+            },
+            context,
+            db,
+        )
+        .await?;
+        id.forget();
+        Ok(())
+    }
+
+    pub fn validate(&mut self) -> bool {
+        if let Some(valid) = self.is_valid {
+            return valid;
+        }
+
+        let valid = self
+            .confirmation
+            .validate(&mut self.password, "Password cannot be empty");
+
+        self.is_valid = Some(valid);
+        valid
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct ConfirmTwoFactorEnrollment {
+    pub code: TextField,
+    is_valid: Option<bool>,
+}
+
+impl ConfirmTwoFactorEnrollment {
+    #[allow(dead_code)]
+    pub fn new(code: &str) -> Self {
+        Self {
+            code: TextField::new(code),
+            is_valid: None,
+        }
+    }
+
+    /// Returns the recovery codes, which are not stored anywhere in plain text.
+    pub async fn call(
+        &self,
+        user: &User,
+        now: DateTime,
+        context: &audit::Context,
+        db: &Pool,
+    ) -> Result<Vec<String>, Error> {
+        debug_assert_eq!(Some(true), self.is_valid);
+        let user = User::find_by_id(user.id, db).await?;
+        let totp = user.totp().ok_or(Error::InvalidCode)?;
+        let step = totp
+            .verify(&self.code.value, now, None)
+            .ok_or(Error::InvalidCode)?;
+
+        user.enable_totp(step, db).await?;
+        AuditEvent::create(
+            CreateAuditEvent {
+                action: "user.two_factor_enable",
+                actor: Some(&user),
+                target_type: "user",
+                target_id: user.id.to_string(),
+                ..Default::default()
+            },
+            context,
+            db,
+        )
+        .await?;
+        user.create_recovery_codes(db).await
+    }
+
+    pub fn validate(&mut self) -> bool {
+        if let Some(valid) = self.is_valid {
+            return valid;
+        }
+
+        let valid = !self.code.value.trim().is_empty();
+        if !valid {
+            self.code.errors.push("Code cannot be empty".to_string());
+        }
+
+        self.is_valid = Some(valid);
+        valid
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct DisableTwoFactor {
+    pub password: PasswordField,
+    is_valid: Option<bool>,
+    #[serde(skip)]
+    confirmation: Confirmation,
+}
+
+impl DisableTwoFactor {
+    #[allow(dead_code)]
+    pub fn new(password: &str) -> Self {
+        Self {
+            password: PasswordField::new(password),
+            is_valid: None,
+            confirmation: Confirmation::default(),
+        }
+    }
+
+    pub fn for_user(mut self, user: &User) -> Self {
+        self.confirmation = Confirmation::for_user(user);
+        self
+    }
+
+    pub async fn call(
+        &self,
+        user: &User,
+        context: &audit::Context,
+        id: &Identity,
+        db: &Pool,
+    ) -> Result<(), Error> {
+        debug_assert_eq!(Some(true), self.is_valid);
+        let user = Confirmation::confirm(user, &self.password, db).await?;
+        user.disable_totp(db).await?;
+        AuditEvent::create(
+            CreateAuditEvent {
+                action: "user.two_factor_disable",
+                actor: Some(&user),
+                target_type: "user",
+                target_id: user.id.to_string(),
+                ..Default::default()
+            },
+            context,
+            db,
+        )
+        .await?;
+
+        let user = User::find_by_id(user.id, db).await?;
+        let string = serde_json::to_string(&user)?;
+        id.remember(string);
+        Ok(())
+    }
+
+    pub fn validate(&mut self) -> bool {
+        if let Some(valid) = self.is_valid {
+            return valid;
+        }
+
+        let valid = self
+            .confirmation
+            .validate(&mut self.password, "Password cannot be empty");
+
+        self.is_valid = Some(valid);
+        valid
+    }
+}
+
+/// The first step of linking an identity to an account that has a password.  The identity is
+/// linked once the provider sends the user back.
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct LinkIdentity {
+    pub password: PasswordField,
+    is_valid: Option<bool>,
+}
+
+impl LinkIdentity {
+    pub async fn call(&self, user: &User, db: &Pool) -> Result<(), Error> {
+        debug_assert_eq!(Some(true), self.is_valid);
+        Confirmation::confirm(user, &self.password, db).await?;
+        Ok(())
+    }
+
+    pub fn validate(&mut self) -> bool {
+        if let Some(valid) = self.is_valid {
+            return valid;
+        }
+
+        let valid = !self.password.value.is_empty();
+        if !valid {
+            self.password
+                .errors
+                .push("Password cannot be empty".to_string());
+        }
+
+        self.is_valid = Some(valid);
+        valid
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct UpdatePreferences {
+    pub timezone: TextField,
+    pub grading_scale: TextField,
+    pub daily_new_limit: TextField,
+    pub daily_review_limit: TextField,
+    pub queue_strategy: TextField,
+    // An unchecked checkbox is left out of the form.
+    #[serde(default)]
+    pub email_digest: bool,
+    pub theme: TextField,
+    is_valid: Option<bool>,
+}
+
+impl From<&UserPreferences> for UpdatePreferences {
+    fn from(preferences: &UserPreferences) -> Self {
+        Self {
+            timezone: TextField::new(&preferences.timezone),
+            grading_scale: TextField::new(&preferences.grading_scale),
+            daily_new_limit: TextField::new(&preferences.daily_new_limit.to_string()),
+            daily_review_limit: TextField::new(&preferences.daily_review_limit.to_string()),
+            queue_strategy: TextField::new(&preferences.queue_strategy),
+            email_digest: preferences.email_digest,
+            theme: TextField::new(&preferences.theme),
+            is_valid: None,
+        }
+    }
+}
+
+// Returns the limit, or adds an error to the field.
+fn validate_daily_limit(field: &mut TextField) -> Option<i32> {
+    let limit = field
+        .value
+        .trim()
+        .parse()
+        .ok()
+        .filter(|limit| (0..=UserPreferences::MAX_DAILY_LIMIT).contains(limit));
+    if limit.is_none() {
+        field.errors.push(format!(
+            "Limit must be a number between 0 and {}",
+            UserPreferences::MAX_DAILY_LIMIT
+        ));
+    }
+    limit
+}
+
+fn validate_choice(field: &mut TextField, choices: &[&str]) -> bool {
+    let valid = choices.contains(&field.value.as_str());
+    if !valid {
+        field
+            .errors
+            .push(format!("Must be one of {}", choices.join(", ")));
+    }
+    valid
+}
+
+impl UpdatePreferences {
+    pub async fn call(
+        &self,
+        user: &User,
+        context: &audit::Context,
+        db: &Pool,
+    ) -> Result<UserPreferences, Error> {
+        debug_assert_eq!(Some(true), self.is_valid);
+        let before = UserPreferences::find(user.id, db).await?;
+        let preferences = self.preferences();
+        preferences.save(user.id, db).await?;
+
+        AuditEvent::create(
+            CreateAuditEvent {
+                action: "user.preferences_change",
+                actor: Some(user),
+                target_type: "user",
+                target_id: user.id.to_string(),
+                changes: Some(audit::diff(
+                    &serde_json::to_value(&before)?,
+                    &serde_json::to_value(&preferences)?,
+                )),
+            },
+            context,
+            db,
+        )
+        .await?;
+        Ok(preferences)
+    }
+
+    fn preferences(&self) -> UserPreferences {
+        UserPreferences {
+            timezone: self.timezone.value.trim().to_string(),
+            grading_scale: self.grading_scale.value.clone(),
+            daily_new_limit: self
+                .daily_new_limit
+                .value
+                .trim()
+                .parse()
+                .unwrap_or_default(),
+            daily_review_limit: self
+                .daily_review_limit
+                .value
+                .trim()
+                .parse()
+                .unwrap_or_default(),
+            queue_strategy: self.queue_strategy.value.clone(),
+            email_digest: self.email_digest,
+            theme: self.theme.value.clone(),
+        }
+    }
+
+    pub fn validate(&mut self) -> bool {
+        if let Some(valid) = self.is_valid {
+            return valid;
+        }
+
+        let mut valid = vec![
+            validate_daily_limit(&mut self.daily_new_limit).is_some(),
+            validate_daily_limit(&mut self.daily_review_limit).is_some(),
+            validate_choice(&mut self.grading_scale, &UserPreferences::GRADING_SCALES),
+            validate_choice(&mut self.queue_strategy, &UserPreferences::QUEUE_STRATEGIES),
+            validate_choice(&mut self.theme, &UserPreferences::THEMES),
+        ];
+
+        if !users::is_timezone(self.timezone.value.trim()) {
+            self.timezone
+                .errors
+                .push("Time zone is not one that we know of".to_string());
+            valid.push(false);
+        }
+
+        let valid = valid.into_iter().all(identity);
+        self.is_valid = Some(valid);
+        valid
+    }
+}
+
+fn optional_link(field: &TextField) -> Option<&str> {
+    Some(field.value.trim()).filter(|link| !link.is_empty())
+}
+
+// An empty solution is no solution.
+fn optional_solution(field: &TextField) -> Option<String> {
+    Some(field.value.trim())
+        .filter(|solution| !solution.is_empty())
+        .map(str::to_string)
+}
+
+fn validate_title(title: &mut TextField) -> bool {
+    let valid = !title.value.trim().is_empty();
+    if !valid {
+        title.errors.push("Title cannot be empty".to_string());
+    }
+    valid
+}
+
+fn validate_link(link: &mut TextField) -> bool {
+    let valid = optional_link(link).is_none_or(|value| url::Url::parse(value).is_ok());
+    if !valid {
+        link.errors.push("Link is not a valid URL".to_string());
+    }
+    valid
+}
+
+// A flashcard needs something on the back.
+pub fn validate_back(back: &mut TextField) -> bool {
+    let valid = !back.value.trim().is_empty();
+    if !valid {
+        back.errors
+            .push("Give the answer for the back of the card".to_string());
+    }
+    valid
+}
+
+// A cloze question needs at least one part left out.
+pub fn validate_clozes(text: &mut TextField) -> bool {
+    let valid = !Markdown::cloze_numbers(&text.value).is_empty();
+    if !valid {
+        text.errors
+            .push("Mark a part of the text to leave out, as in {{c1::Lima}}".to_string());
+    }
+    valid
+}
+
+// Options are written one to a line, with an asterisk in front of the correct ones.
+pub fn validate_options(options: &mut TextField, is_multi_select: bool) -> bool {
+    let parsed = CreateOption::parse_lines(&options.value);
+    let correct = parsed.iter().filter(|option| option.is_correct).count();
+
+    let error = if parsed.len() < 2 {
+        Some("Give at least two options")
+    } else if correct == 0 {
+        Some("Mark the correct option with an asterisk")
+    } else if correct > 1 && !is_multi_select {
+        Some("Mark only one option as correct, or allow more than one")
+    } else {
+        None
+    };
+
+    if let Some(error) = error {
+        options.errors.push(error.to_string());
+    }
+    error.is_none()
+}
+
+// The fields of the expected answer to a numeric question.
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct NumericAnswerFields {
+    #[serde(default)]
+    pub number: TextField,
+    #[serde(default)]
+    pub tolerance: TextField,
+    #[serde(default)]
+    pub tolerance_kind: TextField,
+    #[serde(default)]
+    pub unit: TextField,
+    #[serde(default)]
+    pub significant_figures: TextField,
+}
+
+impl NumericAnswerFields {
+    fn number(&self) -> Option<f64> {
+        self.number
+            .value
+            .trim()
+            .parse()
+            .ok()
+            .filter(|number: &f64| number.is_finite())
+    }
+
+    // No tolerance means that the answer has to be exact.
+    fn tolerance(&self) -> Option<f64> {
+        match self.tolerance.value.trim() {
+            "" => Some(0.0),
+            tolerance => tolerance
+                .parse()
+                .ok()
+                .filter(|tolerance: &f64| tolerance.is_finite() && *tolerance >= 0.0),
+        }
+    }
+
+    fn tolerance_kind(&self) -> &str {
+        match self.tolerance_kind.value.trim() {
+            "" => "absolute",
+            kind => kind,
+        }
+    }
+
+    fn significant_figures(&self) -> Option<Option<i32>> {
+        match self.significant_figures.value.trim() {
+            "" => Some(None),
+            figures => figures
+                .parse()
+                .ok()
+                .filter(|figures| (1..=20).contains(figures))
+                .map(Some),
+        }
+    }
+
+    pub fn numeric_answer(&self) -> Option<NumericAnswer> {
+        Some(NumericAnswer {
+            value: self.number()?,
+            tolerance: self.tolerance()?,
+            tolerance_kind: self.tolerance_kind().to_string(),
+            unit: Some(self.unit.value.trim().to_string()).filter(|unit| !unit.is_empty()),
+            significant_figures: self.significant_figures()?,
+        })
+    }
+
+    // Everything but the number, which a template works out for each variant.
+    fn template(&self, variables: &str, answer: &str) -> Option<QuestionTemplate> {
+        Some(QuestionTemplate {
+            variables: variables.to_string(),
+            answer: answer.trim().to_string(),
+            tolerance: self.tolerance()?,
+            tolerance_kind: self.tolerance_kind().to_string(),
+            unit: Some(self.unit.value.trim().to_string()).filter(|unit| !unit.is_empty()),
+            significant_figures: self.significant_figures()?,
+        })
+    }
+
+    pub fn validate(&mut self) -> bool {
+        let valid = self.number().is_some();
+        if !valid {
+            self.number
+                .errors
+                .push("Give the answer as a number".to_string());
+        }
+        self.validate_grading() && valid
+    }
+
+    pub fn validate_grading(&mut self) -> bool {
+        let mut valid = true;
+        let mut check = |ok: bool, field: &mut TextField, error: &str| {
+            if !ok {
+                field.errors.push(error.to_string());
+                valid = false;
+            }
+        };
+
+        check(
+            self.tolerance().is_some(),
+            &mut self.tolerance,
+            "Tolerance must be a number that is not negative",
+        );
+        check(
+            NumericAnswer::TOLERANCE_KINDS.contains(&self.tolerance_kind()),
+            &mut self.tolerance_kind,
+            "Tolerance must be absolute or relative",
+        );
+        check(
+            self.unit.value.trim().chars().count() <= 30,
+            &mut self.unit,
+            "Unit can be at most 30 characters",
+        );
+        check(
+            self.significant_figures().is_some(),
+            &mut self.significant_figures,
+            "Significant figures must be a whole number from 1 to 20",
+        );
+        valid
+    }
+}
+
+/// A question is written in Markdown, or is a link to a challenge somewhere else, or both.  A
+/// flashcard also has an answer on the back, which is hidden until it is asked for, a
+/// multiple-choice question has options to choose from, and a numeric question has a number for
+/// an answer.  A template is a numeric question whose numbers are drawn from ranges each time it is
+/// asked, and a cloze question is a text with parts left out to be recalled.
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct AddQuestion {
+    pub title: TextField,
+    pub link: TextField,
+    pub text: TextField,
+    #[serde(default)]
+    pub kind: TextField,
+    #[serde(default)]
+    pub back: TextField,
+    #[serde(default)]
+    pub options: TextField,
+    // An unchecked checkbox is left out of the form.
+    #[serde(default)]
+    pub is_multi_select: bool,
+    #[serde(flatten)]
+    pub numeric: NumericAnswerFields,
+    #[serde(default)]
+    pub variables: TextField,
+    #[serde(default)]
+    pub answer_expression: TextField,
+    /// One to a line.
+    #[serde(default)]
+    pub hints: TextField,
+    #[serde(default)]
+    pub solution: TextField,
+    /// Saves the question without listing it or asking it in queues.
+    #[serde(default)]
+    pub draft: bool,
+    is_valid: Option<bool>,
+}
+
+impl AddQuestion {
+    #[allow(dead_code)]
+    pub fn new(title: &str, link: &str, text: &str) -> Self {
+        Self {
+            title: TextField::new(title),
+            link: TextField::new(link),
+            text: TextField::new(text),
+            ..Self::default()
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn flashcard(title: &str, front: &str, back: &str) -> Self {
+        Self {
+            title: TextField::new(title),
+            text: TextField::new(front),
+            kind: TextField::new("flashcard"),
+            back: TextField::new(back),
+            ..Self::default()
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn multiple_choice(title: &str, text: &str, options: &str, is_multi_select: bool) -> Self {
+        Self {
+            title: TextField::new(title),
+            text: TextField::new(text),
+            kind: TextField::new("multiple-choice"),
+            options: TextField::new(options),
+            is_multi_select,
+            ..Self::default()
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn numeric(title: &str, text: &str, numeric: NumericAnswerFields) -> Self {
+        Self {
+            title: TextField::new(title),
+            text: TextField::new(text),
+            kind: TextField::new("numeric"),
+            numeric,
+            ..Self::default()
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn template(
+        title: &str,
+        text: &str,
+        variables: &str,
+        answer_expression: &str,
+        numeric: NumericAnswerFields,
+    ) -> Self {
+        Self {
+            title: TextField::new(title),
+            text: TextField::new(text),
+            kind: TextField::new("template"),
+            variables: TextField::new(variables),
+            answer_expression: TextField::new(answer_expression),
+            numeric,
+            ..Self::default()
+        }
+    }
+
+    pub async fn call(
+        &self,
+        link_logo: Option<String>,
+        user: &User,
+        context: &audit::Context,
+        db: &Pool,
+    ) -> Result<Question, Error> {
+        debug_assert_eq!(Some(true), self.is_valid);
+        let mut question = CreateQuestion {
+            author_id: user.id,
+            title: self.title.value.trim().to_string(),
+            text: self.text.value.clone(),
+            link: self.link().map(str::to_string),
+            link_logo,
+            kind: self.kind().to_string(),
+            hints: parse_hints(&self.hints.value),
+            solution: optional_solution(&self.solution),
+            status: if self.draft { "draft" } else { "published" }.to_string(),
+            ..CreateQuestion::default()
+        };
+        self.question_kind().fill(self, &mut question);
+        let question = Question::create(question, db).await?;
+
+        AuditEvent::create(
+            CreateAuditEvent {
+                action: "question.create",
+                actor: Some(user),
+                target_type: "question",
+                target_id: question.external_id.clone(),
+                changes: Some(json!({
+                    "title": question.title,
+                    "link": question.link,
+                    "kind": question.kind,
+                    "status": question.status,
+                })),
+            },
+            context,
+            db,
+        )
+        .await?;
+        Ok(question)
+    }
+
+    pub fn link(&self) -> Option<&str> {
+        optional_link(&self.link)
+    }
+
+    // Questions are links unless the form says otherwise.
+    pub fn kind(&self) -> &str {
+        match self.kind.value.trim() {
+            "" => "link",
+            kind => kind,
+        }
+    }
+
+    /// An unknown kind is reported by `validate`.
+    pub fn question_kind(&self) -> &'static dyn QuestionKind {
+        kinds::find(self.kind()).unwrap_or(&kinds::Link)
+    }
+
+    pub fn question_template(&self) -> Option<QuestionTemplate> {
+        self.numeric
+            .template(&self.variables.value, &self.answer_expression.value)
+    }
+
+    pub fn validate(&mut self) -> bool {
+        if let Some(valid) = self.is_valid {
+            return valid;
+        }
+
+        let mut valid = vec![
+            self.title.validate(),
+            self.link.validate(),
+            self.text.validate(),
+            validate_title(&mut self.title),
+            validate_link(&mut self.link),
+        ];
+
+        match kinds::find(self.kind()) {
+            Some(kind) => valid.push(kind.validate(self)),
+            None => {
+                self.kind
+                    .errors
+                    .push("Choose a kind of question".to_string());
+                valid.push(false);
+            }
+        }
+
+        let valid = valid.into_iter().all(identity);
+        self.is_valid = Some(valid);
+        valid
+    }
+
+    /// Tries the template out on a number of variants, so that a mistake in it is found before
+    /// someone is asked the question.
+    pub fn validate_template(&mut self) -> bool {
+        if self.variables.value.trim().is_empty() {
+            self.variables
+                .errors
+                .push("Give at least one variable".to_string());
+            return false;
+        }
+        if self.answer_expression.value.trim().is_empty() {
+            self.answer_expression
+                .errors
+                .push("Give the answer in terms of the variables".to_string());
+            return false;
+        }
+        let template = match self.question_template() {
+            Some(template) => template,
+            // The tolerance or significant figures are wrong, which is reported elsewhere.
+            None => return false,
+        };
+
+        for seed in 0..20 {
+            let variant = match template.variant(seed) {
+                Ok(variant) => variant,
+                Err(error) => {
+                    self.variables.errors.push(error);
+                    return false;
+                }
+            };
+            if let Err(error) = template.numeric_answer(&variant) {
+                self.answer_expression.errors.push(error);
+                return false;
+            }
+            if let Err(error) = template.render(&self.text.value, &variant) {
+                self.text.errors.push(error);
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// The kind of a question stays the same once it has been added.
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct EditQuestion {
+    pub title: TextField,
+    pub link: TextField,
+    pub text: TextField,
+    #[serde(default)]
+    pub back: TextField,
+    /// One to a line.
+    #[serde(default)]
+    pub hints: TextField,
+    #[serde(default)]
+    pub solution: TextField,
+    is_valid: Option<bool>,
+}
+
+impl From<&Question> for EditQuestion {
+    fn from(question: &Question) -> Self {
+        Self {
+            title: TextField::new(&question.title),
+            link: TextField::new(question.link.as_deref().unwrap_or_default()),
+            text: TextField::new(question.source_text()),
+            back: TextField::new(question.source_back()),
+            hints: TextField::new(&question.source_hints().join("\n")),
+            solution: TextField::new(question.source_solution()),
+            is_valid: None,
+        }
+    }
+}
+
+impl EditQuestion {
+    #[allow(dead_code)]
+    pub fn new(title: &str, link: &str, text: &str) -> Self {
+        Self {
+            title: TextField::new(title),
+            link: TextField::new(link),
+            text: TextField::new(text),
+            ..Self::default()
+        }
+    }
+
+    /// Only the author of a question can edit it.
+    pub async fn call(
+        &self,
+        question: &Question,
+        link_logo: Option<String>,
+        user: &User,
+        context: &audit::Context,
+        db: &Pool,
+    ) -> Result<Question, Error> {
+        debug_assert_eq!(Some(true), self.is_valid);
+        if question.author_id != Some(user.id) {
+            return Err(Error::Unauthorized);
+        }
+
+        let mut update = UpdateQuestion {
+            editor_id: user.id,
+            title: self.title.value.trim().to_string(),
+            text: self.text.value.clone(),
+            link: self.link().map(str::to_string),
+            link_logo,
+            back: None,
+            hints: parse_hints(&self.hints.value),
+            solution: optional_solution(&self.solution),
+        };
+        question.question_kind().fill_update(self, &mut update);
+        let updated = question.update(update, db).await?;
+
+        AuditEvent::create(
+            CreateAuditEvent {
+                action: "question.update",
+                actor: Some(user),
+                target_type: "question",
+                target_id: question.external_id.clone(),
+                changes: Some(audit::diff(
+                    &json!({
+                        "title": question.title,
+                        "link": question.link,
+                        "text": question.source_text(),
+                        "back": question.source_back(),
+                        "hints": question.source_hints(),
+                        "solution": question.source_solution(),
+                    }),
+                    &json!({
+                        "title": updated.title,
+                        "link": updated.link,
+                        "text": updated.source_text(),
+                        "back": updated.source_back(),
+                        "hints": updated.source_hints(),
+                        "solution": updated.source_solution(),
+                    }),
+                )),
+            },
+            context,
+            db,
+        )
+        .await?;
+        Ok(updated)
+    }
+
+    // An empty link removes it.
+    pub fn link(&self) -> Option<&str> {
+        optional_link(&self.link)
+    }
+
+    pub fn validate(&mut self) -> bool {
+        if let Some(valid) = self.is_valid {
+            return valid;
+        }
+
+        let mut valid = vec![
+            self.title.validate(),
+            self.link.validate(),
+            self.text.validate(),
+        ];
+
+        if self.text.value.trim().is_empty() {
+            self.text
+                .errors
+                .push("Question cannot be empty".to_string());
+            valid.push(false);
+        }
+
+        valid.push(validate_title(&mut self.title));
+        valid.push(validate_link(&mut self.link));
+
+        let valid = valid.into_iter().all(identity);
+        self.is_valid = Some(valid);
+        valid
+    }
+
+    /// Validates the form along with what the kind of the question needs, which is checked
+    /// against what is stored for the question.
+    pub async fn validate_for(&mut self, question: &Question, db: &Pool) -> Result<bool, Error> {
+        let kind = question.question_kind();
+        let mut valid = vec![self.validate(), kind.validate_edit(self)];
+
+        if let Some(error) = kind.text_error(question, &self.text.value, db).await? {
+            self.text.errors.push(error);
+            valid.push(false);
+        }
+
+        let valid = valid.into_iter().all(identity);
+        self.is_valid = Some(valid);
+        Ok(valid)
+    }
+}
+
+/// Puts back an earlier revision of a question.  Only the author can do this.
+#[derive(Debug)]
+pub struct RevertQuestion {
+    pub number: i32,
+}
+
+impl RevertQuestion {
+    pub async fn call(
+        &self,
+        question: &Question,
+        user: &User,
+        context: &audit::Context,
+        db: &Pool,
+    ) -> Result<Question, Error> {
+        if question.author_id != Some(user.id) {
+            return Err(Error::Unauthorized);
+        }
 
-        if self.handle.value.is_empty() {
-            self.handle
-                .errors
-                .push("Username cannot be empty".to_string());
-            valid.push(false);
+        let revision = question.revision(self.number, db).await?;
+        // What is stored for the question can have changed since the revision was made.
+        if let Some(error) = question
+            .question_kind()
+            .text_error(question, &revision.text, db)
+            .await?
+        {
+            return Err(Error::InvalidRevision(error));
         }
+        let reverted = question.revert(&revision, user.id, db).await?;
 
-        if self.password.value.is_empty() {
-            self.password
-                .errors
-                .push("Password cannot be empty".to_string());
-            valid.push(false);
+        AuditEvent::create(
+            CreateAuditEvent {
+                action: "question.revert",
+                actor: Some(user),
+                target_type: "question",
+                target_id: question.external_id.clone(),
+                changes: Some(json!({ "revision": self.number })),
+            },
+            context,
+            db,
+        )
+        .await?;
+        Ok(reverted)
+    }
+}
+
+/// Puts a draft or archived question back in the list and in queues.  Only the author can do
+/// this.
+#[derive(Debug)]
+pub struct PublishQuestion;
+
+impl PublishQuestion {
+    pub async fn call(
+        &self,
+        question: &Question,
+        user: &User,
+        context: &audit::Context,
+        db: &Pool,
+    ) -> Result<Question, Error> {
+        if question.author_id != Some(user.id) {
+            return Err(Error::Unauthorized);
         }
 
-        let valid = valid.into_iter().all(identity);
-        self.is_valid = Some(valid);
-        valid
+        let published = question.publish(db).await?;
+
+        AuditEvent::create(
+            CreateAuditEvent {
+                action: "question.publish",
+                actor: Some(user),
+                target_type: "question",
+                target_id: question.external_id.clone(),
+                changes: Some(audit::diff(
+                    &json!({ "status": question.status }),
+                    &json!({ "status": published.status }),
+                )),
+            },
+            context,
+            db,
+        )
+        .await?;
+        Ok(published)
     }
 }
 
-#[derive(Debug, Default, Serialize, Deserialize, Clone)]
-pub struct DestroyUserSession;
+/// Deletes a question, or archives it when it has already been answered.  Only the author can do
+/// this.
+#[derive(Debug)]
+pub struct DeleteQuestion;
 
-impl DestroyUserSession {
-    pub async fn call(&self, id: &Identity) -> Result<(), Error> {
-        id.forget();
-        Ok(())
+impl DeleteQuestion {
+    pub async fn call(
+        &self,
+        question: &Question,
+        user: &User,
+        context: &audit::Context,
+        db: &Pool,
+    ) -> Result<(), Error> {
+        if question.author_id != Some(user.id) {
+            return Err(Error::Unauthorized);
+        }
+
+        let status = question.delete(db).await?;
+
+        AuditEvent::create(
+            CreateAuditEvent {
+                action: "question.delete",
+                actor: Some(user),
+                target_type: "question",
+                target_id: question.external_id.clone(),
+                changes: Some(json!({ "status": status })),
+            },
+            context,
+            db,
+        )
+        .await
     }
 }
 
+/// The state is how the user rated their own answer.  Multiple-choice questions are graded from
+/// the options that were chosen instead, and numeric questions from the value that was typed.
 #[derive(Debug)]
 pub struct AnswerQuestion {
-    pub handle: String,
+    /// The signed-in user, or the temporary user of a guest.  Only the owner of a queue can
+    /// answer questions in it.
+    pub actor: User,
     pub question_external_id: String,
     pub queue_external_id: String,
-    pub state: String,
+    pub answer: SubmittedAnswer,
+    pub shown_at: Option<DateTime>,
+    pub revealed_at: Option<DateTime>,
 }
 
 impl AnswerQuestion {
@@ -174,39 +1791,86 @@ impl AnswerQuestion {
         true
     }
 
-    pub async fn call(&self, db: &Pool) -> Result<(), Error> {
+    pub async fn call(&self, context: &audit::Context, db: &Pool) -> Result<(), Error> {
+        let queue = Queue::find(&self.queue_external_id, db).await?;
+        if queue.user_id != self.actor.id {
+            return Err(Error::Unauthorized);
+        }
+        let question = Question::find(&self.question_external_id, db).await?;
+        if !question.is_published() || !question.has_cloze(self.answer.cloze) {
+            return Err(Error::NotFound);
+        }
+        let asked = AskedQuestion::find(queue.id, question.id, self.answer.cloze, db).await?;
+        let answer = SubmittedAnswer {
+            seed: asked.as_ref().and_then(|asked| asked.seed),
+            help: asked.as_ref().map(AskedQuestion::help).unwrap_or_default(),
+            ..self.answer.clone()
+        };
+
+        let grade = question
+            .question_kind()
+            .grade(&question, &answer, db)
+            .await?;
+        let state = grade.state;
         info!(
             r#"Answering question {} as "{}"#,
-            self.question_external_id, self.state
+            self.question_external_id, state
         );
 
-        let queue = Queue::find(&self.queue_external_id, db).await?;
-        let user = User::find_by_handle(&self.handle, db).await?;
-        let question = Question::find(&self.question_external_id, db).await?;
-
+        // The answer and its audit event are written together, so that one is never left without
+        // the other.
+        let mut tx = db.begin().await?;
         let answer = Answer::create(
             CreateAnswer {
+                cloze: grade.cloze,
+                help: answer.help.within(&question),
                 queue_id: queue.id,
-                user_id: user.id,
+                user_id: self.actor.id,
                 question_id: question.id,
-                state: self.state.clone(),
+                revealed_at: self.revealed_at,
+                seed: grade.seed,
+                selected_options: grade.selected_options,
+                shown_at: self.shown_at,
+                state: state.clone(),
+                submitted_value: grade.submitted_value,
             },
-            db,
+            &mut tx,
         )
         .await?;
 
-        let last_answer = LastAnswer::find_or_create(&answer, db).await?.record;
+        let last_answer = LastAnswer::find_or_create(&answer, &mut tx).await?.record;
 
-        let consecutive_correct = match self.state.as_ref() {
+        let consecutive_correct = match state.as_ref() {
             "correct" => last_answer.answer_consecutive_correct + 1,
             _ => 0,
         };
 
         let answer = answer
-            .finalize(self.state.clone(), DateTime::now(), consecutive_correct, db)
+            .finalize(state.clone(), DateTime::now(), consecutive_correct, &mut tx)
             .await?;
-        last_answer.update(&answer, db).await?;
+        last_answer.update(&answer, &mut tx).await?;
+        if let Some(asked) = &asked {
+            asked.delete(&mut tx).await?;
+        }
+
+        AuditEvent::create(
+            CreateAuditEvent {
+                action: "answer.create",
+                actor: Some(&self.actor),
+                target_type: "answer",
+                target_id: answer.external_id.clone(),
+                changes: Some(json!({
+                    "queue": self.queue_external_id,
+                    "question": self.question_external_id,
+                    "state": state,
+                })),
+            },
+            context,
+            &mut tx,
+        )
+        .await?;
 
+        tx.commit().await?;
         Ok(())
     }
 }
@@ -226,7 +1890,7 @@ mod tests {
 
     #[test]
     fn register_user_invalid_if_handle_not_long_enough() {
-        let mut mutation = RegisterUser::new("gn", "password1", "password1");
+        let mut mutation = RegisterUser::new("gn", "plover-lantern-58", "plover-lantern-58");
 
         assert!(!mutation.validate());
         assert!(!mutation.handle.is_valid());
@@ -238,7 +1902,8 @@ mod tests {
 
     #[test]
     fn register_user_invalid_if_handle_has_whitespace() {
-        let mut mutation = RegisterUser::new("gnusto frotz", "password1", "password1");
+        let mut mutation =
+            RegisterUser::new("gnusto frotz", "plover-lantern-58", "plover-lantern-58");
 
         assert!(!mutation.validate());
         assert!(!mutation.handle.is_valid());
@@ -246,20 +1911,64 @@ mod tests {
     }
 
     #[test]
-    fn register_user_invalid_if_handle_is_not_ascii() {
-        let mut mutation = RegisterUser::new("ﬀrotz", "password1", "password1");
+    fn register_user_normalizes_handle() {
+        let mut mutation = RegisterUser::new("ﬀrotz", "plover-lantern-58", "plover-lantern-58");
+
+        assert!(mutation.validate());
+        assert_eq!("ffrotz", mutation.handle.value);
+    }
+
+    #[test]
+    fn register_user_with_unicode_handle() {
+        let mut mutation =
+            RegisterUser::new("Ærøskøbing", "plover-lantern-58", "plover-lantern-58");
+        assert!(mutation.validate());
+
+        let mut mutation = RegisterUser::new("山田", "plover-lantern-58", "plover-lantern-58");
+        assert!(!mutation.validate());
+        assert_includes(
+            mutation.handle.errors,
+            "Username must have at least three characters",
+        );
+    }
+
+    #[test]
+    fn register_user_invalid_if_handle_has_special_characters() {
+        let mut mutation =
+            RegisterUser::new("gnusto/frotz", "plover-lantern-58", "plover-lantern-58");
 
         assert!(!mutation.validate());
         assert!(!mutation.handle.is_valid());
         assert_includes(
             mutation.handle.errors,
-            "Username cannot have special characters",
+            "Username can only have letters, numbers, dashes, underscores and periods",
+        );
+    }
+
+    #[test]
+    fn register_user_invalid_if_handle_mixes_scripts() {
+        let mut mutation =
+            RegisterUser::new("gnust\u{43e}", "plover-lantern-58", "plover-lantern-58");
+
+        assert!(!mutation.validate());
+        assert_includes(
+            mutation.handle.errors,
+            "Username cannot mix letters from different alphabets",
         );
     }
 
+    #[test]
+    fn register_user_invalid_if_handle_is_reserved() {
+        let mut mutation = RegisterUser::new("Users", "plover-lantern-58", "plover-lantern-58");
+
+        assert!(!mutation.validate());
+        assert!(!mutation.handle.is_valid());
+        assert_includes(mutation.handle.errors, "Username is reserved");
+    }
+
     #[test]
     fn register_user_invalid_if_password_mismatch() {
-        let mut mutation = RegisterUser::new("gnusto", "password1", "password2");
+        let mut mutation = RegisterUser::new("gnusto", "plover-lantern-58", "plover-lantern-59");
 
         assert!(!mutation.validate());
         assert!(!mutation.password_confirmation.is_valid());
@@ -290,9 +1999,128 @@ mod tests {
         );
     }
 
+    #[test]
+    fn register_user_invalid_if_password_is_common() {
+        let mut mutation = RegisterUser::new("gnusto", "P@ssw0rd!", "P@ssw0rd!");
+
+        assert!(!mutation.validate());
+        assert!(!mutation.password.is_valid());
+        assert_includes(
+            mutation.password.errors.clone(),
+            "This is very close to a common password",
+        );
+
+        let messages = mutation.password.messages();
+        assert_eq!("error", messages[0].level);
+        assert!(messages[1..]
+            .iter()
+            .all(|message| message.level == "warning"));
+    }
+
+    #[test]
+    fn register_user_invalid_if_password_has_handle() {
+        let mut mutation = RegisterUser::new("gnusto", "Gnusto1984!", "Gnusto1984!");
+
+        assert!(!mutation.validate());
+        assert_includes(
+            mutation.password.errors,
+            "Passwords with your username in them are easy to guess",
+        );
+    }
+
+    #[test]
+    fn change_user_password_invalid_if_password_is_weak() {
+        let mut mutation = ChangeUserPassword::new("plover-lantern-57", "qwertyuiop", "qwertyuiop");
+
+        assert!(!mutation.validate());
+        assert!(!mutation.password.is_valid());
+    }
+
+    #[test]
+    fn change_user_password_invalid_if_password_has_handle() {
+        let user = User {
+            handle: "gnustofrotz".to_string(),
+            hashed_password: "hash".to_string(),
+            ..User::default()
+        };
+        let mut mutation =
+            ChangeUserPassword::new("plover-lantern-57", "gnustofrotz1", "gnustofrotz1")
+                .for_user(&user);
+
+        assert!(!mutation.validate());
+        assert_includes(
+            mutation.password.errors,
+            "Passwords with your username in them are easy to guess",
+        );
+    }
+
+    #[test]
+    fn change_user_password_invalid_if_current_password_blank() {
+        let mut mutation = ChangeUserPassword::new("", "plover-lantern-58", "plover-lantern-58");
+
+        assert!(!mutation.validate());
+        assert!(!mutation.current_password.is_valid());
+        assert_includes(
+            mutation.current_password.errors,
+            "Current password cannot be empty",
+        );
+    }
+
+    #[test]
+    fn change_user_password_invalid_if_password_mismatch() {
+        let mut mutation = ChangeUserPassword::new(
+            "plover-lantern-57",
+            "plover-lantern-58",
+            "plover-lantern-59",
+        );
+
+        assert!(!mutation.validate());
+        assert!(!mutation.password_confirmation.is_valid());
+        assert_includes(
+            mutation.password_confirmation.errors,
+            "Passwords do not match",
+        );
+    }
+
+    #[test]
+    fn change_user_handle_invalid_if_handle_has_whitespace() {
+        let mut mutation = ChangeUserHandle::new("gnusto frotz");
+
+        assert!(!mutation.validate());
+        assert!(!mutation.handle.is_valid());
+        assert_includes(mutation.handle.errors, "Username cannot have spaces");
+    }
+
+    #[test]
+    fn destroy_user_invalid_if_password_blank() {
+        let mut mutation = DestroyUser::new("");
+
+        assert!(!mutation.validate());
+        assert!(!mutation.password.is_valid());
+        assert_includes(mutation.password.errors, "Password cannot be empty");
+    }
+
+    #[test]
+    fn verify_two_factor_login_invalid_if_code_blank() {
+        let mut mutation = VerifyTwoFactorLogin::new(" ");
+
+        assert!(!mutation.validate());
+        assert!(!mutation.code.is_valid());
+        assert_includes(mutation.code.errors, "Code cannot be empty");
+    }
+
+    #[test]
+    fn confirm_two_factor_enrollment_invalid_if_code_blank() {
+        let mut mutation = ConfirmTwoFactorEnrollment::new("");
+
+        assert!(!mutation.validate());
+        assert!(!mutation.code.is_valid());
+        assert_includes(mutation.code.errors, "Code cannot be empty");
+    }
+
     #[test]
     fn authenticate_user_invalid_if_username_blank() {
-        let mut mutation = AuthenticateUser::new("", "password1");
+        let mut mutation = AuthenticateUser::new("", "plover-lantern-58");
 
         assert!(!mutation.validate());
         assert!(!mutation.handle.is_valid());
@@ -307,4 +2135,241 @@ mod tests {
         assert!(!mutation.password.is_valid());
         assert_includes(mutation.password.errors, "Password cannot be empty");
     }
+
+    #[test]
+    fn update_preferences_valid() {
+        let mut mutation = UpdatePreferences::from(&UserPreferences::default());
+        assert!(mutation.validate());
+        assert_eq!(UserPreferences::default(), mutation.preferences());
+    }
+
+    #[test]
+    fn update_preferences_invalid() {
+        let mut mutation = UpdatePreferences {
+            timezone: TextField::new("Mars/Olympus_Mons"),
+            daily_new_limit: TextField::new("-1"),
+            theme: TextField::new("sepia"),
+            ..UpdatePreferences::from(&UserPreferences::default())
+        };
+
+        assert!(!mutation.validate());
+        assert_includes(
+            mutation.timezone.errors,
+            "Time zone is not one that we know of",
+        );
+        assert_includes(
+            mutation.daily_new_limit.errors,
+            "Limit must be a number between 0 and 10000",
+        );
+        assert_includes(mutation.theme.errors, "Must be one of system, light, dark");
+        assert!(mutation.daily_review_limit.errors.is_empty());
+    }
+
+    #[test]
+    fn add_question_needs_text_or_link() {
+        let mut mutation = AddQuestion::new("Sorting", "", " ");
+        assert!(!mutation.validate());
+        assert_includes(
+            mutation.text.errors,
+            "Write a question or give a link to one",
+        );
+
+        assert!(AddQuestion::new("Sorting", "https://leetcode.com", "").validate());
+        assert!(AddQuestion::new("Sorting", "", "Sort *this*.").validate());
+    }
+
+    #[test]
+    fn add_flashcard() {
+        let mut mutation = AddQuestion::flashcard("Capitals", "Capital of Peru?", " ");
+        assert!(!mutation.validate());
+        assert_includes(
+            mutation.back.errors,
+            "Give the answer for the back of the card",
+        );
+
+        assert!(AddQuestion::flashcard("Capitals", "Capital of Peru?", "Lima").validate());
+
+        let mut mutation = AddQuestion {
+            kind: TextField::new("essay"),
+            ..AddQuestion::new("Sorting", "", "Sort *this*.")
+        };
+        assert!(!mutation.validate());
+        assert_includes(mutation.kind.errors, "Choose a kind of question");
+    }
+
+    #[test]
+    fn add_multiple_choice() {
+        let options = "* Lima\nQuito\n* Cusco";
+        let mut mutation = AddQuestion::multiple_choice("Peru", "Cities in Peru?", options, false);
+        assert!(!mutation.validate());
+        assert_includes(
+            mutation.options.errors,
+            "Mark only one option as correct, or allow more than one",
+        );
+
+        let mut mutation = AddQuestion::multiple_choice("Peru", "Cities in Peru?", options, true);
+        assert!(mutation.validate());
+        let mut question = CreateQuestion::default();
+        mutation.question_kind().fill(&mutation, &mut question);
+        assert_eq!(3, question.options.len());
+        assert!(question.is_multi_select);
+
+        let mut mutation = AddQuestion::multiple_choice("Peru", "Capital?", "Lima\nQuito", false);
+        assert!(!mutation.validate());
+        assert_includes(
+            mutation.options.errors,
+            "Mark the correct option with an asterisk",
+        );
+
+        let mut mutation = AddQuestion::multiple_choice("Peru", "Capital?", "* Lima", false);
+        assert!(!mutation.validate());
+        assert_includes(mutation.options.errors, "Give at least two options");
+    }
+
+    #[test]
+    fn add_numeric_question() {
+        let numeric = NumericAnswerFields {
+            number: TextField::new("500"),
+            unit: TextField::new("mL"),
+            significant_figures: TextField::new("3"),
+            ..NumericAnswerFields::default()
+        };
+        let mut mutation = AddQuestion::numeric("Volume", "How much?", numeric.clone());
+        assert!(mutation.validate());
+        assert_eq!(
+            Some(NumericAnswer {
+                value: 500.0,
+                tolerance: 0.0,
+                tolerance_kind: "absolute".to_string(),
+                unit: Some("mL".to_string()),
+                significant_figures: Some(3),
+            }),
+            mutation.numeric.numeric_answer()
+        );
+
+        let numeric = NumericAnswerFields {
+            number: TextField::new("five hundred"),
+            tolerance: TextField::new("-1"),
+            significant_figures: TextField::new("0"),
+            ..numeric
+        };
+        let mut mutation = AddQuestion::numeric("Volume", "How much?", numeric);
+        assert!(!mutation.validate());
+        assert_includes(
+            mutation.numeric.number.errors,
+            "Give the answer as a number",
+        );
+        assert_includes(
+            mutation.numeric.tolerance.errors,
+            "Tolerance must be a number that is not negative",
+        );
+        assert_includes(
+            mutation.numeric.significant_figures.errors,
+            "Significant figures must be a whole number from 1 to 20",
+        );
+    }
+
+    #[test]
+    fn add_template_question() {
+        let numeric = NumericAnswerFields {
+            unit: TextField::new("N"),
+            tolerance: TextField::new("0.01"),
+            tolerance_kind: TextField::new("relative"),
+            ..NumericAnswerFields::default()
+        };
+        let mut mutation = AddQuestion::template(
+            "Weight",
+            "What does {{ mass }} kg weigh?",
+            "mass = 2..10",
+            "mass * 9.8",
+            numeric.clone(),
+        );
+        assert!(mutation.validate());
+        assert_eq!(
+            Some("mass * 9.8".to_string()),
+            mutation.question_template().map(|template| template.answer)
+        );
+
+        let mut mutation = AddQuestion::template(
+            "Weight",
+            "What does {{ weight }} kg weigh?",
+            "mass = 10..2",
+            "mass / (mass - mass)",
+            numeric.clone(),
+        );
+        assert!(!mutation.validate());
+        assert_includes(mutation.variables.errors, "The range of mass goes down");
+
+        let mut mutation = AddQuestion::template(
+            "Weight",
+            "What does {{ weight }} kg weigh?",
+            "mass = 2..10",
+            "mass / (mass - mass)",
+            numeric.clone(),
+        );
+        assert!(!mutation.validate());
+        assert_includes(
+            mutation.answer_expression.errors,
+            "The answer is not a number for some values of the variables",
+        );
+
+        let mut mutation = AddQuestion::template(
+            "Weight",
+            "What does {{ weight }} kg weigh?",
+            "mass = 2..10",
+            "mass * 9.8",
+            numeric,
+        );
+        assert!(!mutation.validate());
+        assert_includes(mutation.text.errors, "Unknown variable \"weight\"");
+    }
+
+    #[test]
+    fn edit_question_invalid() {
+        let mut mutation = EditQuestion::new(" ", "not a link", "");
+
+        assert!(!mutation.validate());
+        assert_includes(mutation.title.errors, "Title cannot be empty");
+        assert_includes(mutation.link.errors, "Link is not a valid URL");
+        assert_includes(mutation.text.errors, "Question cannot be empty");
+    }
+
+    #[test]
+    fn edit_question_without_link() {
+        let mut mutation = EditQuestion::new("Sorting", " ", "Sort the list.");
+
+        assert!(mutation.validate());
+        assert_eq!(None, mutation.link());
+    }
+
+    #[test]
+    fn create_invite_valid() {
+        let mut mutation = CreateInvite::new("1", "7");
+        assert!(mutation.validate());
+        assert_eq!(Some(7), mutation.expires_in_days());
+
+        let mut mutation = CreateInvite::new("25", "");
+        assert!(mutation.validate());
+        assert_eq!(None, mutation.expires_in_days());
+    }
+
+    #[test]
+    fn create_invite_invalid_uses() {
+        let mut mutation = CreateInvite::new("0", "7");
+        assert!(!mutation.validate());
+        assert_includes(
+            mutation.max_uses.errors,
+            "Number of uses must be between 1 and 1000",
+        );
+    }
+
+    #[test]
+    fn create_invite_invalid_days() {
+        let mut mutation = CreateInvite::new("1", "a week");
+        assert!(!mutation.validate());
+        assert_includes(
+            mutation.expires_in_days.errors,
+            "Days must be between 1 and 365, or left empty",
+        );
+    }
 }
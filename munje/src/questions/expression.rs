@@ -0,0 +1,182 @@
+use std::{collections::HashMap, iter::Peekable, str::Chars};
+
+/// Evaluates an arithmetic expression such as "2 * pi * sqrt(l / g)", with the given values for
+/// the variables in it.  Supports + - * / ^, parentheses, and a few functions.
+pub fn evaluate(expression: &str, values: &HashMap<String, f64>) -> Result<f64, String> {
+    let mut parser = Parser {
+        chars: expression.chars().peekable(),
+        values,
+    };
+    let value = parser.expression()?;
+    parser.skip_whitespace();
+    match parser.chars.next() {
+        None => Ok(value),
+        Some(c) => Err(format!("Unexpected \"{}\" in \"{}\"", c, expression)),
+    }
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+    values: &'a HashMap<String, f64>,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_whitespace(&mut self) {
+        while self.chars.next_if(|c| c.is_whitespace()).is_some() {}
+    }
+
+    fn next_is(&mut self, expected: char) -> bool {
+        self.skip_whitespace();
+        self.chars.next_if_eq(&expected).is_some()
+    }
+
+    fn expression(&mut self) -> Result<f64, String> {
+        let mut value = self.term()?;
+        loop {
+            if self.next_is('+') {
+                value += self.term()?;
+            } else if self.next_is('-') {
+                value -= self.term()?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn term(&mut self) -> Result<f64, String> {
+        let mut value = self.unary()?;
+        loop {
+            if self.next_is('*') {
+                value *= self.unary()?;
+            } else if self.next_is('/') {
+                value /= self.unary()?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    // A minus sign applies after powers, so that -a^2 is -(a^2).
+    fn unary(&mut self) -> Result<f64, String> {
+        if self.next_is('-') {
+            Ok(-self.unary()?)
+        } else {
+            self.power()
+        }
+    }
+
+    // Powers group to the right, so that 2^3^2 is 2^9.
+    fn power(&mut self) -> Result<f64, String> {
+        let base = self.primary()?;
+        if self.next_is('^') {
+            Ok(base.powf(self.unary()?))
+        } else {
+            Ok(base)
+        }
+    }
+
+    fn primary(&mut self) -> Result<f64, String> {
+        self.skip_whitespace();
+        match self.chars.peek() {
+            Some('(') => {
+                self.chars.next();
+                let value = self.expression()?;
+                if self.next_is(')') {
+                    Ok(value)
+                } else {
+                    Err("Missing \")\"".to_string())
+                }
+            }
+            Some(c) if c.is_ascii_digit() || *c == '.' => self.number(),
+            Some(c) if c.is_alphabetic() || *c == '_' => self.name(),
+            Some(c) => Err(format!("Unexpected \"{}\"", c)),
+            None => Err("The expression ends too soon".to_string()),
+        }
+    }
+
+    fn number(&mut self) -> Result<f64, String> {
+        let mut number = String::new();
+        while let Some(c) = self.chars.next_if(|c| c.is_ascii_digit() || *c == '.') {
+            number.push(c);
+        }
+        number
+            .parse()
+            .map_err(|_| format!("\"{}\" is not a number", number))
+    }
+
+    fn name(&mut self) -> Result<f64, String> {
+        let mut name = String::new();
+        while let Some(c) = self.chars.next_if(|c| c.is_alphanumeric() || *c == '_') {
+            name.push(c);
+        }
+
+        if self.next_is('(') {
+            let argument = self.expression()?;
+            if !self.next_is(')') {
+                return Err("Missing \")\"".to_string());
+            }
+            return apply(&name, argument);
+        }
+
+        match (self.values.get(&name), name.as_ref()) {
+            (Some(value), _) => Ok(*value),
+            (None, "pi") => Ok(std::f64::consts::PI),
+            (None, _) => Err(format!("Unknown variable \"{}\"", name)),
+        }
+    }
+}
+
+fn apply(function: &str, argument: f64) -> Result<f64, String> {
+    let value = match function {
+        "abs" => argument.abs(),
+        "sqrt" => argument.sqrt(),
+        "exp" => argument.exp(),
+        "ln" => argument.ln(),
+        "log" => argument.log10(),
+        "sin" => argument.sin(),
+        "cos" => argument.cos(),
+        "tan" => argument.tan(),
+        "round" => argument.round(),
+        "floor" => argument.floor(),
+        "ceil" => argument.ceil(),
+        _ => return Err(format!("Unknown function \"{}\"", function)),
+    };
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(expression: &str) -> Result<f64, String> {
+        let values = vec![("a".to_string(), 3.0), ("rate_2".to_string(), 0.5)]
+            .into_iter()
+            .collect();
+        evaluate(expression, &values)
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(Ok(7.0), eval("1 + 2 * 3"));
+        assert_eq!(Ok(9.0), eval("(1 + 2) * a"));
+        assert_eq!(Ok(512.0), eval("2^3^2"));
+        assert_eq!(Ok(-9.0), eval("-a^2"));
+        assert_eq!(Ok(1.5), eval("a * rate_2"));
+        assert_eq!(Ok(2.0), eval("sqrt(a + 1)"));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(Err("Unknown variable \"b\"".to_string()), eval("a + b"));
+        assert_eq!(
+            Err("Unknown function \"cube\"".to_string()),
+            eval("cube(a)")
+        );
+        assert_eq!(Err("Missing \")\"".to_string()), eval("(a + 1"));
+        assert_eq!(
+            Err("Unexpected \")\" in \"a + 1)\"".to_string()),
+            eval("a + 1)")
+        );
+        assert_eq!(Err("The expression ends too soon".to_string()), eval("a +"));
+    }
+}
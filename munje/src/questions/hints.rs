@@ -0,0 +1,134 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{questions::Question, types::Markdown};
+
+/// How much help was shown while a question was being answered: the first `hints` of its hints,
+/// in order, and then perhaps the worked solution.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Help {
+    pub hints: i32,
+    pub solution: bool,
+}
+
+impl Help {
+    /// The help as far as the question has any.  The counts come back from the page, so they are
+    /// not trusted.
+    pub fn within(&self, question: &Question) -> Self {
+        Self {
+            hints: self.hints.clamp(0, question.hints.len() as i32),
+            solution: self.solution && question.solution.is_some(),
+        }
+    }
+
+    /// One more step of help: the next hint, or the solution once the hints have run out.  None
+    /// when everything has been shown.
+    pub fn next(&self, question: &Question) -> Option<Self> {
+        let help = self.within(question);
+        if help.hints < question.hints.len() as i32 {
+            Some(Self {
+                hints: help.hints + 1,
+                solution: false,
+            })
+        } else if !help.solution && question.solution.is_some() {
+            Some(Self {
+                solution: true,
+                ..help
+            })
+        } else {
+            None
+        }
+    }
+
+    /// The hints that have been shown, in order.
+    pub fn hints<'q>(&self, question: &'q Question) -> &'q [Markdown] {
+        &question.hints[..self.within(question).hints as usize]
+    }
+
+    /// The worked solution, once it has been shown.
+    pub fn solution<'q>(&self, question: &'q Question) -> Option<&'q Markdown> {
+        question.solution.as_ref().filter(|_| self.solution)
+    }
+}
+
+/// Hints are written one to a line.
+pub fn parse_hints(text: &str) -> Vec<String> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::questions::QuestionRow;
+
+    fn question(hints: &[&str], solution: Option<&str>) -> Question {
+        QuestionRow {
+            author_id: None,
+            back: None,
+            cloze_numbers: vec![],
+            created_at: chrono::Utc::now(),
+            external_id: "1".to_string(),
+            hints: hints.iter().map(|hint| hint.to_string()).collect(),
+            id: 1,
+            is_multi_select: false,
+            kind: "numeric".to_string(),
+            link_logo: None,
+            link: None,
+            solution: solution.map(str::to_string),
+            status: "published".to_string(),
+            text: "How many mL are there in half a liter?".to_string(),
+            title: "Liters".to_string(),
+            updated_at: chrono::Utc::now(),
+        }
+        .to_question()
+    }
+
+    #[test]
+    fn revealed_one_at_a_time() {
+        let question = question(
+            &["A liter is 1000 mL", "Halve it"],
+            Some("1000 mL / 2 = 500 mL"),
+        );
+        let mut help = Help::default();
+        assert!(help.hints(&question).is_empty());
+
+        let mut steps = vec![];
+        while let Some(next) = help.next(&question) {
+            help = next;
+            steps.push((
+                help.hints(&question).len(),
+                help.solution(&question).is_some(),
+            ));
+        }
+        assert_eq!(vec![(1, false), (2, false), (2, true)], steps);
+    }
+
+    #[test]
+    fn kept_within_the_question() {
+        let question = question(&["A liter is 1000 mL"], None);
+        let help = Help {
+            hints: 5,
+            solution: true,
+        };
+        assert_eq!(
+            Help {
+                hints: 1,
+                solution: false
+            },
+            help.within(&question)
+        );
+        assert_eq!(None, help.next(&question));
+        assert!(help.solution(&question).is_none());
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            vec!["A liter is 1000 mL", "Halve it"],
+            parse_hints(" A liter is 1000 mL\n\nHalve it \n")
+        );
+    }
+}
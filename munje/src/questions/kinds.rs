@@ -0,0 +1,618 @@
+use async_trait::async_trait;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::{
+    mutations::{validate_back, validate_clozes, validate_options, AddQuestion, EditQuestion},
+    prelude::*,
+    questions::{
+        grade, shuffle, CreateOption, CreateQuestion, Help, Question, QuestionOption,
+        QuestionTemplate, UpdateQuestion,
+    },
+    types::{Markdown, Pool},
+};
+
+/// What is different about each kind of question: what its forms need, what is stored for it
+/// besides the question itself, how it is put to someone in a queue, how an answer to it is
+/// graded, and what is exported.  A new kind of question is added by implementing this trait and
+/// listing it in `KINDS`.
+#[async_trait]
+pub trait QuestionKind: Sync {
+    /// The name that is kept in `questions.kind`.
+    fn name(&self) -> &'static str;
+
+    /// Checks the fields of the form for a new question that the kind uses, and notes on them
+    /// what is wrong.
+    fn validate(&self, form: &mut AddQuestion) -> bool;
+
+    /// Checks the fields of the form for editing a question of the kind.
+    fn validate_edit(&self, _form: &mut EditQuestion) -> bool {
+        true
+    }
+
+    /// What is wrong with the text for the question, given what is stored for it, if anything.
+    /// This is checked when the question is edited and when an earlier revision is put back.
+    async fn text_error(
+        &self,
+        _question: &Question,
+        _text: &str,
+        _db: &Pool,
+    ) -> Result<Option<String>, Error> {
+        Ok(None)
+    }
+
+    /// Takes what the kind keeps from the form for a new question.
+    fn fill(&self, _form: &AddQuestion, _question: &mut CreateQuestion) {}
+
+    /// Takes what the kind keeps from the form for an edit.
+    fn fill_update(&self, _form: &EditQuestion, _update: &mut UpdateQuestion) {}
+
+    /// The items that the question is reviewed as, each scheduled on its own.  Only cloze
+    /// questions have more than one.
+    fn cloze_numbers(&self, _text: &str) -> Vec<i32> {
+        vec![]
+    }
+
+    /// Saves what the kind keeps apart from the `questions` row, in the transaction that creates
+    /// the question.
+    async fn store(
+        &self,
+        _question_id: i64,
+        _question: &CreateQuestion,
+        _tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// The question as it is put to someone on the queue page.
+    async fn prompt(
+        &self,
+        question: &Question,
+        asking: &Asking,
+        db: &Pool,
+    ) -> Result<Prompt, Error>;
+
+    /// Grades what was submitted from the queue page.
+    async fn grade(
+        &self,
+        question: &Question,
+        submitted: &SubmittedAnswer,
+        db: &Pool,
+    ) -> Result<Grade, Error>;
+
+    /// What the kind adds to the exported question.
+    async fn export(&self, _question: &Question, _db: &Pool) -> Result<Value, Error> {
+        Ok(json!({}))
+    }
+}
+
+static KINDS: [&dyn QuestionKind; 6] = [
+    &Link,
+    &Flashcard,
+    &MultipleChoice,
+    &Numeric,
+    &Template,
+    &Cloze,
+];
+
+/// The kind with the name, if there is one.
+pub fn find(name: &str) -> Option<&'static dyn QuestionKind> {
+    KINDS.iter().copied().find(|kind| kind.name() == name)
+}
+
+/// How a question is being asked: about which cloze deletion, whether the answer has been
+/// revealed yet, and with the variant of a template question that was chosen when it was asked.
+#[derive(Debug, Default)]
+pub struct Asking {
+    pub cloze: i32,
+    pub revealed: bool,
+    pub seed: Option<i64>,
+}
+
+/// The form that the queue page shows under a question.
+#[derive(Debug, PartialEq, Eq)]
+pub enum AnswerForm {
+    /// Buttons for the user to say how they did.
+    SelfGraded,
+    /// A button that shows the answer, after which the user says how they did.
+    Reveal,
+    /// Options to choose from.
+    Options,
+    /// A field for a number.
+    Number,
+}
+
+/// A question as it is put to someone.
+#[derive(Debug)]
+pub struct Prompt {
+    /// The question, as HTML.
+    pub text: String,
+    /// The answer, as HTML, once it has been revealed.
+    pub answer: Option<String>,
+    pub form: AnswerForm,
+    pub options: Vec<QuestionOption>,
+    pub is_multi_select: bool,
+    /// What the answer to a numeric question should look like.
+    pub instructions: Option<String>,
+    pub cloze: i32,
+}
+
+impl Prompt {
+    fn new(text: String, form: AnswerForm) -> Self {
+        Self {
+            text,
+            answer: None,
+            form,
+            options: vec![],
+            is_multi_select: false,
+            instructions: None,
+            cloze: 0,
+        }
+    }
+}
+
+/// What was submitted from the queue page.
+#[derive(Debug, Default, Clone)]
+pub struct SubmittedAnswer {
+    /// The user's own grade, for questions that are not graded for them.
+    pub state: Option<String>,
+    pub selected_options: Vec<i64>,
+    pub value: Option<String>,
+    /// The variant of a template question that was asked, which is looked up rather than taken
+    /// from the page.
+    pub seed: Option<i64>,
+    pub cloze: i32,
+    /// The hints, and perhaps the worked solution, that were shown before answering, which are
+    /// looked up rather than taken from the page.
+    pub help: Help,
+}
+
+/// A graded answer, with what is kept of the submission.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Grade {
+    pub state: String,
+    pub selected_options: Option<Vec<i64>>,
+    pub submitted_value: Option<String>,
+    pub seed: Option<i64>,
+    pub cloze: i32,
+}
+
+// Every kind but a link needs a question to be written.
+fn validate_text(form: &mut AddQuestion, error: &str) -> bool {
+    let valid = !form.text.value.trim().is_empty();
+    if !valid {
+        form.text.errors.push(error.to_string());
+    }
+    valid
+}
+
+impl Grade {
+    fn self_graded(submitted: &SubmittedAnswer) -> Result<Self, Error> {
+        let state = submitted
+            .state
+            .clone()
+            .ok_or_else(|| Error::Generic("No answer was given".to_string()))?;
+        Ok(Self {
+            state,
+            ..Self::default()
+        })
+    }
+
+    fn value(submitted: &SubmittedAnswer) -> String {
+        submitted.value.clone().unwrap_or_default()
+    }
+}
+
+/// A question written in Markdown, or a link to a challenge somewhere else, that the user grades
+/// themselves.
+pub struct Link;
+
+#[async_trait]
+impl QuestionKind for Link {
+    fn name(&self) -> &'static str {
+        "link"
+    }
+
+    fn validate(&self, form: &mut AddQuestion) -> bool {
+        let valid = !form.text.value.trim().is_empty() || form.link().is_some();
+        if !valid {
+            form.text
+                .errors
+                .push("Write a question or give a link to one".to_string());
+        }
+        valid
+    }
+
+    async fn prompt(&self, question: &Question, _: &Asking, _: &Pool) -> Result<Prompt, Error> {
+        Ok(Prompt::new(
+            question.text.markdown(),
+            AnswerForm::SelfGraded,
+        ))
+    }
+
+    async fn grade(
+        &self,
+        _: &Question,
+        submitted: &SubmittedAnswer,
+        _: &Pool,
+    ) -> Result<Grade, Error> {
+        Grade::self_graded(submitted)
+    }
+}
+
+/// A card with the answer on the back, which is hidden until it is asked for.
+pub struct Flashcard;
+
+#[async_trait]
+impl QuestionKind for Flashcard {
+    fn name(&self) -> &'static str {
+        "flashcard"
+    }
+
+    fn validate(&self, form: &mut AddQuestion) -> bool {
+        validate_text(form, "Write the front of the card") & validate_back(&mut form.back)
+    }
+
+    fn validate_edit(&self, form: &mut EditQuestion) -> bool {
+        validate_back(&mut form.back)
+    }
+
+    fn fill(&self, form: &AddQuestion, question: &mut CreateQuestion) {
+        question.back = Some(form.back.value.clone());
+    }
+
+    fn fill_update(&self, form: &EditQuestion, update: &mut UpdateQuestion) {
+        update.back = Some(form.back.value.clone());
+    }
+
+    async fn prompt(
+        &self,
+        question: &Question,
+        asking: &Asking,
+        _: &Pool,
+    ) -> Result<Prompt, Error> {
+        if !asking.revealed {
+            return Ok(Prompt::new(question.text.markdown(), AnswerForm::Reveal));
+        }
+        Ok(Prompt {
+            answer: question.back.as_ref().map(Markdown::markdown),
+            ..Prompt::new(question.text.markdown(), AnswerForm::SelfGraded)
+        })
+    }
+
+    async fn grade(
+        &self,
+        _: &Question,
+        submitted: &SubmittedAnswer,
+        _: &Pool,
+    ) -> Result<Grade, Error> {
+        Grade::self_graded(submitted)
+    }
+
+    async fn export(&self, question: &Question, _: &Pool) -> Result<Value, Error> {
+        Ok(json!({ "back": question.source_back() }))
+    }
+}
+
+/// Options to choose from, one or more of which are correct.
+pub struct MultipleChoice;
+
+#[derive(Serialize)]
+struct ExportedOption<'a> {
+    text: &'a str,
+    is_correct: bool,
+}
+
+#[async_trait]
+impl QuestionKind for MultipleChoice {
+    fn name(&self) -> &'static str {
+        "multiple-choice"
+    }
+
+    fn validate(&self, form: &mut AddQuestion) -> bool {
+        validate_text(form, "Write the question")
+            & validate_options(&mut form.options, form.is_multi_select)
+    }
+
+    fn fill(&self, form: &AddQuestion, question: &mut CreateQuestion) {
+        question.is_multi_select = form.is_multi_select;
+        question.options = CreateOption::parse_lines(&form.options.value);
+    }
+
+    async fn store(
+        &self,
+        question_id: i64,
+        question: &CreateQuestion,
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    ) -> Result<(), Error> {
+        QuestionOption::create_all(question_id, &question.options, tx).await
+    }
+
+    async fn prompt(&self, question: &Question, _: &Asking, db: &Pool) -> Result<Prompt, Error> {
+        let mut options = question.options(db).await?;
+        shuffle(&mut options);
+        Ok(Prompt {
+            options,
+            is_multi_select: question.is_multi_select,
+            ..Prompt::new(question.text.markdown(), AnswerForm::Options)
+        })
+    }
+
+    async fn grade(
+        &self,
+        question: &Question,
+        submitted: &SubmittedAnswer,
+        db: &Pool,
+    ) -> Result<Grade, Error> {
+        let options = question.options(db).await?;
+        Ok(Grade {
+            state: grade(&options, &submitted.selected_options).to_string(),
+            selected_options: Some(submitted.selected_options.clone()),
+            ..Grade::default()
+        })
+    }
+
+    async fn export(&self, question: &Question, db: &Pool) -> Result<Value, Error> {
+        let options = question.options(db).await?;
+        let options: Vec<ExportedOption> = options
+            .iter()
+            .map(|option| ExportedOption {
+                text: &option.text,
+                is_correct: option.is_correct,
+            })
+            .collect();
+        Ok(json!({
+            "is_multi_select": question.is_multi_select,
+            "options": options,
+        }))
+    }
+}
+
+/// A question that is answered with a number, which may be given in other units.
+pub struct Numeric;
+
+#[async_trait]
+impl QuestionKind for Numeric {
+    fn name(&self) -> &'static str {
+        "numeric"
+    }
+
+    fn validate(&self, form: &mut AddQuestion) -> bool {
+        validate_text(form, "Write the question") & form.numeric.validate()
+    }
+
+    fn fill(&self, form: &AddQuestion, question: &mut CreateQuestion) {
+        question.numeric_answer = form.numeric.numeric_answer();
+    }
+
+    async fn store(
+        &self,
+        question_id: i64,
+        question: &CreateQuestion,
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    ) -> Result<(), Error> {
+        match &question.numeric_answer {
+            Some(answer) => answer.create(question_id, tx).await,
+            None => Err(Error::Generic(
+                "A numeric question needs an answer".to_string(),
+            )),
+        }
+    }
+
+    async fn prompt(&self, question: &Question, _: &Asking, db: &Pool) -> Result<Prompt, Error> {
+        let answer = question.numeric_answer(db).await?;
+        Ok(Prompt {
+            instructions: Some(answer.instructions()),
+            ..Prompt::new(question.text.markdown(), AnswerForm::Number)
+        })
+    }
+
+    async fn grade(
+        &self,
+        question: &Question,
+        submitted: &SubmittedAnswer,
+        db: &Pool,
+    ) -> Result<Grade, Error> {
+        let value = Grade::value(submitted);
+        Ok(Grade {
+            state: question.numeric_answer(db).await?.grade(&value).to_string(),
+            submitted_value: Some(value),
+            ..Grade::default()
+        })
+    }
+
+    async fn export(&self, question: &Question, db: &Pool) -> Result<Value, Error> {
+        let answer = question.numeric_answer(db).await?;
+        Ok(json!({ "answer": answer }))
+    }
+}
+
+/// A numeric question whose numbers are drawn afresh each time that it is asked.
+pub struct Template;
+
+#[async_trait]
+impl QuestionKind for Template {
+    fn name(&self) -> &'static str {
+        "template"
+    }
+
+    fn validate(&self, form: &mut AddQuestion) -> bool {
+        validate_text(form, "Write the question")
+            & form.numeric.validate_grading()
+            & form.validate_template()
+    }
+
+    // The text can only use the variables that the question already has.
+    async fn text_error(
+        &self,
+        question: &Question,
+        text: &str,
+        db: &Pool,
+    ) -> Result<Option<String>, Error> {
+        Ok(question.template(db).await?.check_text(text).err())
+    }
+
+    fn fill(&self, form: &AddQuestion, question: &mut CreateQuestion) {
+        question.template = form.question_template();
+    }
+
+    async fn store(
+        &self,
+        question_id: i64,
+        question: &CreateQuestion,
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    ) -> Result<(), Error> {
+        match &question.template {
+            Some(template) => template.create(question_id, tx).await,
+            None => Err(Error::Generic(
+                "A template question needs a template".to_string(),
+            )),
+        }
+    }
+
+    async fn prompt(
+        &self,
+        question: &Question,
+        asking: &Asking,
+        db: &Pool,
+    ) -> Result<Prompt, Error> {
+        let seed = asking.seed.unwrap_or_else(QuestionTemplate::new_seed);
+        let variant = question.variant(seed, db).await?;
+        Ok(Prompt {
+            instructions: Some(variant.answer.instructions()),
+            ..Prompt::new(variant.text.markdown(), AnswerForm::Number)
+        })
+    }
+
+    async fn grade(
+        &self,
+        question: &Question,
+        submitted: &SubmittedAnswer,
+        db: &Pool,
+    ) -> Result<Grade, Error> {
+        let seed = submitted
+            .seed
+            .ok_or_else(|| Error::Generic("The question was not asked".to_string()))?;
+        let value = Grade::value(submitted);
+        Ok(Grade {
+            state: question
+                .variant(seed, db)
+                .await?
+                .answer
+                .grade(&value)
+                .to_string(),
+            submitted_value: Some(value),
+            seed: Some(seed),
+            ..Grade::default()
+        })
+    }
+
+    async fn export(&self, question: &Question, db: &Pool) -> Result<Value, Error> {
+        Ok(json!({ "template": question.template(db).await? }))
+    }
+}
+
+/// A text with parts left out, each of which is reviewed on its own.
+pub struct Cloze;
+
+#[async_trait]
+impl QuestionKind for Cloze {
+    fn name(&self) -> &'static str {
+        "cloze"
+    }
+
+    fn cloze_numbers(&self, text: &str) -> Vec<i32> {
+        Markdown::cloze_numbers(text)
+    }
+
+    fn validate(&self, form: &mut AddQuestion) -> bool {
+        validate_clozes(&mut form.text)
+    }
+
+    fn validate_edit(&self, form: &mut EditQuestion) -> bool {
+        validate_clozes(&mut form.text)
+    }
+
+    async fn prompt(
+        &self,
+        question: &Question,
+        asking: &Asking,
+        _: &Pool,
+    ) -> Result<Prompt, Error> {
+        let prompt = if asking.revealed {
+            Prompt::new(question.text.revealed(asking.cloze), AnswerForm::SelfGraded)
+        } else {
+            Prompt::new(question.text.masked(asking.cloze), AnswerForm::Reveal)
+        };
+        Ok(Prompt {
+            cloze: asking.cloze,
+            ..prompt
+        })
+    }
+
+    async fn grade(
+        &self,
+        _: &Question,
+        submitted: &SubmittedAnswer,
+        _: &Pool,
+    ) -> Result<Grade, Error> {
+        Ok(Grade {
+            cloze: submitted.cloze,
+            ..Grade::self_graded(submitted)?
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_kinds() {
+        for name in [
+            "link",
+            "flashcard",
+            "multiple-choice",
+            "numeric",
+            "template",
+            "cloze",
+        ] {
+            assert_eq!(Some(name), find(name).map(|kind| kind.name()));
+        }
+        assert!(find("essay").is_none());
+    }
+
+    #[test]
+    fn self_graded() {
+        let submitted = SubmittedAnswer {
+            state: Some("correct".to_string()),
+            cloze: 2,
+            ..SubmittedAnswer::default()
+        };
+        assert_eq!(
+            Grade {
+                state: "correct".to_string(),
+                ..Grade::default()
+            },
+            Grade::self_graded(&submitted).unwrap()
+        );
+        assert!(Grade::self_graded(&SubmittedAnswer::default()).is_err());
+    }
+
+    #[test]
+    fn fill_from_form() {
+        let form = AddQuestion::flashcard("Capitals", "Peru?", "Lima");
+        let mut question = CreateQuestion::default();
+        Link.fill(&form, &mut question);
+        assert_eq!(None, question.back);
+        Flashcard.fill(&form, &mut question);
+        assert_eq!(Some("Lima".to_string()), question.back);
+    }
+
+    #[test]
+    fn cloze_numbers() {
+        let text = "{{c2::Lima}} is the capital of {{c1::Peru}}";
+        assert_eq!(vec![1, 2], Cloze.cloze_numbers(text));
+        assert!(Link.cloze_numbers(text).is_empty());
+    }
+}
@@ -1,3 +1,16 @@
+mod expression;
+mod hints;
+pub mod kinds;
 mod models;
+mod numeric;
+mod options;
 pub mod routes;
+mod templates;
+mod units;
+pub use hints::{parse_hints, Help};
+pub use kinds::QuestionKind;
 pub use models::*;
+pub use numeric::NumericAnswer;
+pub use options::{grade, shuffle, CreateOption, QuestionOption};
+pub use templates::{format_number, QuestionTemplate, QuestionVariant, Variant};
+pub use units::Unit;
@@ -1,11 +1,16 @@
 use chrono;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
+use similar::{ChangeTag, TextDiff};
 use sqlx::FromRow;
 
 use crate::{
     models::Creatable,
     prelude::*,
+    questions::{
+        kinds, CreateOption, NumericAnswer, QuestionKind, QuestionOption, QuestionTemplate,
+        QuestionVariant,
+    },
     types::{DateTime, Markdown, Pool},
 };
 
@@ -13,18 +18,75 @@ use crate::{
 pub struct CreateQuestion {
     pub author_id: i64,
     pub title: String,
-    pub link: String,
+    /// Markdown.  When it is left empty, the question asks for the challenge at the link to be
+    /// completed.
+    pub text: String,
+    pub link: Option<String>,
     pub link_logo: Option<String>,
+    /// The name of a `QuestionKind`.
+    pub kind: String,
+    /// The answer on the back of a flashcard, in Markdown.
+    pub back: Option<String>,
+    /// Whether more than one option of a multiple-choice question can be correct.
+    pub is_multi_select: bool,
+    pub options: Vec<CreateOption>,
+    pub numeric_answer: Option<NumericAnswer>,
+    pub template: Option<QuestionTemplate>,
+    /// Revealed one at a time, in order, while the question is being answered.
+    pub hints: Vec<String>,
+    pub solution: Option<String>,
+    /// "published", or "draft" for a question that is not ready to be asked yet.
+    pub status: String,
+}
+
+impl Default for CreateQuestion {
+    fn default() -> Self {
+        Self {
+            author_id: 0,
+            title: String::new(),
+            text: String::new(),
+            link: None,
+            link_logo: None,
+            kind: "link".to_string(),
+            back: None,
+            is_multi_select: false,
+            options: vec![],
+            numeric_answer: None,
+            template: None,
+            hints: vec![],
+            solution: None,
+            status: "published".to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Deserialize, Clone)]
+pub struct UpdateQuestion {
+    pub editor_id: i64,
+    pub title: String,
+    pub text: String,
+    pub link: Option<String>,
+    pub link_logo: Option<String>,
+    pub back: Option<String>,
+    pub hints: Vec<String>,
+    pub solution: Option<String>,
 }
 
 #[derive(Debug, Serialize, FromRow)]
 pub struct QuestionRow {
-    pub author_id: i64,
+    pub author_id: Option<i64>,
+    pub back: Option<String>,
+    pub cloze_numbers: Vec<i32>,
     pub created_at: chrono::DateTime<chrono::Utc>,
     pub external_id: String,
+    pub hints: Vec<String>,
     pub id: i64,
+    pub is_multi_select: bool,
+    pub kind: String,
     pub link_logo: Option<String>,
     pub link: Option<String>,
+    pub solution: Option<String>,
+    pub status: String,
     pub text: String,
     pub title: String,
     pub updated_at: chrono::DateTime<chrono::Utc>,
@@ -32,12 +94,24 @@ pub struct QuestionRow {
 
 #[derive(Debug, Serialize)]
 pub struct Question {
-    pub author_id: i64,
+    pub author_id: Option<i64>,
+    pub back: Option<Markdown>,
+    /// The items that a cloze question is reviewed as.  Empty for other kinds of question.
+    pub cloze_numbers: Vec<i32>,
     pub created_at: DateTime,
     pub external_id: String,
+    /// Revealed one at a time, in order, on the queue page.
+    pub hints: Vec<Markdown>,
     pub id: i64,
+    pub is_multi_select: bool,
+    pub kind: String,
     pub link_logo: Option<String>,
     pub link: Option<String>,
+    /// The worked solution, revealed after the hints.
+    pub solution: Option<Markdown>,
+    /// One of "draft", "published", "archived" or "deleted".  Only published questions are
+    /// listed and asked in queues.
+    pub status: String,
     pub text: Markdown,
     pub title: String,
     pub updated_at: DateTime,
@@ -47,11 +121,18 @@ impl QuestionRow {
     pub fn to_question(&self) -> Question {
         Question {
             author_id: self.author_id,
+            back: self.back.clone().map(Markdown::from),
+            cloze_numbers: self.cloze_numbers.clone(),
             created_at: DateTime(self.created_at),
             external_id: self.external_id.clone(),
+            hints: self.hints.iter().cloned().map(Markdown::from).collect(),
             id: self.id,
+            is_multi_select: self.is_multi_select,
+            kind: self.kind.clone(),
             link_logo: self.link_logo.clone(),
             link: self.link.clone(),
+            solution: self.solution.clone().map(Markdown::from),
+            status: self.status.clone(),
             text: Markdown::from(self.text.clone()),
             title: self.title.clone(),
             updated_at: DateTime(self.updated_at),
@@ -63,9 +144,10 @@ impl Question {
     pub async fn find_all(db: &Pool) -> Result<Vec<Self>, Error> {
         let questions = sqlx::query_as!(
             QuestionRow,
-            "select id, external_id, author_id, title, text, link, link_logo, created_at,
-                updated_at
+            "select id, external_id, author_id, title, text, link, link_logo, kind, back,
+                is_multi_select, cloze_numbers, hints, solution, status, created_at, updated_at
              from questions
+             where status = 'published'
              order by created_at desc",
         )
         .fetch_all(db)
@@ -77,10 +159,12 @@ impl Question {
         Ok(questions)
     }
 
+    /// Questions that have been deleted are not found, although archived ones are, so that they
+    /// can still be looked at alongside their answers.
     pub async fn find(external_id: &str, db: &Pool) -> Result<Self, Error> {
         let row = sqlx::query_as!(
             QuestionRow,
-            "select * from questions where external_id = $1",
+            "select * from questions where external_id = $1 and status <> 'deleted'",
             external_id
         )
         .fetch_one(db)
@@ -96,19 +180,29 @@ impl Question {
     }
 
     pub async fn create(question: CreateQuestion, db: &Pool) -> Result<Self, Error> {
-        let s = format!(
-            "Complete the challenge at [this link]({}). When you're done, come back
-             to this question and indicate whether you solved the problem.",
-            question.link,
-        );
-        let text = Regex::new(r"\s+").unwrap().replace_all(&s, " ").to_string();
+        let text = match &question.link {
+            Some(link) if question.text.trim().is_empty() => {
+                let s = format!(
+                    "Complete the challenge at [this link]({}). When you're done, come back
+                     to this question and indicate whether you solved the problem.",
+                    link,
+                );
+                Regex::new(r"\s+").unwrap().replace_all(&s, " ").to_string()
+            }
+            _ => question.text.clone(),
+        };
+        let kind = kinds::find(&question.kind).ok_or_else(|| {
+            Error::Generic(format!("Unknown kind of question: {}", question.kind))
+        })?;
         let id = Self::next_id("questions_id_seq", db).await?;
 
+        let mut tx = db.begin().await?;
         let row = sqlx::query_as!(
             QuestionRow,
             "insert into questions
-                (id, external_id, author_id, title, text, link, link_logo)
-             values ($1, $2, $3, $4, $5, $6, $7)
+                (id, external_id, author_id, title, text, link, link_logo, kind, back,
+                 is_multi_select, cloze_numbers, hints, solution, status)
+             values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14)
              returning *",
             id.internal_id(),
             id.external_id(),
@@ -117,12 +211,497 @@ impl Question {
             text,
             question.link,
             question.link_logo,
+            question.kind,
+            question.back,
+            question.is_multi_select,
+            &kind.cloze_numbers(&text),
+            &question.hints,
+            question.solution,
+            question.status,
+        )
+        .fetch_one(&mut tx)
+        .await?;
+        kind.store(row.id, &question, &mut tx).await?;
+        QuestionRevision::create(&row, row.author_id, &mut tx).await?;
+        tx.commit().await?;
+
+        Ok(row.to_question())
+    }
+
+    /// Changes the question in place, so that answers keep pointing at it, and adds a revision.
+    pub async fn update(&self, question: UpdateQuestion, db: &Pool) -> Result<Self, Error> {
+        let mut tx = db.begin().await?;
+        let row = sqlx::query_as!(
+            QuestionRow,
+            "update questions set title = $2, text = $3, link = $4, link_logo = $5, back = $6,
+                cloze_numbers = $7, hints = $8, solution = $9
+             where id = $1
+             returning *",
+            self.id,
+            question.title,
+            question.text,
+            question.link,
+            question.link_logo,
+            question.back,
+            &self.question_kind().cloze_numbers(&question.text),
+            &question.hints,
+            question.solution,
+        )
+        .fetch_one(&mut tx)
+        .await?;
+        QuestionRevision::create(&row, Some(question.editor_id), &mut tx).await?;
+        tx.commit().await?;
+
+        Ok(row.to_question())
+    }
+
+    pub async fn publish(&self, db: &Pool) -> Result<Self, Error> {
+        self.set_status("published", db).await
+    }
+
+    /// Removes the question, unless something still points at it.  A question that has been
+    /// answered is archived, so that the answers keep their question, and one that a queue starts
+    /// from is only marked as deleted.  Returns the status that the question is left with.
+    pub async fn delete(&self, db: &Pool) -> Result<&'static str, Error> {
+        let references = sqlx::query!(
+            r#"select
+                exists(select 1 from answers where question_id = $1) "answered!",
+                exists(select 1 from queues where starting_question_id = $1) "queued!""#,
+            self.id,
         )
         .fetch_one(db)
         .await?;
 
+        if references.answered {
+            self.set_status("archived", db).await?;
+            Ok("archived")
+        } else if references.queued {
+            self.set_status("deleted", db).await?;
+            Ok("deleted")
+        } else {
+            // Revisions and what the kind keeps go with it.
+            sqlx::query!("delete from questions where id = $1", self.id)
+                .execute(db)
+                .await?;
+            Ok("deleted")
+        }
+    }
+
+    async fn set_status(&self, status: &str, db: &Pool) -> Result<Self, Error> {
+        let row = sqlx::query_as!(
+            QuestionRow,
+            "update questions set status = $2 where id = $1 returning *",
+            self.id,
+            status,
+        )
+        .fetch_one(db)
+        .await?;
         Ok(row.to_question())
     }
+
+    /// Puts back the content of an earlier revision, as a new revision.
+    pub async fn revert(
+        &self,
+        revision: &QuestionRevision,
+        editor_id: i64,
+        db: &Pool,
+    ) -> Result<Self, Error> {
+        let question = UpdateQuestion {
+            editor_id,
+            title: revision.title.clone(),
+            text: revision.text.clone(),
+            link: revision.link.clone(),
+            link_logo: revision.link_logo.clone(),
+            back: revision.back.clone(),
+            hints: revision.hints.clone(),
+            solution: revision.solution.clone(),
+        };
+        self.update(question, db).await
+    }
+
+    /// Newest first.
+    pub async fn revisions(&self, db: &Pool) -> Result<Vec<QuestionRevision>, Error> {
+        let revisions = sqlx::query_as!(
+            QuestionRevision,
+            r#"select r.*, u.handle "editor_handle?"
+             from question_revisions r
+             left join users u on r.editor_id = u.id
+             where r.question_id = $1
+             order by r.number desc"#,
+            self.id,
+        )
+        .fetch_all(db)
+        .await?;
+        Ok(revisions)
+    }
+
+    pub async fn revision(&self, number: i32, db: &Pool) -> Result<QuestionRevision, Error> {
+        let revision = sqlx::query_as!(
+            QuestionRevision,
+            r#"select r.*, u.handle "editor_handle?"
+             from question_revisions r
+             left join users u on r.editor_id = u.id
+             where r.question_id = $1 and r.number = $2"#,
+            self.id,
+            number,
+        )
+        .fetch_one(db)
+        .await?;
+        Ok(revision)
+    }
+
+    /// The kinds of question are fixed by a check constraint, so an unknown one is not expected.
+    pub fn question_kind(&self) -> &'static dyn QuestionKind {
+        kinds::find(&self.kind).unwrap_or(&kinds::Link)
+    }
+
+    /// The question with what its kind keeps, as JSON.
+    pub async fn export(&self, db: &Pool) -> Result<serde_json::Value, Error> {
+        let mut exported = serde_json::json!({
+            "title": self.title,
+            "kind": self.kind,
+            "text": self.source_text(),
+            "link": self.link,
+            "hints": self.source_hints(),
+            "solution": self.solution.as_ref().map(Markdown::source),
+        });
+        if let (Some(exported), serde_json::Value::Object(extra)) = (
+            exported.as_object_mut(),
+            self.question_kind().export(self, db).await?,
+        ) {
+            exported.extend(extra);
+        }
+        Ok(exported)
+    }
+
+    pub fn source_text(&self) -> &str {
+        self.text.source()
+    }
+
+    pub fn source_back(&self) -> &str {
+        self.back.as_ref().map(Markdown::source).unwrap_or_default()
+    }
+
+    pub fn source_hints(&self) -> Vec<String> {
+        self.hints
+            .iter()
+            .map(|hint| hint.source().to_string())
+            .collect()
+    }
+
+    pub fn source_solution(&self) -> &str {
+        self.solution
+            .as_ref()
+            .map(Markdown::source)
+            .unwrap_or_default()
+    }
+
+    pub fn is_draft(&self) -> bool {
+        self.status == "draft"
+    }
+
+    pub fn is_published(&self) -> bool {
+        self.status == "published"
+    }
+
+    pub fn is_archived(&self) -> bool {
+        self.status == "archived"
+    }
+
+    pub fn is_flashcard(&self) -> bool {
+        self.kind == "flashcard"
+    }
+
+    pub fn is_multiple_choice(&self) -> bool {
+        self.kind == "multiple-choice"
+    }
+
+    pub fn is_numeric(&self) -> bool {
+        self.kind == "numeric"
+    }
+
+    pub async fn numeric_answer(&self, db: &Pool) -> Result<NumericAnswer, Error> {
+        NumericAnswer::find(self.id, db).await
+    }
+
+    pub fn is_cloze(&self) -> bool {
+        self.kind == "cloze"
+    }
+
+    pub fn is_template(&self) -> bool {
+        self.kind == "template"
+    }
+
+    /// Whether the question is reviewed as the item: one of its cloze numbers, or 0 for a
+    /// question without cloze deletions.
+    pub fn has_cloze(&self, cloze: i32) -> bool {
+        if self.cloze_numbers.is_empty() {
+            cloze == 0
+        } else {
+            self.cloze_numbers.contains(&cloze)
+        }
+    }
+
+    pub async fn template(&self, db: &Pool) -> Result<QuestionTemplate, Error> {
+        QuestionTemplate::find(self.id, db).await
+    }
+
+    /// A template question with the numbers that the seed gives.  The same seed always gives the
+    /// same question, so that an earlier answer can be looked at again.
+    pub async fn variant(&self, seed: i64, db: &Pool) -> Result<QuestionVariant, Error> {
+        let template = self.template(db).await?;
+        let variant = template.variant(seed).map_err(Error::Generic)?;
+        let text = template
+            .render(self.source_text(), &variant)
+            .map_err(Error::Generic)?;
+        Ok(QuestionVariant {
+            seed,
+            text: Markdown::from(text),
+            answer: template.numeric_answer(&variant).map_err(Error::Generic)?,
+        })
+    }
+
+    /// In the order in which they were given.
+    pub async fn options(&self, db: &Pool) -> Result<Vec<QuestionOption>, Error> {
+        QuestionOption::find_all(self.id, db).await
+    }
+}
+
+/// A version of a question.
+#[derive(Debug, Serialize)]
+pub struct QuestionRevision {
+    pub back: Option<String>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub editor_handle: Option<String>,
+    pub editor_id: Option<i64>,
+    pub hints: Vec<String>,
+    pub id: i64,
+    pub link_logo: Option<String>,
+    pub link: Option<String>,
+    pub number: i32,
+    pub question_id: i64,
+    pub solution: Option<String>,
+    pub text: String,
+    pub title: String,
+}
+
+/// What changed between two revisions.  The text, the back of a flashcard, the hints and the
+/// worked solution are compared line by line.
+#[derive(Debug)]
+pub struct RevisionDiff {
+    pub title: Option<(String, String)>,
+    pub link: Option<(String, String)>,
+    pub lines: Vec<DiffLine>,
+    pub back_lines: Vec<DiffLine>,
+    pub hint_lines: Vec<DiffLine>,
+    pub solution_lines: Vec<DiffLine>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct DiffLine {
+    // One of "insert", "delete" or "equal".
+    pub change: &'static str,
+    pub text: String,
+}
+
+impl DiffLine {
+    pub fn sign(&self) -> &'static str {
+        match self.change {
+            "insert" => "+",
+            "delete" => "-",
+            _ => " ",
+        }
+    }
+}
+
+impl QuestionRevision {
+    async fn create(
+        row: &QuestionRow,
+        editor_id: Option<i64>,
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    ) -> Result<(), Error> {
+        sqlx::query!(
+            "insert into question_revisions
+                (question_id, number, editor_id, title, text, link, link_logo, back, hints,
+                 solution)
+             select $1, coalesce(max(number), 0) + 1, $2, $3, $4, $5, $6, $7, $8, $9
+             from question_revisions
+             where question_id = $1",
+            row.id,
+            editor_id,
+            row.title,
+            row.text,
+            row.link,
+            row.link_logo,
+            row.back,
+            &row.hints,
+            row.solution,
+        )
+        .execute(tx)
+        .await?;
+        Ok(())
+    }
+
+    pub fn saved_at(&self) -> String {
+        DateTime(self.created_at).humanize()
+    }
+
+    /// The changes that lead from `older` to this revision.
+    pub fn diff(&self, older: &Self) -> RevisionDiff {
+        let changed = |from: &str, to: &str| {
+            if from == to {
+                None
+            } else {
+                Some((from.to_string(), to.to_string()))
+            }
+        };
+
+        let optional_lines = |from: &Option<String>, to: &Option<String>| match (from, to) {
+            (None, None) => vec![],
+            (from, to) => diff_lines(
+                from.as_deref().unwrap_or_default(),
+                to.as_deref().unwrap_or_default(),
+            ),
+        };
+        // Hints are written one to a line.
+        let hint_text = |hints: &[String]| -> String {
+            hints.iter().map(|hint| format!("{}\n", hint)).collect()
+        };
+        let hint_lines = diff_lines(&hint_text(&older.hints), &hint_text(&self.hints));
+
+        RevisionDiff {
+            title: changed(&older.title, &self.title),
+            link: changed(
+                older.link.as_deref().unwrap_or_default(),
+                self.link.as_deref().unwrap_or_default(),
+            ),
+            lines: diff_lines(&older.text, &self.text),
+            back_lines: optional_lines(&older.back, &self.back),
+            hint_lines,
+            solution_lines: optional_lines(&older.solution, &self.solution),
+        }
+    }
+}
+
+fn diff_lines(from: &str, to: &str) -> Vec<DiffLine> {
+    TextDiff::from_lines(from, to)
+        .iter_all_changes()
+        .map(|change| DiffLine {
+            change: match change.tag() {
+                ChangeTag::Insert => "insert",
+                ChangeTag::Delete => "delete",
+                ChangeTag::Equal => "equal",
+            },
+            text: change.value().trim_end_matches('\n').to_string(),
+        })
+        .collect()
 }
 
 impl Creatable for Question {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn revision(number: i32, title: &str, text: &str) -> QuestionRevision {
+        QuestionRevision {
+            back: None,
+            created_at: chrono::Utc::now(),
+            editor_handle: None,
+            editor_id: None,
+            hints: vec![],
+            id: number.into(),
+            link_logo: None,
+            link: None,
+            number,
+            question_id: 1,
+            solution: None,
+            text: text.to_string(),
+            title: title.to_string(),
+        }
+    }
+
+    #[test]
+    fn diff_between_revisions() {
+        let older = revision(1, "Sorting", "Sort the list.\nUse merge sort.\n");
+        let newer = revision(2, "Sorting", "Sort the list.\nUse quicksort.\n");
+        let diff = newer.diff(&older);
+
+        assert_eq!(None, diff.title);
+        assert_eq!(None, diff.link);
+        assert_eq!(
+            vec![
+                ("equal", "Sort the list."),
+                ("delete", "Use merge sort."),
+                ("insert", "Use quicksort."),
+            ],
+            diff.lines
+                .iter()
+                .map(|line| (line.change, line.text.as_str()))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn diff_of_title() {
+        let older = revision(1, "Sorting", "Sort the list.");
+        let newer = revision(2, "Merge sort", "Sort the list.");
+        assert_eq!(
+            Some(("Sorting".to_string(), "Merge sort".to_string())),
+            newer.diff(&older).title
+        );
+    }
+
+    #[test]
+    fn diff_of_back() {
+        let older = revision(1, "Sorting", "What is the best case of quicksort?");
+        assert!(older.diff(&older).back_lines.is_empty());
+
+        let newer = QuestionRevision {
+            back: Some("O(n log n)".to_string()),
+            ..revision(2, "Sorting", "What is the best case of quicksort?")
+        };
+        assert_eq!(
+            vec![("insert", "O(n log n)")],
+            newer
+                .diff(&older)
+                .back_lines
+                .iter()
+                .map(|line| (line.change, line.text.as_str()))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn diff_of_help() {
+        let older = revision(1, "Liters", "How many mL are there in half a liter?");
+        let diff = older.diff(&older);
+        assert!(diff.hint_lines.is_empty());
+        assert!(diff.solution_lines.is_empty());
+
+        let newer = QuestionRevision {
+            hints: vec!["A liter is 1000 mL".to_string(), "Halve it".to_string()],
+            solution: Some("1000 mL / 2 = 500 mL".to_string()),
+            ..revision(2, "Liters", "How many mL are there in half a liter?")
+        };
+        let newest = QuestionRevision {
+            hints: vec!["A liter is 1000 mL".to_string()],
+            ..revision(3, "Liters", "How many mL are there in half a liter?")
+        };
+        let diff = newest.diff(&newer);
+        assert_eq!(
+            vec![("equal", "A liter is 1000 mL"), ("delete", "Halve it")],
+            diff.hint_lines
+                .iter()
+                .map(|line| (line.change, line.text.as_str()))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![("delete", "1000 mL / 2 = 500 mL")],
+            diff.solution_lines
+                .iter()
+                .map(|line| (line.change, line.text.as_str()))
+                .collect::<Vec<_>>()
+        );
+    }
+}
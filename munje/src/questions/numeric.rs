@@ -0,0 +1,228 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{prelude::*, questions::units::Unit, types::Pool};
+
+/// The expected answer to a numeric question.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct NumericAnswer {
+    pub value: f64,
+    pub tolerance: f64,
+    /// One of `NumericAnswer::TOLERANCE_KINDS`.  A relative tolerance is a fraction of the value.
+    pub tolerance_kind: String,
+    pub unit: Option<String>,
+    pub significant_figures: Option<i32>,
+}
+
+/// A number as it was typed, such as "0.500 L".
+#[derive(Debug, PartialEq)]
+pub struct Submission<'a> {
+    pub number: &'a str,
+    pub value: f64,
+    pub unit: Option<&'a str>,
+}
+
+impl<'a> Submission<'a> {
+    pub fn parse(text: &'a str) -> Option<Self> {
+        let re =
+            Regex::new(r"^\s*([-+]?(?:\d+\.?\d*|\.\d+)(?:[eE][-+]?\d+)?)\s*(.*?)\s*$").unwrap();
+        let captures = re.captures(text)?;
+        let number = captures.get(1)?.as_str();
+        let unit = captures.get(2).map(|unit| unit.as_str());
+        Some(Self {
+            number,
+            value: number.parse().ok()?,
+            unit: unit.filter(|unit| !unit.is_empty()),
+        })
+    }
+
+    /// The fewest and the most significant figures that the number could have.  Trailing zeros
+    /// in a number without a decimal point, as in "500", may or may not be significant.
+    pub fn significant_figures(&self) -> (usize, usize) {
+        let mantissa = self
+            .number
+            .split(['e', 'E'])
+            .next()
+            .unwrap_or_default()
+            .trim_start_matches(['-', '+']);
+        let digits: String = mantissa.chars().filter(char::is_ascii_digit).collect();
+        let digits = digits.trim_start_matches('0');
+
+        if digits.is_empty() {
+            (1, 1)
+        } else if mantissa.contains('.') {
+            (digits.len(), digits.len())
+        } else {
+            (digits.trim_end_matches('0').len(), digits.len())
+        }
+    }
+}
+
+impl NumericAnswer {
+    pub const TOLERANCE_KINDS: [&'static str; 2] = ["absolute", "relative"];
+
+    pub async fn find(question_id: i64, db: &Pool) -> Result<Self, Error> {
+        let answer = sqlx::query_as!(
+            Self,
+            "select value, tolerance, tolerance_kind, unit, significant_figures
+             from question_numeric_answers
+             where question_id = $1",
+            question_id,
+        )
+        .fetch_one(db)
+        .await?;
+        Ok(answer)
+    }
+
+    pub(super) async fn create(
+        &self,
+        question_id: i64,
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    ) -> Result<(), Error> {
+        sqlx::query!(
+            "insert into question_numeric_answers
+                (question_id, value, tolerance, tolerance_kind, unit, significant_figures)
+             values ($1, $2, $3, $4, $5, $6)",
+            question_id,
+            self.value,
+            self.tolerance,
+            self.tolerance_kind,
+            self.unit,
+            self.significant_figures,
+        )
+        .execute(tx)
+        .await?;
+        Ok(())
+    }
+
+    /// A number without a unit is taken to be in the unit of the answer.  A number in another
+    /// unit of the same kind is converted first, so that 0.5 L is accepted for 500 mL.
+    pub fn grade(&self, submitted: &str) -> &'static str {
+        match self.is_correct(submitted) {
+            Some(true) => "correct",
+            _ => "incorrect",
+        }
+    }
+
+    fn is_correct(&self, submitted: &str) -> Option<bool> {
+        let submission = Submission::parse(submitted)?;
+        let value = match (submission.unit, self.unit.as_deref()) {
+            (None, _) => submission.value,
+            (Some(from), Some(to)) if from == to => submission.value,
+            (Some(from), Some(to)) => {
+                Unit::find(from)?.convert(submission.value, &Unit::find(to)?)?
+            }
+            (Some(_), None) => return None,
+        };
+
+        let allowed = match self.tolerance_kind.as_ref() {
+            "relative" => self.tolerance * self.value.abs(),
+            _ => self.tolerance,
+        };
+        // Leaves room for rounding in the conversion.
+        let slack = 1e-9 * self.value.abs().max(1.0);
+        if (value - self.value).abs() > allowed + slack {
+            return Some(false);
+        }
+
+        let (fewest, most) = submission.significant_figures();
+        Some(
+            self.significant_figures
+                .is_none_or(|figures| (fewest..=most).contains(&(figures as usize))),
+        )
+    }
+
+    /// What the person answering needs to know about the form of the answer.
+    pub fn instructions(&self) -> String {
+        let mut instructions = match &self.unit {
+            Some(unit) => format!("Give the answer in {}", unit),
+            None => "Give the answer as a number".to_string(),
+        };
+        if let Some(figures) = self.significant_figures {
+            instructions.push_str(&format!(" to {} significant figures", figures));
+        }
+        instructions.push('.');
+        instructions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn answer(value: f64, unit: Option<&str>) -> NumericAnswer {
+        NumericAnswer {
+            value,
+            tolerance: 0.0,
+            tolerance_kind: "absolute".to_string(),
+            unit: unit.map(str::to_string),
+            significant_figures: None,
+        }
+    }
+
+    #[test]
+    fn parse_submission() {
+        assert_eq!(
+            Some(Submission {
+                number: "0.500",
+                value: 0.5,
+                unit: Some("L"),
+            }),
+            Submission::parse(" 0.500 L ")
+        );
+        assert_eq!(Some(None), Submission::parse("-1.5e3").map(|s| s.unit));
+        assert_eq!(None, Submission::parse("about five"));
+    }
+
+    #[test]
+    fn significant_figures() {
+        let figures = |text| Submission::parse(text).unwrap().significant_figures();
+        assert_eq!((3, 3), figures("0.00520"));
+        assert_eq!((1, 3), figures("500"));
+        assert_eq!((3, 3), figures("500."));
+        assert_eq!((4, 4), figures("1.230e-4"));
+        assert_eq!((1, 1), figures("0"));
+    }
+
+    #[test]
+    fn units() {
+        let answer = answer(500.0, Some("mL"));
+        assert_eq!("correct", answer.grade("500"));
+        assert_eq!("correct", answer.grade("500 mL"));
+        assert_eq!("correct", answer.grade("0.5 L"));
+        assert_eq!("incorrect", answer.grade("0.5 kg"));
+        assert_eq!("incorrect", answer.grade("0.5 furlongs"));
+        assert_eq!("incorrect", answer.grade("5 L"));
+    }
+
+    #[test]
+    fn tolerance() {
+        let mut answer = answer(9.81, None);
+        assert_eq!("incorrect", answer.grade("9.8"));
+
+        answer.tolerance = 0.01;
+        assert_eq!("correct", answer.grade("9.8"));
+        assert_eq!("incorrect", answer.grade("9.79"));
+
+        answer.tolerance_kind = "relative".to_string();
+        answer.tolerance = 0.05;
+        assert_eq!("correct", answer.grade("9.5"));
+        assert_eq!("incorrect", answer.grade("9.3"));
+        assert_eq!("incorrect", answer.grade("9.8 m"));
+    }
+
+    #[test]
+    fn significant_figures_required() {
+        let answer = NumericAnswer {
+            significant_figures: Some(3),
+            ..answer(0.0052, Some("g"))
+        };
+        assert_eq!("correct", answer.grade("0.00520 g"));
+        assert_eq!("correct", answer.grade("5.20 mg"));
+        assert_eq!("incorrect", answer.grade("0.0052 g"));
+        assert_eq!(
+            "Give the answer in g to 3 significant figures.",
+            answer.instructions()
+        );
+    }
+}
@@ -0,0 +1,148 @@
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+use crate::{prelude::*, types::Pool};
+
+/// One of the options of a multiple-choice question.
+#[derive(Debug, Clone, Serialize)]
+pub struct QuestionOption {
+    pub id: i64,
+    pub is_correct: bool,
+    pub position: i32,
+    pub question_id: i64,
+    pub text: String,
+}
+
+/// An option as it is given when a question is added.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CreateOption {
+    pub text: String,
+    pub is_correct: bool,
+}
+
+impl CreateOption {
+    /// Reads options written one to a line, with the correct ones marked by a leading asterisk,
+    /// as in "* Lima".
+    pub fn parse_lines(text: &str) -> Vec<Self> {
+        text.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(|line| match line.strip_prefix('*') {
+                Some(rest) => Self {
+                    text: rest.trim().to_string(),
+                    is_correct: true,
+                },
+                None => Self {
+                    text: line.to_string(),
+                    is_correct: false,
+                },
+            })
+            .collect()
+    }
+}
+
+impl QuestionOption {
+    pub async fn find_all(question_id: i64, db: &Pool) -> Result<Vec<Self>, Error> {
+        let options = sqlx::query_as!(
+            Self,
+            "select id, is_correct, position, question_id, text
+             from question_options
+             where question_id = $1
+             order by position",
+            question_id,
+        )
+        .fetch_all(db)
+        .await?;
+        Ok(options)
+    }
+
+    pub(super) async fn create_all(
+        question_id: i64,
+        options: &[CreateOption],
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    ) -> Result<(), Error> {
+        for (position, option) in options.iter().enumerate() {
+            sqlx::query!(
+                "insert into question_options (question_id, position, text, is_correct)
+                 values ($1, $2, $3, $4)",
+                question_id,
+                position as i32,
+                option.text,
+                option.is_correct,
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        Ok(())
+    }
+}
+
+/// Puts the options in a new order for each attempt, so that an answer cannot be remembered by
+/// where it is in the list.
+pub fn shuffle(options: &mut [QuestionOption]) {
+    options.shuffle(&mut rand::thread_rng());
+}
+
+/// An answer is correct when exactly the correct options were chosen.  Ids that are not among the
+/// options are ignored.
+pub fn grade(options: &[QuestionOption], selected: &[i64]) -> &'static str {
+    let correct: BTreeSet<i64> = options
+        .iter()
+        .filter(|option| option.is_correct)
+        .map(|option| option.id)
+        .collect();
+    let chosen: BTreeSet<i64> = selected
+        .iter()
+        .copied()
+        .filter(|id| options.iter().any(|option| option.id == *id))
+        .collect();
+
+    if chosen == correct {
+        "correct"
+    } else {
+        "incorrect"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn option(id: i64, is_correct: bool) -> QuestionOption {
+        QuestionOption {
+            id,
+            is_correct,
+            position: id as i32,
+            question_id: 1,
+            text: format!("Option {}", id),
+        }
+    }
+
+    #[test]
+    fn parse_lines() {
+        assert_eq!(
+            vec![
+                CreateOption {
+                    text: "Lima".to_string(),
+                    is_correct: true,
+                },
+                CreateOption {
+                    text: "Quito".to_string(),
+                    is_correct: false,
+                },
+            ],
+            CreateOption::parse_lines("*  Lima\n\n  Quito \n")
+        );
+    }
+
+    #[test]
+    fn grading() {
+        let options = vec![option(1, true), option(2, false), option(3, true)];
+        assert_eq!("correct", grade(&options, &[3, 1]));
+        assert_eq!("incorrect", grade(&options, &[1]));
+        assert_eq!("incorrect", grade(&options, &[1, 2, 3]));
+        assert_eq!("incorrect", grade(&options, &[]));
+        assert_eq!("correct", grade(&options, &[1, 3, 99]));
+    }
+}
@@ -5,21 +5,34 @@ use actix_web::{
 };
 use askama::Template;
 use reqwest;
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
+use serde_json::json;
 use url::Url;
 
 use crate::{
+    audit::{self, AuditEvent, CreateAuditEvent},
+    mutations::{AddQuestion, DeleteQuestion, EditQuestion, PublishQuestion, RevertQuestion},
     page::Page,
     prelude::*,
-    questions::{CreateQuestion, Question},
+    questions::{Question, QuestionOption, QuestionRevision, QuestionVariant, RevisionDiff},
     queues::{CreateQueue, Queue},
-    types::{CurrentPage, Message},
+    types::{CurrentPage, Markdown, Message},
+    users::handle,
 };
 
 pub fn register(cfg: &mut web::ServiceConfig) {
     cfg.service(list)
+        .service(preview)
         .service(show_or_new)
         .service(create)
+        .service(edit)
+        .service(update)
+        .service(publish)
+        .service(delete)
+        .service(export)
+        .service(revisions)
+        .service(show_revision)
+        .service(revert)
         .service(start_queue);
 }
 
@@ -37,7 +50,7 @@ async fn list(request: HttpRequest, id: Identity) -> Result<HttpResponse, Error>
     let s = List {
         questions: &questions,
         messages: &Message::none(),
-        page: CurrentPage::from("/questions", auth::user_or_guest(&id)?),
+        page: CurrentPage::new(&request, "/questions", auth::user_or_guest(&id)?),
     }
     .render()
     .unwrap();
@@ -45,16 +58,10 @@ async fn list(request: HttpRequest, id: Identity) -> Result<HttpResponse, Error>
     Ok(HttpResponse::Ok().content_type("text/html").body(s))
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct QuestionForm {
-    title: String,
-    link: String,
-}
-
 #[derive(Template)]
 #[template(path = "questions/new.jinja")]
 struct New<'a> {
-    form: &'a QuestionForm,
+    form: AddQuestion,
     messages: &'a Vec<Message>,
     page: CurrentPage,
 }
@@ -63,10 +70,22 @@ struct New<'a> {
 #[template(path = "questions/show.jinja")]
 struct Show<'a> {
     question: &'a Question,
+    options: Vec<QuestionOption>,
+    // One variant of a template question, to show what it looks like when it is asked.
+    example: Option<QuestionVariant>,
     messages: &'a Vec<Message>,
     page: CurrentPage,
 }
 
+// A draft is not shown to anyone but its author until it is published.  Deleted questions are
+// not found in the first place.
+fn ensure_visible(question: &Question, user: &User) -> Result<(), Error> {
+    if question.is_draft() && !(user.is_authenticated() && question.author_id == Some(user.id)) {
+        return Err(Error::NotFound);
+    }
+    Ok(())
+}
+
 #[get("/questions/{external_id}")]
 async fn show_or_new(
     path: Path<String>,
@@ -78,26 +97,29 @@ async fn show_or_new(
     let user = auth::user_or_guest(&id)?;
 
     let s = match external_id.as_ref() {
-        "new" => {
-            let form = &QuestionForm {
-                title: "".to_string(),
-                link: "".to_string(),
-            };
-            New {
-                form,
-                messages,
-                page: CurrentPage::from("/questions", user),
-            }
-            .render()
-            .unwrap()
+        "new" => New {
+            form: AddQuestion::default(),
+            messages,
+            page: CurrentPage::new(&request, "/questions", user),
         }
+        .render()
+        .unwrap(),
         _ => {
             let db = request.db()?;
             let question = Question::find(&external_id, db).await?;
+            ensure_visible(&question, &user)?;
+            let page = CurrentPage::new(&request, "/questions", user);
+            let example = if question.is_template() {
+                Some(question.variant(0, db).await?)
+            } else {
+                None
+            };
             Show {
                 question: &question,
+                options: question.options(db).await?,
+                example,
                 messages,
-                page: CurrentPage::from("/questions", user),
+                page,
             }
             .render()
             .unwrap()
@@ -115,35 +137,76 @@ async fn fetch_page(link: &String) -> Result<Page, Error> {
     Ok(page)
 }
 
+// A page that cannot be fetched just goes without a logo.
+async fn fetch_logo(link: &str) -> Option<String> {
+    fetch_page(&link.to_string())
+        .await
+        .ok()
+        .and_then(|page| page.meta_image().map(|url| url.to_string()))
+}
+
 #[post("/questions")]
 async fn create(
-    form: Form<QuestionForm>,
+    form: Form<AddQuestion>,
     request: HttpRequest,
     id: Identity,
 ) -> Result<HttpResponse, Error> {
     let user = auth::user(&id)?;
-    let form = form.into_inner();
-    let page = fetch_page(&form.link).await?;
-
-    let question = CreateQuestion {
-        author_id: user.id,
-        link: form.link.clone(),
-        link_logo: page.meta_image().map(|url| url.to_string()),
-        title: form.title.clone(),
+
+    let mut mutation = form.into_inner();
+    if !mutation.validate() {
+        let s = New {
+            form: mutation,
+            messages: &Message::none(),
+            page: CurrentPage::new(&request, "/questions", user),
+        }
+        .render()
+        .unwrap();
+        return Ok(HttpResponse::BadRequest().content_type("text/html").body(s));
+    }
+
+    let link_logo = match mutation.link() {
+        Some(link) => fetch_logo(link).await,
+        None => None,
     };
-    Question::create(question, request.db()?).await?;
+    let context = audit::Context::from_request(&request);
+    let question = mutation
+        .call(link_logo, &user, &context, request.db()?)
+        .await?;
 
+    // Drafts are not listed, so the author is taken to the draft instead.
+    if question.is_draft() {
+        return request.redirect(format!("/questions/{}", question.external_id).as_ref());
+    }
     request.redirect("/questions")
 }
 
+#[derive(Deserialize)]
+struct PreviewForm {
+    text: String,
+}
+
+// Renders Markdown the same way that the question page will, for the preview on the question
+// forms.  Raw HTML is left out, as it is on the question page.
+#[post("/questions/preview")]
+async fn preview(form: Form<PreviewForm>) -> Result<HttpResponse, Error> {
+    let html = Markdown::from(form.into_inner().text).markdown();
+    Ok(HttpResponse::Ok().content_type("text/html").body(html))
+}
+
 #[post("/questions/{external_id}/queues")]
 async fn start_queue(
     path: Path<String>,
     request: HttpRequest,
     id: Identity,
 ) -> Result<HttpResponse, Error> {
-    let user = auth::user(&id)?;
+    // Guests can practice too, under a temporary user that is claimed when they sign up.
+    let db = request.db()?;
+    let user = auth::user_or_new_temporary(&id, db).await?;
     let external_id = path.into_inner();
+    if !Question::find(&external_id, db).await?.is_published() {
+        return Err(Error::NotFound);
+    }
 
     let queue = CreateQueue {
         description: "Questions related to algorithms and data structures".to_string(),
@@ -151,8 +214,254 @@ async fn start_queue(
         title: "Algorithms and data strucures".to_string(),
         user_id: user.id,
     };
-    let result = Queue::find_or_create(queue, request.db()?).await?;
+    let result = Queue::find_or_create(queue, db).await?;
+    if result.created {
+        AuditEvent::create(
+            CreateAuditEvent {
+                action: "queue.create",
+                actor: Some(&user),
+                target_type: "queue",
+                target_id: result.record.external_id.clone(),
+                changes: Some(json!({ "starting_question": external_id })),
+            },
+            &audit::Context::from_request(&request),
+            db,
+        )
+        .await?;
+    }
 
-    let path = format!("/{}/queues/{}", user.handle, result.record.external_id);
+    let path = format!(
+        "/{}/queues/{}",
+        handle::encode(&user.handle),
+        result.record.external_id
+    );
     request.redirect(path.as_ref())
 }
+
+#[derive(Template)]
+#[template(path = "questions/edit.jinja")]
+struct Edit<'a> {
+    question: &'a Question,
+    form: EditQuestion,
+    messages: Vec<Message>,
+    page: CurrentPage,
+}
+
+#[get("/questions/{external_id}/edit")]
+async fn edit(
+    path: Path<String>,
+    request: HttpRequest,
+    id: Identity,
+) -> Result<HttpResponse, Error> {
+    let user = auth::user(&id)?;
+    let question = Question::find(&path.into_inner(), request.db()?).await?;
+    if question.author_id != Some(user.id) {
+        return Err(Error::Unauthorized);
+    }
+
+    let s = Edit {
+        question: &question,
+        form: EditQuestion::from(&question),
+        messages: Message::none(),
+        page: CurrentPage::new(&request, "/questions", user),
+    }
+    .render()
+    .unwrap();
+    Ok(HttpResponse::Ok().content_type("text/html").body(s))
+}
+
+// The logo is only looked up again when the link changes.
+async fn link_logo(question: &Question, link: Option<&str>) -> Option<String> {
+    match link {
+        None => None,
+        Some(link) if question.link.as_deref() == Some(link) => question.link_logo.clone(),
+        Some(link) => fetch_logo(link).await,
+    }
+}
+
+#[post("/questions/{external_id}/edit")]
+async fn update(
+    form: Form<EditQuestion>,
+    path: Path<String>,
+    request: HttpRequest,
+    id: Identity,
+) -> Result<HttpResponse, Error> {
+    let user = auth::user(&id)?;
+    let db = request.db()?;
+    let question = Question::find(&path.into_inner(), db).await?;
+    // Checked before the form is shown again with errors, since it shows the stored question.
+    if question.author_id != Some(user.id) {
+        return Err(Error::Unauthorized);
+    }
+
+    let mut mutation = form.into_inner();
+    if !mutation.validate_for(&question, db).await? {
+        let s = Edit {
+            question: &question,
+            form: mutation,
+            messages: Message::none(),
+            page: CurrentPage::new(&request, "/questions", user),
+        }
+        .render()
+        .unwrap();
+        return Ok(HttpResponse::BadRequest().content_type("text/html").body(s));
+    }
+
+    let link_logo = link_logo(&question, mutation.link()).await;
+    let context = audit::Context::from_request(&request);
+    mutation
+        .call(&question, link_logo, &user, &context, db)
+        .await?;
+
+    request.redirect(format!("/questions/{}", question.external_id).as_ref())
+}
+
+#[post("/questions/{external_id}/publish")]
+async fn publish(
+    path: Path<String>,
+    request: HttpRequest,
+    id: Identity,
+) -> Result<HttpResponse, Error> {
+    let user = auth::user(&id)?;
+    let db = request.db()?;
+    let question = Question::find(&path.into_inner(), db).await?;
+
+    let context = audit::Context::from_request(&request);
+    PublishQuestion.call(&question, &user, &context, db).await?;
+
+    request.redirect(format!("/questions/{}", question.external_id).as_ref())
+}
+
+// A question that has been answered is archived rather than deleted, and can still be looked at.
+#[post("/questions/{external_id}/delete")]
+async fn delete(
+    path: Path<String>,
+    request: HttpRequest,
+    id: Identity,
+) -> Result<HttpResponse, Error> {
+    let user = auth::user(&id)?;
+    let db = request.db()?;
+    let question = Question::find(&path.into_inner(), db).await?;
+
+    let context = audit::Context::from_request(&request);
+    DeleteQuestion.call(&question, &user, &context, db).await?;
+
+    request.redirect("/questions")
+}
+
+#[derive(Template)]
+#[template(path = "questions/revisions.jinja")]
+struct Revisions<'a> {
+    question: &'a Question,
+    revisions: Vec<QuestionRevision>,
+    messages: Vec<Message>,
+    page: CurrentPage,
+}
+
+// The question as JSON, with whatever its kind keeps besides the text.  The answers are
+// included, so only the author can export a question.
+#[get("/questions/{external_id}/export")]
+async fn export(
+    path: Path<String>,
+    request: HttpRequest,
+    id: Identity,
+) -> Result<HttpResponse, Error> {
+    let user = auth::user(&id)?;
+    let db = request.db()?;
+    let question = Question::find(&path.into_inner(), db).await?;
+    if question.author_id != Some(user.id) {
+        return Err(Error::Unauthorized);
+    }
+    Ok(HttpResponse::Ok().json(question.export(db).await?))
+}
+
+#[get("/questions/{external_id}/revisions")]
+async fn revisions(
+    path: Path<String>,
+    request: HttpRequest,
+    id: Identity,
+) -> Result<HttpResponse, Error> {
+    let db = request.db()?;
+    let question = Question::find(&path.into_inner(), db).await?;
+    let user = auth::user_or_guest(&id)?;
+    ensure_visible(&question, &user)?;
+
+    let s = Revisions {
+        question: &question,
+        revisions: question.revisions(db).await?,
+        messages: Message::none(),
+        page: CurrentPage::new(&request, "/questions", user),
+    }
+    .render()
+    .unwrap();
+    Ok(HttpResponse::Ok().content_type("text/html").body(s))
+}
+
+#[derive(Deserialize)]
+struct CompareQuery {
+    compare: Option<i32>,
+}
+
+#[derive(Template)]
+#[template(path = "questions/revision.jinja")]
+struct ShowRevision<'a> {
+    question: &'a Question,
+    revision: QuestionRevision,
+    older: Option<QuestionRevision>,
+    diff: Option<RevisionDiff>,
+    messages: Vec<Message>,
+    page: CurrentPage,
+}
+
+// Compares a revision with the one before it, or with the revision given by `compare`.
+#[get("/questions/{external_id}/revisions/{number}")]
+async fn show_revision(
+    path: Path<(String, i32)>,
+    query: web::Query<CompareQuery>,
+    request: HttpRequest,
+    id: Identity,
+) -> Result<HttpResponse, Error> {
+    let (external_id, number) = path.into_inner();
+    let db = request.db()?;
+    let question = Question::find(&external_id, db).await?;
+    let user = auth::user_or_guest(&id)?;
+    ensure_visible(&question, &user)?;
+    let revision = question.revision(number, db).await?;
+
+    let older = match query.compare.unwrap_or(number - 1) {
+        0 => None,
+        compare => Some(question.revision(compare, db).await?),
+    };
+    let diff = older.as_ref().map(|older| revision.diff(older));
+
+    let s = ShowRevision {
+        question: &question,
+        revision,
+        older,
+        diff,
+        messages: Message::none(),
+        page: CurrentPage::new(&request, "/questions", user),
+    }
+    .render()
+    .unwrap();
+    Ok(HttpResponse::Ok().content_type("text/html").body(s))
+}
+
+#[post("/questions/{external_id}/revisions/{number}/revert")]
+async fn revert(
+    path: Path<(String, i32)>,
+    request: HttpRequest,
+    id: Identity,
+) -> Result<HttpResponse, Error> {
+    let user = auth::user(&id)?;
+    let (external_id, number) = path.into_inner();
+    let db = request.db()?;
+    let question = Question::find(&external_id, db).await?;
+
+    let context = audit::Context::from_request(&request);
+    RevertQuestion { number }
+        .call(&question, &user, &context, db)
+        .await?;
+
+    request.redirect(format!("/questions/{}/revisions", question.external_id).as_ref())
+}
@@ -0,0 +1,333 @@
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::{
+    prelude::*,
+    questions::{expression, NumericAnswer},
+    types::{Markdown, Pool},
+};
+
+/// A question whose numbers change from one review to the next, so that the answer cannot be
+/// remembered.  Variables are written one to a line, as in "mass = 2..10" or
+/// "rate = 0.5..2.5 step 0.5", and the text refers to them, or to expressions of them, in double
+/// braces, as in "{{ mass * 9.8 }}".  The answer is an expression of the variables, and is graded
+/// like the answer to a numeric question.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct QuestionTemplate {
+    pub variables: String,
+    pub answer: String,
+    pub tolerance: f64,
+    pub tolerance_kind: String,
+    pub unit: Option<String>,
+    pub significant_figures: Option<i32>,
+}
+
+/// A variable and the values that it can take, from `min` to `max` in steps of `step`.
+#[derive(Debug, PartialEq)]
+pub struct Variable {
+    pub name: String,
+    pub min: f64,
+    pub max: f64,
+    pub step: f64,
+    // Values are rounded to as many decimals as the range is written with.
+    decimals: i32,
+}
+
+/// One version of a template, with the values that a seed gave its variables.  The same seed
+/// always gives the same values, so that a past attempt can be shown again.
+#[derive(Debug)]
+pub struct Variant {
+    pub seed: i64,
+    values: HashMap<String, f64>,
+}
+
+/// A template question as it is put to someone in one review.
+#[derive(Debug)]
+pub struct QuestionVariant {
+    pub seed: i64,
+    pub text: Markdown,
+    pub answer: NumericAnswer,
+}
+
+impl QuestionVariant {
+    /// The expected answer, as in "9.8 m".
+    pub fn answer_text(&self) -> String {
+        let value = format_number(self.answer.value);
+        match &self.answer.unit {
+            Some(unit) => format!("{} {}", value, unit),
+            None => value,
+        }
+    }
+}
+
+// SplitMix64.  The generator is written out here rather than taken from rand, whose generators
+// may change between versions, so that a seed gives the same variant after an upgrade.
+struct Generator(u64);
+
+impl Generator {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+fn decimals(number: &str) -> i32 {
+    number
+        .split_once('.')
+        .map(|(_, fraction)| fraction.len() as i32)
+        .unwrap_or_default()
+}
+
+impl Variable {
+    fn parse(line: &str) -> Result<Self, String> {
+        let re = Regex::new(
+            r"^\s*([A-Za-z_]\w*)\s*=\s*(-?[\d.]+)\s*\.\.\s*(-?[\d.]+)(?:\s+step\s+([\d.]+))?\s*$",
+        )
+        .unwrap();
+        let captures = re
+            .captures(line)
+            .ok_or_else(|| format!("Write \"{}\" as, for example, \"a = 1..10\"", line.trim()))?;
+
+        let number = |index: usize| -> Result<Option<(f64, i32)>, String> {
+            match captures.get(index) {
+                None => Ok(None),
+                Some(text) => text
+                    .as_str()
+                    .parse()
+                    .map(|number| Some((number, decimals(text.as_str()))))
+                    .map_err(|_| format!("\"{}\" is not a number", text.as_str())),
+            }
+        };
+        let (min, min_decimals) = number(2)?.unwrap_or_default();
+        let (max, max_decimals) = number(3)?.unwrap_or_default();
+        let (step, step_decimals) = number(4)?.unwrap_or((1.0, 0));
+
+        let name = captures[1].to_string();
+        if max < min {
+            return Err(format!("The range of {} goes down", name));
+        }
+        if step <= 0.0 {
+            return Err(format!("The step of {} has to be more than zero", name));
+        }
+
+        Ok(Self {
+            name,
+            min,
+            max,
+            step,
+            decimals: min_decimals.max(max_decimals).max(step_decimals),
+        })
+    }
+
+    fn count(&self) -> u64 {
+        ((self.max - self.min) / self.step + 1e-9).floor() as u64 + 1
+    }
+
+    fn value(&self, index: u64) -> f64 {
+        let scale = 10f64.powi(self.decimals);
+        ((self.min + index as f64 * self.step) * scale).round() / scale
+    }
+}
+
+/// Writes a number as it should appear in the text of a question, without trailing zeros.
+pub fn format_number(number: f64) -> String {
+    if (number - number.round()).abs() < 1e-9 {
+        format!("{}", number.round() as i64)
+    } else {
+        let text = format!("{:.6}", number);
+        text.trim_end_matches('0').trim_end_matches('.').to_string()
+    }
+}
+
+impl QuestionTemplate {
+    pub async fn find(question_id: i64, db: &Pool) -> Result<Self, Error> {
+        let template = sqlx::query_as!(
+            Self,
+            "select variables, answer, tolerance, tolerance_kind, unit, significant_figures
+             from question_templates
+             where question_id = $1",
+            question_id,
+        )
+        .fetch_one(db)
+        .await?;
+        Ok(template)
+    }
+
+    pub(super) async fn create(
+        &self,
+        question_id: i64,
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    ) -> Result<(), Error> {
+        sqlx::query!(
+            "insert into question_templates
+                (question_id, variables, answer, tolerance, tolerance_kind, unit,
+                 significant_figures)
+             values ($1, $2, $3, $4, $5, $6, $7)",
+            question_id,
+            self.variables,
+            self.answer,
+            self.tolerance,
+            self.tolerance_kind,
+            self.unit,
+            self.significant_figures,
+        )
+        .execute(tx)
+        .await?;
+        Ok(())
+    }
+
+    pub fn new_seed() -> i64 {
+        rand::random()
+    }
+
+    pub fn parse_variables(&self) -> Result<Vec<Variable>, String> {
+        self.variables
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(Variable::parse)
+            .collect()
+    }
+
+    pub fn variant(&self, seed: i64) -> Result<Variant, String> {
+        let mut generator = Generator(seed as u64);
+        let values = self
+            .parse_variables()?
+            .into_iter()
+            .map(|variable| {
+                let value = variable.value(generator.next() % variable.count());
+                (variable.name, value)
+            })
+            .collect();
+        Ok(Variant { seed, values })
+    }
+
+    /// The text of the question with the values of the variant put in.
+    pub fn render(&self, text: &str, variant: &Variant) -> Result<String, String> {
+        let re = Regex::new(r"\{\{([^{}]+)\}\}").unwrap();
+        let mut error = None;
+        let rendered = re.replace_all(text, |captures: &Captures| {
+            match expression::evaluate(&captures[1], &variant.values) {
+                Ok(value) => format_number(value),
+                Err(message) => {
+                    error.get_or_insert(message);
+                    String::new()
+                }
+            }
+        });
+        match error {
+            Some(message) => Err(message),
+            None => Ok(rendered.to_string()),
+        }
+    }
+
+    /// Whether the text only uses the variables of the template, and uses them in expressions
+    /// that can be worked out, checked against a few variants.
+    pub fn check_text(&self, text: &str) -> Result<(), String> {
+        for seed in 0..20 {
+            self.render(text, &self.variant(seed)?)?;
+        }
+        Ok(())
+    }
+
+    /// The expected answer for the variant.
+    pub fn numeric_answer(&self, variant: &Variant) -> Result<NumericAnswer, String> {
+        let value = expression::evaluate(&self.answer, &variant.values)?;
+        if !value.is_finite() {
+            return Err("The answer is not a number for some values of the variables".to_string());
+        }
+        Ok(NumericAnswer {
+            value,
+            tolerance: self.tolerance,
+            tolerance_kind: self.tolerance_kind.clone(),
+            unit: self.unit.clone(),
+            significant_figures: self.significant_figures,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn template(variables: &str, answer: &str) -> QuestionTemplate {
+        QuestionTemplate {
+            variables: variables.to_string(),
+            answer: answer.to_string(),
+            tolerance: 0.0,
+            tolerance_kind: "absolute".to_string(),
+            unit: None,
+            significant_figures: None,
+        }
+    }
+
+    #[test]
+    fn parse_variables() {
+        let template = template("mass = 2..10\n\n rate = 0.5..2.5 step 0.5 ", "mass");
+        let variables = template.parse_variables().unwrap();
+        assert_eq!(2, variables.len());
+        assert_eq!(9, variables[0].count());
+        assert_eq!(5, variables[1].count());
+        assert_eq!(2.5, variables[1].value(4));
+
+        assert_eq!(
+            Err("Write \"mass = heavy\" as, for example, \"a = 1..10\"".to_string()),
+            self::template("mass = heavy", "mass").parse_variables()
+        );
+        assert_eq!(
+            Err("The range of mass goes down".to_string()),
+            self::template("mass = 10..2", "mass").parse_variables()
+        );
+    }
+
+    #[test]
+    fn variants_are_reproducible() {
+        let template = template("a = 1..1000\nb = 0.1..0.9 step 0.1", "a * b");
+        let text = "What is {{ a }} times {{b}}?";
+
+        let first = template.variant(42).unwrap();
+        let again = template.variant(42).unwrap();
+        assert_eq!(template.render(text, &first), template.render(text, &again));
+        assert_eq!(
+            template.numeric_answer(&first),
+            template.numeric_answer(&again)
+        );
+
+        let rendered: Vec<String> = (0..20)
+            .map(|seed| {
+                template
+                    .render(text, &template.variant(seed).unwrap())
+                    .unwrap()
+            })
+            .collect();
+        assert!(rendered.iter().any(|text| *text != rendered[0]));
+    }
+
+    #[test]
+    fn render() {
+        let template = template("a = 3..3", "a^2");
+        let variant = template.variant(7).unwrap();
+        assert_eq!(
+            Ok("A square with sides of 3 m has an area of 9 m².".to_string()),
+            template.render(
+                "A square with sides of {{a}} m has an area of {{ a^2 }} m².",
+                &variant
+            )
+        );
+        assert_eq!(9.0, template.numeric_answer(&variant).unwrap().value);
+        assert_eq!(
+            Err("Unknown variable \"b\"".to_string()),
+            template.render("What is {{ b }}?", &variant)
+        );
+    }
+
+    #[test]
+    fn format_numbers() {
+        assert_eq!("3", format_number(3.0000000001));
+        assert_eq!("29.4", format_number(9.8 * 3.0));
+        assert_eq!("-0.125", format_number(-0.125));
+    }
+}
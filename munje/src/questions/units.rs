@@ -0,0 +1,124 @@
+/// A unit of measure that answers to numeric questions can be given in.  Values are converted
+/// through the base unit of the dimension, as `value * factor + offset`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Unit {
+    pub symbol: &'static str,
+    pub dimension: &'static str,
+    factor: f64,
+    offset: f64,
+}
+
+const fn unit(symbol: &'static str, dimension: &'static str, factor: f64) -> Unit {
+    Unit {
+        symbol,
+        dimension,
+        factor,
+        offset: 0.0,
+    }
+}
+
+const UNITS: &[Unit] = &[
+    unit("m", "length", 1.0),
+    unit("km", "length", 1e3),
+    unit("cm", "length", 1e-2),
+    unit("mm", "length", 1e-3),
+    unit("µm", "length", 1e-6),
+    unit("um", "length", 1e-6),
+    unit("nm", "length", 1e-9),
+    unit("in", "length", 0.0254),
+    unit("ft", "length", 0.3048),
+    unit("mi", "length", 1609.344),
+    unit("kg", "mass", 1.0),
+    unit("g", "mass", 1e-3),
+    unit("mg", "mass", 1e-6),
+    unit("µg", "mass", 1e-9),
+    unit("ug", "mass", 1e-9),
+    unit("lb", "mass", 0.453_592_37),
+    unit("oz", "mass", 0.028_349_523_125),
+    unit("L", "volume", 1.0),
+    unit("l", "volume", 1.0),
+    unit("dL", "volume", 1e-1),
+    unit("cL", "volume", 1e-2),
+    unit("mL", "volume", 1e-3),
+    unit("ml", "volume", 1e-3),
+    unit("µL", "volume", 1e-6),
+    unit("uL", "volume", 1e-6),
+    unit("cm3", "volume", 1e-3),
+    unit("m3", "volume", 1e3),
+    unit("s", "time", 1.0),
+    unit("ms", "time", 1e-3),
+    unit("min", "time", 60.0),
+    unit("h", "time", 3600.0),
+    unit("mol", "amount", 1.0),
+    unit("mmol", "amount", 1e-3),
+    unit("µmol", "amount", 1e-6),
+    unit("umol", "amount", 1e-6),
+    unit("M", "concentration", 1.0),
+    unit("mM", "concentration", 1e-3),
+    unit("µM", "concentration", 1e-6),
+    unit("uM", "concentration", 1e-6),
+    unit("mol/L", "concentration", 1.0),
+    unit("Pa", "pressure", 1.0),
+    unit("kPa", "pressure", 1e3),
+    unit("bar", "pressure", 1e5),
+    unit("atm", "pressure", 101_325.0),
+    unit("mmHg", "pressure", 133.322_387_415),
+    unit("J", "energy", 1.0),
+    unit("kJ", "energy", 1e3),
+    unit("cal", "energy", 4.184),
+    unit("kcal", "energy", 4184.0),
+    unit("K", "temperature", 1.0),
+    Unit {
+        symbol: "°C",
+        dimension: "temperature",
+        factor: 1.0,
+        offset: 273.15,
+    },
+    Unit {
+        symbol: "°F",
+        dimension: "temperature",
+        factor: 5.0 / 9.0,
+        offset: 273.15 - 32.0 * 5.0 / 9.0,
+    },
+];
+
+impl Unit {
+    pub fn find(symbol: &str) -> Option<Self> {
+        let symbol = symbol.trim();
+        UNITS.iter().find(|unit| unit.symbol == symbol).copied()
+    }
+
+    /// The value in `to`, or nothing when the units measure different things.
+    pub fn convert(&self, value: f64, to: &Self) -> Option<f64> {
+        if self.dimension != to.dimension {
+            return None;
+        }
+        let base = value * self.factor + self.offset;
+        Some((base - to.offset) / to.factor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn convert(value: f64, from: &str, to: &str) -> Option<f64> {
+        Unit::find(from)
+            .unwrap()
+            .convert(value, &Unit::find(to).unwrap())
+    }
+
+    #[test]
+    fn conversions() {
+        assert!((convert(0.5, "L", "mL").unwrap() - 500.0).abs() < 1e-9);
+        assert!((convert(1.0, "atm", "kPa").unwrap() - 101.325).abs() < 1e-9);
+        assert!((convert(100.0, "°C", "°F").unwrap() - 212.0).abs() < 1e-9);
+        assert_eq!(None, convert(1.0, "L", "kg"));
+    }
+
+    #[test]
+    fn unknown_unit() {
+        assert_eq!(None, Unit::find("furlong"));
+        assert_eq!(Some("mL"), Unit::find(" mL ").map(|unit| unit.symbol));
+    }
+}
@@ -27,14 +27,22 @@ pub enum TimeUnit {
 pub struct ChoiceRow {
     pub answer_answered_at: Option<chrono::DateTime<chrono::Utc>>,
     pub answer_consecutive_correct: Option<i32>,
+    pub answer_hints_revealed: Option<i32>,
+    pub answer_solution_revealed: Option<bool>,
     pub answer_state: Option<String>,
+    pub cloze: i32,
     pub question_id: i64,
 }
 
+/// A question, or one cloze deletion of a cloze question, which is scheduled apart from the other
+/// deletions of the same question.  `cloze` is 0 for other kinds of question.
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Choice {
     stage: i32,
+    /// Whether hints or the worked solution were shown before the last answer was given.
+    pub aided: bool,
     pub answered_at: DateTime,
+    pub cloze: i32,
     pub consecutive_correct: i32,
     pub question_id: i64,
     pub state: State,
@@ -93,7 +101,7 @@ pub struct Random {
 ///    to ask that have already been attempted in the past, the new question is shown to the user
 ///    for the first time.
 ///
-///  3. A question is ready to show if the number of days since it was last attempted is equal to
+/// 3. A question is ready to show if the number of days since it was last attempted is equal to
 ///    the stage tracked for the user's progress with that question.
 ///
 /// 4. If a question is attempted and answered incorrectly, the stage for the question is
@@ -171,12 +179,17 @@ impl ChoiceRow {
         let answered_at = self.answer_answered_at.unwrap_or(already);
         let consecutive_correct = self.answer_consecutive_correct.unwrap_or(0);
 
-        Choice::new(
-            self.question_id,
-            DateTime(answered_at),
-            consecutive_correct,
-            state,
-        )
+        Choice {
+            aided: self.answer_hints_revealed.unwrap_or(0) > 0
+                || self.answer_solution_revealed.unwrap_or(false),
+            cloze: self.cloze,
+            ..Choice::new(
+                self.question_id,
+                DateTime(answered_at),
+                consecutive_correct,
+                state,
+            )
+        }
     }
 }
 
@@ -184,8 +197,10 @@ impl fmt::Debug for ChoiceRow {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "ChoiceRow {{ question_id: {}, answer_consecutive_correct: {}, answer_state: {} }}",
+            "ChoiceRow {{ question_id: {}, cloze: {}, answer_consecutive_correct: {}, \
+             answer_state: {} }}",
             self.question_id,
+            self.cloze,
             self.answer_consecutive_correct.unwrap_or(0),
             self.answer_state
                 .clone()
@@ -203,7 +218,9 @@ impl Choice {
         state: State,
     ) -> Self {
         Self {
+            aided: false,
             answered_at,
+            cloze: 0,
             consecutive_correct,
             question_id,
             stage: Self::stage_from(consecutive_correct),
@@ -218,7 +235,9 @@ impl Choice {
 
     fn clone(&self) -> Self {
         Self {
+            aided: self.aided,
             answered_at: self.answered_at,
+            cloze: self.cloze,
             consecutive_correct: self.consecutive_correct,
             question_id: self.question_id,
             stage: self.stage,
@@ -305,8 +324,8 @@ impl Strategy for SpacedRepetition {
     fn to_vec(&self) -> Vec<Choice> {
         let mut choices = self.choices.clone();
         let threshold = self.clock.threshold();
-        choices.sort_by_key(|c| (c.question_id, Reverse(c.answered_at)));
-        choices.dedup_by_key(|c| c.question_id);
+        choices.sort_by_key(|c| (c.question_id, c.cloze, Reverse(c.answered_at)));
+        choices.dedup_by_key(|c| (c.question_id, c.cloze));
         choices.sort_by_key(|c| {
             (
                 Reverse(threshold - self.available_at(c)),
@@ -323,6 +342,9 @@ impl Strategy for SpacedRepetition {
     fn available_at(&self, choice: &Choice) -> DateTime {
         let ticks = match choice.state {
             State::Unsure => 90,
+            // A correct answer given with help says less about what is remembered, so the
+            // question comes back after half the time.
+            State::Correct if choice.aided => (choice.stage / 2).max(1).into(),
             _ => choice.stage.into(),
         };
 
@@ -447,21 +469,60 @@ mod tests {
                 choices: vec![
                     ChoiceRow {
                         question_id: 0,
+                        cloze: 0,
                         answer_answered_at: None,
                         answer_consecutive_correct: None,
+                        answer_hints_revealed: None,
+                        answer_solution_revealed: None,
                         answer_state: None,
                     }
                     .to_choice(&clock),
                     ChoiceRow {
                         question_id: 1,
+                        cloze: 0,
                         answer_answered_at: None,
                         answer_consecutive_correct: None,
+                        answer_hints_revealed: None,
+                        answer_solution_revealed: None,
                         answer_state: None,
                     }
                     .to_choice(&clock),
                 ],
                 expected: (Some(0), clock.ticks(-1)),
             },
+            TestCase {
+                name: "Each cloze deletion of a question is scheduled on its own",
+                choices: vec![
+                    Choice {
+                        cloze: 1,
+                        ..C(1, 3, clock.ticks(0), State::Correct)
+                    },
+                    Choice {
+                        cloze: 2,
+                        ..C(1, 0, clock.ticks(-2), State::Incorrect)
+                    },
+                ],
+                expected: (Some(1), clock.ticks(-1)),
+            },
+            TestCase {
+                name: "A correct answer given with hints comes back sooner",
+                choices: vec![
+                    Choice {
+                        aided: true,
+                        ..C(0, 3, clock.ticks(-5), State::Correct)
+                    },
+                    C(1, 3, clock.ticks(-5), State::Correct),
+                ],
+                expected: (Some(0), clock.ticks(-1)),
+            },
+            TestCase {
+                name: "An aided correct answer is still put off for at least one tick",
+                choices: vec![Choice {
+                    aided: true,
+                    ..C(0, 0, clock.ticks(0), State::Correct)
+                }],
+                expected: (None, clock.ticks(1)),
+            },
             TestCase {
                 name: "Questions that were too hard are not shown for 90 days",
                 choices: vec![
@@ -484,8 +545,7 @@ mod tests {
         ];
 
         for case in cases {
-            let chooser =
-                SpacedRepetition::new(case.choices.clone(), clock.clone(), TimeUnit::Minutes);
+            let chooser = SpacedRepetition::new(case.choices.clone(), clock, TimeUnit::Minutes);
 
             let (choice, available_at) = chooser.next_question().unwrap();
             let expected_choice = match case.expected.0 {
@@ -6,7 +6,7 @@ use sqlx::FromRow;
 use crate::{
     models::{Creatable, UpsertResult},
     prelude::*,
-    questions::{Question, QuestionRow},
+    questions::{Help, Question, QuestionRow, QuestionTemplate, QuestionVariant},
     queues::choosers::{Choice, ChoiceRow, SpacedRepetition, Strategy, TimeUnit},
     types::{DateTime, Markdown, Pool},
 };
@@ -45,30 +45,56 @@ pub struct Queue {
 
 pub struct NextQuestion {
     pub question: Option<Question>,
+    /// The cloze deletion to ask about, or 0 when the question is not a cloze question.
+    pub cloze: i32,
     next_available_at: DateTime,
 }
 
 #[derive(Debug, FromRow)]
 pub struct AnswerRow {
     pub answered_at: chrono::DateTime<chrono::Utc>,
+    pub cloze: i32,
     pub consecutive_correct: i32,
     pub external_id: String,
+    pub hints_revealed: i32,
     pub id: i64,
     pub question_id: i64,
     pub queue_id: i64,
+    pub revealed_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub seed: Option<i64>,
+    pub selected_options: Option<Vec<i64>>,
+    pub shown_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub solution_revealed: bool,
     pub state: String,
+    pub submitted_value: Option<String>,
     pub user_id: i64,
 }
 
 #[derive(Debug, Serialize)]
 pub struct Answer {
     pub answered_at: DateTime,
+    /// The cloze deletion that was asked about, or 0 for the whole question.
+    pub cloze: i32,
     pub consecutive_correct: i32,
     pub external_id: String,
+    /// How many of the hints of the question were shown before it was answered.
+    pub hints_revealed: i32,
     pub id: i64,
     pub question_id: i64,
     pub queue_id: i64,
+    /// When the back of a flashcard was shown.
+    pub revealed_at: Option<DateTime>,
+    /// The seed that the numbers of a template question were drawn with.
+    pub seed: Option<i64>,
+    /// The options that were chosen for a multiple-choice question.
+    pub selected_options: Option<Vec<i64>>,
+    /// When the question was put in front of the user.
+    pub shown_at: Option<DateTime>,
+    /// Whether the worked solution was shown before the question was answered.
+    pub solution_revealed: bool,
     pub state: String,
+    /// What was typed in answer to a numeric question.
+    pub submitted_value: Option<String>,
     pub user_id: i64,
 }
 
@@ -84,6 +110,12 @@ pub struct WideAnswer {
     pub answer_state: String,
     pub answer_answered_at: chrono::DateTime<chrono::Utc>,
     pub answer_consecutive_correct: i32,
+    pub answer_revealed_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub answer_shown_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub answer_submitted_value: Option<String>,
+    pub answer_cloze: i32,
+    pub answer_hints_revealed: i32,
+    pub answer_solution_revealed: bool,
     pub question_title: String,
     pub question_text: String,
     pub question_link: Option<String>,
@@ -97,6 +129,9 @@ pub struct LastAnswer {
     pub answer_state: String,
     pub answer_answered_at: chrono::DateTime<chrono::Utc>,
     pub answer_consecutive_correct: i32,
+    pub answer_hints_revealed: i32,
+    pub answer_solution_revealed: bool,
+    pub cloze: i32,
     pub created_at: chrono::DateTime<Utc>,
     pub id: i64,
     pub question_id: i64,
@@ -105,6 +140,21 @@ pub struct LastAnswer {
     pub user_id: i64,
 }
 
+/// A question that has been put to the owner of a queue and not yet answered.  The variant of a
+/// template question is chosen here, so that the answer is graded against the variant that was
+/// shown rather than one that the page asks for.
+#[derive(Debug, Serialize, FromRow)]
+pub struct AskedQuestion {
+    pub cloze: i32,
+    pub created_at: chrono::DateTime<Utc>,
+    pub hints_revealed: i32,
+    pub id: i64,
+    pub question_id: i64,
+    pub queue_id: i64,
+    pub seed: Option<i64>,
+    pub solution_revealed: bool,
+}
+
 pub struct UpsertLastAnswer {
     pub answer_answered_at: DateTime,
     pub answer_consecutive_correct: i32,
@@ -116,9 +166,16 @@ pub struct UpsertLastAnswer {
 }
 
 pub struct CreateAnswer {
+    pub cloze: i32,
+    pub help: Help,
     pub question_id: i64,
     pub queue_id: i64,
+    pub revealed_at: Option<DateTime>,
+    pub seed: Option<i64>,
+    pub selected_options: Option<Vec<i64>>,
+    pub shown_at: Option<DateTime>,
     pub state: String,
+    pub submitted_value: Option<String>,
     pub user_id: i64,
 }
 
@@ -126,12 +183,20 @@ impl AnswerRow {
     pub fn to_answer(&self) -> Answer {
         Answer {
             answered_at: DateTime(self.answered_at),
+            cloze: self.cloze,
             consecutive_correct: self.consecutive_correct,
             external_id: self.external_id.clone(),
+            hints_revealed: self.hints_revealed,
             id: self.id,
             question_id: self.question_id,
             queue_id: self.queue_id,
+            revealed_at: self.revealed_at.map(DateTime),
+            seed: self.seed,
+            selected_options: self.selected_options.clone(),
+            shown_at: self.shown_at.map(DateTime),
+            solution_revealed: self.solution_revealed,
             state: self.state.clone(),
+            submitted_value: self.submitted_value.clone(),
             user_id: self.user_id,
         }
     }
@@ -243,14 +308,22 @@ impl Queue {
             ChoiceRow,
             r#"select
                 q.id question_id,
+                c.cloze "cloze!",
                 la.answer_state "answer_state?",
                 la.answer_answered_at "answer_answered_at?",
-                la.answer_consecutive_correct "answer_consecutive_correct?"
+                la.answer_consecutive_correct "answer_consecutive_correct?",
+                la.answer_hints_revealed "answer_hints_revealed?",
+                la.answer_solution_revealed "answer_solution_revealed?"
              from questions q
+             cross join lateral unnest(
+                case when cardinality(q.cloze_numbers) = 0 then array[0] else q.cloze_numbers end
+             ) c(cloze)
              left join last_answers la
                 on  q.id = la.question_id
+                and c.cloze = la.cloze
                 and la.user_id = $1
                 and la.queue_id = $2
+             where q.status = 'published'
              limit 1000"#,
             self.user_id,
             self.id,
@@ -258,9 +331,14 @@ impl Queue {
         .fetch_all(db)
         .await?;
 
+        // Every question in the queue may have been archived or deleted since it was started.
         if choices.is_empty() {
-            let error = Error::Generic(format!("No choices found for queue {:?}", self));
-            return Err(error);
+            info!("No published questions for queue {:?}", self);
+            return Ok(NextQuestion {
+                question: None,
+                cloze: 0,
+                next_available_at: DateTime::now(),
+            });
         }
 
         info!("Choosing from choices: {:?}", choices);
@@ -273,6 +351,7 @@ impl Queue {
                 info!("Found a next question: {:?}", question);
                 NextQuestion {
                     question: Some(question),
+                    cloze: choice.cloze,
                     next_available_at,
                 }
             }
@@ -280,6 +359,7 @@ impl Queue {
                 info!("No question ready to work on");
                 NextQuestion {
                     question: None,
+                    cloze: 0,
                     next_available_at,
                 }
             }
@@ -300,7 +380,13 @@ impl Queue {
                 q.link question_link,
                 a.queue_id,
                 a.answered_at answer_answered_at,
-                a.consecutive_correct answer_consecutive_correct
+                a.consecutive_correct answer_consecutive_correct,
+                a.revealed_at answer_revealed_at,
+                a.shown_at answer_shown_at,
+                a.submitted_value answer_submitted_value,
+                a.cloze answer_cloze,
+                a.hints_revealed answer_hints_revealed,
+                a.solution_revealed answer_solution_revealed
              from answers a
              join questions q on a.question_id = q.id
              where a.queue_id = $1 order by a.answered_at desc limit 6",
@@ -327,15 +413,19 @@ impl Answer {
         Ok(row.to_answer())
     }
 
-    pub async fn create(answer: CreateAnswer, db: &Pool) -> Result<Self, Error> {
-        let id = Self::next_id("last_answers_id_seq", db).await?;
+    pub async fn create(
+        answer: CreateAnswer,
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    ) -> Result<Self, Error> {
+        let id = Self::next_id("last_answers_id_seq", &mut *tx).await?;
 
         let row = sqlx::query_as!(
             AnswerRow,
             "insert into answers
                 (id, external_id, user_id, queue_id, question_id, state, answered_at,
-                 consecutive_correct)
-             values ($1, $2, $3, $4, $5, $6, $7, $8)
+                 consecutive_correct, shown_at, revealed_at, selected_options, submitted_value,
+                 seed, cloze, hints_revealed, solution_revealed)
+             values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16)
              returning *",
             id.internal_id(),
             id.external_id(),
@@ -345,8 +435,16 @@ impl Answer {
             answer.state,
             DateTime::now().to_chrono(),
             0,
+            answer.shown_at.map(|time| time.to_chrono()),
+            answer.revealed_at.map(|time| time.to_chrono()),
+            answer.selected_options.as_deref(),
+            answer.submitted_value,
+            answer.seed,
+            answer.cloze,
+            answer.help.hints,
+            answer.help.solution,
         )
-        .fetch_one(db)
+        .fetch_one(&mut *tx)
         .await?;
         info!("Answer created: {:?}", id);
 
@@ -358,7 +456,7 @@ impl Answer {
         state: String,
         answered_at: DateTime,
         consecutive_correct: i32,
-        db: &Pool,
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
     ) -> Result<Self, Error> {
         let row = sqlx::query_as!(
             AnswerRow,
@@ -373,7 +471,7 @@ impl Answer {
             consecutive_correct,
             self.id,
         )
-        .fetch_one(db)
+        .fetch_one(&mut *tx)
         .await?;
 
         Ok(row.to_answer())
@@ -389,6 +487,14 @@ impl Answer {
         .await?;
         Ok(row.to_question())
     }
+
+    /// The template question as it was put to the user when this answer was given.
+    pub async fn variant(&self, db: &Pool) -> Result<Option<QuestionVariant>, Error> {
+        match self.seed {
+            Some(seed) => Ok(Some(self.question(db).await?.variant(seed, db).await?)),
+            None => Ok(None),
+        }
+    }
 }
 
 impl NextQuestion {
@@ -397,6 +503,18 @@ impl NextQuestion {
     }
 }
 
+// A question that is already known to be the next one, such as a flashcard whose answer is being
+// revealed.
+impl From<Question> for NextQuestion {
+    fn from(question: Question) -> Self {
+        Self {
+            question: Some(question),
+            cloze: 0,
+            next_available_at: DateTime::now(),
+        }
+    }
+}
+
 impl WideAnswer {
     pub fn tag_class(&self) -> String {
         match self.answer_state.as_ref() {
@@ -426,24 +544,48 @@ impl WideAnswer {
     pub fn answer_stage(&self) -> i32 {
         Choice::stage_from(self.answer_consecutive_correct)
     }
+
+    /// How much help was shown before the answer was given, as in "2 hints" or "solution".
+    pub fn help_used(&self) -> Option<String> {
+        match (self.answer_hints_revealed, self.answer_solution_revealed) {
+            (_, true) => Some("solution".to_string()),
+            (0, false) => None,
+            (1, false) => Some("1 hint".to_string()),
+            (hints, false) => Some(format!("{} hints", hints)),
+        }
+    }
+
+    /// How long it took to recall the answer to a flashcard before revealing it, as in "12s".
+    pub fn recall_time(&self) -> Option<String> {
+        let seconds = (self.answer_revealed_at? - self.answer_shown_at?).num_seconds();
+        Some(match seconds {
+            0..=59 => format!("{}s", seconds),
+            _ => format!("{}m {}s", seconds / 60, seconds % 60),
+        })
+    }
 }
 
 impl Creatable for LastAnswer {}
 
 impl LastAnswer {
-    pub async fn find_or_create(answer: &Answer, db: &Pool) -> Result<UpsertResult<Self>, Error> {
+    pub async fn find_or_create(
+        answer: &Answer,
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    ) -> Result<UpsertResult<Self>, Error> {
         let result = sqlx::query_as!(
             Self,
             "select * from last_answers
                 where user_id = $1
                   and question_id = $2
                   and queue_id = $3
+                  and cloze = $4
              limit 1",
             answer.user_id,
             answer.question_id,
             answer.queue_id,
+            answer.cloze,
         )
-        .fetch_optional(db)
+        .fetch_optional(&mut *tx)
         .await?;
 
         let upsert_result = match result {
@@ -452,7 +594,7 @@ impl LastAnswer {
                 created: false,
             },
             None => UpsertResult {
-                record: Self::create_from(answer, db).await?,
+                record: Self::create_from(answer, tx).await?,
                 created: true,
             },
         };
@@ -460,7 +602,10 @@ impl LastAnswer {
         Ok(upsert_result)
     }
 
-    async fn create_from(answer: &Answer, db: &Pool) -> Result<Self, Error> {
+    async fn create_from(
+        answer: &Answer,
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    ) -> Result<Self, Error> {
         let last_answer = sqlx::query_as!(
             Self,
             "insert into last_answers
@@ -471,9 +616,12 @@ impl LastAnswer {
                     answer_consecutive_correct,
                     question_id,
                     queue_id,
-                    user_id
+                    user_id,
+                    cloze,
+                    answer_hints_revealed,
+                    answer_solution_revealed
                 )
-                values ($1, $2, $3, $4, $5, $6, $7)
+                values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
                 returning *",
             answer.answered_at.to_chrono(),
             answer.id,
@@ -482,29 +630,126 @@ impl LastAnswer {
             answer.question_id,
             answer.queue_id,
             answer.user_id,
+            answer.cloze,
+            answer.hints_revealed,
+            answer.solution_revealed,
         )
-        .fetch_one(db)
+        .fetch_one(&mut *tx)
         .await?;
 
         Ok(last_answer)
     }
 
-    pub async fn update(&self, answer: &Answer, db: &Pool) -> Result<(), Error> {
+    pub async fn update(
+        &self,
+        answer: &Answer,
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    ) -> Result<(), Error> {
         sqlx::query!(
             "update last_answers set
                 answer_id = $1,
                 answer_consecutive_correct = $2,
                 answer_state = $3,
-                answer_answered_at = $4
-             where id = $5",
+                answer_answered_at = $4,
+                answer_hints_revealed = $5,
+                answer_solution_revealed = $6
+             where id = $7",
             answer.id,
             answer.consecutive_correct,
             answer.state,
             answer.answered_at.to_chrono(),
+            answer.hints_revealed,
+            answer.solution_revealed,
             self.id
         )
+        .execute(&mut *tx)
+        .await?;
+        Ok(())
+    }
+}
+
+impl AskedQuestion {
+    /// The question as it is already being asked, or else as it is asked for the first time.
+    /// Asking again, as when the page is reloaded, does not choose another variant.
+    pub async fn find_or_create(
+        queue_id: i64,
+        question: &Question,
+        cloze: i32,
+        db: &Pool,
+    ) -> Result<Self, Error> {
+        let seed = Some(QuestionTemplate::new_seed()).filter(|_| question.is_template());
+        sqlx::query!(
+            "insert into asked_questions (queue_id, question_id, cloze, seed)
+                values ($1, $2, $3, $4)
+             on conflict (queue_id, question_id, cloze) do nothing",
+            queue_id,
+            question.id,
+            cloze,
+            seed,
+        )
         .execute(db)
         .await?;
+
+        let asked = Self::find(queue_id, question.id, cloze, db)
+            .await?
+            .ok_or(Error::NotFound)?;
+        Ok(asked)
+    }
+
+    pub async fn find(
+        queue_id: i64,
+        question_id: i64,
+        cloze: i32,
+        db: &Pool,
+    ) -> Result<Option<Self>, Error> {
+        let asked = sqlx::query_as!(
+            Self,
+            "select * from asked_questions
+                where queue_id = $1 and question_id = $2 and cloze = $3",
+            queue_id,
+            question_id,
+            cloze,
+        )
+        .fetch_optional(db)
+        .await?;
+        Ok(asked)
+    }
+
+    /// The help that has been shown so far.
+    pub fn help(&self) -> Help {
+        Help {
+            hints: self.hints_revealed,
+            solution: self.solution_revealed,
+        }
+    }
+
+    /// Records that the help is being shown.  Help that has been shown stays counted, whatever
+    /// is asked for afterwards.
+    pub async fn reveal(&self, help: Help, db: &Pool) -> Result<Self, Error> {
+        let asked = sqlx::query_as!(
+            Self,
+            "update asked_questions set
+                hints_revealed = greatest(hints_revealed, $2),
+                solution_revealed = solution_revealed or $3
+             where id = $1
+             returning *",
+            self.id,
+            help.hints,
+            help.solution,
+        )
+        .fetch_one(db)
+        .await?;
+        Ok(asked)
+    }
+
+    /// Once the question has been answered, it is asked afresh the next time.
+    pub async fn delete(
+        &self,
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    ) -> Result<(), Error> {
+        sqlx::query!("delete from asked_questions where id = $1", self.id)
+            .execute(&mut *tx)
+            .await?;
         Ok(())
     }
 }
@@ -1,17 +1,23 @@
 use actix_identity::Identity;
 use actix_web::{
     get, post, web,
-    web::{Form, Path},
+    web::{Form, Path, Query},
 };
 use askama::Template;
+use chrono::TimeZone;
 use serde::{Deserialize, Serialize};
 
 use crate::{
+    audit,
     mutations::AnswerQuestion,
     prelude::*,
-    queues::{choosers, NextQuestion, Queue, WideAnswer},
-    types::{CurrentPage, Message},
-    users::User,
+    questions::{
+        kinds::{AnswerForm, Asking, Prompt, SubmittedAnswer},
+        Help, Question,
+    },
+    queues::{choosers, AskedQuestion, NextQuestion, Queue, WideAnswer},
+    types::{CurrentPage, DateTime, Message},
+    users::{handle::encode, User},
 };
 
 pub fn register(cfg: &mut web::ServiceConfig) {
@@ -25,9 +31,15 @@ struct Show<'a> {
     messages: &'a Vec<Message>,
     page: CurrentPage,
     next_question: NextQuestion,
+    prompt: Option<Prompt>,
+    help: Help,
+    next_help: Option<Help>,
     recent_answers: Vec<WideAnswer>,
+    shown_at: i64,
+    revealed_at: Option<i64>,
 }
 
+#[allow(dead_code)]
 #[derive(Template)]
 #[template(path = "queues/not-found.jinja")]
 struct NotFound<'a> {
@@ -43,6 +55,19 @@ struct List<'a> {
     messages: &'a Vec<Message>,
 }
 
+// Returns the current handle when `handle` is one that a user has since changed away from.
+async fn renamed_handle(handle: &str, db: &Pool) -> Result<Option<String>, Error> {
+    match User::find_by_handle(handle, db).await {
+        Ok(_) => Ok(None),
+        Err(Error::Database(sqlx::Error::RowNotFound)) => {
+            Ok(User::find_by_previous_handle(handle, db)
+                .await?
+                .map(|user| user.handle))
+        }
+        Err(error) => Err(error),
+    }
+}
+
 #[get("/{handle}/queues")]
 async fn list(
     path: Path<String>,
@@ -52,11 +77,22 @@ async fn list(
     let handle = path.into_inner();
     let messages = Message::none();
     let db = request.db()?;
-    let queues = User::find_by_handle(&handle, db).await?.queues(db).await?;
+    let user = auth::user_or_temporary(&id)?;
+
+    if let Some(handle) = renamed_handle(&handle, db).await? {
+        return request.redirect(format!("/{}/queues", encode(&handle)).as_ref());
+    }
+
+    // Queues, and the answers in them, are only shown to their owner.
+    let owner = User::find_by_handle(&handle, db).await?;
+    if owner.id != user.id {
+        return Err(Error::Unauthorized);
+    }
+    let queues = owner.queues(db).await?;
 
     let s = List {
         messages: &messages,
-        page: CurrentPage::from("/queues", auth::user(&id)?),
+        page: CurrentPage::new(&request, "/queues", user),
         queues: &queues,
     }
     .render()
@@ -64,15 +100,48 @@ async fn list(
     Ok(HttpResponse::Ok().content_type("text/html").body(s))
 }
 
+/// Asks for the back of a flashcard, or for another hint.  The question is kept in the query
+/// string so that the same card comes back, along with the time at which the front was first
+/// shown and the help that has been shown so far.
+#[derive(Deserialize)]
+struct ShowQuery {
+    reveal: Option<String>,
+    /// A question that is being answered with help, without revealing its answer.
+    question: Option<String>,
+    #[serde(default)]
+    cloze: i32,
+    #[serde(default)]
+    hints: i32,
+    #[serde(default)]
+    solution: bool,
+    shown_at: Option<i64>,
+}
+
+impl ShowQuery {
+    fn help(&self) -> Help {
+        Help {
+            hints: self.hints,
+            solution: self.solution,
+        }
+    }
+}
+
 #[get("/{handle}/queues/{queue_id}")]
 async fn show(
     path: Path<(String, String)>,
+    query: Query<ShowQuery>,
     request: HttpRequest,
     id: Identity,
 ) -> Result<HttpResponse, Error> {
-    let (_handle, queue_id) = path.into_inner();
+    let (handle, queue_id) = path.into_inner();
     let messages = &Message::none();
     let db = request.db()?;
+    let user = auth::user_or_temporary(&id)?;
+
+    if let Some(handle) = renamed_handle(&handle, db).await? {
+        let path = format!("/{}/queues/{}", encode(&handle), queue_id);
+        return request.redirect(path.as_ref());
+    }
 
     #[cfg(feature = "production")]
     let unit = choosers::TimeUnit::Days;
@@ -81,15 +150,64 @@ async fn show(
     let unit = choosers::TimeUnit::Minutes;
 
     let queue = &Queue::find(&queue_id, db).await?;
-    let next_question = queue.next_question(unit, db).await?;
+    if queue.user_id != user.id {
+        return Err(Error::Unauthorized);
+    }
+    let now = DateTime::now().to_chrono().timestamp_millis();
+    let (next_question, shown_at, revealed_at) = match (&query.reveal, &query.question) {
+        (Some(question_id), _) | (None, Some(question_id)) => {
+            let question = Question::find(question_id, db).await?;
+            // Queues only ask published questions, so a draft or an archived question is not
+            // found here either.
+            if !question.is_published() || !question.has_cloze(query.cloze) {
+                return Err(Error::NotFound);
+            }
+            let shown_at = query.shown_at.filter(|&time| time <= now).unwrap_or(now);
+            let mut next_question = NextQuestion::from(question);
+            next_question.cloze = query.cloze;
+            let revealed_at = query.reveal.as_ref().map(|_| now);
+            (next_question, shown_at, revealed_at)
+        }
+        (None, None) => (queue.next_question(unit, db).await?, now, None),
+    };
+    let (prompt, help, next_help) = match &next_question.question {
+        Some(question) => {
+            let mut asked =
+                AskedQuestion::find_or_create(queue.id, question, next_question.cloze, db).await?;
+            // A hint is recorded before it is shown, and the answer is recorded with it.
+            let requested = query.help().within(question);
+            if requested != Help::default() {
+                asked = asked.reveal(requested, db).await?;
+            }
+            let asking = Asking {
+                cloze: next_question.cloze,
+                revealed: revealed_at.is_some(),
+                seed: asked.seed,
+            };
+            let prompt = question
+                .question_kind()
+                .prompt(question, &asking, db)
+                .await?;
+            let help = asked.help().within(question);
+            // Once the answer is showing, there is no point in asking for a hint.
+            let next_help = help.next(question).filter(|_| revealed_at.is_none());
+            (Some(prompt), help, next_help)
+        }
+        None => (None, Help::default(), None),
+    };
     let recent_answers = queue.recent_answers(db).await?;
 
     let s = Show {
         queue,
         messages,
-        page: CurrentPage::from("/queues", auth::user(&id)?),
+        page: CurrentPage::new(&request, "/queues", user),
         next_question,
+        prompt,
+        help,
+        next_help,
         recent_answers,
+        shown_at,
+        revealed_at,
     }
     .render()
     .unwrap();
@@ -97,14 +215,78 @@ async fn show(
     Ok(HttpResponse::Ok().content_type("text/html").body(s))
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Default)]
 pub struct AnswerQuestionForm {
+    /// Empty for multiple-choice questions, which are graded from the options chosen.
+    #[serde(default)]
     pub state: String,
+    /// Milliseconds since the epoch.
+    #[serde(default)]
+    pub shown_at: Option<i64>,
+    #[serde(default)]
+    pub revealed_at: Option<i64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub options: Vec<i64>,
+    /// What was typed in answer to a numeric question.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub value: String,
+    /// The cloze deletion that was asked about.
+    #[serde(default)]
+    pub cloze: i32,
+}
+
+// The checkboxes of a multi-select question all have the same name, which the form extractor
+// cannot put into a struct, so the fields are gathered by hand.
+impl From<Vec<(String, String)>> for AnswerQuestionForm {
+    fn from(pairs: Vec<(String, String)>) -> Self {
+        let mut form = Self::default();
+        for (name, value) in pairs {
+            match name.as_ref() {
+                "state" => form.state = value,
+                "shown_at" => form.shown_at = value.parse().ok(),
+                "revealed_at" => form.revealed_at = value.parse().ok(),
+                "option" => form.options.extend(value.parse::<i64>().ok()),
+                "value" => form.value = value,
+                "cloze" => form.cloze = value.parse().unwrap_or_default(),
+                _ => {}
+            }
+        }
+        form
+    }
 }
 
 impl AnswerQuestionForm {
-    fn translated_state(&self) -> Result<String, Error> {
+    // The times come back from the page, so they are only kept when they make sense.
+    fn timings(&self) -> (Option<DateTime>, Option<DateTime>) {
+        let time = |millis: i64| {
+            chrono::Utc
+                .timestamp_millis_opt(millis)
+                .single()
+                .map(DateTime)
+        };
+        let shown_at = self.shown_at.and_then(time);
+        let revealed_at = self.revealed_at.and_then(time);
+        match (shown_at, revealed_at) {
+            (Some(shown_at), Some(revealed_at)) if revealed_at < shown_at => (None, None),
+            (None, Some(_)) => (None, None),
+            timings => timings,
+        }
+    }
+
+    fn answer(&self) -> Result<SubmittedAnswer, Error> {
+        Ok(SubmittedAnswer {
+            state: self.translated_state()?,
+            selected_options: self.options.clone(),
+            value: Some(self.value.clone()),
+            seed: None,
+            cloze: self.cloze,
+            help: Help::default(),
+        })
+    }
+
+    fn translated_state(&self) -> Result<Option<String>, Error> {
         let state = match self.state.as_ref() {
+            "" => return Ok(None),
             "Correct" => Ok("correct"),
             "Incorrect" => Ok("incorrect"),
             "Too hard" => Ok("unsure"),
@@ -112,34 +294,45 @@ impl AnswerQuestionForm {
         }?
         .to_string();
 
-        Ok(state)
+        Ok(Some(state))
     }
 }
 
 #[post("/{handle}/queues/{queue_id}/questions/{question_id}")]
 async fn answer_question(
-    form: Form<AnswerQuestionForm>,
+    form: Form<Vec<(String, String)>>,
     path: Path<(String, String, String)>,
     request: HttpRequest,
     id: Identity,
 ) -> Result<HttpResponse, Error> {
-    if id.identity().is_none() {
-        return request.redirect("/");
-    }
+    // The answer is recorded for whoever is signed in, whatever handle is in the path.
+    let actor = match auth::user_or_temporary(&id) {
+        Ok(user) => user,
+        Err(_) => return request.redirect("/"),
+    };
 
-    let (handle, queue_external_id, question_external_id) = path.into_inner();
-    let form = form.into_inner();
+    let (_, queue_external_id, question_external_id) = path.into_inner();
+    let form = AnswerQuestionForm::from(form.into_inner());
+    let (shown_at, revealed_at) = form.timings();
     let mutation = AnswerQuestion {
-        handle: handle.clone(),
+        actor,
         question_external_id: question_external_id.clone(),
         queue_external_id: queue_external_id.clone(),
-        state: form.translated_state()?,
+        answer: form.answer()?,
+        shown_at,
+        revealed_at,
     };
 
     if !mutation.validate() {
         // something here
     }
-    mutation.call(request.db()?).await?;
+    let context = audit::Context::from_request(&request);
+    mutation.call(&context, request.db()?).await?;
 
-    request.redirect(format!("/{}/queues/{}", handle, queue_external_id).as_ref())
+    let path = format!(
+        "/{}/queues/{}",
+        encode(&mutation.actor.handle),
+        queue_external_id
+    );
+    request.redirect(path.as_ref())
 }
@@ -9,7 +9,7 @@ use crate::error::Error;
 
 /// A trait for making certain types of response handling easier.
 pub trait Render {
-    /// Shorthand for rendering a template, with a specific HTTP response code.
+    // Shorthand for rendering a template, with a specific HTTP response code.
     // fn render(
     //     &self,
     //     code: usize,
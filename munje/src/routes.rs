@@ -12,6 +12,34 @@ pub fn register(cfg: &mut web::ServiceConfig) {
     cfg.service(home).service(overview).service(robots);
 }
 
+/// Handles that cannot be registered, because queues live at `/{handle}/queues` and a user with
+/// one of these handles would be shadowed by, or would shadow, a route of the application.  The
+/// first segment of every route must be in this list, which a test below checks.  The rest are
+/// held back for routes that are likely to be added later.
+pub const RESERVED_HANDLES: &[&str] = &[
+    "about",
+    "admin",
+    "api",
+    "assets",
+    "favicon.ico",
+    "help",
+    "login",
+    "logout",
+    "overview",
+    "questions",
+    "queues",
+    "robots.txt",
+    "settings",
+    "signup",
+    "static",
+    "users",
+];
+
+pub fn is_reserved_handle(handle: &str) -> bool {
+    let handle = handle.to_lowercase();
+    RESERVED_HANDLES.contains(&handle.as_str())
+}
+
 #[derive(Template)]
 #[template(path = "home.jinja")]
 struct Home {
@@ -20,10 +48,10 @@ struct Home {
 }
 
 #[get("/")]
-async fn home(id: Identity) -> Result<HttpResponse, Error> {
+async fn home(request: HttpRequest, id: Identity) -> Result<HttpResponse, Error> {
     let s = Home {
         messages: Message::none(),
-        page: CurrentPage::from("/", auth::user_or_guest(&id)?),
+        page: CurrentPage::new(&request, "/", auth::user_or_guest(&id)?),
     }
     .render()
     .unwrap();
@@ -38,10 +66,10 @@ struct Overview {
 }
 
 #[get("/overview")]
-async fn overview(id: Identity) -> Result<HttpResponse, Error> {
+async fn overview(request: HttpRequest, id: Identity) -> Result<HttpResponse, Error> {
     let s = Overview {
         messages: Message::none(),
-        page: CurrentPage::from("/overview", auth::user(&id)?),
+        page: CurrentPage::new(&request, "/overview", auth::user(&id)?),
     }
     .render()
     .unwrap();
@@ -57,3 +85,57 @@ async fn robots() -> Result<HttpResponse, Error> {
     let s = Robots.render().unwrap();
     Ok(HttpResponse::Ok().content_type("text/plain").body(s))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, path::Path};
+
+    // Collects the first path segment of every `#[get(...)]` and `#[post(...)]` route under
+    // `src`, so that adding a route without reserving its prefix fails this test.
+    fn route_prefixes(dir: &Path, prefixes: &mut Vec<String>) {
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                route_prefixes(&path, prefixes);
+                continue;
+            }
+
+            let source = fs::read_to_string(&path).unwrap();
+            for line in source.lines().map(str::trim) {
+                let route = match line
+                    .strip_prefix("#[get(\"/")
+                    .or_else(|| line.strip_prefix("#[post(\"/"))
+                {
+                    Some(route) => route,
+                    None => continue,
+                };
+                let prefix: String = route.chars().take_while(|c| !"/\"".contains(*c)).collect();
+                if !prefix.is_empty() && !prefix.starts_with('{') {
+                    prefixes.push(prefix);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn routes_are_reserved() {
+        let mut prefixes = vec![];
+        route_prefixes(
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("src"),
+            &mut prefixes,
+        );
+        assert!(prefixes.contains(&"users".to_string()));
+
+        for prefix in prefixes {
+            assert!(is_reserved_handle(&prefix), "{} is not reserved", prefix);
+        }
+    }
+
+    #[test]
+    fn reserved_ignores_case() {
+        assert!(is_reserved_handle("users"));
+        assert!(is_reserved_handle("Questions"));
+        assert!(!is_reserved_handle("gnusto"));
+    }
+}
@@ -0,0 +1,281 @@
+use actix_identity::IdentityPolicy;
+use actix_web::{
+    cookie::{Cookie, CookieJar, Key, SameSite},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderValue, SET_COOKIE},
+    HttpMessage, HttpRequest,
+};
+use serde::{Deserialize, Serialize};
+use std::future::{ready, Ready};
+use time::{Duration, OffsetDateTime};
+
+use crate::types::Config;
+
+/// How long a login lasts.  A browser session ends when the browser is closed, or after
+/// `idle_timeout` without a request.  A session started with "Remember me" checked survives
+/// restarts of the browser and ends `remember_for` after logging in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SessionDurations {
+    pub idle_timeout: Duration,
+    pub remember_for: Duration,
+}
+
+impl Default for SessionDurations {
+    fn default() -> Self {
+        Self {
+            idle_timeout: Duration::hours(1),
+            remember_for: Duration::days(30),
+        }
+    }
+}
+
+impl SessionDurations {
+    pub fn from_config(config: &Config) -> Self {
+        let default = Self::default();
+        Self {
+            idle_timeout: config
+                .session_idle_timeout_minutes
+                .map(Duration::minutes)
+                .unwrap_or(default.idle_timeout),
+            remember_for: config
+                .remember_me_days
+                .map(Duration::days)
+                .unwrap_or(default.remember_for),
+        }
+    }
+}
+
+/// Keeps the identity in an encrypted cookie, along with whether the session is to be remembered
+/// and the times that the deadlines are checked against.  Browser sessions get a cookie without
+/// an expiry that is written again on every request, to move the idle deadline along.
+///
+/// Cookies are encrypted with the first of the session keys and can be opened with any of them,
+/// so that a new key can be put in front of the old one without logging everyone out.  A cookie
+/// opened with an older key is encrypted again with the first one.
+pub struct SessionPolicy {
+    keys: Vec<Key>,
+    durations: SessionDurations,
+}
+
+/// Which kind of session the current request belongs to.  Logging in sets it with `persist`,
+/// and otherwise it carries over from the cookie.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SessionKind {
+    Browser,
+    Remembered,
+}
+
+// When the session in the cookie was started, in seconds since the epoch.
+#[derive(Clone, Copy, Debug)]
+struct LoggedInAt(i64);
+
+// The cookie was encrypted with a key other than the first.
+#[derive(Clone, Copy, Debug)]
+struct OlderKey;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SessionCookie {
+    identity: String,
+    remembered: bool,
+    logged_in_at: i64,
+    visited_at: i64,
+}
+
+impl SessionPolicy {
+    pub const COOKIE_NAME: &'static str = "auth-cookie";
+
+    pub fn new(session_keys: &[impl AsRef<[u8]>], durations: SessionDurations) -> Self {
+        assert!(
+            !session_keys.is_empty(),
+            "At least one session key is needed"
+        );
+        Self {
+            keys: session_keys
+                .iter()
+                .map(|key| Key::derive_from(key.as_ref()))
+                .collect(),
+            durations,
+        }
+    }
+
+    fn is_expired(&self, session: &SessionCookie, now: i64) -> bool {
+        if session.remembered {
+            now - session.logged_in_at > self.durations.remember_for.whole_seconds()
+        } else {
+            now - session.visited_at > self.durations.idle_timeout.whole_seconds()
+        }
+    }
+
+    // Returns the session along with the position of the key that opened it.
+    fn load(&self, request: &ServiceRequest) -> Option<(SessionCookie, usize)> {
+        let mut jar = CookieJar::new();
+        jar.add_original(request.cookie(Self::COOKIE_NAME)?);
+        let (cookie, position) = self
+            .keys
+            .iter()
+            .enumerate()
+            .find_map(|(i, key)| Some((jar.private(key).get(Self::COOKIE_NAME)?, i)))?;
+        let session: SessionCookie = serde_json::from_str(cookie.value()).ok()?;
+        if self.is_expired(&session, now()) {
+            return None;
+        }
+        Some((session, position))
+    }
+
+    fn cookie(&self, value: String) -> Cookie<'static> {
+        Cookie::build(Self::COOKIE_NAME, value)
+            .path("/")
+            .same_site(SameSite::Strict)
+            .http_only(true)
+            .secure(true)
+            .finish()
+    }
+
+    fn write<B>(
+        &self,
+        response: &mut ServiceResponse<B>,
+        session: Option<SessionCookie>,
+    ) -> Result<(), actix_web::Error> {
+        let mut jar = CookieJar::new();
+        match session {
+            Some(session) => {
+                let mut cookie = self.cookie(serde_json::to_string(&session)?);
+                if session.remembered {
+                    let elapsed = Duration::seconds(session.visited_at - session.logged_in_at);
+                    cookie.set_max_age(self.durations.remember_for - elapsed);
+                }
+                jar.private_mut(&self.keys[0]).add(cookie);
+            }
+            None => {
+                let cookie = self.cookie(String::new());
+                jar.add_original(cookie.clone());
+                jar.remove(cookie);
+            }
+        }
+
+        for cookie in jar.delta() {
+            let value = HeaderValue::from_str(&cookie.to_string())?;
+            response.headers_mut().append(SET_COOKIE, value);
+        }
+        Ok(())
+    }
+}
+
+impl IdentityPolicy for SessionPolicy {
+    type Future = Ready<Result<Option<String>, actix_web::Error>>;
+    type ResponseFuture = Ready<Result<(), actix_web::Error>>;
+
+    fn from_request(&self, request: &mut ServiceRequest) -> Self::Future {
+        let session = self.load(request).map(|(session, position)| {
+            if position > 0 {
+                request.extensions_mut().insert(OlderKey);
+            }
+            let kind = if session.remembered {
+                SessionKind::Remembered
+            } else {
+                SessionKind::Browser
+            };
+            request.extensions_mut().insert(kind);
+            request
+                .extensions_mut()
+                .insert(LoggedInAt(session.logged_in_at));
+            session.identity
+        });
+        ready(Ok(session))
+    }
+
+    fn to_response<B>(
+        &self,
+        identity: Option<String>,
+        changed: bool,
+        response: &mut ServiceResponse<B>,
+    ) -> Self::ResponseFuture {
+        let now = now();
+        let extensions = response.request().extensions();
+        let remembered = extensions.get::<SessionKind>() == Some(&SessionKind::Remembered);
+        let logged_in_at = extensions
+            .get::<LoggedInAt>()
+            .map_or(now, |logged_in_at| logged_in_at.0);
+        let older_key = extensions.get::<OlderKey>().is_some();
+        drop(extensions);
+
+        let session = identity.map(|identity| SessionCookie {
+            identity,
+            remembered,
+            logged_in_at,
+            visited_at: now,
+        });
+
+        // A remembered session has nothing to update until the identity changes.
+        let update =
+            changed || older_key || session.as_ref().is_some_and(|session| !session.remembered);
+        let result = if update {
+            self.write(response, session)
+        } else {
+            Ok(())
+        };
+        ready(result)
+    }
+}
+
+/// Starts a new session on logging in, which outlives the browser if `remember_me` is set.
+pub fn persist(request: &HttpRequest, remember_me: bool) {
+    let kind = if remember_me {
+        SessionKind::Remembered
+    } else {
+        SessionKind::Browser
+    };
+    let mut extensions = request.extensions_mut();
+    extensions.insert(kind);
+    extensions.remove::<LoggedInAt>();
+}
+
+/// A new random key, for `munje generate-key`.
+pub fn generate_key() -> String {
+    use rand::Rng;
+    let mut bytes = [0u8; 64];
+    rand::thread_rng().fill(&mut bytes[..]);
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn now() -> i64 {
+    OffsetDateTime::now_utc().unix_timestamp()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(remembered: bool, logged_in_at: i64, visited_at: i64) -> SessionCookie {
+        SessionCookie {
+            identity: "{}".to_string(),
+            remembered,
+            logged_in_at,
+            visited_at,
+        }
+    }
+
+    #[test]
+    fn browser_sessions_expire_when_idle() {
+        let policy = SessionPolicy::new(&[generate_key()], SessionDurations::default());
+        let hour = 60 * 60;
+        assert!(!policy.is_expired(&session(false, 0, 10 * hour), 10 * hour + 59 * 60));
+        assert!(policy.is_expired(&session(false, 0, 10 * hour), 11 * hour + 60));
+    }
+
+    #[test]
+    fn remembered_sessions_expire_after_login() {
+        let policy = SessionPolicy::new(&[generate_key()], SessionDurations::default());
+        let day = 24 * 60 * 60;
+        assert!(!policy.is_expired(&session(true, 0, 0), 29 * day));
+        assert!(policy.is_expired(&session(true, 0, 29 * day), 31 * day));
+    }
+
+    #[test]
+    fn generated_keys() {
+        let key = generate_key();
+        assert_eq!(128, key.len());
+        assert!(key.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(key, generate_key());
+    }
+}
@@ -1,18 +1,56 @@
+use actix_web::{web::Data, HttpMessage, HttpRequest};
 use chrono;
 use chrono_humanize::HumanTime;
 use comrak::{markdown_to_html, ComrakOptions};
 use envy;
 use harsh;
+use regex::{Captures, Regex};
 use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
-use std::ops::{Add, Sub};
+use std::{
+    net::IpAddr,
+    ops::{Add, Sub},
+};
 
-use crate::users::User;
+use crate::{
+    csrf::{self, CsrfProtection},
+    users::{self, oidc, HashParams, User, UserPreferences},
+};
 
 pub type Pool = PgPool;
 
 pub struct AppState {
+    pub clock: Clock,
     pub db: Pool,
+    pub invite_only: bool,
+    pub oidc: Option<oidc::Client>,
+    pub password_hashing: HashParams,
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+/// The time that one-time codes are checked against.  Tests fix it, so that a code cannot go
+/// stale between being worked out and being checked.
+#[derive(Debug, Default, Clone, Copy)]
+pub enum Clock {
+    #[default]
+    System,
+    Fixed(DateTime),
+}
+
+impl Clock {
+    pub fn now(&self) -> DateTime {
+        match self {
+            Self::System => DateTime::now(),
+            Self::Fixed(now) => *now,
+        }
+    }
+
+    pub fn from_request(request: &HttpRequest) -> Self {
+        request
+            .app_data::<Data<AppState>>()
+            .map(|state| state.clock)
+            .unwrap_or_default()
+    }
 }
 
 pub struct Message {
@@ -44,18 +82,46 @@ impl Clone for Message {
 
 #[derive(Default)]
 pub struct CurrentPage {
+    pub csrf_token: String,
     pub path: String,
+    pub preferences: UserPreferences,
     pub user: User,
 }
 
 impl CurrentPage {
     pub fn from(path: &str, user: User) -> Self {
         Self {
+            csrf_token: String::new(),
             path: path.to_string(),
+            preferences: UserPreferences::default(),
             user,
         }
     }
 
+    /// Use this rather than `from` when the page has forms on it, which is most pages, since the
+    /// layout has a logout form.
+    ///
+    /// The preferences are the ones loaded by `LoadPreferences` for the logged-in user.
+    pub fn new(request: &HttpRequest, path: &str, user: User) -> Self {
+        Self {
+            csrf_token: csrf::token(request),
+            preferences: request
+                .extensions()
+                .get::<UserPreferences>()
+                .cloned()
+                .unwrap_or_default(),
+            ..Self::from(path, user)
+        }
+    }
+
+    pub fn csrf_field(&self) -> String {
+        format!(
+            r#"<input type="hidden" name="{}" value="{}">"#,
+            CsrfProtection::FIELD_NAME,
+            self.csrf_token
+        )
+    }
+
     pub fn at(&self, path: &str) -> bool {
         self.path == path
     }
@@ -72,8 +138,28 @@ impl CurrentPage {
         !self.user.is_anonymous
     }
 
+    /// A guest who is practicing under a temporary user.
+    pub fn is_temporary(&self) -> bool {
+        self.user.is_temporary
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.is_authenticated() && self.user.is_admin
+    }
+
+    /// Whether the current user wrote something with this author.
+    pub fn is_author(&self, author_id: &Option<i64>) -> bool {
+        self.is_authenticated() && *author_id == Some(self.user.id)
+    }
+
+    /// A time on the current user's clock.
+    pub fn local_time(&self, time: DateTime) -> String {
+        self.preferences.local_time(&time)
+    }
+
+    /// The current user's handle, escaped for use in links.
     pub fn handle(&self) -> String {
-        self.user.handle.clone()
+        users::handle::encode(&self.user.handle)
     }
 }
 
@@ -129,25 +215,114 @@ pub struct Markdown {
     text: String,
 }
 
+// A cloze deletion, as in "{{c1::Lima}}", or "{{c1::Lima::a city}}" with a hint.
+fn cloze_regex() -> Regex {
+    Regex::new(r"\{\{c(\d+)::(.*?)\}\}").unwrap()
+}
+
 impl Markdown {
     pub fn from(text: String) -> Self {
         Self { text }
     }
 
+    /// Cloze deletions are shown as ordinary text.
     pub fn markdown(&self) -> String {
-        markdown_to_html(&self.text, &ComrakOptions::default())
+        self.render_clozes(|_, answer, _| answer.to_string())
+    }
+
+    pub fn source(&self) -> &str {
+        &self.text
+    }
+
+    /// The numbers of the cloze deletions in the text, in order and without repeats.  Deletions
+    /// with the same number are asked about together.
+    pub fn cloze_numbers(text: &str) -> Vec<i32> {
+        let mut numbers: Vec<i32> = cloze_regex()
+            .captures_iter(text)
+            .filter_map(|captures| captures[1].parse().ok())
+            .filter(|number| *number > 0)
+            .collect();
+        numbers.sort_unstable();
+        numbers.dedup();
+        numbers
+    }
+
+    /// The text with the deletions numbered `number` hidden, or replaced by their hints, and the
+    /// others shown.
+    pub fn masked(&self, number: i32) -> String {
+        self.render_clozes(|n, answer, hint| {
+            if n == number {
+                format!("**[{}]**", hint.unwrap_or("..."))
+            } else {
+                answer.to_string()
+            }
+        })
+    }
+
+    /// The text with the deletions numbered `number` picked out, for checking an answer.
+    pub fn revealed(&self, number: i32) -> String {
+        self.render_clozes(|n, answer, _| {
+            if n == number {
+                format!("**{}**", answer)
+            } else {
+                answer.to_string()
+            }
+        })
+    }
+
+    fn render_clozes<F>(&self, render: F) -> String
+    where
+        F: Fn(i32, &str, Option<&str>) -> String,
+    {
+        let text = cloze_regex().replace_all(&self.text, |captures: &Captures| {
+            let number = captures[1].parse().unwrap_or_default();
+            let (answer, hint) = match captures[2].split_once("::") {
+                Some((answer, hint)) => (answer, Some(hint)),
+                None => (&captures[2], None),
+            };
+            render(number, answer, hint)
+        });
+        markdown_to_html(&text, &ComrakOptions::default())
     }
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Config {
     pub database_url: String,
-    pub session_key: String,
+    // Separated by commas.  The first key signs cookies and all of them are accepted, so that a
+    // new key can be added in front and the old one dropped once its cookies have expired.
+    #[serde(rename = "session_key")]
+    pub session_keys: Vec<String>,
     pub session_domain: String,
     pub rust_log: String,
+    // Closes signup to everyone without an invite code.
+    #[serde(default)]
+    pub invite_only: bool,
+    pub oidc_issuer_url: Option<String>,
+    pub oidc_client_id: Option<String>,
+    pub oidc_client_secret: Option<String>,
+    pub oidc_redirect_url: Option<String>,
+    pub oidc_provider_name: Option<String>,
+    pub argon2_memory_kib: Option<u32>,
+    pub argon2_iterations: Option<u32>,
+    pub argon2_parallelism: Option<u32>,
+    pub session_idle_timeout_minutes: Option<i64>,
+    pub remember_me_days: Option<i64>,
+    // Separated by commas.  The addresses of the proxies in front of the application, which are
+    // believed about where a request came from.
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
 }
 
 impl Config {
+    /// Addresses that cannot be parsed are left out.
+    pub fn trusted_proxies(&self) -> Vec<IpAddr> {
+        self.trusted_proxies
+            .iter()
+            .filter_map(|address| address.trim().parse().ok())
+            .collect()
+    }
+
     pub fn test() -> Result<Self, envy::Error> {
         dotenv::from_filename(".env.test.local").ok();
         dotenv::dotenv().ok();
@@ -182,7 +357,7 @@ impl Id {
 
     pub fn external_id(&self) -> String {
         let ids = harsh::Harsh::builder().salt(Self::SALT).build().unwrap();
-        let input = vec![self.0 as u64];
+        let input = [self.0 as u64];
         ids.encode(&input[..])
     }
 }
@@ -203,6 +378,31 @@ mod tests {
         assert_eq!("LD".to_string(), id.external_id());
     }
 
+    #[test]
+    fn cloze_deletions() {
+        let text = "{{c2::Lima}} is the capital of {{c1::Peru::a country}}, on the {{c2::Rímac}}.";
+        assert_eq!(vec![1, 2], Markdown::cloze_numbers(text));
+        assert!(Markdown::cloze_numbers("No {{ deletions }} here").is_empty());
+
+        let markdown = Markdown::from(text.to_string());
+        assert_eq!(
+            "<p>Lima is the capital of <strong>[a country]</strong>, on the Rímac.</p>\n",
+            markdown.masked(1)
+        );
+        assert_eq!(
+            "<p><strong>[...]</strong> is the capital of Peru, on the <strong>[...]</strong>.</p>\n",
+            markdown.masked(2)
+        );
+        assert_eq!(
+            "<p><strong>Lima</strong> is the capital of Peru, on the <strong>Rímac</strong>.</p>\n",
+            markdown.revealed(2)
+        );
+        assert_eq!(
+            "<p>Lima is the capital of Peru, on the Rímac.</p>\n",
+            markdown.markdown()
+        );
+    }
+
     #[test]
     fn current_page_at() {
         let page = CurrentPage::from("/path", User::guest());
@@ -224,6 +424,21 @@ mod tests {
         assert!(!page.is_authenticated());
     }
 
+    #[test]
+    fn current_page_is_admin() {
+        let user = User {
+            handle: "gnusto".to_string(),
+            is_admin: true,
+            is_anonymous: false,
+            ..User::default()
+        };
+        let page = CurrentPage::from("/path", user);
+        assert!(page.is_admin());
+
+        let page = CurrentPage::from("/path", User::guest());
+        assert!(!page.is_admin());
+    }
+
     #[test]
     fn current_page_handle() {
         let user = User {
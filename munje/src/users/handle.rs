@@ -0,0 +1,97 @@
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use unicode_normalization::UnicodeNormalization;
+use unicode_security::{RestrictionLevel, RestrictionLevelDetection};
+
+// Everything other than letters, digits and the punctuation allowed in handles is escaped.
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'_').remove(b'.');
+
+/// The form in which a handle is stored and looked up.  NFKC folds compatibility characters like
+/// "ﬀ" and full-width letters into their plain forms, so that two handles that look the same are
+/// also stored the same.
+pub fn normalize(handle: &str) -> String {
+    handle.trim().nfkc().collect()
+}
+
+/// The form under which login attempts are counted.  Logins find accounts by their lowercased
+/// handle, so "Gnusto" and "ｇｎｕｓｔｏ" share the count of failures for "gnusto".  Lowercasing
+/// can lengthen a handle, so it is cut back to the length of the column it is stored in.
+pub fn login_key(handle: &str) -> String {
+    normalize(handle).to_lowercase().chars().take(30).collect()
+}
+
+/// Whether every character is a letter, a digit, or one of `-`, `_` and `.`.
+pub fn has_allowed_characters(handle: &str) -> bool {
+    handle
+        .chars()
+        .all(|c| c.is_alphanumeric() || "-_.".contains(c))
+}
+
+/// Whether the handle sticks to one script, or to a combination that is commonly written
+/// together, like Latin with Han and Kana.  Handles that mix, say, Latin and Cyrillic letters are
+/// almost always an attempt to look like someone else.
+pub fn is_single_script(handle: &str) -> bool {
+    handle.check_restriction_level(RestrictionLevel::HighlyRestrictive)
+}
+
+/// The confusable skeleton from Unicode TR39.  Handles with the same skeleton look alike, like
+/// "gnusto" and "gnustо" with a Cyrillic "о", and only one of them can be registered.
+pub fn skeleton(handle: &str) -> String {
+    unicode_security::skeleton(&handle.to_lowercase()).collect()
+}
+
+/// The handle escaped for use as a segment of a path, as in `/{handle}/queues`.
+pub fn encode(handle: &str) -> String {
+    utf8_percent_encode(handle, PATH_SEGMENT).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalized_handles() {
+        assert_eq!("ffrotz", normalize("ﬀrotz"));
+        assert_eq!("gnusto", normalize(" ｇｎｕｓｔｏ "));
+        assert_eq!("Zoë", normalize("Zoe\u{308}"));
+    }
+
+    #[test]
+    fn login_keys() {
+        assert_eq!("gnusto", login_key(" Gnusto "));
+        assert_eq!("gnusto", login_key("ｇｎｕｓｔｏ"));
+        assert_eq!(30, login_key(&"\u{130}".repeat(30)).chars().count());
+    }
+
+    #[test]
+    fn allowed_characters() {
+        assert!(has_allowed_characters("jane.doe-99_x"));
+        assert!(has_allowed_characters("Ærøskøbing"));
+        assert!(has_allowed_characters("山田"));
+        assert!(!has_allowed_characters("gnusto/frotz"));
+        assert!(!has_allowed_characters("gnusto?"));
+        assert!(!has_allowed_characters("a\u{200b}b"));
+    }
+
+    #[test]
+    fn mixed_scripts() {
+        assert!(is_single_script("gnusto"));
+        assert!(is_single_script("Ærøskøbing"));
+        assert!(is_single_script("Дмитрий"));
+        assert!(is_single_script("山田taro"));
+        assert!(!is_single_script("gnust\u{43e}"));
+    }
+
+    #[test]
+    fn confusable_skeletons() {
+        assert_eq!(skeleton("gnusto"), skeleton("gnust\u{43e}"));
+        assert_eq!(skeleton("gnusto"), skeleton("\u{261}nusto"));
+        assert_eq!(skeleton("Gnusto"), skeleton("gnusto"));
+        assert_ne!(skeleton("gnusto"), skeleton("frotz"));
+    }
+
+    #[test]
+    fn encoded_handles() {
+        assert_eq!("jane.doe-99_x", encode("jane.doe-99_x"));
+        assert_eq!("Zo%C3%AB", encode("Zoë"));
+    }
+}
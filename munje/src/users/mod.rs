@@ -1,3 +1,9 @@
+pub mod handle;
 mod models;
+pub mod oidc;
+mod preferences;
 pub mod routes;
+mod throttle;
+pub mod totp;
 pub use models::*;
+pub use preferences::{is_timezone, LoadPreferences, UserPreferences};
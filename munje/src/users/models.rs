@@ -1,13 +1,23 @@
+use actix_web::{
+    web::{self, Data},
+    HttpRequest,
+};
 use argon2;
 use chrono;
-use derive_more::{Display, Error};
 use serde::{Deserialize, Serialize};
 
 use crate::{
     error::Error,
     mutations::{AuthenticateUser, RegisterUser},
     queues::{Queue, QueueRow},
-    types::{DateTime, Pool},
+    routes,
+    types::{AppState, Config, DateTime, Pool},
+    users::{
+        handle,
+        oidc::ExternalIdentity,
+        throttle::{Failures, Throttle},
+        totp::Totp,
+    },
 };
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
@@ -15,11 +25,24 @@ pub struct User {
     pub created_at: DateTime,
     pub handle: String,
     pub id: i64,
+    #[serde(skip_serializing, skip_deserializing)]
+    pub invite_id: Option<i64>,
     pub is_admin: bool,
     pub is_anonymous: bool,
+    // A guest who has started practicing.  Temporary users are anonymous as well.
+    #[serde(default)]
+    pub is_temporary: bool,
     pub last_login: Option<DateTime>,
     #[serde(skip_serializing, skip_deserializing)]
     pub hashed_password: String,
+    #[serde(skip_serializing, skip_deserializing)]
+    pub reauthenticated_at: Option<DateTime>,
+    #[serde(default)]
+    pub totp_enabled: bool,
+    #[serde(skip_serializing, skip_deserializing)]
+    pub totp_last_used_step: Option<i64>,
+    #[serde(skip_serializing, skip_deserializing)]
+    pub totp_secret: Option<String>,
     pub updated_at: DateTime,
 }
 
@@ -27,31 +50,226 @@ pub struct User {
 pub struct UserRow {
     pub created_at: chrono::DateTime<chrono::Utc>,
     pub handle: String,
+    pub handle_skeleton: Option<String>,
     pub hashed_password: String,
     pub id: i64,
+    pub invite_id: Option<i64>,
+    pub is_admin: bool,
+    pub is_temporary: bool,
     pub last_login: Option<chrono::DateTime<chrono::Utc>>,
+    pub reauthenticated_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub totp_enabled: bool,
+    pub totp_last_used_step: Option<i64>,
+    pub totp_secret: Option<String>,
     pub updated_at: chrono::DateTime<chrono::Utc>,
 }
 
+#[derive(Serialize, Debug, Clone)]
+pub struct LoginAttempt {
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub handle: String,
+    pub id: i64,
+    pub ip_address: String,
+    pub succeeded: bool,
+}
+
+/// Lets someone sign up while registration is closed.  Invites made by admins can be used more
+/// than once.
+#[derive(Serialize, Debug, Clone)]
+pub struct Invite {
+    pub code: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub created_by_id: i64,
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub id: i64,
+    pub max_uses: i32,
+    pub use_count: i32,
+}
+
+/// An invite along with the handles of the accounts that were created with it.
+#[derive(Debug, Clone)]
+pub struct InviteSummary {
+    pub invite: Invite,
+    pub handles: Vec<String>,
+}
+
 #[derive(Debug)]
 struct Password(String);
 
-#[derive(Debug, Display, Error)]
-struct HashPasswordError {
-    details: String,
+/// Argon2id settings for new password hashes, set with the `ARGON2_*` environment variables.
+/// Passwords that were hashed with weaker settings are hashed again the next time their owner logs
+/// in, since that is the only time the password itself is known.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HashParams {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+struct RecoveryCode;
+
+impl RecoveryCode {
+    const COUNT: usize = 10;
+    // Letters and digits that are hard to confuse with one another when written down.
+    const ALPHABET: &'static [u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+    fn generate() -> String {
+        use rand::Rng;
+        let mut rng = rand::thread_rng();
+        let mut chars =
+            (0..10).map(|_| Self::ALPHABET[rng.gen_range(0..Self::ALPHABET.len())] as char);
+        let first: String = chars.by_ref().take(5).collect();
+        let second: String = chars.collect();
+        format!("{}-{}", first, second)
+    }
+}
+
+// The unique indexes that keep two users from having the same, or confusable, handles.
+const HANDLE_CONSTRAINTS: [&str; 3] = [
+    "users_handle_key",
+    "users_lower_handle_idx",
+    "users_handle_skeleton_idx",
+];
+
+// A second signup with the same handle can slip in between the check and the insert, in which
+// case the unique index on the handle catches it.  Any other conflict is a bug rather than a
+// taken handle.
+fn taken_on_conflict(error: sqlx::Error) -> Error {
+    match &error {
+        sqlx::Error::Database(e)
+            if e.code().as_deref() == Some("23505")
+                && e.constraint()
+                    .is_some_and(|name| HANDLE_CONSTRAINTS.contains(&name)) =>
+        {
+            Error::HandleTaken
+        }
+        _ => Error::Database(error),
+    }
+}
+
+// The minimum recommended by OWASP for Argon2id.
+impl Default for HashParams {
+    fn default() -> Self {
+        Self {
+            memory_kib: 19 * 1024,
+            iterations: 2,
+            parallelism: 1,
+        }
+    }
+}
+
+impl HashParams {
+    // Recovery codes are random, so they do not need a slow hash to stand up to guessing, and a
+    // login with one checks every unused code.
+    const RECOVERY_CODES: Self = Self {
+        memory_kib: 4096,
+        iterations: 1,
+        parallelism: 1,
+    };
+
+    pub fn from_config(config: &Config) -> Self {
+        let default = Self::default();
+        Self {
+            memory_kib: config.argon2_memory_kib.unwrap_or(default.memory_kib),
+            iterations: config.argon2_iterations.unwrap_or(default.iterations),
+            parallelism: config.argon2_parallelism.unwrap_or(default.parallelism),
+        }
+    }
+
+    pub fn from_request(request: &HttpRequest) -> Self {
+        request
+            .app_data::<Data<AppState>>()
+            .map(|state| state.password_hashing)
+            .unwrap_or_default()
+    }
+
+    fn to_argon2(self) -> argon2::Config<'static> {
+        argon2::Config {
+            variant: argon2::Variant::Argon2id,
+            version: argon2::Version::Version13,
+            mem_cost: self.memory_kib,
+            time_cost: self.iterations,
+            lanes: self.parallelism,
+            ..argon2::Config::default()
+        }
+    }
+
+    // Reads the settings out of an encoded hash like "$argon2id$v=19$m=19456,t=2,p=1$...".
+    fn of_hash(hash: &str) -> Option<(argon2::Variant, Self)> {
+        let mut parts = hash.split('$').skip(1);
+        let variant = argon2::Variant::from_str(parts.next()?).ok()?;
+        let mut part = parts.next()?;
+        if part.starts_with("v=") {
+            part = parts.next()?;
+        }
+
+        let mut params = Self {
+            memory_kib: 0,
+            iterations: 0,
+            parallelism: 0,
+        };
+        for pair in part.split(',') {
+            let (name, value) = pair.split_once('=')?;
+            let value = value.parse().ok()?;
+            match name {
+                "m" => params.memory_kib = value,
+                "t" => params.iterations = value,
+                "p" => params.parallelism = value,
+                _ => return None,
+            }
+        }
+        Some((variant, params))
+    }
 }
 
 impl Password {
-    fn to_hash(&self) -> Result<String, Error> {
+    // Stored in place of a hash for accounts that do not have a password, such as those created
+    // by logging in with an identity provider.
+    const NONE: &'static str = "*";
+
+    fn to_hash(&self, params: &HashParams) -> argon2::Result<String> {
         use rand::Rng;
         let salt: [u8; 32] = rand::thread_rng().gen();
-        let config = argon2::Config::default();
-        let hash = argon2::hash_encoded(self.0.as_bytes(), &salt, &config)?;
+        argon2::hash_encoded(self.0.as_bytes(), &salt, &params.to_argon2())
+    }
+
+    fn verify(&self, password: &str) -> argon2::Result<bool> {
+        if self.0 == Self::NONE {
+            return Ok(false);
+        }
+        argon2::verify_encoded(&self.0, password.as_bytes())
+    }
+
+    // Whether the hash was made with another variant of Argon2, or with less memory or time than
+    // is now asked for.  Settings that are stronger than the current ones are left alone.
+    fn needs_rehash(&self, params: &HashParams) -> bool {
+        if self.0 == Self::NONE {
+            return false;
+        }
+        match HashParams::of_hash(&self.0) {
+            Some((variant, current)) => {
+                variant != argon2::Variant::Argon2id
+                    || current.memory_kib < params.memory_kib
+                    || current.iterations < params.iterations
+                    || current.parallelism < params.parallelism
+            }
+            None => true,
+        }
+    }
+
+    // Hashing is slow on purpose, so it is done on the blocking thread pool, where it does not hold
+    // up the other requests that an actix worker is handling.
+    async fn hash(password: &str, params: HashParams) -> Result<String, Error> {
+        let password = Self(password.to_string());
+        let hash = web::block(move || password.to_hash(&params)).await??;
         Ok(hash)
     }
 
-    fn verify(&self, password: &str) -> Result<bool, Error> {
-        Ok(argon2::verify_encoded(&self.0, password.as_bytes())?)
+    async fn check(hash: &str, password: &str) -> Result<bool, Error> {
+        let hash = Self(hash.to_string());
+        let password = password.to_string();
+        let valid = web::block(move || hash.verify(&password)).await??;
+        Ok(valid)
     }
 }
 
@@ -62,9 +280,15 @@ impl UserRow {
             handle: self.handle.clone(),
             hashed_password: self.hashed_password.clone(),
             id: self.id,
-            is_admin: false,
-            is_anonymous: false,
+            invite_id: self.invite_id,
+            is_admin: self.is_admin,
+            is_anonymous: self.is_temporary,
+            is_temporary: self.is_temporary,
             last_login: self.last_login.map(DateTime),
+            reauthenticated_at: self.reauthenticated_at.map(DateTime),
+            totp_enabled: self.totp_enabled,
+            totp_last_used_step: self.totp_last_used_step,
+            totp_secret: self.totp_secret.clone(),
             updated_at: DateTime(self.updated_at),
         }
     }
@@ -83,30 +307,268 @@ impl User {
     }
 
     pub async fn find_by_handle(handle: &str, db: &Pool) -> Result<Self, Error> {
-        let row = sqlx::query_as!(UserRow, "select * from users where handle = $1", handle)
+        let row = sqlx::query_as!(
+            UserRow,
+            "select * from users where lower(handle) = lower($1)",
+            handle::normalize(handle),
+        )
+        .fetch_one(db)
+        .await?;
+        Ok(row.to_user())
+    }
+
+    pub async fn find_by_id(id: i64, db: &Pool) -> Result<Self, Error> {
+        let row = sqlx::query_as!(UserRow, "select * from users where id = $1", id)
             .fetch_one(db)
             .await?;
         Ok(row.to_user())
     }
 
-    pub async fn register(mutation: &RegisterUser, db: &Pool) -> Result<Self, Error> {
-        let password = mutation.password.value.clone();
-        let hashed_password = Password(password.to_string()).to_hash().unwrap();
+    pub async fn find_by_previous_handle(handle: &str, db: &Pool) -> Result<Option<Self>, Error> {
+        let row = sqlx::query_as!(
+            UserRow,
+            "select u.*
+             from users u
+             join handle_redirects hr on hr.user_id = u.id
+             where lower(hr.old_handle) = lower($1)",
+            handle::normalize(handle),
+        )
+        .fetch_optional(db)
+        .await?;
+        Ok(row.map(|row| row.to_user()))
+    }
+
+    // When registration is invite-only, an invite code is required.  Otherwise one is optional,
+    // but it is still checked and used up if it is given, so that it is clear who invited whom.
+    // A guest's queues and answers under `temporary` are moved to the new account.
+    pub async fn register(
+        mutation: &RegisterUser,
+        temporary: Option<&User>,
+        invite_only: bool,
+        params: HashParams,
+        db: &Pool,
+    ) -> Result<Self, Error> {
+        let hashed_password = Password::hash(&mutation.password.value, params).await?;
+
+        let mut tx = db.begin().await?;
+
+        let handle = &mutation.handle.value;
+        if Self::handle_taken(handle, None, &mut tx).await? {
+            return Err(Error::HandleTaken);
+        }
+
+        let code = mutation.invite_code.value.trim();
+        let invite_id = if code.is_empty() {
+            if invite_only {
+                return Err(Error::InvalidInvite);
+            }
+            None
+        } else {
+            Some(Invite::redeem(code, &mut tx).await?.id)
+        };
 
         let row = sqlx::query_as!(
             UserRow,
-            "insert into users (handle, hashed_password, last_login) values ($1, $2, $3)
+            "insert into users (handle, handle_skeleton, hashed_password, last_login, invite_id)
+             values ($1, $2, $3, $4, $5)
              returning *",
-            mutation.handle.value.clone(),
+            handle,
+            handle::skeleton(handle),
             hashed_password,
             chrono::Utc::now(),
+            invite_id,
+        )
+        .fetch_one(&mut tx)
+        .await
+        .map_err(taken_on_conflict)?;
+
+        if let Some(temporary) = temporary.filter(|user| user.is_temporary) {
+            Self::claim_temporary(temporary.id, row.id, &mut tx).await?;
+        }
+
+        tx.commit().await?;
+        Ok(row.to_user())
+    }
+
+    // Temporary users never show up anywhere but in the guest's own links, so the handle only
+    // has to be unique.
+    pub async fn create_temporary(db: &Pool) -> Result<Self, Error> {
+        use rand::{distributions::Alphanumeric, Rng};
+        let suffix: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(12)
+            .map(|byte| (byte as char).to_ascii_lowercase())
+            .collect();
+        let handle = format!("guest-{}", suffix);
+
+        let row = sqlx::query_as!(
+            UserRow,
+            "insert into users (handle, handle_skeleton, hashed_password, is_temporary)
+             values ($1, $2, $3, true)
+             returning *",
+            handle,
+            handle::skeleton(&handle),
+            Password::NONE,
         )
         .fetch_one(db)
         .await?;
+        Ok(row.to_user())
+    }
+
+    async fn claim_temporary(
+        temporary_id: i64,
+        user_id: i64,
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    ) -> Result<(), Error> {
+        let temporary = sqlx::query_scalar!(
+            "select is_temporary from users where id = $1 for update",
+            temporary_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        // Already claimed, from another tab perhaps.
+        if temporary != Some(true) {
+            return Ok(());
+        }
+
+        sqlx::query!(
+            "update queues set user_id = $2 where user_id = $1",
+            temporary_id,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            "update answers set user_id = $2 where user_id = $1",
+            temporary_id,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            "update last_answers set user_id = $2 where user_id = $1",
+            temporary_id,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!("delete from users where id = $1", temporary_id)
+            .execute(&mut *tx)
+            .await?;
+
+        info!(
+            "Moved the queues of temporary user {} to user {}",
+            temporary_id, user_id
+        );
+        Ok(())
+    }
+
+    pub async fn find_by_identity(
+        identity: &ExternalIdentity,
+        db: &Pool,
+    ) -> Result<Option<Self>, Error> {
+        let row = sqlx::query_as!(
+            UserRow,
+            "select u.*
+             from users u
+             join user_identities ui on ui.user_id = u.id
+             where ui.issuer = $1 and ui.subject = $2",
+            identity.issuer,
+            identity.subject,
+        )
+        .fetch_optional(db)
+        .await?;
+        Ok(row.map(|row| row.to_user()))
+    }
+
+    // Accounts created this way have no password.  The suggested handle is used if it is free,
+    // and otherwise the first free handle with a number added to the end.  A reserved handle
+    // counts as taken.  While registration is closed an invite is needed, as it is for signing
+    // up with a password.
+    pub async fn register_identity(
+        identity: &ExternalIdentity,
+        invite_code: Option<&str>,
+        invite_only: bool,
+        db: &Pool,
+    ) -> Result<Self, Error> {
+        let mut tx = db.begin().await?;
+
+        let code = invite_code.map(str::trim).unwrap_or_default();
+        let invite_id = if code.is_empty() {
+            if invite_only {
+                return Err(Error::InvalidInvite);
+            }
+            None
+        } else {
+            Some(Invite::redeem(code, &mut tx).await?.id)
+        };
+
+        let mut handle = identity.suggested_handle.clone();
+        for n in 2.. {
+            if !routes::is_reserved_handle(&handle)
+                && !Self::handle_taken(&handle, None, &mut tx).await?
+            {
+                break;
+            }
+            handle = format!("{}{}", identity.suggested_handle, n);
+        }
+
+        let row = sqlx::query_as!(
+            UserRow,
+            "insert into users (handle, handle_skeleton, hashed_password, last_login, invite_id)
+             values ($1, $2, $3, $4, $5)
+             returning *",
+            handle,
+            handle::skeleton(&handle),
+            Password::NONE,
+            chrono::Utc::now(),
+            invite_id,
+        )
+        .fetch_one(&mut tx)
+        .await
+        .map_err(taken_on_conflict)?;
+
+        sqlx::query!(
+            "insert into user_identities (issuer, subject, user_id) values ($1, $2, $3)",
+            identity.issuer,
+            identity.subject,
+            row.id,
+        )
+        .execute(&mut tx)
+        .await?;
 
+        tx.commit().await?;
         Ok(row.to_user())
     }
 
+    // Accounts created before handles had skeletons get them here.  An account whose handle
+    // looks like someone else's is left without one and logged, so that it can be renamed.
+    pub async fn fill_handle_skeletons(db: &Pool) -> Result<(), Error> {
+        let rows = sqlx::query!("select id, handle from users where handle_skeleton is null")
+            .fetch_all(db)
+            .await?;
+
+        for row in rows {
+            let result = sqlx::query!(
+                "update users set handle_skeleton = $1 where id = $2",
+                handle::skeleton(&row.handle),
+                row.id,
+            )
+            .execute(db)
+            .await;
+
+            match result.map_err(taken_on_conflict) {
+                Ok(_) => {}
+                Err(Error::HandleTaken) => {
+                    warn!("Handle {} looks like another user's handle", row.handle)
+                }
+                Err(error) => return Err(error),
+            }
+        }
+
+        Ok(())
+    }
+
     pub async fn update_last_login(id: i64, db: &Pool) -> Result<(), Error> {
         sqlx::query!("update users set last_login = now() where id = $1", id)
             .execute(db)
@@ -115,15 +577,350 @@ impl User {
         Ok(())
     }
 
-    pub async fn authenticate(mutation: &AuthenticateUser, db: &Pool) -> Result<User, Error> {
+    // A failure to upgrade the hash is logged rather than returned, since the password was right.
+    pub async fn authenticate(
+        mutation: &AuthenticateUser,
+        params: HashParams,
+        db: &Pool,
+    ) -> Result<User, Error> {
         let user = Self::find_by_handle(&mutation.handle.value, db).await?;
-        let password = Password(user.hashed_password.clone());
-        if !password.verify(&mutation.password.value)? {
+        if !user.verify_password(&mutation.password.value).await? {
             return Err(Error::InvalidPassword);
         }
+
+        if Password(user.hashed_password.clone()).needs_rehash(&params) {
+            info!("Upgrading the password hash of {}", user.handle);
+            if let Err(e) = user
+                .update_password(&mutation.password.value, params, db)
+                .await
+            {
+                error!(
+                    "Unable to upgrade the password hash of {}: {:?}",
+                    user.handle, e
+                );
+            }
+        }
+
         Ok(user)
     }
 
+    pub async fn verify_password(&self, password: &str) -> Result<bool, Error> {
+        Password::check(&self.hashed_password, password).await
+    }
+
+    /// Accounts created by logging in with an identity provider have no password until one is set.
+    pub fn has_password(&self) -> bool {
+        self.hashed_password != Password::NONE
+    }
+
+    fn reauthentication_lasts() -> chrono::Duration {
+        chrono::Duration::minutes(5)
+    }
+
+    /// Whether the user logged in with the identity provider again recently enough for it to
+    /// stand in for a password.
+    pub fn is_reauthenticated(&self, now: DateTime) -> bool {
+        self.reauthenticated_at
+            .is_some_and(|at| now - at < Self::reauthentication_lasts())
+    }
+
+    pub async fn reauthenticate(&self, db: &Pool) -> Result<(), Error> {
+        sqlx::query!(
+            "update users set reauthenticated_at = now() where id = $1",
+            self.id,
+        )
+        .execute(db)
+        .await?;
+        Ok(())
+    }
+
+    /// Checks that it is the user who is asking for a sensitive change: with the password, or
+    /// for an account without one, with a recent login with the identity provider.  Such a login
+    /// is good for one change.
+    pub async fn confirm(&self, password: &str, now: DateTime, db: &Pool) -> Result<bool, Error> {
+        if self.has_password() {
+            return self.verify_password(password).await;
+        }
+
+        let result = sqlx::query!(
+            "update users set reauthenticated_at = null
+             where id = $1 and reauthenticated_at > $2",
+            self.id,
+            now.to_chrono() - Self::reauthentication_lasts(),
+        )
+        .execute(db)
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    pub async fn link_identity(&self, identity: &ExternalIdentity, db: &Pool) -> Result<(), Error> {
+        sqlx::query!(
+            "insert into user_identities (issuer, subject, user_id) values ($1, $2, $3)",
+            identity.issuer,
+            identity.subject,
+            self.id,
+        )
+        .execute(db)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn update_password(
+        &self,
+        password: &str,
+        params: HashParams,
+        db: &Pool,
+    ) -> Result<(), Error> {
+        let hashed_password = Password::hash(password, params).await?;
+        sqlx::query!(
+            "update users set hashed_password = $1 where id = $2",
+            hashed_password,
+            self.id,
+        )
+        .execute(db)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn handle_available(&self, handle: &str, db: &Pool) -> Result<bool, Error> {
+        Ok(!Self::handle_taken(handle, Some(self.id), db).await?)
+    }
+
+    // Handles are compared without regard to case, so that "Gnusto" and "gnusto" cannot belong to
+    // different people, and by their skeletons, so that neither can a lookalike with a Cyrillic
+    // "о".  Old handles that still redirect are taken as well.
+    async fn handle_taken<'e, E>(handle: &str, except_id: Option<i64>, db: E) -> Result<bool, Error>
+    where
+        E: sqlx::PgExecutor<'e>,
+    {
+        let row = sqlx::query!(
+            r#"select exists (
+                select 1 from users
+                where (lower(handle) = lower($1) or handle_skeleton = $3)
+                  and id is distinct from $2
+                union all
+                select 1 from handle_redirects
+                where lower(old_handle) = lower($1) and user_id is distinct from $2
+             ) "taken!""#,
+            handle,
+            except_id,
+            handle::skeleton(handle),
+        )
+        .fetch_one(db)
+        .await?;
+
+        Ok(row.taken)
+    }
+
+    // The old handle is kept around so that links to /{handle}/queues continue to work.
+    pub async fn update_handle(&self, handle: &str, db: &Pool) -> Result<Self, Error> {
+        let mut tx = db.begin().await?;
+
+        sqlx::query!(
+            "delete from handle_redirects
+             where lower(old_handle) in (lower($1), lower($2)) and user_id = $3",
+            handle,
+            self.handle,
+            self.id,
+        )
+        .execute(&mut tx)
+        .await?;
+
+        let row = sqlx::query_as!(
+            UserRow,
+            "update users set handle = $1, handle_skeleton = $2 where id = $3 returning *",
+            handle,
+            handle::skeleton(handle),
+            self.id,
+        )
+        .fetch_one(&mut tx)
+        .await
+        .map_err(taken_on_conflict)?;
+
+        // Lookups ignore case, so a change in case alone does not need a redirect.
+        if handle.to_lowercase() != self.handle.to_lowercase() {
+            sqlx::query!(
+                "insert into handle_redirects (old_handle, user_id) values ($1, $2)",
+                self.handle,
+                self.id,
+            )
+            .execute(&mut tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+        Ok(row.to_user())
+    }
+
+    // Questions that only this user has worked on are removed.  Questions that appear in other
+    // people's queues are kept, but no longer point at an author.
+    pub async fn delete(&self, db: &Pool) -> Result<(), Error> {
+        let mut tx = db.begin().await?;
+
+        sqlx::query!("delete from last_answers where user_id = $1", self.id)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query!("delete from answers where user_id = $1", self.id)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query!("delete from queues where user_id = $1", self.id)
+            .execute(&mut tx)
+            .await?;
+
+        sqlx::query!(
+            "delete from questions q
+             where q.author_id = $1
+               and not exists (select 1 from queues where starting_question_id = q.id)
+               and not exists (select 1 from answers where question_id = q.id)",
+            self.id
+        )
+        .execute(&mut tx)
+        .await?;
+        sqlx::query!(
+            "update questions set author_id = null where author_id = $1",
+            self.id
+        )
+        .execute(&mut tx)
+        .await?;
+
+        sqlx::query!("delete from users where id = $1", self.id)
+            .execute(&mut tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    pub fn totp(&self) -> Option<Totp> {
+        self.totp_secret.as_deref().and_then(Totp::from_base32)
+    }
+
+    // Enrollment is finished by `enable_totp`, once the user has shown that they can produce a
+    // code from the new secret.
+    pub async fn start_totp_enrollment(&self, db: &Pool) -> Result<Totp, Error> {
+        let totp = Totp::generate();
+        sqlx::query!(
+            "update users set
+                totp_secret = $1,
+                totp_enabled = false,
+                totp_last_used_step = null
+             where id = $2",
+            totp.to_base32(),
+            self.id,
+        )
+        .execute(db)
+        .await?;
+
+        Ok(totp)
+    }
+
+    pub async fn enable_totp(&self, step: i64, db: &Pool) -> Result<(), Error> {
+        sqlx::query!(
+            "update users set totp_enabled = true, totp_last_used_step = $1 where id = $2",
+            step,
+            self.id,
+        )
+        .execute(db)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn disable_totp(&self, db: &Pool) -> Result<(), Error> {
+        let mut tx = db.begin().await?;
+
+        sqlx::query!("delete from recovery_codes where user_id = $1", self.id)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query!(
+            "update users set
+                totp_secret = null,
+                totp_enabled = false,
+                totp_last_used_step = null
+             where id = $1",
+            self.id,
+        )
+        .execute(&mut tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    pub async fn verify_totp(&self, code: &str, now: DateTime, db: &Pool) -> Result<bool, Error> {
+        let step = match self.totp() {
+            Some(totp) => totp.verify(code, now, self.totp_last_used_step),
+            None => None,
+        };
+
+        // A code that is used twice at once only logs in once.
+        match step {
+            Some(step) => {
+                let result = sqlx::query!(
+                    "update users set totp_last_used_step = $1
+                     where id = $2
+                       and (totp_last_used_step is null or totp_last_used_step < $1)",
+                    step,
+                    self.id,
+                )
+                .execute(db)
+                .await?;
+                Ok(result.rows_affected() == 1)
+            }
+            None => Ok(false),
+        }
+    }
+
+    // Recovery codes are shown to the user once and only their hashes are kept.  Creating a new
+    // set replaces the old one.
+    pub async fn create_recovery_codes(&self, db: &Pool) -> Result<Vec<String>, Error> {
+        let codes: Vec<String> = (0..RecoveryCode::COUNT)
+            .map(|_| RecoveryCode::generate())
+            .collect();
+
+        let mut tx = db.begin().await?;
+        sqlx::query!("delete from recovery_codes where user_id = $1", self.id)
+            .execute(&mut tx)
+            .await?;
+
+        for code in &codes {
+            let hashed_code = Password::hash(code, HashParams::RECOVERY_CODES).await?;
+            sqlx::query!(
+                "insert into recovery_codes (user_id, hashed_code) values ($1, $2)",
+                self.id,
+                hashed_code,
+            )
+            .execute(&mut tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+        Ok(codes)
+    }
+
+    pub async fn use_recovery_code(&self, code: &str, db: &Pool) -> Result<bool, Error> {
+        let code = code.trim().to_lowercase();
+        let rows = sqlx::query!(
+            "select id, hashed_code from recovery_codes where user_id = $1 and used_at is null",
+            self.id,
+        )
+        .fetch_all(db)
+        .await?;
+
+        for row in rows {
+            // Whoever deletes the code gets to use it.
+            if Password::check(&row.hashed_code, &code).await? {
+                let result = sqlx::query!("delete from recovery_codes where id = $1", row.id)
+                    .execute(db)
+                    .await?;
+                return Ok(result.rows_affected() == 1);
+            }
+        }
+
+        Ok(false)
+    }
+
     pub async fn queues(&self, db: &Pool) -> Result<Vec<Queue>, Error> {
         let queues = sqlx::query_as!(QueueRow, "select * from queues where user_id = $1", self.id,)
             .fetch_all(db)
@@ -136,6 +933,205 @@ impl User {
     }
 }
 
+impl Invite {
+    // Letters and digits that are hard to confuse with one another when read out loud.
+    const ALPHABET: &'static [u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+    fn generate_code() -> String {
+        use rand::Rng;
+        let mut rng = rand::thread_rng();
+        (0..12)
+            .map(|_| Self::ALPHABET[rng.gen_range(0..Self::ALPHABET.len())] as char)
+            .collect()
+    }
+
+    pub async fn create(
+        user: &User,
+        max_uses: i32,
+        expires_at: Option<DateTime>,
+        db: &Pool,
+    ) -> Result<Self, Error> {
+        let invite = sqlx::query_as!(
+            Self,
+            "insert into invites (code, created_by_id, expires_at, max_uses)
+             values ($1, $2, $3, $4)
+             returning *",
+            Self::generate_code(),
+            user.id,
+            expires_at.map(|at| at.to_chrono()),
+            max_uses,
+        )
+        .fetch_one(db)
+        .await?;
+
+        Ok(invite)
+    }
+
+    pub async fn find_by_creator(user: &User, db: &Pool) -> Result<Vec<InviteSummary>, Error> {
+        let rows = sqlx::query!(
+            r#"select i.*, array_remove(array_agg(u.handle order by u.created_at), null) "handles!"
+               from invites i
+               left join users u on u.invite_id = i.id
+               where i.created_by_id = $1
+               group by i.id
+               order by i.created_at desc"#,
+            user.id,
+        )
+        .fetch_all(db)
+        .await?;
+
+        let invites = rows
+            .into_iter()
+            .map(|row| InviteSummary {
+                invite: Self {
+                    code: row.code,
+                    created_at: row.created_at,
+                    created_by_id: row.created_by_id,
+                    expires_at: row.expires_at,
+                    id: row.id,
+                    max_uses: row.max_uses,
+                    use_count: row.use_count,
+                },
+                handles: row.handles,
+            })
+            .collect();
+
+        Ok(invites)
+    }
+
+    // The row is locked so that two people signing up at the same time cannot both take the last
+    // use of an invite.
+    async fn redeem(
+        code: &str,
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    ) -> Result<Self, Error> {
+        let invite = sqlx::query_as!(
+            Self,
+            "select * from invites where code = $1 for update",
+            code.to_lowercase(),
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(Error::InvalidInvite)?;
+
+        if !invite.is_usable(DateTime::now()) {
+            return Err(Error::InvalidInvite);
+        }
+
+        sqlx::query!(
+            "update invites set use_count = use_count + 1 where id = $1",
+            invite.id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        Ok(invite)
+    }
+
+    pub fn is_usable(&self, now: DateTime) -> bool {
+        let expired = self
+            .expires_at
+            .is_some_and(|expires_at| DateTime(expires_at) <= now);
+        !expired && self.use_count < self.max_uses
+    }
+
+    pub fn expires(&self) -> String {
+        match self.expires_at {
+            Some(expires_at) => DateTime(expires_at).humanize(),
+            None => "never".to_string(),
+        }
+    }
+}
+
+impl LoginAttempt {
+    pub async fn create(
+        handle: &str,
+        ip_address: &str,
+        succeeded: bool,
+        db: &Pool,
+    ) -> Result<(), Error> {
+        sqlx::query!(
+            "insert into login_attempts (handle, ip_address, succeeded) values ($1, $2, $3)",
+            handle::login_key(handle),
+            ip_address,
+            succeeded,
+        )
+        .execute(db)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn find_failed(db: &Pool) -> Result<Vec<Self>, Error> {
+        let attempts = sqlx::query_as!(
+            Self,
+            "select * from login_attempts
+             where not succeeded
+             order by created_at desc
+             limit 100"
+        )
+        .fetch_all(db)
+        .await?;
+
+        Ok(attempts)
+    }
+
+    // Failures for an account are counted from its last successful login, so that someone who
+    // mistypes their password now and then is not locked out.  Failures from an IP address are
+    // not reset by a successful login, since one valid account would otherwise be enough to keep
+    // guessing passwords for others.
+    pub async fn locked_until(
+        handle: &str,
+        ip_address: &str,
+        db: &Pool,
+    ) -> Result<Option<DateTime>, Error> {
+        let now = DateTime::now();
+        let since = (now + -Throttle::window()).to_chrono();
+
+        let row = sqlx::query!(
+            r#"select count(*) "count!", max(created_at) last_failed_at
+               from login_attempts
+               where handle = $1
+                 and not succeeded
+                 and created_at > greatest(
+                    $2,
+                    (select max(created_at) from login_attempts where handle = $1 and succeeded)
+                 )"#,
+            handle::login_key(handle),
+            since,
+        )
+        .fetch_one(db)
+        .await?;
+        let account = Failures {
+            count: row.count,
+            last_failed_at: row.last_failed_at.map(DateTime),
+        };
+
+        let row = sqlx::query!(
+            r#"select count(*) "count!", max(created_at) last_failed_at
+               from login_attempts
+               where ip_address = $1 and not succeeded and created_at > $2"#,
+            ip_address,
+            since,
+        )
+        .fetch_one(db)
+        .await?;
+        let ip_address = Failures {
+            count: row.count,
+            last_failed_at: row.last_failed_at.map(DateTime),
+        };
+
+        Ok(std::cmp::max(
+            Throttle::account().locked_until(&account, now),
+            Throttle::ip_address().locked_until(&ip_address, now),
+        ))
+    }
+
+    pub fn attempted_at(&self) -> String {
+        DateTime(self.created_at).humanize()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -143,6 +1139,93 @@ mod tests {
     #[test]
     fn hash_password() {
         let pass = Password("keyboard cat".to_string());
-        assert_eq!(116, pass.to_hash().unwrap().len());
+        let hash = pass.to_hash(&HashParams::default()).unwrap();
+        assert!(hash.starts_with("$argon2id$v=19$m=19456,t=2,p=1$"));
+        assert!(Password(hash).verify("keyboard cat").unwrap());
+    }
+
+    #[test]
+    fn hash_params_of_hash() {
+        let hash = Password("keyboard cat".to_string())
+            .to_hash(&HashParams::RECOVERY_CODES)
+            .unwrap();
+        assert_eq!(
+            Some((argon2::Variant::Argon2id, HashParams::RECOVERY_CODES)),
+            HashParams::of_hash(&hash)
+        );
+        assert_eq!(None, HashParams::of_hash("*"));
+    }
+
+    #[test]
+    fn weaker_hash_needs_rehash() {
+        let params = HashParams::default();
+        let hash = Password("keyboard cat".to_string())
+            .to_hash(&HashParams::RECOVERY_CODES)
+            .unwrap();
+        assert!(Password(hash).needs_rehash(&params));
+
+        let legacy = argon2::hash_encoded(b"keyboard cat", &[0; 32], &argon2::Config::default());
+        assert!(Password(legacy.unwrap()).needs_rehash(&params));
+
+        let stronger = HashParams {
+            memory_kib: params.memory_kib * 2,
+            ..params
+        };
+        let hash = Password("keyboard cat".to_string())
+            .to_hash(&stronger)
+            .unwrap();
+        assert!(!Password(hash).needs_rehash(&params));
+        assert!(!Password(Password::NONE.to_string()).needs_rehash(&params));
+    }
+
+    #[test]
+    fn password_none() {
+        let pass = Password(Password::NONE.to_string());
+        assert!(!pass.verify("*").unwrap());
+        assert!(!pass.verify("").unwrap());
+    }
+
+    fn invite(max_uses: i32, use_count: i32, expires_at: Option<DateTime>) -> Invite {
+        Invite {
+            code: Invite::generate_code(),
+            created_at: chrono::Utc::now(),
+            created_by_id: 1,
+            expires_at: expires_at.map(|at| at.to_chrono()),
+            id: 1,
+            max_uses,
+            use_count,
+        }
+    }
+
+    #[test]
+    fn invite_code() {
+        let code = Invite::generate_code();
+        assert_eq!(12, code.len());
+        assert_ne!(code, Invite::generate_code());
+    }
+
+    #[test]
+    fn invite_uses() {
+        let now = DateTime::now();
+        assert!(invite(1, 0, None).is_usable(now));
+        assert!(!invite(1, 1, None).is_usable(now));
+        assert!(invite(5, 4, None).is_usable(now));
+    }
+
+    #[test]
+    fn invite_expiry() {
+        let now = DateTime::now();
+        let later = now + chrono::Duration::days(1);
+        let earlier = now + chrono::Duration::days(-1);
+        assert!(invite(1, 0, Some(later)).is_usable(now));
+        assert!(!invite(1, 0, Some(earlier)).is_usable(now));
+    }
+
+    #[test]
+    fn recovery_code() {
+        let code = RecoveryCode::generate();
+        assert_eq!(11, code.len());
+        assert_eq!(Some(5), code.find('-'));
+        assert_ne!(code, RecoveryCode::generate());
     }
 }
@@ -0,0 +1,465 @@
+use actix_web::{
+    cookie::{time::Duration, Cookie, CookieJar, Key, SameSite},
+    web::Data,
+    HttpRequest,
+};
+use jsonwebtoken::{decode, decode_header, jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::{
+    error::Error,
+    types::{AppState, Config, DateTime},
+};
+
+/// Logging in with an OpenID Connect identity provider, using the authorization code flow.  The
+/// provider is configured with the `OIDC_*` environment variables, and the login button is only
+/// shown when all of them are set.
+#[derive(Clone)]
+pub struct Client {
+    issuer_url: String,
+    client_id: String,
+    client_secret: String,
+    redirect_url: String,
+    pub provider_name: String,
+    // The pending login is encrypted with the first key and can be opened with any of them, like
+    // the session cookie.
+    cookie_keys: Vec<Key>,
+}
+
+/// An account at the identity provider, which is linked to at most one user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExternalIdentity {
+    pub issuer: String,
+    pub subject: String,
+    pub suggested_handle: String,
+}
+
+/// What a round trip to the identity provider is for.  The signed-in user is kept in the login
+/// cookie, since the identity cookie does not come back with the redirect from the provider.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Purpose {
+    /// Logging in, or signing up on the first login with the invite code given, if any.
+    Login(Option<String>),
+    /// Confirming a sensitive change to an account that has no password.
+    Reauthenticate(i64),
+    /// Adding the identity to an account that was created with a password.
+    Link(i64),
+}
+
+/// The query string that the identity provider redirects back with.
+#[derive(Debug, Deserialize)]
+pub struct Callback {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+}
+
+// The state and nonce are kept in their own cookie rather than in the identity cookie.  The
+// identity cookie is SameSite=Strict, so the browser leaves it off the redirect back from the
+// identity provider.
+#[derive(Debug, Serialize, Deserialize)]
+struct PendingLogin {
+    state: String,
+    nonce: String,
+    started_at: DateTime,
+    purpose: Purpose,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Claims {
+    sub: String,
+    nonce: Option<String>,
+    preferred_username: Option<String>,
+    email: Option<String>,
+    auth_time: Option<i64>,
+}
+
+impl PendingLogin {
+    fn new(purpose: Purpose) -> Self {
+        Self {
+            state: random_token(),
+            nonce: random_token(),
+            started_at: DateTime::now(),
+            purpose,
+        }
+    }
+
+    fn is_expired(&self, now: DateTime) -> bool {
+        now - self.started_at > chrono::Duration::minutes(10)
+    }
+
+    // The provider logged the user in after the round trip was started, rather than relying on
+    // an earlier session.  A minute is allowed for clocks that do not agree.
+    fn is_fresh(&self, claims: &Claims) -> bool {
+        let started_at = self.started_at.to_chrono().timestamp();
+        claims
+            .auth_time
+            .is_some_and(|auth_time| auth_time >= started_at - 60)
+    }
+}
+
+impl Client {
+    pub const COOKIE_NAME: &'static str = "oidc-login";
+    const COOKIE_PATH: &'static str = "/users/auth/oidc";
+
+    pub fn new(
+        issuer_url: &str,
+        client_id: &str,
+        client_secret: &str,
+        redirect_url: &str,
+        provider_name: &str,
+        session_keys: &[impl AsRef<[u8]>],
+    ) -> Self {
+        assert!(
+            !session_keys.is_empty(),
+            "At least one session key is needed"
+        );
+        Self {
+            issuer_url: issuer_url.to_string(),
+            client_id: client_id.to_string(),
+            client_secret: client_secret.to_string(),
+            redirect_url: redirect_url.to_string(),
+            provider_name: provider_name.to_string(),
+            cookie_keys: session_keys
+                .iter()
+                .map(|key| Key::derive_from(key.as_ref()))
+                .collect(),
+        }
+    }
+
+    pub fn from_config(config: &Config) -> Option<Self> {
+        Some(Self::new(
+            config.oidc_issuer_url.as_deref()?,
+            config.oidc_client_id.as_deref()?,
+            config.oidc_client_secret.as_deref()?,
+            config.oidc_redirect_url.as_deref()?,
+            config
+                .oidc_provider_name
+                .as_deref()
+                .unwrap_or("single sign-on"),
+            &config.session_keys,
+        ))
+    }
+
+    pub fn from_request(request: &HttpRequest) -> Option<&Self> {
+        request
+            .app_data::<Data<AppState>>()
+            .and_then(|state| state.oidc.as_ref())
+    }
+
+    /// Returns the address of the identity provider's login page, along with a cookie that must
+    /// be set so that the callback can be checked.
+    pub async fn start_login(&self, purpose: Purpose) -> Result<(String, Cookie<'static>), Error> {
+        let metadata = self.discover().await?;
+        let pending = PendingLogin::new(purpose);
+
+        let mut url = Url::parse(&metadata.authorization_endpoint)?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.client_id)
+            .append_pair("redirect_uri", &self.redirect_url)
+            .append_pair("scope", "openid profile email")
+            .append_pair("state", &pending.state)
+            .append_pair("nonce", &pending.nonce);
+
+        // A session that the provider already has is not enough to confirm a change.
+        if let Purpose::Reauthenticate(_) = pending.purpose {
+            url.query_pairs_mut()
+                .append_pair("prompt", "login")
+                .append_pair("max_age", "0");
+        }
+
+        Ok((url.to_string(), self.seal(&pending)?))
+    }
+
+    /// Exchanges the authorization code for an ID token and returns the identity that it
+    /// vouches for, along with what the login was started for.
+    pub async fn finish_login(
+        &self,
+        callback: &Callback,
+        request: &HttpRequest,
+    ) -> Result<(Purpose, ExternalIdentity), Error> {
+        if let Some(error) = &callback.error {
+            return Err(Error::OidcError(format!("provider returned {}", error)));
+        }
+
+        let pending = request
+            .cookie(Self::COOKIE_NAME)
+            .and_then(|cookie| self.open(cookie))
+            .ok_or_else(|| Error::OidcError("no login in progress".to_string()))?;
+        if pending.is_expired(DateTime::now()) {
+            return Err(Error::OidcError("login has expired".to_string()));
+        }
+        if callback.state.as_deref() != Some(pending.state.as_str()) {
+            return Err(Error::OidcError("state does not match".to_string()));
+        }
+        let code = callback
+            .code
+            .as_deref()
+            .ok_or_else(|| Error::OidcError("no authorization code".to_string()))?;
+
+        let metadata = self.discover().await?;
+        let id_token = self.exchange_code(&metadata, code).await?;
+        let claims = self.verify_id_token(&metadata, &id_token).await?;
+        if claims.nonce.as_deref() != Some(pending.nonce.as_str()) {
+            return Err(Error::OidcError("nonce does not match".to_string()));
+        }
+        if let Purpose::Reauthenticate(_) = pending.purpose {
+            if !pending.is_fresh(&claims) {
+                return Err(Error::OidcError("login is not fresh".to_string()));
+            }
+        }
+
+        let identity = ExternalIdentity {
+            issuer: metadata.issuer,
+            suggested_handle: suggested_handle(&claims),
+            subject: claims.sub,
+        };
+        Ok((pending.purpose, identity))
+    }
+
+    pub fn removal_cookie() -> Cookie<'static> {
+        Cookie::build(Self::COOKIE_NAME, "")
+            .path(Self::COOKIE_PATH)
+            .finish()
+    }
+
+    async fn discover(&self) -> Result<ProviderMetadata, Error> {
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            self.issuer_url.trim_end_matches('/')
+        );
+        let body = reqwest::get(&url).await?.error_for_status()?.text().await?;
+        let metadata: ProviderMetadata = serde_json::from_str(&body)?;
+
+        if metadata.issuer.trim_end_matches('/') != self.issuer_url.trim_end_matches('/') {
+            return Err(Error::OidcError(format!(
+                "expected issuer {}, got {}",
+                self.issuer_url, metadata.issuer
+            )));
+        }
+        Ok(metadata)
+    }
+
+    async fn exchange_code(
+        &self,
+        metadata: &ProviderMetadata,
+        code: &str,
+    ) -> Result<String, Error> {
+        let body = reqwest::Client::new()
+            .post(&metadata.token_endpoint)
+            .basic_auth(&self.client_id, Some(&self.client_secret))
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", &self.redirect_url),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        let response: TokenResponse = serde_json::from_str(&body)?;
+        Ok(response.id_token)
+    }
+
+    // The signature is checked even though the token came straight from the token endpoint, so
+    // that a misconfigured token endpoint cannot be used to log in as someone else.
+    async fn verify_id_token(
+        &self,
+        metadata: &ProviderMetadata,
+        id_token: &str,
+    ) -> Result<Claims, Error> {
+        let header = decode_header(id_token)?;
+        let body = reqwest::get(&metadata.jwks_uri)
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        let jwks: JwkSet = serde_json::from_str(&body)?;
+        let jwk = match &header.kid {
+            Some(kid) => jwks.find(kid),
+            None => jwks.keys.first(),
+        }
+        .ok_or_else(|| Error::OidcError("signing key not found".to_string()))?;
+
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.set_audience(&[&self.client_id]);
+        validation.set_issuer(&[&metadata.issuer]);
+        let data = decode::<Claims>(id_token, &DecodingKey::from_jwk(jwk)?, &validation)?;
+        Ok(data.claims)
+    }
+
+    fn seal(&self, pending: &PendingLogin) -> Result<Cookie<'static>, Error> {
+        let mut jar = CookieJar::new();
+        jar.private_mut(&self.cookie_keys[0]).add(
+            Cookie::build(Self::COOKIE_NAME, serde_json::to_string(pending)?)
+                .path(Self::COOKIE_PATH)
+                .max_age(Duration::minutes(10))
+                .same_site(SameSite::Lax)
+                .http_only(true)
+                .secure(true)
+                .finish(),
+        );
+        Ok(jar.get(Self::COOKIE_NAME).unwrap().clone())
+    }
+
+    fn open(&self, cookie: Cookie<'static>) -> Option<PendingLogin> {
+        let mut jar = CookieJar::new();
+        jar.add_original(cookie);
+        let cookie = self
+            .cookie_keys
+            .iter()
+            .find_map(|key| jar.private(key).get(Self::COOKIE_NAME))?;
+        serde_json::from_str(cookie.value()).ok()
+    }
+}
+
+fn random_token() -> String {
+    use rand::Rng;
+    let bytes: [u8; 24] = rand::thread_rng().gen();
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// A starting point for the handle of an account created on first login.  A number is added to
+// the end if it is already taken.
+fn suggested_handle(claims: &Claims) -> String {
+    let name = claims
+        .preferred_username
+        .as_deref()
+        .or_else(|| {
+            claims
+                .email
+                .as_deref()
+                .and_then(|email| email.split('@').next())
+        })
+        .unwrap_or_default();
+
+    let handle: String = name
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_' || *c == '.')
+        .take(25)
+        .collect();
+
+    if handle.len() < 3 {
+        "user".to_string()
+    } else {
+        handle
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims(preferred_username: Option<&str>, email: Option<&str>) -> Claims {
+        Claims {
+            sub: "1234".to_string(),
+            nonce: None,
+            preferred_username: preferred_username.map(str::to_string),
+            email: email.map(str::to_string),
+            auth_time: None,
+        }
+    }
+
+    const SESSION_KEY: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    fn client_with_keys(session_keys: &[&str]) -> Client {
+        Client::new(
+            "https://id.example.com",
+            "munje",
+            "secret",
+            "https://munje.example.com/users/auth/oidc/callback",
+            "Example",
+            session_keys,
+        )
+    }
+
+    fn client() -> Client {
+        client_with_keys(&[SESSION_KEY])
+    }
+
+    #[test]
+    fn handle_from_preferred_username() {
+        let claims = claims(Some("frotz"), Some("gnusto@example.com"));
+        assert_eq!("frotz", suggested_handle(&claims));
+    }
+
+    #[test]
+    fn handle_from_email() {
+        let claims = claims(None, Some("gnusto@example.com"));
+        assert_eq!("gnusto", suggested_handle(&claims));
+    }
+
+    #[test]
+    fn handle_without_special_characters() {
+        assert_eq!(
+            "JaneDoeadmin",
+            suggested_handle(&claims(Some("Jane Doe (admin)"), None))
+        );
+        assert_eq!("user", suggested_handle(&claims(Some("zé"), None)));
+    }
+
+    #[test]
+    fn pending_login_round_trip() {
+        let client = client();
+        let pending = PendingLogin::new(Purpose::Link(7));
+        let cookie = client.seal(&pending).unwrap();
+        assert!(!cookie.value().contains(&pending.state));
+
+        let opened = client.open(cookie).unwrap();
+        assert_eq!(pending.state, opened.state);
+        assert_eq!(pending.nonce, opened.nonce);
+        assert_eq!(Purpose::Link(7), opened.purpose);
+    }
+
+    #[test]
+    fn pending_login_from_older_key() {
+        let pending = PendingLogin::new(Purpose::Login(None));
+        let cookie = client().seal(&pending).unwrap();
+
+        let rotated = client_with_keys(&[&"x".repeat(64), SESSION_KEY]);
+        assert_eq!(pending.state, rotated.open(cookie).unwrap().state);
+
+        let cookie = rotated.seal(&pending).unwrap();
+        assert!(client().open(cookie).is_none());
+    }
+
+    #[test]
+    fn reauthentication_must_be_fresh() {
+        let pending = PendingLogin::new(Purpose::Reauthenticate(7));
+        let started_at = pending.started_at.to_chrono().timestamp();
+        let mut claims = claims(Some("frotz"), None);
+        assert!(!pending.is_fresh(&claims));
+
+        claims.auth_time = Some(started_at - 3600);
+        assert!(!pending.is_fresh(&claims));
+
+        claims.auth_time = Some(started_at + 5);
+        assert!(pending.is_fresh(&claims));
+    }
+
+    #[test]
+    fn pending_login_tampered() {
+        let client = client();
+        let cookie = client
+            .seal(&PendingLogin::new(Purpose::Login(None)))
+            .unwrap();
+        let tampered = Cookie::new(Client::COOKIE_NAME, format!("x{}", cookie.value()));
+        assert!(client.open(tampered).is_none());
+    }
+}
@@ -0,0 +1,311 @@
+use actix_identity::RequestIdentity;
+use actix_web::{
+    cookie::{Cookie, CookieJar, Key, SameSite},
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    web::Data,
+    HttpMessage,
+};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::{
+    future::{ready, Future, Ready},
+    pin::Pin,
+    rc::Rc,
+};
+
+use crate::{
+    error::Error,
+    types::{AppState, DateTime, Pool},
+    users::User,
+};
+
+/// Settings that each user can change.  Users who have not changed anything, and guests, get the
+/// defaults.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct UserPreferences {
+    pub timezone: String,
+    pub grading_scale: String,
+    pub daily_new_limit: i32,
+    pub daily_review_limit: i32,
+    pub queue_strategy: String,
+    pub email_digest: bool,
+    pub theme: String,
+}
+
+impl Default for UserPreferences {
+    fn default() -> Self {
+        Self {
+            timezone: "UTC".to_string(),
+            grading_scale: "three-point".to_string(),
+            daily_new_limit: 20,
+            daily_review_limit: 200,
+            queue_strategy: "spaced-repetition".to_string(),
+            email_digest: false,
+            theme: "system".to_string(),
+        }
+    }
+}
+
+impl UserPreferences {
+    /// Correct, incorrect and too hard, or just correct and incorrect.
+    pub const GRADING_SCALES: [&'static str; 2] = ["three-point", "two-point"];
+    pub const QUEUE_STRATEGIES: [&'static str; 2] = ["spaced-repetition", "random"];
+    pub const THEMES: [&'static str; 3] = ["system", "light", "dark"];
+    pub const MAX_DAILY_LIMIT: i32 = 10_000;
+
+    pub async fn find(user_id: i64, db: &Pool) -> Result<Self, Error> {
+        let preferences = sqlx::query_as!(
+            Self,
+            "select timezone, grading_scale, daily_new_limit, daily_review_limit, queue_strategy,
+                email_digest, theme
+             from user_preferences where user_id = $1",
+            user_id,
+        )
+        .fetch_optional(db)
+        .await?;
+        Ok(preferences.unwrap_or_default())
+    }
+
+    pub async fn save(&self, user_id: i64, db: &Pool) -> Result<(), Error> {
+        sqlx::query!(
+            "insert into user_preferences
+                (user_id, timezone, grading_scale, daily_new_limit, daily_review_limit,
+                 queue_strategy, email_digest, theme)
+             values ($1, $2, $3, $4, $5, $6, $7, $8)
+             on conflict (user_id) do update set
+                timezone = excluded.timezone,
+                grading_scale = excluded.grading_scale,
+                daily_new_limit = excluded.daily_new_limit,
+                daily_review_limit = excluded.daily_review_limit,
+                queue_strategy = excluded.queue_strategy,
+                email_digest = excluded.email_digest,
+                theme = excluded.theme,
+                updated_at = now()",
+            user_id,
+            self.timezone,
+            self.grading_scale,
+            self.daily_new_limit,
+            self.daily_review_limit,
+            self.queue_strategy,
+            self.email_digest,
+            self.theme,
+        )
+        .execute(db)
+        .await?;
+        Ok(())
+    }
+
+    pub fn tz(&self) -> Tz {
+        self.timezone.parse().unwrap_or(Tz::UTC)
+    }
+
+    /// A time as it is on the user's clock.
+    pub fn local_time(&self, time: &DateTime) -> String {
+        time.0
+            .with_timezone(&self.tz())
+            .format("%Y-%m-%d %H:%M %Z")
+            .to_string()
+    }
+
+    pub fn is_two_point(&self) -> bool {
+        self.grading_scale == "two-point"
+    }
+}
+
+pub fn is_timezone(name: &str) -> bool {
+    name.parse::<Tz>().is_ok()
+}
+
+/// Loads the preferences of the logged-in user for each request, so that `CurrentPage` can make
+/// them available to templates.  Has to be wrapped inside the identity service.
+///
+/// Guests have nothing to load.  The preferences of a logged-in user are kept for a few minutes
+/// in a cookie, encrypted like the session cookie, so that most requests do not go to the
+/// database.  A handler that changes them puts the new ones in the request extensions, and the
+/// cookie is written again with those.
+pub struct LoadPreferences {
+    keys: Vec<Key>,
+}
+
+pub struct LoadPreferencesMiddleware<S> {
+    service: Rc<S>,
+    keys: Vec<Key>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedPreferences {
+    user_id: i64,
+    // Seconds since the epoch.
+    loaded_at: i64,
+    preferences: UserPreferences,
+}
+
+impl CachedPreferences {
+    // Changes made on another device show up once the cookie is this old.
+    const LIFETIME_SECONDS: i64 = 5 * 60;
+
+    fn is_current(&self, user_id: i64, now: i64) -> bool {
+        self.user_id == user_id && (0..Self::LIFETIME_SECONDS).contains(&(now - self.loaded_at))
+    }
+}
+
+impl LoadPreferences {
+    pub const COOKIE_NAME: &'static str = "preferences";
+
+    pub fn new(session_keys: &[impl AsRef<[u8]>]) -> Self {
+        assert!(
+            !session_keys.is_empty(),
+            "At least one session key is needed"
+        );
+        Self {
+            keys: session_keys
+                .iter()
+                .map(|key| Key::derive_from(key.as_ref()))
+                .collect(),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for LoadPreferences
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = LoadPreferencesMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(LoadPreferencesMiddleware {
+            service: Rc::new(service),
+            keys: self.keys.clone(),
+        }))
+    }
+}
+
+impl<S, B> Service<ServiceRequest> for LoadPreferencesMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let keys = self.keys.clone();
+
+        Box::pin(async move {
+            // A pending login or a guest has nothing stored.
+            let user_id = request
+                .get_identity()
+                .and_then(|identity| serde_json::from_str::<User>(&identity).ok())
+                .filter(|user| !user.is_anonymous)
+                .map(|user| user.id);
+            let state = request.app_data::<Data<AppState>>().cloned();
+            let (user_id, state) = match (user_id, state) {
+                (Some(user_id), Some(state)) => (user_id, state),
+                _ => return service.call(request).await,
+            };
+
+            let now = state.clock.now().to_chrono().timestamp();
+            let cached = request
+                .cookie(LoadPreferences::COOKIE_NAME)
+                .and_then(|cookie| open(&keys, cookie))
+                .filter(|cached| cached.is_current(user_id, now))
+                .map(|cached| cached.preferences);
+            let loaded = match &cached {
+                Some(preferences) => preferences.clone(),
+                None => UserPreferences::find(user_id, &state.db).await?,
+            };
+            request.extensions_mut().insert(loaded.clone());
+
+            let mut response = service.call(request).await?;
+            let preferences = response
+                .request()
+                .extensions()
+                .get::<UserPreferences>()
+                .cloned()
+                .unwrap_or(loaded);
+            if cached.as_ref() != Some(&preferences) {
+                let cached = CachedPreferences {
+                    user_id,
+                    loaded_at: now,
+                    preferences,
+                };
+                response
+                    .response_mut()
+                    .add_cookie(&seal(&keys[0], &cached)?)?;
+            }
+            Ok(response)
+        })
+    }
+}
+
+fn seal(key: &Key, cached: &CachedPreferences) -> Result<Cookie<'static>, Error> {
+    let mut jar = CookieJar::new();
+    jar.private_mut(key).add(
+        Cookie::build(LoadPreferences::COOKIE_NAME, serde_json::to_string(cached)?)
+            .path("/")
+            .same_site(SameSite::Strict)
+            .http_only(true)
+            .secure(true)
+            .finish(),
+    );
+    Ok(jar.get(LoadPreferences::COOKIE_NAME).unwrap().clone())
+}
+
+fn open(keys: &[Key], cookie: Cookie<'static>) -> Option<CachedPreferences> {
+    let mut jar = CookieJar::new();
+    jar.add_original(cookie);
+    let cookie = keys
+        .iter()
+        .find_map(|key| jar.private(key).get(LoadPreferences::COOKIE_NAME))?;
+    serde_json::from_str(cookie.value()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn local_time() {
+        let time = DateTime(chrono::Utc.with_ymd_and_hms(2021, 7, 1, 12, 30, 0).unwrap());
+        let preferences = UserPreferences {
+            timezone: "America/Los_Angeles".to_string(),
+            ..UserPreferences::default()
+        };
+        assert_eq!("2021-07-01 05:30 PDT", preferences.local_time(&time));
+        assert_eq!(
+            "2021-07-01 12:30 UTC",
+            UserPreferences::default().local_time(&time)
+        );
+    }
+
+    #[test]
+    fn cached_for_a_few_minutes() {
+        let cached = CachedPreferences {
+            user_id: 7,
+            loaded_at: 1_000,
+            preferences: UserPreferences::default(),
+        };
+        assert!(cached.is_current(7, 1_000 + 60));
+        assert!(!cached.is_current(8, 1_000 + 60));
+        assert!(!cached.is_current(7, 1_000 + 10 * 60));
+        assert!(!cached.is_current(7, 1_000 - 60));
+    }
+
+    #[test]
+    fn timezones() {
+        assert!(is_timezone("Europe/Berlin"));
+        assert!(is_timezone("UTC"));
+        assert!(!is_timezone("Mars/Olympus_Mons"));
+    }
+}
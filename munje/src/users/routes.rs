@@ -1,21 +1,54 @@
 use actix_identity::Identity;
-use actix_web::{get, post, web, web::Form};
+use actix_web::{
+    get,
+    http::header::LOCATION,
+    post, web,
+    web::{Data, Form},
+    HttpMessage,
+};
 use anyhow::Result;
 use askama::Template;
+use serde::{Deserialize, Serialize};
 
 use crate::{
+    audit,
     error::Error,
-    mutations::{AuthenticateUser, DestroyUserSession, RegisterUser},
+    forms::{strength, strength::Strength, TextField, Validate},
+    mutations::{
+        self, AuthenticateUser, ChangeUserHandle, ChangeUserPassword, ConfirmTwoFactorEnrollment,
+        CreateInvite, DestroyUser, DestroyUserSession, DisableTwoFactor, LinkIdentity, LoginStep,
+        RegisterUser, UpdatePreferences, VerifyTwoFactorLogin,
+    },
     prelude::*,
-    types::{CurrentPage, Message},
+    session,
+    types::{Clock, CurrentPage, Message},
+    users::{oidc, HashParams, Invite, InviteSummary, LoginAttempt},
 };
 
 pub fn register(cfg: &mut web::ServiceConfig) {
     cfg.service(signup)
         .service(create_user)
+        .service(password_strength)
         .service(login)
         .service(create_session)
-        .service(destroy_session);
+        .service(start_oidc_login)
+        .service(start_oidc_reauthentication)
+        .service(finish_oidc_login)
+        .service(verify_login)
+        .service(create_verified_session)
+        .service(destroy_session)
+        .service(settings)
+        .service(update_preferences)
+        .service(update_password)
+        .service(update_handle)
+        .service(destroy_user)
+        .service(link_identity)
+        .service(start_two_factor)
+        .service(confirm_two_factor)
+        .service(disable_two_factor)
+        .service(invites)
+        .service(create_invite)
+        .service(login_attempts);
 }
 
 #[derive(Template)]
@@ -23,19 +56,40 @@ pub fn register(cfg: &mut web::ServiceConfig) {
 struct Signup {
     messages: Vec<Message>,
     form: RegisterUser,
+    invite_only: bool,
+    oidc_provider: Option<String>,
     page: CurrentPage,
 }
 
+#[derive(Deserialize)]
+struct SignupQuery {
+    invite: Option<String>,
+}
+
+fn invite_only(request: &HttpRequest) -> bool {
+    request
+        .app_data::<Data<AppState>>()
+        .is_some_and(|state| state.invite_only)
+}
+
 #[get("/users/signup")]
-async fn signup(request: HttpRequest, id: Identity) -> Result<HttpResponse, Error> {
-    if id.identity().is_some() {
+async fn signup(
+    query: web::Query<SignupQuery>,
+    request: HttpRequest,
+    id: Identity,
+) -> Result<HttpResponse, Error> {
+    if auth::is_authenticated(&id) {
         return request.redirect_home();
     }
 
+    let mut form = RegisterUser::default();
+    form.invite_code = TextField::new(query.invite.as_deref().unwrap_or_default());
     let s = Signup {
         messages: Message::none(),
-        form: RegisterUser::default(),
-        page: CurrentPage::from("/users", auth::user_or_guest(&id)?),
+        form,
+        invite_only: invite_only(&request),
+        oidc_provider: oidc_provider(&request),
+        page: CurrentPage::new(&request, "/users", auth::user_or_guest(&id)?),
     }
     .render()
     .unwrap();
@@ -48,7 +102,7 @@ async fn create_user(
     request: HttpRequest,
     id: Identity,
 ) -> Result<HttpResponse, Error> {
-    if id.identity().is_some() {
+    if auth::is_authenticated(&id) {
         return request.redirect_home();
     }
 
@@ -57,7 +111,9 @@ async fn create_user(
         let s = Signup {
             messages: Message::none(),
             form: mutation,
-            page: CurrentPage::from("/users", auth::user_or_guest(&id)?),
+            invite_only: invite_only(&request),
+            oidc_provider: oidc_provider(&request),
+            page: CurrentPage::new(&request, "/users", auth::user_or_guest(&id)?),
         }
         .render()
         .unwrap();
@@ -65,8 +121,71 @@ async fn create_user(
     }
 
     let db = request.db()?;
-    mutation.call(&id, db).await?;
-    request.redirect_home()
+    let params = HashParams::from_request(&request);
+    let context = audit::Context::from_request(&request);
+    match mutation
+        .call(invite_only(&request), params, &context, &id, db)
+        .await
+    {
+        Ok(_) => request.redirect_home(),
+
+        Err(error @ (Error::HandleTaken | Error::InvalidInvite)) => {
+            if let Error::HandleTaken = error {
+                mutation
+                    .handle
+                    .errors
+                    .push("Username is already taken".to_string());
+            } else if mutation.invite_code.value.trim().is_empty() {
+                mutation
+                    .invite_code
+                    .errors
+                    .push("An invite code is needed to sign up".to_string());
+            } else {
+                mutation
+                    .invite_code
+                    .errors
+                    .push("Invite code is invalid or has expired".to_string());
+            }
+            let s = Signup {
+                messages: Message::none(),
+                form: mutation,
+                invite_only: invite_only(&request),
+                oidc_provider: oidc_provider(&request),
+                page: CurrentPage::new(&request, "/users", auth::user_or_guest(&id)?),
+            }
+            .render()
+            .unwrap();
+            Ok(HttpResponse::BadRequest().content_type("text/html").body(s))
+        }
+
+        Err(error) => Err(error),
+    }
+}
+
+#[derive(Deserialize)]
+struct PasswordStrengthQuery {
+    password: String,
+    #[serde(default)]
+    handle: String,
+}
+
+#[derive(Serialize)]
+struct PasswordStrength {
+    #[serde(flatten)]
+    strength: Strength,
+    label: &'static str,
+    color: &'static str,
+}
+
+// For the strength meter on the signup page, which updates as the password is typed.
+#[post("/users/password-strength")]
+async fn password_strength(form: Form<PasswordStrengthQuery>) -> Result<HttpResponse, Error> {
+    let strength = strength::estimate(&form.password, &[form.handle.trim()]);
+    Ok(HttpResponse::Ok().json(PasswordStrength {
+        label: strength.label(),
+        color: strength.color(),
+        strength,
+    }))
 }
 
 #[derive(Template)]
@@ -74,19 +193,23 @@ async fn create_user(
 struct Login {
     messages: Vec<Message>,
     form: AuthenticateUser,
+    locked_until: Option<DateTime>,
+    oidc_provider: Option<String>,
     page: CurrentPage,
 }
 
 #[get("/users/login")]
 async fn login(request: HttpRequest, id: Identity) -> Result<HttpResponse, Error> {
-    if id.identity().is_some() {
+    if auth::is_authenticated(&id) {
         return request.redirect_home();
     }
 
     let s = Login {
         messages: Message::none(),
         form: AuthenticateUser::default(),
-        page: CurrentPage::from("/users", auth::user_or_guest(&id)?),
+        locked_until: None,
+        oidc_provider: oidc_provider(&request),
+        page: CurrentPage::new(&request, "/users", auth::user_or_guest(&id)?),
     }
     .render()
     .unwrap();
@@ -100,7 +223,7 @@ async fn create_session(
     request: HttpRequest,
     id: Identity,
 ) -> Result<HttpResponse, Error> {
-    if id.identity().is_some() {
+    if auth::is_authenticated(&id) {
         return request.redirect_home();
     }
     let guest = auth::user_or_guest(&id)?;
@@ -110,7 +233,9 @@ async fn create_session(
         let s = Login {
             messages: Message::none(),
             form: mutation,
-            page: CurrentPage::from("/users", guest),
+            locked_until: None,
+            oidc_provider: oidc_provider(&request),
+            page: CurrentPage::new(&request, "/users", guest),
         }
         .render()
         .unwrap();
@@ -118,8 +243,34 @@ async fn create_session(
     }
 
     let db = request.db()?;
-    match mutation.call(&id, db).await {
-        Ok(()) => request.redirect_home(),
+    let params = HashParams::from_request(&request);
+    let context = audit::Context::from_request(&request);
+    match mutation.call(params, &context, &id, db).await {
+        Ok(LoginStep::Complete) => {
+            session::persist(&request, mutation.remember_me);
+            request.redirect_home()
+        }
+
+        // Remember me is carried by the pending login and applied at the second step.
+        Ok(LoginStep::SecondFactor) => {
+            session::persist(&request, false);
+            request.redirect("/users/login/verify")
+        }
+
+        Err(Error::TooManyLoginAttempts(locked_until)) => {
+            let s = Login {
+                messages: Message::none(),
+                form: mutation,
+                locked_until: Some(locked_until),
+                oidc_provider: oidc_provider(&request),
+                page: CurrentPage::new(&request, "/users", guest),
+            }
+            .render()
+            .unwrap();
+            Ok(HttpResponse::TooManyRequests()
+                .content_type("text/html")
+                .body(s))
+        }
 
         Err(Error::InvalidPassword) | Err(Error::Database(sqlx::Error::RowNotFound)) => {
             mutation
@@ -129,11 +280,239 @@ async fn create_session(
             let s = Login {
                 messages: Message::none(),
                 form: mutation,
-                page: CurrentPage::from("/users", guest),
+                locked_until: None,
+                oidc_provider: oidc_provider(&request),
+                page: CurrentPage::new(&request, "/users", guest),
+            }
+            .render()
+            .unwrap();
+            Ok(HttpResponse::BadRequest().content_type("text/html").body(s))
+        }
+
+        Err(error) => Err(error),
+    }
+}
+
+fn oidc_provider(request: &HttpRequest) -> Option<String> {
+    oidc::Client::from_request(request).map(|client| client.provider_name.clone())
+}
+
+// The login page is shown again with the message, and the login cookie is removed.
+fn oidc_login_failed(request: &HttpRequest, message: &str) -> Result<HttpResponse, Error> {
+    let s = Login {
+        messages: vec![Message::new(message, "danger")],
+        form: AuthenticateUser::default(),
+        locked_until: None,
+        oidc_provider: oidc_provider(request),
+        page: CurrentPage::new(request, "/users", User::guest()),
+    }
+    .render()
+    .unwrap();
+    let mut response = HttpResponse::BadRequest().content_type("text/html").body(s);
+    response
+        .add_removal_cookie(&oidc::Client::removal_cookie())
+        .map_err(actix_web::Error::from)?;
+    Ok(response)
+}
+
+// An invite code is passed along from the signup page, for when registration is closed.
+#[get("/users/auth/oidc")]
+async fn start_oidc_login(
+    query: web::Query<SignupQuery>,
+    request: HttpRequest,
+    id: Identity,
+) -> Result<HttpResponse, Error> {
+    if auth::is_authenticated(&id) {
+        return request.redirect_home();
+    }
+
+    let client = oidc::Client::from_request(&request).ok_or(Error::NotFound)?;
+    let purpose = oidc::Purpose::Login(query.into_inner().invite);
+    let (location, cookie) = client.start_login(purpose).await?;
+    Ok(HttpResponse::Found()
+        .append_header((LOCATION, location))
+        .cookie(cookie)
+        .finish())
+}
+
+#[get("/users/auth/oidc/reauthenticate")]
+async fn start_oidc_reauthentication(
+    request: HttpRequest,
+    id: Identity,
+) -> Result<HttpResponse, Error> {
+    let user = auth::user(&id)?;
+    let client = oidc::Client::from_request(&request).ok_or(Error::NotFound)?;
+    let (location, cookie) = client
+        .start_login(oidc::Purpose::Reauthenticate(user.id))
+        .await?;
+    Ok(HttpResponse::Found()
+        .append_header((LOCATION, location))
+        .cookie(cookie)
+        .finish())
+}
+
+#[get("/users/auth/oidc/callback")]
+async fn finish_oidc_login(
+    query: web::Query<oidc::Callback>,
+    request: HttpRequest,
+    id: Identity,
+) -> Result<HttpResponse, Error> {
+    let client = oidc::Client::from_request(&request).ok_or(Error::NotFound)?;
+
+    let (purpose, identity) = match client.finish_login(&query, &request).await {
+        Ok(finished) => finished,
+
+        Err(error @ Error::OidcError(_))
+        | Err(error @ Error::IdTokenError(_))
+        | Err(error @ Error::FetchPageError(_)) => {
+            warn!(
+                "Unable to log in with {}: {:?}",
+                client.provider_name, error
+            );
+            let message = format!("Unable to log in with {}", client.provider_name);
+            return oidc_login_failed(&request, &message);
+        }
+
+        Err(error) => return Err(error),
+    };
+
+    let db = request.db()?;
+    let context = audit::Context::from_request(&request);
+    let mut response = match purpose {
+        oidc::Purpose::Login(invite_code) => {
+            let invite_only = invite_only(&request);
+            match mutations::login_with_identity(
+                &identity,
+                invite_code.as_deref(),
+                invite_only,
+                &context,
+                &id,
+                db,
+            )
+            .await
+            {
+                Ok(_) => request.redirect_home()?,
+
+                Err(Error::InvalidInvite) if invite_code.is_none() => {
+                    return oidc_login_failed(&request, "An invite code is needed to sign up");
+                }
+
+                Err(Error::InvalidInvite) => {
+                    return oidc_login_failed(&request, "Invite code is invalid or has expired");
+                }
+
+                Err(error) => return Err(error),
+            }
+        }
+
+        oidc::Purpose::Reauthenticate(user_id) => {
+            mutations::reauthenticate_with_identity(user_id, &identity, &context, db).await?;
+            request.redirect("/users/settings")?
+        }
+
+        oidc::Purpose::Link(user_id) => {
+            mutations::link_identity(user_id, &identity, &context, db).await?;
+            request.redirect("/users/settings")?
+        }
+    };
+    response
+        .add_removal_cookie(&oidc::Client::removal_cookie())
+        .map_err(actix_web::Error::from)?;
+    Ok(response)
+}
+
+#[derive(Template)]
+#[template(path = "users/verify-login.jinja")]
+struct VerifyLogin {
+    messages: Vec<Message>,
+    form: VerifyTwoFactorLogin,
+    locked_until: Option<DateTime>,
+    page: CurrentPage,
+}
+
+#[get("/users/login/verify")]
+async fn verify_login(request: HttpRequest, id: Identity) -> Result<HttpResponse, Error> {
+    if auth::pending_login(&id).is_err() {
+        return request.redirect("/users/login");
+    }
+
+    let s = VerifyLogin {
+        messages: Message::none(),
+        form: VerifyTwoFactorLogin::default(),
+        locked_until: None,
+        page: CurrentPage::new(&request, "/users", User::guest()),
+    }
+    .render()
+    .unwrap();
+    Ok(HttpResponse::Ok().content_type("text/html").body(s))
+}
+
+#[post("/users/login/verify")]
+async fn create_verified_session(
+    form: Form<VerifyTwoFactorLogin>,
+    request: HttpRequest,
+    id: Identity,
+) -> Result<HttpResponse, Error> {
+    let pending = match auth::pending_login(&id) {
+        Ok(pending) => pending,
+        Err(_) => return request.redirect("/users/login"),
+    };
+
+    let mut mutation = form.into_inner();
+    if !mutation.validate() {
+        let s = VerifyLogin {
+            messages: Message::none(),
+            form: mutation,
+            locked_until: None,
+            page: CurrentPage::new(&request, "/users", User::guest()),
+        }
+        .render()
+        .unwrap();
+        return Ok(HttpResponse::BadRequest().content_type("text/html").body(s));
+    }
+
+    let db = request.db()?;
+    let context = audit::Context::from_request(&request);
+    match mutation
+        .call(
+            &pending,
+            Clock::from_request(&request).now(),
+            &context,
+            &id,
+            db,
+        )
+        .await
+    {
+        Ok(()) => {
+            session::persist(&request, pending.remember_me);
+            request.redirect_home()
+        }
+
+        Err(Error::TooManyLoginAttempts(locked_until)) => {
+            let s = VerifyLogin {
+                messages: Message::none(),
+                form: mutation,
+                locked_until: Some(locked_until),
+                page: CurrentPage::new(&request, "/users", User::guest()),
+            }
+            .render()
+            .unwrap();
+            Ok(HttpResponse::TooManyRequests()
+                .content_type("text/html")
+                .body(s))
+        }
+
+        Err(Error::InvalidCode) => {
+            mutation.code.errors.push("Code is invalid".to_string());
+            let s = VerifyLogin {
+                messages: Message::none(),
+                form: mutation,
+                locked_until: None,
+                page: CurrentPage::new(&request, "/users", User::guest()),
             }
             .render()
             .unwrap();
-            return Ok(HttpResponse::BadRequest().content_type("text/html").body(s));
+            Ok(HttpResponse::BadRequest().content_type("text/html").body(s))
         }
 
         Err(error) => Err(error),
@@ -142,6 +521,503 @@ async fn create_session(
 
 #[post("/users/logout")]
 async fn destroy_session(request: HttpRequest, id: Identity) -> Result<HttpResponse, Error> {
-    DestroyUserSession {}.call(&id).await?;
+    let context = audit::Context::from_request(&request);
+    DestroyUserSession {}
+        .call(&context, &id, request.db()?)
+        .await?;
     request.redirect("/")
 }
+
+#[derive(Template)]
+#[template(path = "users/settings.jinja")]
+struct Settings {
+    messages: Vec<Message>,
+    password_form: ChangeUserPassword,
+    handle_form: ChangeUserHandle,
+    delete_form: DestroyUser,
+    preferences_form: UpdatePreferences,
+    enrollment: Option<Enrollment>,
+    enrollment_form: ConfirmTwoFactorEnrollment,
+    disable_two_factor_form: DisableTwoFactor,
+    link_identity_form: LinkIdentity,
+    oidc_provider: Option<String>,
+    // An account without a password has logged in with the identity provider again recently.
+    reauthenticated: bool,
+    recovery_codes: Vec<String>,
+    page: CurrentPage,
+}
+
+struct Enrollment {
+    secret: String,
+    qr_code: String,
+}
+
+impl Settings {
+    fn new(request: &HttpRequest, user: User) -> Result<Self, Error> {
+        let enrollment = match user.totp() {
+            Some(totp) if !user.totp_enabled => Some(Enrollment {
+                secret: totp.to_base32(),
+                qr_code: totp.qr_code_svg(&user.handle)?,
+            }),
+            _ => None,
+        };
+
+        let reauthenticated = user.is_reauthenticated(DateTime::now());
+        let page = CurrentPage::new(request, "/users/settings", user);
+        Ok(Self {
+            messages: Message::none(),
+            password_form: ChangeUserPassword::default(),
+            handle_form: ChangeUserHandle::new(&page.user.handle),
+            delete_form: DestroyUser::default(),
+            preferences_form: UpdatePreferences::from(&page.preferences),
+            enrollment,
+            enrollment_form: ConfirmTwoFactorEnrollment::default(),
+            disable_two_factor_form: DisableTwoFactor::default(),
+            link_identity_form: LinkIdentity::default(),
+            oidc_provider: oidc_provider(request),
+            reauthenticated,
+            recovery_codes: Vec::new(),
+            page,
+        })
+    }
+}
+
+// Accounts without a password are confirmed by logging in with the identity provider again
+// instead.
+fn invalid_password(user: &User) -> String {
+    if user.has_password() {
+        "Password is invalid".to_string()
+    } else {
+        "Confirm that it is you first".to_string()
+    }
+}
+
+// The settings page shows two-factor state that is not kept in the identity cookie, so the user
+// is loaded from the database.
+async fn current_user(request: &HttpRequest, id: &Identity) -> Result<User, Error> {
+    let user = auth::user(id)?;
+    User::find_by_id(user.id, request.db()?).await
+}
+
+#[get("/users/settings")]
+async fn settings(request: HttpRequest, id: Identity) -> Result<HttpResponse, Error> {
+    let user = current_user(&request, &id).await?;
+    let s = Settings::new(&request, user)?.render().unwrap();
+    Ok(HttpResponse::Ok().content_type("text/html").body(s))
+}
+
+#[post("/users/settings/preferences")]
+async fn update_preferences(
+    form: Form<UpdatePreferences>,
+    request: HttpRequest,
+    id: Identity,
+) -> Result<HttpResponse, Error> {
+    let user = current_user(&request, &id).await?;
+
+    let mut mutation = form.into_inner();
+    if !mutation.validate() {
+        let s = Settings {
+            preferences_form: mutation,
+            ..Settings::new(&request, user)?
+        }
+        .render()
+        .unwrap();
+        return Ok(HttpResponse::BadRequest().content_type("text/html").body(s));
+    }
+
+    let context = audit::Context::from_request(&request);
+    let preferences = mutation.call(&user, &context, request.db()?).await?;
+
+    // The page is rendered with the new preferences rather than the ones loaded for the request,
+    // and `LoadPreferences` keeps them in its cookie.
+    request.extensions_mut().insert(preferences);
+    let s = Settings {
+        messages: vec![Message::new("Your preferences have been saved", "success")],
+        ..Settings::new(&request, user)?
+    }
+    .render()
+    .unwrap();
+    Ok(HttpResponse::Ok().content_type("text/html").body(s))
+}
+
+#[post("/users/settings/password")]
+async fn update_password(
+    form: Form<ChangeUserPassword>,
+    request: HttpRequest,
+    id: Identity,
+) -> Result<HttpResponse, Error> {
+    let user = current_user(&request, &id).await?;
+
+    let mut mutation = form.into_inner().for_user(&user);
+    if !mutation.validate() {
+        let s = Settings {
+            password_form: mutation,
+            ..Settings::new(&request, user)?
+        }
+        .render()
+        .unwrap();
+        return Ok(HttpResponse::BadRequest().content_type("text/html").body(s));
+    }
+
+    let params = HashParams::from_request(&request);
+    let context = audit::Context::from_request(&request);
+    let db = request.db()?;
+    match mutation.call(&user, params, &context, db).await {
+        Ok(()) => {
+            // An account that did not have a password has one now.
+            let user = User::find_by_id(user.id, db).await?;
+            let s = Settings {
+                messages: vec![Message::new("Your password has been changed", "success")],
+                ..Settings::new(&request, user)?
+            }
+            .render()
+            .unwrap();
+            Ok(HttpResponse::Ok().content_type("text/html").body(s))
+        }
+
+        Err(Error::InvalidPassword) => {
+            mutation
+                .current_password
+                .errors
+                .push(invalid_password(&user));
+            let s = Settings {
+                password_form: mutation,
+                ..Settings::new(&request, user)?
+            }
+            .render()
+            .unwrap();
+            Ok(HttpResponse::BadRequest().content_type("text/html").body(s))
+        }
+
+        Err(error) => Err(error),
+    }
+}
+
+#[post("/users/settings/handle")]
+async fn update_handle(
+    form: Form<ChangeUserHandle>,
+    request: HttpRequest,
+    id: Identity,
+) -> Result<HttpResponse, Error> {
+    let user = current_user(&request, &id).await?;
+
+    let mut mutation = form.into_inner();
+    if !mutation.validate() {
+        let s = Settings {
+            handle_form: mutation,
+            ..Settings::new(&request, user)?
+        }
+        .render()
+        .unwrap();
+        return Ok(HttpResponse::BadRequest().content_type("text/html").body(s));
+    }
+
+    let context = audit::Context::from_request(&request);
+    match mutation.call(&user, &context, &id, request.db()?).await {
+        Ok(user) => {
+            let s = Settings {
+                messages: vec![Message::new("Your username has been changed", "success")],
+                ..Settings::new(&request, user)?
+            }
+            .render()
+            .unwrap();
+            Ok(HttpResponse::Ok().content_type("text/html").body(s))
+        }
+
+        Err(Error::HandleTaken) => {
+            mutation
+                .handle
+                .errors
+                .push("Username is already taken".to_string());
+            let s = Settings {
+                handle_form: mutation,
+                ..Settings::new(&request, user)?
+            }
+            .render()
+            .unwrap();
+            Ok(HttpResponse::BadRequest().content_type("text/html").body(s))
+        }
+
+        Err(error) => Err(error),
+    }
+}
+
+#[post("/users/settings/delete")]
+async fn destroy_user(
+    form: Form<DestroyUser>,
+    request: HttpRequest,
+    id: Identity,
+) -> Result<HttpResponse, Error> {
+    let user = current_user(&request, &id).await?;
+
+    let mut mutation = form.into_inner().for_user(&user);
+    if !mutation.validate() {
+        let s = Settings {
+            delete_form: mutation,
+            ..Settings::new(&request, user)?
+        }
+        .render()
+        .unwrap();
+        return Ok(HttpResponse::BadRequest().content_type("text/html").body(s));
+    }
+
+    let context = audit::Context::from_request(&request);
+    match mutation.call(&user, &context, &id, request.db()?).await {
+        Ok(()) => request.redirect("/"),
+
+        Err(Error::InvalidPassword) => {
+            mutation.password.errors.push(invalid_password(&user));
+            let s = Settings {
+                delete_form: mutation,
+                ..Settings::new(&request, user)?
+            }
+            .render()
+            .unwrap();
+            Ok(HttpResponse::BadRequest().content_type("text/html").body(s))
+        }
+
+        Err(error) => Err(error),
+    }
+}
+
+#[post("/users/settings/identity")]
+async fn link_identity(
+    form: Form<LinkIdentity>,
+    request: HttpRequest,
+    id: Identity,
+) -> Result<HttpResponse, Error> {
+    let user = current_user(&request, &id).await?;
+    let client = oidc::Client::from_request(&request).ok_or(Error::NotFound)?;
+
+    let mut mutation = form.into_inner();
+    if !mutation.validate() {
+        let s = Settings {
+            link_identity_form: mutation,
+            ..Settings::new(&request, user)?
+        }
+        .render()
+        .unwrap();
+        return Ok(HttpResponse::BadRequest().content_type("text/html").body(s));
+    }
+
+    match mutation.call(&user, request.db()?).await {
+        Ok(()) => {
+            let (location, cookie) = client.start_login(oidc::Purpose::Link(user.id)).await?;
+            Ok(HttpResponse::Found()
+                .append_header((LOCATION, location))
+                .cookie(cookie)
+                .finish())
+        }
+
+        Err(Error::InvalidPassword) => {
+            mutation.password.errors.push(invalid_password(&user));
+            let s = Settings {
+                link_identity_form: mutation,
+                ..Settings::new(&request, user)?
+            }
+            .render()
+            .unwrap();
+            Ok(HttpResponse::BadRequest().content_type("text/html").body(s))
+        }
+
+        Err(error) => Err(error),
+    }
+}
+
+#[derive(Template)]
+#[template(path = "users/invites.jinja")]
+struct Invites {
+    messages: Vec<Message>,
+    form: CreateInvite,
+    invites: Vec<InviteSummary>,
+    page: CurrentPage,
+}
+
+#[get("/users/invites")]
+async fn invites(request: HttpRequest, id: Identity) -> Result<HttpResponse, Error> {
+    let user = auth::user(&id)?;
+    let invites = Invite::find_by_creator(&user, request.db()?).await?;
+
+    let s = Invites {
+        messages: Message::none(),
+        form: CreateInvite::default(),
+        invites,
+        page: CurrentPage::new(&request, "/users/invites", user),
+    }
+    .render()
+    .unwrap();
+    Ok(HttpResponse::Ok().content_type("text/html").body(s))
+}
+
+#[post("/users/invites")]
+async fn create_invite(
+    form: Form<CreateInvite>,
+    request: HttpRequest,
+    id: Identity,
+) -> Result<HttpResponse, Error> {
+    let user = auth::user(&id)?;
+    let db = request.db()?;
+
+    let mut mutation = form.into_inner();
+    if !mutation.validate() {
+        let s = Invites {
+            messages: Message::none(),
+            invites: Invite::find_by_creator(&user, db).await?,
+            form: mutation,
+            page: CurrentPage::new(&request, "/users/invites", user),
+        }
+        .render()
+        .unwrap();
+        return Ok(HttpResponse::BadRequest().content_type("text/html").body(s));
+    }
+
+    let context = audit::Context::from_request(&request);
+    mutation.call(&user, &context, db).await?;
+    request.redirect("/users/invites")
+}
+
+#[derive(Template)]
+#[template(path = "users/login-attempts.jinja")]
+struct LoginAttempts {
+    messages: Vec<Message>,
+    attempts: Vec<LoginAttempt>,
+    page: CurrentPage,
+}
+
+#[get("/admin/login-attempts")]
+async fn login_attempts(request: HttpRequest, id: Identity) -> Result<HttpResponse, Error> {
+    let db = request.db()?;
+    let user = auth::admin(&id, db).await?;
+    let attempts = LoginAttempt::find_failed(db).await?;
+
+    let s = LoginAttempts {
+        messages: Message::none(),
+        attempts,
+        page: CurrentPage::new(&request, "/admin/login-attempts", user),
+    }
+    .render()
+    .unwrap();
+    Ok(HttpResponse::Ok().content_type("text/html").body(s))
+}
+
+#[post("/users/settings/two-factor")]
+async fn start_two_factor(request: HttpRequest, id: Identity) -> Result<HttpResponse, Error> {
+    let user = current_user(&request, &id).await?;
+    if user.totp_enabled {
+        return request.redirect("/users/settings");
+    }
+
+    let db = request.db()?;
+    user.start_totp_enrollment(db).await?;
+    let user = User::find_by_id(user.id, db).await?;
+
+    let s = Settings::new(&request, user)?.render().unwrap();
+    Ok(HttpResponse::Ok().content_type("text/html").body(s))
+}
+
+#[post("/users/settings/two-factor/confirm")]
+async fn confirm_two_factor(
+    form: Form<ConfirmTwoFactorEnrollment>,
+    request: HttpRequest,
+    id: Identity,
+) -> Result<HttpResponse, Error> {
+    let user = current_user(&request, &id).await?;
+
+    let mut mutation = form.into_inner();
+    if !mutation.validate() {
+        let s = Settings {
+            enrollment_form: mutation,
+            ..Settings::new(&request, user)?
+        }
+        .render()
+        .unwrap();
+        return Ok(HttpResponse::BadRequest().content_type("text/html").body(s));
+    }
+
+    let db = request.db()?;
+    let context = audit::Context::from_request(&request);
+    let now = Clock::from_request(&request).now();
+    match mutation.call(&user, now, &context, db).await {
+        Ok(recovery_codes) => {
+            let user = User::find_by_id(user.id, db).await?;
+            let string = serde_json::to_string(&user)?;
+            id.remember(string);
+
+            let s = Settings {
+                messages: vec![Message::new(
+                    "Two-factor authentication has been turned on",
+                    "success",
+                )],
+                recovery_codes,
+                ..Settings::new(&request, user)?
+            }
+            .render()
+            .unwrap();
+            Ok(HttpResponse::Ok().content_type("text/html").body(s))
+        }
+
+        Err(Error::InvalidCode) => {
+            mutation.code.errors.push("Code is invalid".to_string());
+            let s = Settings {
+                enrollment_form: mutation,
+                ..Settings::new(&request, user)?
+            }
+            .render()
+            .unwrap();
+            Ok(HttpResponse::BadRequest().content_type("text/html").body(s))
+        }
+
+        Err(error) => Err(error),
+    }
+}
+
+#[post("/users/settings/two-factor/delete")]
+async fn disable_two_factor(
+    form: Form<DisableTwoFactor>,
+    request: HttpRequest,
+    id: Identity,
+) -> Result<HttpResponse, Error> {
+    let user = current_user(&request, &id).await?;
+
+    let mut mutation = form.into_inner().for_user(&user);
+    if !mutation.validate() {
+        let s = Settings {
+            disable_two_factor_form: mutation,
+            ..Settings::new(&request, user)?
+        }
+        .render()
+        .unwrap();
+        return Ok(HttpResponse::BadRequest().content_type("text/html").body(s));
+    }
+
+    let db = request.db()?;
+    let context = audit::Context::from_request(&request);
+    match mutation.call(&user, &context, &id, db).await {
+        Ok(()) => {
+            let user = User::find_by_id(user.id, db).await?;
+            let s = Settings {
+                messages: vec![Message::new(
+                    "Two-factor authentication has been turned off",
+                    "success",
+                )],
+                ..Settings::new(&request, user)?
+            }
+            .render()
+            .unwrap();
+            Ok(HttpResponse::Ok().content_type("text/html").body(s))
+        }
+
+        Err(Error::InvalidPassword) => {
+            mutation.password.errors.push(invalid_password(&user));
+            let s = Settings {
+                disable_two_factor_form: mutation,
+                ..Settings::new(&request, user)?
+            }
+            .render()
+            .unwrap();
+            Ok(HttpResponse::BadRequest().content_type("text/html").body(s))
+        }
+
+        Err(error) => Err(error),
+    }
+}
@@ -0,0 +1,152 @@
+use chrono;
+use std::convert::TryFrom;
+
+use crate::types::DateTime;
+
+/// Recent failed login attempts, either for a single account or from a single IP address.
+#[derive(Debug, Clone, Copy)]
+pub struct Failures {
+    pub count: i64,
+    pub last_failed_at: Option<DateTime>,
+}
+
+/// Login attempts are throttled according to the following rules:
+///
+/// 1. Each failed attempt doubles the time that must pass before the next attempt is accepted,
+///    starting at one second.
+///
+/// 2. Once `max_failures` attempts have failed within the window, further attempts are refused
+///    until the lockout period has passed since the last failure.
+///
+/// Accounts and IP addresses are tracked separately, so that guessing many passwords for one
+/// account and guessing a few passwords for many accounts are both slowed down.
+#[derive(Debug, Clone, Copy)]
+pub struct Throttle {
+    max_failures: i64,
+    lockout: chrono::Duration,
+}
+
+impl Throttle {
+    /// How far back failed attempts are counted.
+    pub fn window() -> chrono::Duration {
+        chrono::Duration::hours(1)
+    }
+
+    pub fn account() -> Self {
+        Self {
+            max_failures: 5,
+            lockout: chrono::Duration::minutes(15),
+        }
+    }
+
+    pub fn ip_address() -> Self {
+        Self {
+            max_failures: 20,
+            lockout: chrono::Duration::minutes(15),
+        }
+    }
+
+    /// The time before which another attempt will be refused.
+    pub fn next_attempt_at(&self, failures: &Failures) -> Option<DateTime> {
+        let last_failed_at = failures.last_failed_at?;
+        if failures.count < 1 {
+            return None;
+        }
+
+        if failures.count >= self.max_failures {
+            return Some(last_failed_at + self.lockout);
+        }
+
+        let exponent = u32::try_from(failures.count - 1).unwrap_or(0);
+        let delay = chrono::Duration::seconds(2_i64.pow(exponent));
+        Some(last_failed_at + delay)
+    }
+
+    pub fn locked_until(&self, failures: &Failures, now: DateTime) -> Option<DateTime> {
+        self.next_attempt_at(failures).filter(|at| *at > now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failures(count: i64, last_failed_at: DateTime) -> Failures {
+        Failures {
+            count,
+            last_failed_at: Some(last_failed_at),
+        }
+    }
+
+    #[test]
+    fn no_failures() {
+        let now = DateTime::now();
+        let failures = Failures {
+            count: 0,
+            last_failed_at: None,
+        };
+        assert_eq!(None, Throttle::account().locked_until(&failures, now));
+    }
+
+    #[test]
+    fn exponential_backoff() {
+        let now = DateTime::now();
+        let throttle = Throttle::account();
+
+        assert_eq!(
+            Some(now + chrono::Duration::seconds(1)),
+            throttle.locked_until(&failures(1, now), now)
+        );
+        assert_eq!(
+            Some(now + chrono::Duration::seconds(8)),
+            throttle.locked_until(&failures(4, now), now)
+        );
+    }
+
+    #[test]
+    fn backoff_expires() {
+        let now = DateTime::now();
+        let earlier = now + chrono::Duration::seconds(-10);
+        assert_eq!(
+            None,
+            Throttle::account().locked_until(&failures(4, earlier), now)
+        );
+    }
+
+    #[test]
+    fn lockout_after_max_failures() {
+        let now = DateTime::now();
+        let earlier = now + chrono::Duration::minutes(-10);
+        let throttle = Throttle::account();
+
+        assert_eq!(
+            Some(earlier + chrono::Duration::minutes(15)),
+            throttle.locked_until(&failures(5, earlier), now)
+        );
+    }
+
+    #[test]
+    fn lockout_expires() {
+        let now = DateTime::now();
+        let earlier = now + chrono::Duration::minutes(-20);
+        assert_eq!(
+            None,
+            Throttle::account().locked_until(&failures(5, earlier), now)
+        );
+    }
+
+    #[test]
+    fn ip_addresses_allow_more_failures() {
+        let now = DateTime::now();
+        let earlier = now + chrono::Duration::minutes(-1);
+
+        assert_eq!(
+            Some(earlier + chrono::Duration::minutes(15)),
+            Throttle::account().locked_until(&failures(5, earlier), now)
+        );
+        assert_eq!(
+            None,
+            Throttle::ip_address().locked_until(&failures(5, earlier), now)
+        );
+    }
+}
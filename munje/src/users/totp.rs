@@ -0,0 +1,171 @@
+use base32::Alphabet;
+use hmac::{Hmac, Mac};
+use qrcode::{render::svg, QrCode};
+use sha1::Sha1;
+use url::Url;
+
+use crate::{error::Error, types::DateTime};
+
+/// Time-based one-time passwords (RFC 6238), compatible with the authenticator apps that people
+/// already have on their phones.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Totp {
+    secret: Vec<u8>,
+}
+
+impl Totp {
+    const ALPHABET: Alphabet = Alphabet::Rfc4648 { padding: false };
+    const DIGITS: u32 = 6;
+    const ISSUER: &'static str = "Munje";
+    const STEP_SECONDS: i64 = 30;
+    // Codes from one step on either side of the current one are accepted, to allow for clock
+    // drift and for the time it takes to type the code in.
+    const SKEW: i64 = 1;
+
+    pub fn generate() -> Self {
+        use rand::Rng;
+        let secret: [u8; 20] = rand::thread_rng().gen();
+        Self {
+            secret: secret.to_vec(),
+        }
+    }
+
+    pub fn from_base32(string: &str) -> Option<Self> {
+        base32::decode(Self::ALPHABET, string).map(|secret| Self { secret })
+    }
+
+    pub fn to_base32(&self) -> String {
+        base32::encode(Self::ALPHABET, &self.secret)
+    }
+
+    pub fn step(now: DateTime) -> i64 {
+        now.to_chrono().timestamp() / Self::STEP_SECONDS
+    }
+
+    pub fn code_at(&self, now: DateTime) -> String {
+        self.code_for_step(Self::step(now))
+    }
+
+    /// Returns the step of the matching code, which should be saved and passed in as
+    /// `last_used_step` next time so that a code cannot be used twice.
+    pub fn verify(&self, code: &str, now: DateTime, last_used_step: Option<i64>) -> Option<i64> {
+        let code = code.trim();
+        let current = Self::step(now);
+        (current - Self::SKEW..=current + Self::SKEW)
+            .filter(|step| last_used_step.is_none_or(|last| *step > last))
+            .find(|step| self.code_for_step(*step) == code)
+    }
+
+    pub fn provisioning_uri(&self, handle: &str) -> String {
+        let mut url = Url::parse("otpauth://totp/").unwrap();
+        url.set_path(&format!("{}:{}", Self::ISSUER, handle));
+        url.query_pairs_mut()
+            .append_pair("secret", &self.to_base32())
+            .append_pair("issuer", Self::ISSUER)
+            .append_pair("digits", &Self::DIGITS.to_string())
+            .append_pair("period", &Self::STEP_SECONDS.to_string());
+        url.to_string()
+    }
+
+    pub fn qr_code_svg(&self, handle: &str) -> Result<String, Error> {
+        let code = QrCode::new(self.provisioning_uri(handle))
+            .map_err(|e| Error::Generic(format!("Unable to create QR code: {:?}", e)))?;
+        Ok(code.render::<svg::Color>().min_dimensions(200, 200).build())
+    }
+
+    fn code_for_step(&self, step: i64) -> String {
+        let mut mac = Hmac::<Sha1>::new_from_slice(&self.secret).expect("any key length is valid");
+        mac.update(&step.to_be_bytes());
+        let digest = mac.finalize().into_bytes();
+
+        let offset = (digest[digest.len() - 1] & 0xf) as usize;
+        let value = u32::from_be_bytes([
+            digest[offset] & 0x7f,
+            digest[offset + 1],
+            digest[offset + 2],
+            digest[offset + 3],
+        ]);
+
+        format!(
+            "{:0width$}",
+            value % 10_u32.pow(Self::DIGITS),
+            width = Self::DIGITS as usize
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The SHA-1 secret from the test vectors in RFC 6238.
+    fn rfc_totp() -> Totp {
+        Totp {
+            secret: b"12345678901234567890".to_vec(),
+        }
+    }
+
+    fn at(timestamp: i64) -> DateTime {
+        use chrono::TimeZone;
+        DateTime(chrono::Utc.timestamp_opt(timestamp, 0).unwrap())
+    }
+
+    #[test]
+    fn rfc_test_vectors() {
+        let totp = rfc_totp();
+        assert_eq!("287082", totp.code_at(at(59)));
+        assert_eq!("081804", totp.code_at(at(1111111109)));
+        assert_eq!("050471", totp.code_at(at(1111111111)));
+        assert_eq!("005924", totp.code_at(at(1234567890)));
+        assert_eq!("279037", totp.code_at(at(2000000000)));
+    }
+
+    #[test]
+    fn base32_round_trip() {
+        let totp = rfc_totp();
+        assert_eq!("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ", totp.to_base32());
+        assert_eq!(Some(totp.clone()), Totp::from_base32(&totp.to_base32()));
+        assert_eq!(None, Totp::from_base32("not base32!"));
+    }
+
+    #[test]
+    fn verify_current_code() {
+        let totp = rfc_totp();
+        let now = at(1111111109);
+        assert_eq!(Some(Totp::step(now)), totp.verify(" 081804 ", now, None));
+        assert_eq!(None, totp.verify("081805", now, None));
+    }
+
+    #[test]
+    fn verify_allows_clock_drift() {
+        let totp = rfc_totp();
+        let code = totp.code_at(at(1111111109));
+        assert!(totp.verify(&code, at(1111111109 + 30), None).is_some());
+        assert!(totp.verify(&code, at(1111111109 - 30), None).is_some());
+        assert!(totp.verify(&code, at(1111111109 + 90), None).is_none());
+    }
+
+    #[test]
+    fn verify_rejects_reused_code() {
+        let totp = rfc_totp();
+        let now = at(1111111109);
+        let step = totp.verify("081804", now, None);
+        assert!(step.is_some());
+        assert_eq!(None, totp.verify("081804", now, step));
+    }
+
+    #[test]
+    fn provisioning_uri() {
+        assert_eq!(
+            "otpauth://totp/Munje:gnusto?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ&issuer=Munje\
+             &digits=6&period=30",
+            rfc_totp().provisioning_uri("gnusto")
+        );
+    }
+
+    #[test]
+    fn qr_code_svg() {
+        let svg = rfc_totp().qr_code_svg("gnusto").unwrap();
+        assert!(svg.contains("<svg"));
+    }
+}
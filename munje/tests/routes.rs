@@ -1,14 +1,17 @@
 mod support;
 
-use actix_web::{http, test, web};
+use actix_web::{cookie::Cookie, http, test, web};
 use munje::{
+    error::Error,
     questions::{CreateQuestion, Question},
     queues::routes::AnswerQuestionForm,
-    queues::{CreateQueue, Queue},
+    queues::{AskedQuestion, CreateQueue, Queue},
+    types::DateTime,
+    users::{totp::Totp, User, UserPreferences},
 };
 use serde::Serialize;
 
-use crate::support::{Runner, TestResult};
+use crate::support::{HttpResult, MockOidcProvider, Runner, TestResult};
 
 #[actix_rt::test]
 async fn home_unauthenticated() -> TestResult {
@@ -92,6 +95,70 @@ async fn new_question() -> TestResult {
     Ok(())
 }
 
+#[actix_rt::test]
+async fn create_markdown_question() -> TestResult {
+    let runner = Runner::build().to_runner().await;
+    runner.reset_database().await?;
+    register_user(&runner, "frotz").await?;
+    let runner = Runner::build().auth_as("frotz").to_runner().await;
+
+    let form = web::Form([
+        ("title", "Molar mass"),
+        ("link", ""),
+        ("text", "What is the molar mass of **water**?"),
+    ]);
+    let req = test::TestRequest::post().uri("/questions").set_form(&form);
+    let res = runner.call(req).await;
+    assert_eq!(http::StatusCode::FOUND, res.status);
+
+    let question = Question::find_all(&runner.db)
+        .await?
+        .into_iter()
+        .find(|question| question.title == "Molar mass")
+        .unwrap();
+    assert_eq!(None, question.link);
+    assert_eq!(
+        "What is the molar mass of **water**?",
+        question.source_text()
+    );
+
+    let res = runner
+        .get(&format!("/questions/{}", question.external_id))
+        .await;
+    assert_eq!(
+        Some("water".to_string()),
+        res.doc.select_text(".markdown-body strong")
+    );
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn create_question_without_text_or_link() -> TestResult {
+    let runner = Runner::build().auth().to_runner().await;
+    let form = web::Form([("title", "Molar mass"), ("link", ""), ("text", " ")]);
+    let req = test::TestRequest::post().uri("/questions").set_form(&form);
+    let res = runner.call(req).await;
+
+    assert_eq!(http::StatusCode::BAD_REQUEST, res.status);
+    assert!(res.doc.css("form.new-question p.is-danger")?.exists());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn preview_markdown() -> TestResult {
+    let runner = Runner::build().auth().to_runner().await;
+    let form = web::Form([("text", "Balance *this*.\n\n<script>alert(1)</script>")]);
+    let req = test::TestRequest::post()
+        .uri("/questions/preview")
+        .set_form(&form);
+    let res = runner.call(req).await;
+
+    assert_eq!(http::StatusCode::OK, res.status);
+    assert_eq!(Some("this".to_string()), res.doc.select_text("em"));
+    assert!(res.doc.css("script")?.none());
+    Ok(())
+}
+
 #[actix_rt::test]
 async fn show_unknown_question() -> TestResult {
     let res = Runner::build()
@@ -112,8 +179,9 @@ async fn show_question() -> TestResult {
     let question = CreateQuestion {
         author_id: runner.user.id,
         title: "some-title".to_string(),
-        link: "some-link".to_string(),
+        link: Some("some-link".to_string()),
         link_logo: Some("logo-url".to_string()),
+        ..CreateQuestion::default()
     };
 
     let question = Question::create(question, &runner.db).await?;
@@ -132,14 +200,221 @@ async fn show_question() -> TestResult {
     Ok(())
 }
 
+fn edit_question_request(question: &Question, title: &str, text: &str) -> test::TestRequest {
+    let form = web::Form([
+        ("title", title),
+        ("link", "https://leetcode.com/problems/two-sum"),
+        ("text", text),
+    ]);
+    test::TestRequest::post()
+        .uri(&format!("/questions/{}/edit", question.external_id))
+        .set_form(&form)
+}
+
+#[actix_rt::test]
+async fn edit_question() -> TestResult {
+    let runner = Runner::build().to_runner().await;
+    runner.reset_database().await?;
+    let user = register_user(&runner, "frotz").await?;
+    let runner = Runner::build().auth_as("frotz").to_runner().await;
+    let question = Question::create(
+        CreateQuestion {
+            author_id: user.id,
+            title: "Two sum".to_string(),
+            link: Some("https://leetcode.com/problems/two-sum".to_string()),
+            link_logo: Some("logo-url".to_string()),
+            ..CreateQuestion::default()
+        },
+        &runner.db,
+    )
+    .await?;
+
+    let res = runner
+        .get(&format!("/questions/{}", question.external_id))
+        .await;
+    assert!(res.doc.css("a.edit-question")?.exists());
+
+    let res = runner
+        .call(edit_question_request(
+            &question,
+            "Two sum",
+            "Find two numbers.\nUse a map.",
+        ))
+        .await;
+    assert_eq!(http::StatusCode::FOUND, res.status);
+
+    let edited = Question::find(&question.external_id, &runner.db).await?;
+    assert_eq!(question.id, edited.id);
+    assert_eq!("Find two numbers.\nUse a map.", edited.source_text());
+    assert_eq!(Some("logo-url".to_string()), edited.link_logo);
+
+    let res = runner
+        .call(edit_question_request(
+            &question,
+            "Pairs",
+            "Find two numbers.\nUse a set.",
+        ))
+        .await;
+    assert_eq!(http::StatusCode::FOUND, res.status);
+
+    let res = runner
+        .get(&format!("/questions/{}/revisions/3", question.external_id))
+        .await;
+    assert_eq!(http::StatusCode::OK, res.status);
+    assert_eq!(
+        vec!["Use a map."],
+        res.doc
+            .select_texts("table.revision-diff tr.delete td:last-child")
+    );
+    assert_eq!(
+        vec!["Use a set."],
+        res.doc
+            .select_texts("table.revision-diff tr.insert td:last-child")
+    );
+    assert!(res.doc.css("p.title-change")?.exists());
+
+    let res = runner
+        .get(&format!("/questions/{}/revisions", question.external_id))
+        .await;
+    assert_eq!(3, res.doc.select_texts("table.revisions tbody tr").len());
+
+    let req = test::TestRequest::post().uri(&format!(
+        "/questions/{}/revisions/1/revert",
+        question.external_id
+    ));
+    let res = runner.call(req).await;
+    assert_eq!(http::StatusCode::FOUND, res.status);
+
+    let reverted = Question::find(&question.external_id, &runner.db).await?;
+    assert_eq!("Two sum", reverted.title);
+    assert_eq!(question.source_text(), reverted.source_text());
+    assert_eq!(4, reverted.revisions(&runner.db).await?.len());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn revert_question_help() -> TestResult {
+    let runner = Runner::build().to_runner().await;
+    runner.reset_database().await?;
+    let user = register_user(&runner, "frotz").await?;
+    register_user(&runner, "xyzzy").await?;
+    let author = Runner::build().auth_as("frotz").to_runner().await;
+    let question = Question::create(
+        CreateQuestion {
+            author_id: user.id,
+            title: "Two sum".to_string(),
+            kind: "link".to_string(),
+            text: "Find two numbers.".to_string(),
+            hints: vec!["Use a map".to_string()],
+            solution: Some("Look up the complement".to_string()),
+            ..CreateQuestion::default()
+        },
+        &runner.db,
+    )
+    .await?;
+
+    let form = web::Form([
+        ("title", "Two sum"),
+        ("link", ""),
+        ("text", "Find two numbers."),
+        ("hints", "Use a set\nSort first"),
+        ("solution", ""),
+    ]);
+    let req = test::TestRequest::post()
+        .uri(&format!("/questions/{}/edit", question.external_id))
+        .set_form(&form);
+    assert_eq!(http::StatusCode::FOUND, author.call(req).await.status);
+
+    let revision = format!("/questions/{}/revisions/2", question.external_id);
+    let res = author.get(&revision).await;
+    assert_eq!(
+        vec!["Use a map"],
+        res.doc
+            .select_texts("table.hints-diff tr.delete td:last-child")
+    );
+    assert_eq!(
+        vec!["Look up the complement"],
+        res.doc
+            .select_texts("table.solution-diff tr.delete td:last-child")
+    );
+
+    // Anyone else gets the help one step at a time while answering, where it is counted.
+    let other = Runner::build().auth_as("xyzzy").to_runner().await;
+    let res = other.get(&revision).await;
+    assert_eq!(http::StatusCode::OK, res.status);
+    assert!(!res.doc.css("table.hints-diff")?.exists());
+    let res = other
+        .get(&format!("/questions/{}", question.external_id))
+        .await;
+    assert!(!res.doc.css(".question-hints")?.exists());
+    let res = author
+        .get(&format!("/questions/{}", question.external_id))
+        .await;
+    assert!(res.doc.css(".question-hints")?.exists());
+
+    let req = test::TestRequest::post().uri(&format!(
+        "/questions/{}/revisions/1/revert",
+        question.external_id
+    ));
+    assert_eq!(http::StatusCode::FOUND, author.call(req).await.status);
+
+    let reverted = Question::find(&question.external_id, &runner.db).await?;
+    assert_eq!(vec!["Use a map".to_string()], reverted.source_hints());
+    assert_eq!("Look up the complement", reverted.source_solution());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn edit_question_requires_author() -> TestResult {
+    let runner = Runner::build().to_runner().await;
+    runner.reset_database().await?;
+    let user = register_user(&runner, "frotz").await?;
+    register_user(&runner, "xyzzy").await?;
+    let question = Question::create(
+        CreateQuestion {
+            author_id: user.id,
+            title: "Two sum".to_string(),
+            link: Some("https://leetcode.com/problems/two-sum".to_string()),
+            link_logo: None,
+            ..CreateQuestion::default()
+        },
+        &runner.db,
+    )
+    .await?;
+
+    let runner = Runner::build().auth_as("xyzzy").to_runner().await;
+    let res = runner
+        .get(&format!("/questions/{}/edit", question.external_id))
+        .await;
+    assert_eq!(http::StatusCode::UNAUTHORIZED, res.status);
+
+    let res = runner
+        .call(edit_question_request(&question, "Pairs", "Something else"))
+        .await;
+    assert_eq!(http::StatusCode::UNAUTHORIZED, res.status);
+
+    // Nor is the question shown back along with the errors in the form.
+    let res = runner.call(edit_question_request(&question, "", "")).await;
+    assert_eq!(http::StatusCode::UNAUTHORIZED, res.status);
+    assert!(res.doc.css("input[name='title']")?.none());
+    assert_eq!(
+        "Two sum",
+        Question::find(&question.external_id, &runner.db)
+            .await?
+            .title
+    );
+    Ok(())
+}
+
 #[actix_rt::test]
 async fn start_queue() -> TestResult {
     let runner = Runner::build().auth().to_runner().await;
     let question = CreateQuestion {
         author_id: runner.user.id,
         title: "some-title".to_string(),
-        link: "some-link".to_string(),
+        link: Some("some-link".to_string()),
         link_logo: Some("logo-url".to_string()),
+        ..CreateQuestion::default()
     };
 
     let question = Question::create(question, &runner.db).await?;
@@ -152,6 +427,132 @@ async fn start_queue() -> TestResult {
     Ok(())
 }
 
+#[actix_rt::test]
+async fn queues_are_private() -> TestResult {
+    let owner = runner_without_questions().await?;
+    let question = Question::create(
+        CreateQuestion {
+            author_id: owner.user.id,
+            title: "Binary search".to_string(),
+            text: "What is the running time of binary search?".to_string(),
+            ..CreateQuestion::default()
+        },
+        &owner.db,
+    )
+    .await?;
+    let res = owner
+        .call(test::TestRequest::post().uri(&format!("/questions/{}/queues", question.external_id)))
+        .await;
+    let path = res.location().unwrap();
+    assert_eq!(http::StatusCode::OK, owner.get(&path).await.status);
+    let action = owner
+        .get(&path)
+        .await
+        .doc
+        .select_attr("form.next-question", "action")
+        .unwrap();
+
+    let anonymous = Runner::build().to_runner().await;
+    register_user(&anonymous, "xyzzy").await?;
+    let other = Runner::build().auth_as("xyzzy").to_runner().await;
+    for runner in [&other, &anonymous] {
+        for path in [path.as_str(), "/frotz/queues"] {
+            assert_eq!(
+                http::StatusCode::UNAUTHORIZED,
+                runner.get(path).await.status,
+                "{}",
+                path
+            );
+        }
+    }
+
+    let form = web::Form([("state", "Correct")]);
+    let res = other
+        .call(test::TestRequest::post().uri(&action).set_form(&form))
+        .await;
+    assert_eq!(http::StatusCode::UNAUTHORIZED, res.status);
+    let answers: i64 = sqlx::query_scalar("select count(*) from answers")
+        .fetch_one(&owner.db)
+        .await?;
+    assert_eq!(0, answers);
+
+    owner.reset_database().await?;
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn guest_practice_claimed_on_signup() -> TestResult {
+    let runner = Runner::build().to_runner().await;
+    runner.reset_database().await?;
+    let author = User::find_by_handle("gnusto", &runner.db).await?;
+    let question = Question::create(
+        CreateQuestion {
+            author_id: author.id,
+            title: "some-title".to_string(),
+            link: Some("some-link".to_string()),
+            link_logo: None,
+            ..CreateQuestion::default()
+        },
+        &runner.db,
+    )
+    .await?;
+
+    let res = runner
+        .call(test::TestRequest::post().uri(&format!("/questions/{}/queues", question.external_id)))
+        .await;
+    assert_eq!(http::StatusCode::FOUND, res.status);
+    let queue_path = res.location().unwrap();
+    assert!(queue_path.starts_with("/guest-"), "{}", queue_path);
+    let cookie = res.cookie("auth-cookie").unwrap();
+
+    let req = test::TestRequest::get()
+        .uri(&queue_path)
+        .cookie(cookie.clone());
+    let res = runner.call(req).await;
+    assert_eq!(http::StatusCode::OK, res.status);
+    assert!(res.doc.css(".guest-practice")?.exists());
+
+    // A temporary user is not logged in as far as the rest of the site is concerned.
+    let req = test::TestRequest::get()
+        .uri("/users/settings")
+        .cookie(cookie.clone());
+    assert_eq!(
+        http::StatusCode::UNAUTHORIZED,
+        runner.call(req).await.status
+    );
+
+    let form = web::Form(AnswerQuestionForm {
+        state: "Correct".to_string(),
+        ..AnswerQuestionForm::default()
+    });
+    let req = test::TestRequest::post()
+        .uri(&format!(
+            "{}/questions/{}",
+            queue_path, question.external_id
+        ))
+        .cookie(cookie.clone())
+        .set_form(&form);
+    assert_eq!(http::StatusCode::FOUND, runner.call(req).await.status);
+
+    let res = runner
+        .call(simple_signup_request("frotz").cookie(cookie))
+        .await;
+    assert_eq!(http::StatusCode::FOUND, res.status);
+
+    let user = User::find_by_handle("frotz", &runner.db).await?;
+    assert_eq!(1, user.queues(&runner.db).await?.len());
+    let answers: i64 = sqlx::query_scalar("select count(*) from answers where user_id = $1")
+        .bind(user.id)
+        .fetch_one(&runner.db)
+        .await?;
+    assert_eq!(1, answers);
+    let temporary: i64 = sqlx::query_scalar("select count(*) from users where is_temporary")
+        .fetch_one(&runner.db)
+        .await?;
+    assert_eq!(0, temporary);
+    Ok(())
+}
+
 #[actix_rt::test]
 async fn show_queue() -> TestResult {
     let runner = Runner::build().auth().to_runner().await;
@@ -160,8 +561,9 @@ async fn show_queue() -> TestResult {
         CreateQuestion {
             author_id: runner.user.id,
             title: "some-title".to_string(),
-            link: "some-link".to_string(),
+            link: Some("some-link".to_string()),
             link_logo: Some("logo-url".to_string()),
+            ..CreateQuestion::default()
         },
         &runner.db,
     )
@@ -206,8 +608,9 @@ async fn answer_question() -> TestResult {
         CreateQuestion {
             author_id: runner.user.id,
             title: "some-title".to_string(),
-            link: "some-link".to_string(),
+            link: Some("some-link".to_string()),
             link_logo: Some("logo-url".to_string()),
+            ..CreateQuestion::default()
         },
         &runner.db,
     )
@@ -227,6 +630,7 @@ async fn answer_question() -> TestResult {
 
     let form = web::Form(AnswerQuestionForm {
         state: "Correct".to_string(),
+        ..AnswerQuestionForm::default()
     });
 
     let uri = format!(
@@ -241,77 +645,2845 @@ async fn answer_question() -> TestResult {
     Ok(())
 }
 
-#[actix_rt::test]
-async fn list_queues() -> TestResult {
-    let runner = Runner::build().auth().to_runner().await;
-    let path = format!("/{}/queues", runner.user.handle);
-    let res = runner.get(path.as_ref()).await;
-
-    assert_eq!(res.status, http::StatusCode::OK);
-    assert_eq!(
-        "Queues you are working on",
-        res.doc.select_text("h2").unwrap()
-    );
-    Ok(())
+// For tests in which a question has to be the only one for it to come up next in a queue.  The
+// question is written and answered by a user of its own, so that resetting the database at the
+// end of the test removes it and it does not come up in other tests.
+async fn runner_without_questions() -> Result<Runner, Error> {
+    let runner = Runner::build().to_runner().await;
+    runner.reset_database().await?;
+    sqlx::query("delete from queues")
+        .execute(&runner.db)
+        .await?;
+    sqlx::query("delete from questions")
+        .execute(&runner.db)
+        .await?;
+    register_user(&runner, "frotz").await?;
+    Ok(Runner::build().auth_as("frotz").to_runner().await)
 }
 
 #[actix_rt::test]
-async fn user_signup() -> TestResult {
-    let res = Runner::build().to_runner().await.get("/users/signup").await;
+async fn answer_flashcard() -> TestResult {
+    let runner = runner_without_questions().await?;
+
+    let question = Question::create(
+        CreateQuestion {
+            author_id: runner.user.id,
+            title: "Capitals".to_string(),
+            text: "What is the capital of Peru?".to_string(),
+            kind: "flashcard".to_string(),
+            back: Some("Lima".to_string()),
+            ..CreateQuestion::default()
+        },
+        &runner.db,
+    )
+    .await?;
+
+    let queue = Queue::find_or_create(
+        CreateQueue {
+            user_id: runner.user.id,
+            starting_question_external_id: question.external_id.clone(),
+            title: "Geography".to_string(),
+            description: "A queue".to_string(),
+        },
+        &runner.db,
+    )
+    .await?
+    .record;
+
+    // Only the front is shown at first.
+    let path = format!("/{}/queues/{}", runner.user.handle, queue.external_id);
+    let res = runner.get(&path).await;
+    assert_eq!(http::StatusCode::OK, res.status);
+    assert!(res.doc.css("form.reveal-answer")?.exists());
+    assert!(res.doc.css("form.next-question")?.none());
+    assert!(res.doc.css(".flashcard-back")?.none());
 
+    let shown_at: i64 = res
+        .doc
+        .select_attr("form.reveal-answer input[name=shown_at]", "value")
+        .unwrap()
+        .parse()
+        .unwrap();
+
+    // Revealing the back, as though the front had been shown five seconds earlier.
+    let res = runner
+        .get(&format!(
+            "{}?reveal={}&shown_at={}",
+            path,
+            question.external_id,
+            shown_at - 5_000
+        ))
+        .await;
     assert_eq!(http::StatusCode::OK, res.status);
+    assert!(res.doc.css("form.reveal-answer")?.none());
+    assert!(res
+        .doc
+        .select_text(".flashcard-back")
+        .unwrap()
+        .contains("Lima"));
+
+    let field = |name: &str| {
+        let selector = format!("form.next-question input[name={}]", name);
+        res.doc.select_attr(&selector, "value").unwrap()
+    };
+    let form = web::Form([
+        ("state", "Correct".to_string()),
+        ("shown_at", field("shown_at")),
+        ("revealed_at", field("revealed_at")),
+    ]);
+    let action = res.doc.select_attr("form.next-question", "action").unwrap();
+    let res = runner
+        .call(test::TestRequest::post().uri(&action).set_form(&form))
+        .await;
+    assert_eq!(http::StatusCode::FOUND, res.status);
+
+    let recall: f64 = sqlx::query_scalar(
+        "select extract(epoch from revealed_at - shown_at)::float8 from answers
+         where question_id = $1",
+    )
+    .bind(question.id)
+    .fetch_one(&runner.db)
+    .await?;
+    assert!(
+        (5.0..6.0).contains(&recall),
+        "Unexpected recall: {}",
+        recall
+    );
+
+    let res = runner.get(&path).await;
     assert_eq!(
-        Some("Sign up"),
+        vec!["5s"],
         res.doc
-            .css("input[type=submit]")?
-            .first()
-            .unwrap()
-            .value()
-            .attr("value")
+            .select_texts("td.recall-time span")
+            .iter()
+            .map(|text| text.trim())
+            .collect::<Vec<_>>()
     );
+
+    runner.reset_database().await?;
     Ok(())
 }
 
 #[actix_rt::test]
-async fn user_login() -> TestResult {
-    let res = Runner::build().to_runner().await.get("/users/login").await;
+async fn answer_multiple_choice() -> TestResult {
+    let runner = runner_without_questions().await?;
 
-    assert_eq!(http::StatusCode::OK, res.status);
+    let form = web::Form([
+        ("title", "Peru"),
+        ("kind", "multiple-choice"),
+        ("text", "Which of these cities are in Peru?"),
+        ("options", "* Lima\nQuito\n* Cusco"),
+        ("is_multi_select", "true"),
+        ("link", ""),
+    ]);
+    let res = runner
+        .call(test::TestRequest::post().uri("/questions").set_form(&form))
+        .await;
+    assert_eq!(http::StatusCode::FOUND, res.status);
+
+    let question = Question::find_all(&runner.db).await?.remove(0);
+    assert!(question.is_multiple_choice());
+    assert!(question.is_multi_select);
+    let options = question.options(&runner.db).await?;
     assert_eq!(
-        Some("Your username"),
-        res.doc
-            .css("input[type=text]")?
-            .first()
-            .unwrap()
-            .value()
-            .attr("placeholder")
+        vec![("Lima", true), ("Quito", false), ("Cusco", true)],
+        options
+            .iter()
+            .map(|option| (option.text.as_str(), option.is_correct))
+            .collect::<Vec<_>>()
     );
-    Ok(())
-}
 
-#[actix_rt::test]
-async fn create_user() -> TestResult {
-    let runner = Runner::build().to_runner().await;
-    runner.reset_database().await?;
+    let res = runner
+        .get(&format!("/questions/{}/export", question.external_id))
+        .await;
+    assert_eq!(http::StatusCode::OK, res.status);
+    let json = res.json();
+    assert_eq!("multiple-choice", json["kind"]);
 
-    #[derive(Serialize)]
-    struct SimpleSignupForm<'a> {
-        handle: &'a str,
-        password: &'a str,
-        password_confirmation: &'a str,
+    // The answers are in the export, so no one else can see it
+    for other in [
+        Runner::build().to_runner().await,
+        Runner::build().auth().to_runner().await,
+    ] {
+        let res = other
+            .get(&format!("/questions/{}/export", question.external_id))
+            .await;
+        assert_eq!(http::StatusCode::UNAUTHORIZED, res.status);
     }
 
-    let form = web::Form(SimpleSignupForm {
-        handle: "frotz",
-        password: "Password1",
-        password_confirmation: "Password1",
-    });
+    assert_eq!("Which of these cities are in Peru?", json["text"]);
+    assert_eq!(true, json["is_multi_select"]);
+    assert_eq!(
+        serde_json::json!({"text": "Quito", "is_correct": false}),
+        json["options"][1]
+    );
+
+    let queue = Queue::find_or_create(
+        CreateQueue {
+            user_id: runner.user.id,
+            starting_question_external_id: question.external_id.clone(),
+            title: "Geography".to_string(),
+            description: "A queue".to_string(),
+        },
+        &runner.db,
+    )
+    .await?
+    .record;
+
+    let path = format!("/{}/queues/{}", runner.user.handle, queue.external_id);
+    let res = runner.get(&path).await;
+    assert_eq!(http::StatusCode::OK, res.status);
+    assert_eq!(
+        3,
+        res.doc
+            .select_texts("form.multiple-choice input[name=option][type=checkbox]")
+            .len()
+    );
+    assert!(res.doc.css("form.next-question")?.none());
+
+    // The state is worked out from the options, whatever the form says.
+    let action = res
+        .doc
+        .select_attr("form.multiple-choice", "action")
+        .unwrap();
+    let answer = |chosen: Vec<i64>| {
+        let mut pairs = vec![("state".to_string(), "Correct".to_string())];
+        pairs.extend(
+            chosen
+                .iter()
+                .map(|id| ("option".to_string(), id.to_string())),
+        );
+        test::TestRequest::post().uri(&action).set_form(&pairs)
+    };
+    let res = runner.call(answer(vec![options[0].id])).await;
+    assert_eq!(http::StatusCode::FOUND, res.status);
+    let res = runner
+        .call(answer(vec![options[2].id, options[0].id]))
+        .await;
+    assert_eq!(http::StatusCode::FOUND, res.status);
+
+    let answers: Vec<(String, Vec<i64>)> = sqlx::query_as(
+        "select state, selected_options from answers where question_id = $1 order by id",
+    )
+    .bind(question.id)
+    .fetch_all(&runner.db)
+    .await?;
+    assert_eq!(
+        vec![
+            ("incorrect".to_string(), vec![options[0].id]),
+            ("correct".to_string(), vec![options[2].id, options[0].id]),
+        ],
+        answers
+    );
+
+    runner.reset_database().await?;
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn answer_numeric_question() -> TestResult {
+    let runner = runner_without_questions().await?;
+
+    let form = web::Form([
+        ("title", "Dilution"),
+        ("kind", "numeric"),
+        ("text", "How much water is needed?"),
+        ("number", "500"),
+        ("unit", "mL"),
+        ("tolerance", "0.01"),
+        ("tolerance_kind", "relative"),
+        ("significant_figures", ""),
+        ("link", ""),
+    ]);
+    let res = runner
+        .call(test::TestRequest::post().uri("/questions").set_form(&form))
+        .await;
+    assert_eq!(http::StatusCode::FOUND, res.status);
+    let invalid = web::Form(form.map(|(name, value)| match name {
+        "tolerance" => (name, "-1"),
+        _ => (name, value),
+    }));
+    let res = runner
+        .call(
+            test::TestRequest::post()
+                .uri("/questions")
+                .set_form(&invalid),
+        )
+        .await;
+    assert_eq!(http::StatusCode::BAD_REQUEST, res.status);
+    assert_eq!(
+        Some("Tolerance must be a number that is not negative".to_string()),
+        res.doc.select_text(".numeric-field .help.is-danger")
+    );
+
+    let question = Question::find_all(&runner.db).await?.remove(0);
+    assert!(question.is_numeric());
+    let json = runner
+        .get(&format!("/questions/{}/export", question.external_id))
+        .await
+        .json();
+    assert_eq!(500.0, json["answer"]["value"]);
+    assert_eq!("mL", json["answer"]["unit"]);
+    let queue = Queue::find_or_create(
+        CreateQueue {
+            user_id: runner.user.id,
+            starting_question_external_id: question.external_id.clone(),
+            title: "Chemistry".to_string(),
+            description: "A queue".to_string(),
+        },
+        &runner.db,
+    )
+    .await?
+    .record;
+
+    let path = format!("/{}/queues/{}", runner.user.handle, queue.external_id);
+    let res = runner.get(&path).await;
+    assert_eq!(
+        Some("Give the answer in mL.".to_string()),
+        res.doc.select_text(".numeric-instructions")
+    );
+    let action = res
+        .doc
+        .select_attr("form.numeric-answer", "action")
+        .unwrap();
+
+    for value in ["0.5 L", "5 L"] {
+        let form = web::Form([("value", value)]);
+        let res = runner
+            .call(test::TestRequest::post().uri(&action).set_form(&form))
+            .await;
+        assert_eq!(http::StatusCode::FOUND, res.status);
+    }
+
+    let answers: Vec<(String, String)> = sqlx::query_as(
+        "select state, submitted_value from answers where question_id = $1 order by id",
+    )
+    .bind(question.id)
+    .fetch_all(&runner.db)
+    .await?;
+    assert_eq!(
+        vec![
+            ("correct".to_string(), "0.5 L".to_string()),
+            ("incorrect".to_string(), "5 L".to_string()),
+        ],
+        answers
+    );
+
+    runner.reset_database().await?;
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn answer_with_hints() -> TestResult {
+    let runner = runner_without_questions().await?;
+
+    let form = web::Form([
+        ("title", "Dilution"),
+        ("kind", "numeric"),
+        ("text", "How many mL are there in half a liter?"),
+        ("number", "500"),
+        ("unit", "mL"),
+        ("tolerance", "0"),
+        ("tolerance_kind", "absolute"),
+        ("significant_figures", ""),
+        ("hints", "A liter is 1000 mL\n\nHalve it\n"),
+        ("solution", "1000 mL / 2 = 500 mL"),
+        ("link", ""),
+    ]);
+    let res = runner
+        .call(test::TestRequest::post().uri("/questions").set_form(&form))
+        .await;
+    assert_eq!(http::StatusCode::FOUND, res.status);
+
+    let question = Question::find_all(&runner.db).await?.remove(0);
+    assert_eq!(
+        vec!["A liter is 1000 mL".to_string(), "Halve it".to_string()],
+        question.source_hints()
+    );
+    let queue = Queue::find_or_create(
+        CreateQueue {
+            user_id: runner.user.id,
+            starting_question_external_id: question.external_id.clone(),
+            title: "Chemistry".to_string(),
+            description: "A queue".to_string(),
+        },
+        &runner.db,
+    )
+    .await?
+    .record;
+
+    let path = format!("/{}/queues/{}", runner.user.handle, queue.external_id);
+    let res = runner.get(&path).await;
+    assert!(res.doc.select_texts(".hint").is_empty());
+    assert_eq!(
+        Some("Show a hint".to_string()),
+        res.doc
+            .select_attr("form.show-help input[type=submit]", "value")
+    );
+    assert_eq!(
+        Some("1".to_string()),
+        res.doc
+            .select_attr("form.show-help input[name=hints]", "value")
+    );
+    let shown_at = res
+        .doc
+        .select_attr("form.show-help input[name=shown_at]", "value")
+        .unwrap();
+
+    let res = runner
+        .get(&format!(
+            "{}?question={}&hints=1&shown_at={}",
+            path, question.external_id, shown_at
+        ))
+        .await;
+    assert_eq!(
+        vec!["A liter is 1000 mL".to_string()],
+        res.doc.select_texts(".hint p")
+    );
+    assert!(!res
+        .doc
+        .css("form.numeric-answer input[name=hints]")?
+        .exists());
+    assert_eq!(
+        Some(shown_at.clone()),
+        res.doc
+            .select_attr("form.numeric-answer input[name=shown_at]", "value")
+    );
+
+    let res = runner
+        .get(&format!(
+            "{}?question={}&hints=2",
+            path, question.external_id
+        ))
+        .await;
+    assert_eq!(2, res.doc.select_texts(".hint p").len());
+    assert_eq!(
+        Some("Show the solution".to_string()),
+        res.doc
+            .select_attr("form.show-help input[type=submit]", "value")
+    );
+
+    let res = runner
+        .get(&format!(
+            "{}?question={}&hints=2&solution=true",
+            path, question.external_id
+        ))
+        .await;
+    assert_eq!(
+        Some("1000 mL / 2 = 500 mL".to_string()),
+        res.doc.select_text(".worked-solution p")
+    );
+    assert!(res.doc.select_text("form.show-help").is_none());
+    let action = res
+        .doc
+        .select_attr("form.numeric-answer", "action")
+        .unwrap();
+
+    // The help that has been shown is kept on the server, so reloading the page does not hide it.
+    let res = runner.get(&path).await;
+    assert_eq!(2, res.doc.select_texts(".hint p").len());
+    assert!(res.doc.select_text(".worked-solution p").is_some());
+
+    // What the page says about the help is not believed in either direction.
+    for form in [
+        vec![("value", "0.5 L"), ("hints", "0")],
+        vec![("value", "500 mL"), ("hints", "9"), ("solution", "true")],
+    ] {
+        let res = runner
+            .call(
+                test::TestRequest::post()
+                    .uri(&action)
+                    .set_form(web::Form(form)),
+            )
+            .await;
+        assert_eq!(http::StatusCode::FOUND, res.status);
+    }
+
+    let answers: Vec<(String, i32, bool)> = sqlx::query_as(
+        "select state, hints_revealed, solution_revealed from answers
+         where question_id = $1 order by id",
+    )
+    .bind(question.id)
+    .fetch_all(&runner.db)
+    .await?;
+    assert_eq!(
+        vec![
+            ("correct".to_string(), 2, true),
+            ("correct".to_string(), 0, false),
+        ],
+        answers
+    );
+
+    let res = runner.get(&path).await;
+    assert_eq!(
+        vec!["solution".to_string()],
+        res.doc.select_texts(".help-used")
+    );
+
+    runner.reset_database().await?;
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn drafts_are_private() -> TestResult {
+    let runner = runner_without_questions().await?;
+
+    let form = web::Form([
+        ("title", "Binary search"),
+        ("text", "What is the running time of binary search?"),
+        ("link", ""),
+        ("draft", "true"),
+    ]);
+    let res = runner
+        .call(test::TestRequest::post().uri("/questions").set_form(&form))
+        .await;
+    let path = res.location().unwrap();
+    let pages = [
+        path.clone(),
+        format!("{}/revisions", path),
+        format!("{}/revisions/1", path),
+    ];
+
+    let guest = Runner::build().to_runner().await;
+    for page in &pages {
+        assert_eq!(
+            http::StatusCode::OK,
+            runner.get(page).await.status,
+            "{}",
+            page
+        );
+        assert_eq!(
+            http::StatusCode::NOT_FOUND,
+            guest.get(page).await.status,
+            "{}",
+            page
+        );
+    }
+
+    // Not even the author sees a question once it is deleted
+    sqlx::query("update questions set status = 'deleted'")
+        .execute(&runner.db)
+        .await?;
+    for page in &pages {
+        assert_eq!(
+            http::StatusCode::NOT_FOUND,
+            runner.get(page).await.status,
+            "{}",
+            page
+        );
+    }
+
+    runner.reset_database().await?;
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn question_lifecycle() -> TestResult {
+    let runner = runner_without_questions().await?;
+
+    let form = web::Form([
+        ("title", "Binary search"),
+        ("text", "What is the running time of binary search?"),
+        ("link", ""),
+        ("draft", "true"),
+    ]);
+    let res = runner
+        .call(test::TestRequest::post().uri("/questions").set_form(&form))
+        .await;
+    assert_eq!(http::StatusCode::FOUND, res.status);
+    let path = res.location().unwrap();
+
+    // Drafts are shown to their author, but are not listed or put in queues.
+    assert!(Question::find_all(&runner.db).await?.is_empty());
+    let res = runner.get(&path).await;
+    assert_eq!(
+        Some("Draft".to_string()),
+        res.doc.select_text(".question-status")
+    );
+    assert!(res.doc.css("button.start-queue")?.none());
+    let res = runner
+        .call(test::TestRequest::post().uri(&format!("{}/queues", path)))
+        .await;
+    assert_eq!(http::StatusCode::NOT_FOUND, res.status);
+
+    let res = runner
+        .call(test::TestRequest::post().uri(&format!("{}/publish", path)))
+        .await;
+    assert_eq!(http::StatusCode::FOUND, res.status);
+    let question = Question::find_all(&runner.db).await?.remove(0);
+    assert!(question.is_published());
+    assert!(runner
+        .get("/questions")
+        .await
+        .doc
+        .css(&format!("a[href='{}']", path))?
+        .exists());
+
+    let res = runner
+        .call(test::TestRequest::post().uri(&format!("{}/queues", path)))
+        .await;
+    let queue_path = res.location().unwrap();
+    let action = runner
+        .get(&queue_path)
+        .await
+        .doc
+        .select_attr("form.next-question", "action")
+        .unwrap();
+    let form = web::Form([("state", "Correct")]);
+    let res = runner
+        .call(test::TestRequest::post().uri(&action).set_form(&form))
+        .await;
+    assert_eq!(http::StatusCode::FOUND, res.status);
+
+    // A question that has been answered is archived rather than deleted.
+    let res = runner
+        .call(test::TestRequest::post().uri(&format!("{}/delete", path)))
+        .await;
+    assert_eq!(http::StatusCode::FOUND, res.status);
+    assert!(Question::find_all(&runner.db).await?.is_empty());
+    let question = Question::find(&question.external_id, &runner.db).await?;
+    assert!(question.is_archived());
+    assert_eq!(
+        Some("Archived".to_string()),
+        runner.get(&path).await.doc.select_text(".question-status")
+    );
+    assert_eq!(
+        Some("No questions at this time".to_string()),
+        runner
+            .get(&queue_path)
+            .await
+            .doc
+            .select_text(".card-header-title")
+    );
+
+    // One that a queue starts from is kept, but can no longer be found.
+    let queued = Question::create(
+        CreateQuestion {
+            author_id: runner.user.id,
+            title: "Merge sort".to_string(),
+            text: "What is the running time of merge sort?".to_string(),
+            ..CreateQuestion::default()
+        },
+        &runner.db,
+    )
+    .await?;
+    runner
+        .call(test::TestRequest::post().uri(&format!("/questions/{}/queues", queued.external_id)))
+        .await;
+    // And one that nothing points at is removed.
+    let unused = Question::create(
+        CreateQuestion {
+            author_id: runner.user.id,
+            title: "Quicksort".to_string(),
+            text: "What is the running time of quicksort?".to_string(),
+            ..CreateQuestion::default()
+        },
+        &runner.db,
+    )
+    .await?;
+    for question in [&queued, &unused] {
+        let res = runner
+            .call(
+                test::TestRequest::post()
+                    .uri(&format!("/questions/{}/delete", question.external_id)),
+            )
+            .await;
+        assert_eq!(http::StatusCode::FOUND, res.status);
+        let res = runner
+            .get(&format!("/questions/{}", question.external_id))
+            .await;
+        assert_eq!(http::StatusCode::NOT_FOUND, res.status);
+    }
+
+    let statuses: Vec<(String, String)> =
+        sqlx::query_as("select title, status from questions order by id")
+            .fetch_all(&runner.db)
+            .await?;
+    assert_eq!(
+        vec![
+            ("Binary search".to_string(), "archived".to_string()),
+            ("Merge sort".to_string(), "deleted".to_string()),
+        ],
+        statuses
+    );
+
+    runner.reset_database().await?;
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn queues_only_ask_published_questions() -> TestResult {
+    let runner = runner_without_questions().await?;
+    let author = register_user(&Runner::build().to_runner().await, "xyzzy").await?;
+
+    let question = Question::create(
+        CreateQuestion {
+            author_id: runner.user.id,
+            title: "Merge sort".to_string(),
+            text: "What is the running time of merge sort?".to_string(),
+            ..CreateQuestion::default()
+        },
+        &runner.db,
+    )
+    .await?;
+    let res = runner
+        .call(test::TestRequest::post().uri(&format!("/questions/{}/queues", question.external_id)))
+        .await;
+    let queue_path = res.location().unwrap();
+
+    for (title, status) in [("Binary search", "draft"), ("Quicksort", "archived")] {
+        let hidden = Question::create(
+            CreateQuestion {
+                author_id: author.id,
+                title: title.to_string(),
+                text: "The answer is n log n".to_string(),
+                ..CreateQuestion::default()
+            },
+            &runner.db,
+        )
+        .await?;
+        sqlx::query("update questions set status = $1 where id = $2")
+            .bind(status)
+            .bind(hidden.id)
+            .execute(&runner.db)
+            .await?;
+
+        for param in ["reveal", "question"] {
+            let res = runner
+                .get(&format!("{}?{}={}", queue_path, param, hidden.external_id))
+                .await;
+            assert_eq!(http::StatusCode::NOT_FOUND, res.status, "{}", status);
+        }
+
+        let form = web::Form([("state", "Correct")]);
+        let req = test::TestRequest::post()
+            .uri(&format!("{}/questions/{}", queue_path, hidden.external_id))
+            .set_form(&form);
+        assert_eq!(
+            http::StatusCode::NOT_FOUND,
+            runner.call(req).await.status,
+            "{}",
+            status
+        );
+    }
+
+    let (count,): (i64,) = sqlx::query_as("select count(*) from asked_questions a join questions q on q.id = a.question_id where q.status <> 'published'")
+        .fetch_one(&runner.db)
+        .await?;
+    assert_eq!(0, count);
+    let (count,): (i64,) = sqlx::query_as("select count(*) from answers")
+        .fetch_one(&runner.db)
+        .await?;
+    assert_eq!(0, count);
+
+    runner.reset_database().await?;
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn edit_template_question() -> TestResult {
+    let runner = runner_without_questions().await?;
+
+    let form = web::Form([
+        ("title", "Area of a square"),
+        ("kind", "template"),
+        (
+            "text",
+            "A square has sides of {{ a }} m.  What is its area?",
+        ),
+        ("variables", "a = 2..9"),
+        ("answer_expression", "a^2"),
+        ("link", ""),
+    ]);
+    let res = runner
+        .call(test::TestRequest::post().uri("/questions").set_form(&form))
+        .await;
+    assert_eq!(http::StatusCode::FOUND, res.status);
+    let question = Question::find_all(&runner.db).await?.remove(0);
+
+    let res = runner
+        .call(edit_question_request(
+            &question,
+            "Area of a square",
+            "A square has sides of {{ b }} m.  What is its area?",
+        ))
+        .await;
+    assert_eq!(http::StatusCode::BAD_REQUEST, res.status);
+    assert_eq!(
+        Some("Unknown variable \"b\"".to_string()),
+        res.doc.select_text(".help.is-danger")
+    );
+    let res = runner
+        .call(edit_question_request(
+            &question,
+            "Area of a square",
+            "A square has sides of {{ a }} cm.  What is its area?",
+        ))
+        .await;
+    assert_eq!(http::StatusCode::FOUND, res.status);
+
+    // A revision that no longer goes with the variables is not put back.
+    sqlx::query("update question_templates set variables = 'side = 2..9', answer = 'side^2'")
+        .execute(&runner.db)
+        .await?;
+    sqlx::query("update questions set text = replace(text, '{{ a }}', '{{ side }}')")
+        .execute(&runner.db)
+        .await?;
+    let req = test::TestRequest::post().uri(&format!(
+        "/questions/{}/revisions/1/revert",
+        question.external_id
+    ));
+    let res = runner.call(req).await;
+    assert_eq!(http::StatusCode::BAD_REQUEST, res.status);
+    assert_eq!(
+        2,
+        Question::find(&question.external_id, &runner.db)
+            .await?
+            .revisions(&runner.db)
+            .await?
+            .len()
+    );
+
+    runner.reset_database().await?;
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn answer_template_question() -> TestResult {
+    let runner = runner_without_questions().await?;
+
+    let form = web::Form([
+        ("title", "Area of a square"),
+        ("kind", "template"),
+        (
+            "text",
+            "A square has sides of {{ a }} m.  What is its area?",
+        ),
+        ("variables", "a = 2..9"),
+        ("answer_expression", "a^2"),
+        ("link", ""),
+    ]);
+    let invalid = web::Form(form.map(|(name, value)| match name {
+        "answer_expression" => (name, "b^2"),
+        _ => (name, value),
+    }));
+    let res = runner
+        .call(
+            test::TestRequest::post()
+                .uri("/questions")
+                .set_form(&invalid),
+        )
+        .await;
+    assert_eq!(http::StatusCode::BAD_REQUEST, res.status);
+    assert_eq!(
+        Some("Unknown variable \"b\"".to_string()),
+        res.doc.select_text(".template-field .help.is-danger")
+    );
+    let res = runner
+        .call(test::TestRequest::post().uri("/questions").set_form(&form))
+        .await;
+    assert_eq!(http::StatusCode::FOUND, res.status);
+
+    let question = Question::find_all(&runner.db).await?.remove(0);
+    assert!(question.is_template());
+    let queue = Queue::find_or_create(
+        CreateQueue {
+            user_id: runner.user.id,
+            starting_question_external_id: question.external_id.clone(),
+            title: "Geometry".to_string(),
+            description: "A queue".to_string(),
+        },
+        &runner.db,
+    )
+    .await?
+    .record;
+
+    let path = format!("/{}/queues/{}", runner.user.handle, queue.external_id);
+    let res = runner.get(&path).await;
+    let shown = res
+        .doc
+        .select_text(".card-content .markdown-body p")
+        .unwrap();
+    assert!(!shown.contains("{{"));
+    let action = res
+        .doc
+        .select_attr("form.numeric-answer", "action")
+        .unwrap();
+    // The variant is kept on the server rather than in the page.
+    assert!(!res.doc.css("input[name=seed]")?.exists());
+    let seed = AskedQuestion::find(queue.id, question.id, 0, &runner.db)
+        .await?
+        .unwrap()
+        .seed
+        .unwrap();
+    let variant = question.variant(seed, &runner.db).await?;
+    assert_eq!(shown.trim(), variant.text.source());
+
+    // Reloading the page asks about the same variant.
+    let res = runner.get(&path).await;
+    assert_eq!(
+        shown,
+        res.doc
+            .select_text(".card-content .markdown-body p")
+            .unwrap()
+    );
+
+    // A seed sent back from the page, for a variant whose answer is known, is ignored.
+    let other = question.variant(seed.wrapping_add(1), &runner.db).await?;
+    let value = format!("{}", variant.answer.value);
+    let other_seed = other.seed.to_string();
+    let form = web::Form([("value", value.as_str()), ("seed", other_seed.as_str())]);
+    let res = runner
+        .call(test::TestRequest::post().uri(&action).set_form(&form))
+        .await;
+    assert_eq!(http::StatusCode::FOUND, res.status);
+
+    let answer = queue.answers(&runner.db).await?.remove(0);
+    assert_eq!("correct", answer.state);
+    assert_eq!(Some(value), answer.submitted_value);
+    assert_eq!(Some(seed), answer.seed);
+    let reconstructed = answer.variant(&runner.db).await?.unwrap();
+    assert_eq!(variant.text.source(), reconstructed.text.source());
+
+    // Once answered, the question is asked afresh.
+    assert!(AskedQuestion::find(queue.id, question.id, 0, &runner.db)
+        .await?
+        .is_none());
+
+    runner.reset_database().await?;
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn answer_cloze_question() -> TestResult {
+    let runner = runner_without_questions().await?;
+
+    let form = web::Form([
+        ("title", "Peru"),
+        ("kind", "cloze"),
+        (
+            "text",
+            "{{c1::Lima}} is the capital of {{c2::Peru::a country}}.",
+        ),
+        ("link", ""),
+    ]);
+    let invalid = web::Form(form.map(|(name, value)| match name {
+        "text" => (name, "Lima is the capital of Peru."),
+        _ => (name, value),
+    }));
+    let res = runner
+        .call(
+            test::TestRequest::post()
+                .uri("/questions")
+                .set_form(&invalid),
+        )
+        .await;
+    assert_eq!(http::StatusCode::BAD_REQUEST, res.status);
+    assert_eq!(
+        Some("Mark a part of the text to leave out, as in {{c1::Lima}}".to_string()),
+        res.doc.select_text(".markdown-field .help.is-danger")
+    );
+    let res = runner
+        .call(test::TestRequest::post().uri("/questions").set_form(&form))
+        .await;
+    assert_eq!(http::StatusCode::FOUND, res.status);
+
+    let question = Question::find_all(&runner.db).await?.remove(0);
+    assert_eq!(vec![1, 2], question.cloze_numbers);
+    let queue = Queue::find_or_create(
+        CreateQueue {
+            user_id: runner.user.id,
+            starting_question_external_id: question.external_id.clone(),
+            title: "Geography".to_string(),
+            description: "A queue".to_string(),
+        },
+        &runner.db,
+    )
+    .await?
+    .record;
+
+    // Each deletion is asked about once, and the one that has been answered waits its turn.
+    let path = format!("/{}/queues/{}", runner.user.handle, queue.external_id);
+    let mut answered = vec![];
+    for _ in 0..2 {
+        let res = runner.get(&path).await;
+        let text = res
+            .doc
+            .select_text(".card-content .markdown-body p")
+            .unwrap();
+        assert!(text.contains("<strong>[") && !text.contains("{{"));
+        let cloze = res
+            .doc
+            .select_attr("form.reveal-answer input[name=cloze]", "value")
+            .unwrap();
+        assert!(!answered.contains(&cloze));
+
+        let res = runner
+            .get(&format!(
+                "{}?reveal={}&cloze={}",
+                path, question.external_id, cloze
+            ))
+            .await;
+        let text = res
+            .doc
+            .select_text(".card-content .markdown-body p")
+            .unwrap();
+        assert!(!text.contains("<strong>["));
+        let form = web::Form([("state", "Correct".to_string()), ("cloze", cloze.clone())]);
+        let action = res.doc.select_attr("form.next-question", "action").unwrap();
+        let res = runner
+            .call(test::TestRequest::post().uri(&action).set_form(&form))
+            .await;
+        assert_eq!(http::StatusCode::FOUND, res.status);
+        answered.push(cloze);
+    }
+
+    let clozes: Vec<i32> =
+        sqlx::query_scalar("select cloze from last_answers where question_id = $1 order by cloze")
+            .bind(question.id)
+            .fetch_all(&runner.db)
+            .await?;
+    assert_eq!(vec![1, 2], clozes);
+    let res = runner.get(&path).await;
+    assert_eq!(
+        vec!["c2".to_string(), "c1".to_string()],
+        res.doc.select_texts(".cloze-number")
+    );
+
+    // There is no third deletion to answer or to reveal.
+    let res = runner
+        .get(&format!("{}?reveal={}&cloze=3", path, question.external_id))
+        .await;
+    assert_eq!(http::StatusCode::NOT_FOUND, res.status);
+    let action = format!("{}/questions/{}", path, question.external_id);
+    let form = web::Form([("state", "Correct"), ("cloze", "3")]);
+    let res = runner
+        .call(test::TestRequest::post().uri(&action).set_form(&form))
+        .await;
+    assert_eq!(http::StatusCode::NOT_FOUND, res.status);
+    assert_eq!(2, queue.answers(&runner.db).await?.len());
+
+    runner.reset_database().await?;
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn create_flashcard_without_back() -> TestResult {
+    let runner = Runner::build().auth().to_runner().await;
+    let form = web::Form([
+        ("title", "Capitals"),
+        ("kind", "flashcard"),
+        ("text", "What is the capital of Peru?"),
+        ("back", ""),
+        ("link", ""),
+    ]);
+    let res = runner
+        .call(test::TestRequest::post().uri("/questions").set_form(&form))
+        .await;
+    assert_eq!(http::StatusCode::BAD_REQUEST, res.status);
+    assert_eq!(
+        Some("Give the answer for the back of the card".to_string()),
+        res.doc.select_text(".back-field .help.is-danger")
+    );
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn list_queues() -> TestResult {
+    let runner = Runner::build().auth().to_runner().await;
+    let path = format!("/{}/queues", runner.user.handle);
+    let res = runner.get(path.as_ref()).await;
+
+    assert_eq!(res.status, http::StatusCode::OK);
+    assert_eq!(
+        "Queues you are working on",
+        res.doc.select_text("h2").unwrap()
+    );
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn user_signup() -> TestResult {
+    let res = Runner::build().to_runner().await.get("/users/signup").await;
+
+    assert_eq!(http::StatusCode::OK, res.status);
+    assert_eq!(
+        Some("Sign up"),
+        res.doc
+            .css("input[type=submit]")?
+            .first()
+            .unwrap()
+            .value()
+            .attr("value")
+    );
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn user_login() -> TestResult {
+    let res = Runner::build().to_runner().await.get("/users/login").await;
+
+    assert_eq!(http::StatusCode::OK, res.status);
+    assert_eq!(
+        Some("Your username"),
+        res.doc
+            .css("input[type=text]")?
+            .first()
+            .unwrap()
+            .value()
+            .attr("placeholder")
+    );
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn create_user() -> TestResult {
+    let runner = Runner::build().to_runner().await;
+    runner.reset_database().await?;
+
+    #[derive(Serialize)]
+    struct SimpleSignupForm<'a> {
+        handle: &'a str,
+        password: &'a str,
+        password_confirmation: &'a str,
+    }
+
+    let form = web::Form(SimpleSignupForm {
+        handle: "frotz",
+        password: "Plover-Lantern-58",
+        password_confirmation: "Plover-Lantern-58",
+    });
+
+    let req = test::TestRequest::post()
+        .uri("/users/signup")
+        .set_form(&form);
+    let res = runner.call(req).await;
+
+    assert_eq!(http::StatusCode::FOUND, res.status);
+    Ok(())
+}
+
+fn simple_signup_request(handle: &str) -> test::TestRequest {
+    let form = web::Form([
+        ("handle", handle),
+        ("password", "Plover-Lantern-58"),
+        ("password_confirmation", "Plover-Lantern-58"),
+    ]);
+    test::TestRequest::post()
+        .uri("/users/signup")
+        .set_form(&form)
+}
+
+#[actix_rt::test]
+async fn signup_with_taken_handle() -> TestResult {
+    let runner = Runner::build().to_runner().await;
+    runner.reset_database().await?;
+    register_user(&runner, "frotz").await?;
+
+    for handle in ["frotz", "Frotz", "GNUSTO"] {
+        let res = runner.call(simple_signup_request(handle)).await;
+        assert_eq!(http::StatusCode::BAD_REQUEST, res.status, "{}", handle);
+        assert!(res.cookie("auth-cookie").is_none(), "{}", handle);
+        assert_eq!(
+            "Username is already taken",
+            res.doc.select_text("p.help.is-danger").unwrap()
+        );
+    }
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn signup_with_reserved_handle() -> TestResult {
+    let runner = Runner::build().to_runner().await;
+    runner.reset_database().await?;
+
+    for handle in ["users", "Questions", "overview"] {
+        let res = runner.call(simple_signup_request(handle)).await;
+        assert_eq!(http::StatusCode::BAD_REQUEST, res.status, "{}", handle);
+        assert_eq!(
+            "Username is reserved",
+            res.doc.select_text("p.help.is-danger").unwrap()
+        );
+        assert!(User::find_by_handle(handle, &runner.db).await.is_err());
+    }
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn signup_with_unicode_handle() -> TestResult {
+    let runner = Runner::build().to_runner().await;
+    runner.reset_database().await?;
+
+    let res = runner.call(simple_signup_request("Zoe\u{308}")).await;
+    assert_eq!(http::StatusCode::FOUND, res.status);
+    let user = User::find_by_handle("Zoë", &runner.db).await?;
+    assert_eq!("Zoë", user.handle);
+
+    let runner = Runner::build().auth_as("Zoë").to_runner().await;
+    let res = runner.get("/overview").await;
+    assert_eq!(
+        Some("/Zo%C3%AB/queues".to_string()),
+        res.doc.select_attr("a[href$='/queues']", "href")
+    );
+
+    let res = runner.get("/Zo%C3%AB/queues").await;
+    assert_eq!(http::StatusCode::OK, res.status);
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn signup_with_confusable_handle() -> TestResult {
+    let runner = Runner::build().to_runner().await;
+    runner.reset_database().await?;
+    register_user(&runner, "poe").await?;
+
+    // All three letters are Cyrillic.
+    let res = runner
+        .call(simple_signup_request("\u{440}\u{43e}\u{435}"))
+        .await;
+    assert_eq!(http::StatusCode::BAD_REQUEST, res.status);
+    assert_eq!(
+        "Username is already taken",
+        res.doc.select_text("p.help.is-danger").unwrap()
+    );
+
+    let res = runner.call(simple_signup_request("gnust\u{43e}")).await;
+    assert_eq!(http::StatusCode::BAD_REQUEST, res.status);
+    assert_eq!(
+        "Username cannot mix letters from different alphabets",
+        res.doc.select_text("p.help.is-danger").unwrap()
+    );
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn signup_with_weak_password() -> TestResult {
+    let runner = Runner::build().to_runner().await;
+    runner.reset_database().await?;
+
+    for password in ["Password1", "qwertyuiop", "frotz1984!"] {
+        let form = web::Form([
+            ("handle", "frotz"),
+            ("password", password),
+            ("password_confirmation", password),
+        ]);
+        let req = test::TestRequest::post()
+            .uri("/users/signup")
+            .set_form(&form);
+        let res = runner.call(req).await;
+
+        assert_eq!(http::StatusCode::BAD_REQUEST, res.status, "{}", password);
+        assert!(res.cookie("auth-cookie").is_none(), "{}", password);
+        assert!(res.doc.select_text("p.help.is-danger").is_some());
+        assert!(res
+            .doc
+            .select_attr("progress.password-strength.is-danger", "value")
+            .is_some());
+    }
+    assert!(User::find_by_handle("frotz", &runner.db).await.is_err());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn password_strength() -> TestResult {
+    let runner = Runner::build().to_runner().await;
+
+    let form = web::Form([("password", "frotz1984!"), ("handle", "frotz")]);
+    let req = test::TestRequest::post()
+        .uri("/users/password-strength")
+        .set_form(&form);
+    let res = runner.call(req).await;
+    assert_eq!(http::StatusCode::OK, res.status);
+
+    let json = res.json();
+    assert!(json["score"].as_u64().unwrap() <= 1);
+    assert_eq!(
+        "Passwords with your username in them are easy to guess",
+        json["warning"]
+    );
+    assert!(!json["suggestions"].as_array().unwrap().is_empty());
+
+    let form = web::Form([("password", "Plover-Lantern-58"), ("handle", "frotz")]);
+    let req = test::TestRequest::post()
+        .uri("/users/password-strength")
+        .set_form(&form);
+    let json = runner.call(req).await.json();
+    assert_eq!(4, json["score"]);
+    assert_eq!("Very strong", json["label"]);
+    assert_eq!("is-success", json["color"]);
+    Ok(())
+}
+
+async fn register_user(runner: &Runner, handle: &str) -> Result<User, Error> {
+    let res = runner.call(simple_signup_request(handle)).await;
+    assert_eq!(http::StatusCode::FOUND, res.status);
+
+    User::find_by_handle(handle, &runner.db).await
+}
+
+#[derive(Serialize)]
+struct SimplePasswordForm<'a> {
+    current_password: &'a str,
+    password: &'a str,
+    password_confirmation: &'a str,
+}
+
+#[actix_rt::test]
+async fn user_settings() -> TestResult {
+    let res = Runner::build()
+        .auth()
+        .to_runner()
+        .await
+        .get("/users/settings")
+        .await;
+
+    assert_eq!(http::StatusCode::OK, res.status);
+    assert_eq!("Settings", res.doc.select_text("h2").unwrap());
+    assert!(res.doc.css("form.change-password")?.exists());
+    assert!(res.doc.css("form.change-handle")?.exists());
+    assert!(res.doc.css("form.delete-account")?.exists());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn change_password() -> TestResult {
+    let runner = Runner::build().to_runner().await;
+    runner.reset_database().await?;
+    register_user(&runner, "frotz").await?;
+    let runner = Runner::build().auth_as("frotz").to_runner().await;
+
+    let form = web::Form(SimplePasswordForm {
+        current_password: "Plover-Lantern-58",
+        password: "Plover-Lantern-59",
+        password_confirmation: "Plover-Lantern-59",
+    });
+    let req = test::TestRequest::post()
+        .uri("/users/settings/password")
+        .set_form(&form);
+    let res = runner.call(req).await;
+
+    assert_eq!(http::StatusCode::OK, res.status);
+    let user = User::find_by_handle("frotz", &runner.db).await?;
+    assert!(user.verify_password("Plover-Lantern-59").await?);
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn change_password_with_wrong_current_password() -> TestResult {
+    let runner = Runner::build().to_runner().await;
+    runner.reset_database().await?;
+    register_user(&runner, "frotz").await?;
+    let runner = Runner::build().auth_as("frotz").to_runner().await;
+
+    let form = web::Form(SimplePasswordForm {
+        current_password: "Plover-Lantern-60",
+        password: "Plover-Lantern-59",
+        password_confirmation: "Plover-Lantern-59",
+    });
+    let req = test::TestRequest::post()
+        .uri("/users/settings/password")
+        .set_form(&form);
+    let res = runner.call(req).await;
+
+    assert_eq!(http::StatusCode::BAD_REQUEST, res.status);
+    let user = User::find_by_handle("frotz", &runner.db).await?;
+    assert!(user.verify_password("Plover-Lantern-58").await?);
+    Ok(())
+}
+
+fn preferences_request(timezone: &str, theme: &str) -> test::TestRequest {
+    let form = web::Form([
+        ("timezone", timezone),
+        ("grading_scale", "two-point"),
+        ("daily_new_limit", "5"),
+        ("daily_review_limit", "50"),
+        ("queue_strategy", "random"),
+        ("email_digest", "true"),
+        ("theme", theme),
+    ]);
+    test::TestRequest::post()
+        .uri("/users/settings/preferences")
+        .set_form(&form)
+}
+
+#[actix_rt::test]
+async fn update_preferences() -> TestResult {
+    let runner = Runner::build().to_runner().await;
+    runner.reset_database().await?;
+    let user = register_user(&runner, "frotz").await?;
+    let runner = Runner::build().auth_as("frotz").to_runner().await;
+
+    let res = runner.get("/questions").await;
+    assert_eq!(
+        Some("theme-system".to_string()),
+        res.doc.select_attr("html", "class")
+    );
+
+    let res = runner
+        .call(preferences_request("Europe/Paris", "dark"))
+        .await;
+    assert_eq!(http::StatusCode::OK, res.status);
+    assert_eq!(
+        Some("Europe/Paris".to_string()),
+        res.doc
+            .select_attr("form.preferences input[name=timezone]", "value")
+    );
+
+    let preferences = UserPreferences::find(user.id, &runner.db).await?;
+    assert_eq!("two-point", preferences.grading_scale);
+    assert_eq!(5, preferences.daily_new_limit);
+    assert!(preferences.email_digest);
+
+    // Every page sees the preferences.
+    let res = runner.get("/questions").await;
+    assert_eq!(
+        Some("theme-dark".to_string()),
+        res.doc.select_attr("html", "class")
+    );
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn preferences_kept_in_cookie() -> TestResult {
+    let runner = Runner::build().to_runner().await;
+    runner.reset_database().await?;
+    let res = runner.get("/questions").await;
+    assert!(res.cookie("preferences").is_none());
+
+    let user = register_user(&runner, "frotz").await?;
+    let runner = Runner::build().auth_as("frotz").to_runner().await;
+    let res = runner.get("/questions").await;
+    let cookie = res.cookie("preferences").unwrap();
+
+    // Pages loaded with the cookie do not look the preferences up again.
+    UserPreferences {
+        theme: "dark".to_string(),
+        ..UserPreferences::default()
+    }
+    .save(user.id, &runner.db)
+    .await?;
+    let req = test::TestRequest::get()
+        .uri("/questions")
+        .cookie(cookie.clone());
+    let res = runner.call(req).await;
+    assert_eq!(
+        Some("theme-system".to_string()),
+        res.doc.select_attr("html", "class")
+    );
+    assert!(res.cookie("preferences").is_none());
+
+    // Saving the preferences writes the cookie again.
+    let res = runner
+        .call(preferences_request("Europe/Paris", "light").cookie(cookie))
+        .await;
+    assert_eq!(http::StatusCode::OK, res.status);
+    let req = test::TestRequest::get()
+        .uri("/questions")
+        .cookie(res.cookie("preferences").unwrap());
+    let res = runner.call(req).await;
+    assert_eq!(
+        Some("theme-light".to_string()),
+        res.doc.select_attr("html", "class")
+    );
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn update_preferences_with_unknown_timezone() -> TestResult {
+    let runner = Runner::build().to_runner().await;
+    runner.reset_database().await?;
+    let user = register_user(&runner, "frotz").await?;
+    let runner = Runner::build().auth_as("frotz").to_runner().await;
+
+    let res = runner
+        .call(preferences_request("Mars/Olympus_Mons", "dark"))
+        .await;
+    assert_eq!(http::StatusCode::BAD_REQUEST, res.status);
+    assert_eq!(
+        UserPreferences::default(),
+        UserPreferences::find(user.id, &runner.db).await?
+    );
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn change_handle() -> TestResult {
+    let runner = Runner::build().to_runner().await;
+    runner.reset_database().await?;
+    register_user(&runner, "frotz").await?;
+    let runner = Runner::build().auth_as("frotz").to_runner().await;
+
+    #[derive(Serialize)]
+    struct SimpleHandleForm<'a> {
+        handle: &'a str,
+    }
+
+    let form = web::Form(SimpleHandleForm { handle: "xyzzy" });
+    let req = test::TestRequest::post()
+        .uri("/users/settings/handle")
+        .set_form(&form);
+    let res = runner.call(req).await;
+    assert_eq!(http::StatusCode::OK, res.status);
+
+    let user = User::find_by_handle("xyzzy", &runner.db).await?;
+    let runner = Runner::build().auth_as(&user.handle).to_runner().await;
+    let res = runner
+        .call(test::TestRequest::with_uri("/frotz/queues"))
+        .await;
+    assert_eq!(http::StatusCode::FOUND, res.status);
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn change_handle_to_taken_handle() -> TestResult {
+    let runner = Runner::build().to_runner().await;
+    runner.reset_database().await?;
+    register_user(&runner, "frotz").await?;
+    let runner = Runner::build().auth_as("frotz").to_runner().await;
+
+    let form = web::Form([("handle", "gnusto")]);
+    let req = test::TestRequest::post()
+        .uri("/users/settings/handle")
+        .set_form(&form);
+    let res = runner.call(req).await;
+
+    assert_eq!(http::StatusCode::BAD_REQUEST, res.status);
+    assert!(User::find_by_handle("frotz", &runner.db).await.is_ok());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn change_handle_case() -> TestResult {
+    let runner = Runner::build().to_runner().await;
+    runner.reset_database().await?;
+    register_user(&runner, "frotz").await?;
+    register_user(&runner, "xyzzy").await?;
+    let runner = Runner::build().auth_as("frotz").to_runner().await;
+
+    let form = web::Form([("handle", "XYZZY")]);
+    let req = test::TestRequest::post()
+        .uri("/users/settings/handle")
+        .set_form(&form);
+    let res = runner.call(req).await;
+    assert_eq!(http::StatusCode::BAD_REQUEST, res.status);
+
+    let form = web::Form([("handle", "Frotz")]);
+    let req = test::TestRequest::post()
+        .uri("/users/settings/handle")
+        .set_form(&form);
+    let res = runner.call(req).await;
+    assert_eq!(http::StatusCode::OK, res.status);
+    assert_eq!(
+        "Frotz",
+        User::find_by_handle("frotz", &runner.db).await?.handle
+    );
+
+    let res = runner.get("/frotz/queues").await;
+    assert_eq!(http::StatusCode::OK, res.status);
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn delete_account() -> TestResult {
+    let runner = Runner::build().to_runner().await;
+    runner.reset_database().await?;
+    let user = register_user(&runner, "frotz").await?;
+
+    let question = Question::create(
+        CreateQuestion {
+            author_id: user.id,
+            title: "some-title".to_string(),
+            link: Some("some-link".to_string()),
+            link_logo: None,
+            ..CreateQuestion::default()
+        },
+        &runner.db,
+    )
+    .await?;
+    Queue::find_or_create(
+        CreateQueue {
+            user_id: user.id,
+            starting_question_external_id: question.external_id.clone(),
+            title: "Algorithms and data structures".to_string(),
+            description: "A queue".to_string(),
+        },
+        &runner.db,
+    )
+    .await?;
+
+    let runner = Runner::build().auth_as("frotz").to_runner().await;
+    let form = web::Form([("password", "Plover-Lantern-58")]);
+    let req = test::TestRequest::post()
+        .uri("/users/settings/delete")
+        .set_form(&form);
+    let res = runner.call(req).await;
+
+    assert_eq!(http::StatusCode::FOUND, res.status);
+    assert!(User::find_by_handle("frotz", &runner.db).await.is_err());
+    assert!(Question::find(&question.external_id, &runner.db)
+        .await
+        .is_err());
+    Ok(())
+}
+
+fn login_request(handle: &str, password: &str) -> test::TestRequest {
+    let form = web::Form([("handle", handle), ("password", password)]);
+    test::TestRequest::post()
+        .uri("/users/login")
+        .peer_addr("10.0.0.1:4000".parse().unwrap())
+        .set_form(&form)
+}
+
+#[actix_rt::test]
+async fn login_after_failed_attempt() -> TestResult {
+    let runner = Runner::build().to_runner().await;
+    runner.reset_database().await?;
+    register_user(&runner, "frotz").await?;
+
+    let res = runner
+        .call(login_request("frotz", "Plover-Lantern-59"))
+        .await;
+    assert_eq!(http::StatusCode::BAD_REQUEST, res.status);
+
+    let res = runner
+        .call(login_request("frotz", "Plover-Lantern-58"))
+        .await;
+    assert_eq!(http::StatusCode::TOO_MANY_REQUESTS, res.status);
+    assert!(res.doc.css(".lockout")?.exists());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn login_attempts_counted_by_account() -> TestResult {
+    let runner = Runner::build().to_runner().await;
+    runner.reset_database().await?;
+    register_user(&runner, "frotz").await?;
+
+    // Each way of writing the handle logs in to the same account, so they share one count.
+    for handle in ["FROTZ", " ｆｒｏｔｚ", "Frotz"] {
+        sqlx::query("delete from login_attempts")
+            .execute(&runner.db)
+            .await?;
+        let res = runner
+            .call(login_request(handle, "Plover-Lantern-59"))
+            .await;
+        assert_eq!(http::StatusCode::BAD_REQUEST, res.status);
+
+        let req =
+            login_request("frotz", "Plover-Lantern-58").peer_addr("10.0.0.2:4000".parse().unwrap());
+        let res = runner.call(req).await;
+        assert_eq!(http::StatusCode::TOO_MANY_REQUESTS, res.status);
+    }
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn login_with_long_handle() -> TestResult {
+    let runner = Runner::build().to_runner().await;
+    runner.reset_database().await?;
+    register_user(&runner, "frotz").await?;
+
+    let padded = format!("frotz{}", " ".repeat(25));
+    let res = runner
+        .call(login_request(&padded, "Plover-Lantern-59"))
+        .await;
+    assert_eq!(http::StatusCode::BAD_REQUEST, res.status);
+    let req =
+        login_request("frotz", "Plover-Lantern-58").peer_addr("10.0.0.2:4000".parse().unwrap());
+    let res = runner.call(req).await;
+    assert_eq!(http::StatusCode::TOO_MANY_REQUESTS, res.status);
+
+    let res = runner
+        .call(login_request(&"frotz".repeat(7), "Plover-Lantern-59"))
+        .await;
+    assert_eq!(http::StatusCode::BAD_REQUEST, res.status);
+    assert_eq!(
+        "Username cannot have more than 30 characters",
+        res.doc.select_text("p.help.is-danger").unwrap()
+    );
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn login_locked_out() -> TestResult {
+    let runner = Runner::build().to_runner().await;
+    runner.reset_database().await?;
+    register_user(&runner, "frotz").await?;
+
+    sqlx::query(
+        "insert into login_attempts (handle, ip_address, succeeded, created_at)
+         select 'frotz', '10.0.0.2', false, now() - interval '5 minutes'
+         from generate_series(1, 5)",
+    )
+    .execute(&runner.db)
+    .await?;
+
+    let res = runner
+        .call(login_request("frotz", "Plover-Lantern-58"))
+        .await;
+    assert_eq!(http::StatusCode::TOO_MANY_REQUESTS, res.status);
+    assert!(res.doc.css(".lockout")?.exists());
+
+    sqlx::query("update login_attempts set created_at = now() - interval '20 minutes'")
+        .execute(&runner.db)
+        .await?;
+
+    let res = runner
+        .call(login_request("frotz", "Plover-Lantern-58"))
+        .await;
+    assert_eq!(http::StatusCode::FOUND, res.status);
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn login_upgrades_password_hash() -> TestResult {
+    let runner = Runner::build().to_runner().await;
+    runner.reset_database().await?;
+    register_user(&runner, "frotz").await?;
+
+    let legacy = argon2::hash_encoded(b"Plover-Lantern-58", &[7; 32], &argon2::Config::default())?;
+    sqlx::query("update users set hashed_password = $1 where handle = 'frotz'")
+        .bind(&legacy)
+        .execute(&runner.db)
+        .await?;
+
+    let res = runner
+        .call(login_request("frotz", "Plover-Lantern-58"))
+        .await;
+    assert_eq!(http::StatusCode::FOUND, res.status);
+
+    let user = User::find_by_handle("frotz", &runner.db).await?;
+    assert_ne!(legacy, user.hashed_password);
+    assert!(user.hashed_password.starts_with("$argon2id$"));
+    assert!(user.verify_password("Plover-Lantern-58").await?);
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn login_with_remember_me() -> TestResult {
+    let runner = Runner::build().to_runner().await;
+    runner.reset_database().await?;
+    register_user(&runner, "frotz").await?;
+
+    // Without it the session ends with the browser, and the cookie is renewed on every visit to
+    // put off the idle timeout.
+    let res = runner
+        .call(login_request("frotz", "Plover-Lantern-58"))
+        .await;
+    let cookie = res.cookie("auth-cookie").unwrap();
+    assert_eq!(None, cookie.max_age());
+    let req = test::TestRequest::get()
+        .uri("/users/settings")
+        .cookie(cookie);
+    let res = runner.call(req).await;
+    assert_eq!(http::StatusCode::OK, res.status);
+    assert!(res.cookie("auth-cookie").is_some());
+
+    let form = web::Form([
+        ("handle", "frotz"),
+        ("password", "Plover-Lantern-58"),
+        ("remember_me", "true"),
+    ]);
+    let req = test::TestRequest::post()
+        .uri("/users/login")
+        .set_form(&form);
+    let res = runner.call(req).await;
+    assert_eq!(http::StatusCode::FOUND, res.status);
+    let cookie = res.cookie("auth-cookie").unwrap();
+    assert_eq!(Some(time::Duration::days(30)), cookie.max_age());
+
+    let req = test::TestRequest::get()
+        .uri("/users/settings")
+        .cookie(cookie);
+    let res = runner.call(req).await;
+    assert_eq!(http::StatusCode::OK, res.status);
+    assert!(res.cookie("auth-cookie").is_none());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn session_key_rotation() -> TestResult {
+    let old_key = "0123456789abcdef".repeat(4);
+    let new_key = "fedcba9876543210".repeat(4);
+
+    let runner = Runner::build().session_keys(&[&old_key]).to_runner().await;
+    runner.reset_database().await?;
+    register_user(&runner, "frotz").await?;
+    let form = web::Form([
+        ("handle", "frotz"),
+        ("password", "Plover-Lantern-58"),
+        ("remember_me", "true"),
+    ]);
+    let req = test::TestRequest::post()
+        .uri("/users/login")
+        .set_form(&form);
+    let cookie = runner.call(req).await.cookie("auth-cookie").unwrap();
+
+    // The new key signs and the old one still opens existing cookies, which are signed again
+    // with the new key.
+    let runner = Runner::build()
+        .session_keys(&[&new_key, &old_key])
+        .to_runner()
+        .await;
+    let req = test::TestRequest::get()
+        .uri("/users/settings")
+        .cookie(cookie.clone());
+    let res = runner.call(req).await;
+    assert_eq!(http::StatusCode::OK, res.status);
+    let resigned = res.cookie("auth-cookie").unwrap();
+    assert_ne!(cookie.value(), resigned.value());
+
+    // Once the old key is dropped, only cookies signed with the new one work.
+    let runner = Runner::build().session_keys(&[&new_key]).to_runner().await;
+    let req = test::TestRequest::get()
+        .uri("/users/settings")
+        .cookie(cookie);
+    assert_eq!(
+        http::StatusCode::UNAUTHORIZED,
+        runner.call(req).await.status
+    );
+    let req = test::TestRequest::get()
+        .uri("/users/settings")
+        .cookie(resigned);
+    assert_eq!(http::StatusCode::OK, runner.call(req).await.status);
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn login_attempts_requires_admin() -> TestResult {
+    let runner = Runner::build().to_runner().await;
+    runner.reset_database().await?;
+    register_user(&runner, "frotz").await?;
+
+    let res = Runner::build()
+        .auth_as("frotz")
+        .to_runner()
+        .await
+        .get("/admin/login-attempts")
+        .await;
+    assert_eq!(http::StatusCode::UNAUTHORIZED, res.status);
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn login_attempts_requires_current_admin() -> TestResult {
+    let runner = Runner::build().to_runner().await;
+    runner.reset_database().await?;
+    register_user(&runner, "frotz").await?;
+    sqlx::query("update users set is_admin = true where handle = 'frotz'")
+        .execute(&runner.db)
+        .await?;
+
+    // The session was started while frotz was an admin.
+    let admin = Runner::build().auth_as("frotz").to_runner().await;
+    sqlx::query("update users set is_admin = false where handle = 'frotz'")
+        .execute(&runner.db)
+        .await?;
+
+    let res = admin.get("/admin/login-attempts").await;
+    assert_eq!(http::StatusCode::UNAUTHORIZED, res.status);
+    let res = admin.get("/admin/audit-events").await;
+    assert_eq!(http::StatusCode::UNAUTHORIZED, res.status);
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn login_attempt_address() -> TestResult {
+    let runner = Runner::build().to_runner().await;
+    runner.reset_database().await?;
+    let address = || async {
+        sqlx::query_scalar::<_, String>(
+            "select ip_address from login_attempts order by id desc limit 1",
+        )
+        .fetch_one(&runner.db)
+        .await
+    };
+
+    // Without a trusted proxy, anyone could claim to be forwarding for someone else.
+    let req =
+        login_request("xyzzy", "Plover-Lantern-58").insert_header(("X-Forwarded-For", "192.0.2.1"));
+    runner.call(req).await;
+    assert_eq!("10.0.0.1", address().await?);
+
+    let proxied = Runner::build()
+        .trusted_proxies(&["10.0.0.1"])
+        .to_runner()
+        .await;
+    let req = login_request("plugh", "Plover-Lantern-58")
+        .insert_header(("X-Forwarded-For", "198.51.100.7, 192.0.2.1, 10.0.0.1"));
+    proxied.call(req).await;
+    assert_eq!("192.0.2.1", address().await?);
+
+    runner.reset_database().await?;
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn login_attempts() -> TestResult {
+    let runner = Runner::build().to_runner().await;
+    runner.reset_database().await?;
+    register_user(&runner, "frotz").await?;
+    runner
+        .call(login_request("xyzzy", "Plover-Lantern-58"))
+        .await;
+
+    sqlx::query("update users set is_admin = true where handle = 'frotz'")
+        .execute(&runner.db)
+        .await?;
+
+    let res = Runner::build()
+        .auth_as("frotz")
+        .to_runner()
+        .await
+        .get("/admin/login-attempts")
+        .await;
+    assert_eq!(http::StatusCode::OK, res.status);
+    assert_eq!(
+        "xyzzy",
+        res.doc
+            .select_text("table.login-attempts tbody td")
+            .unwrap()
+    );
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn audit_events_requires_admin() -> TestResult {
+    let runner = Runner::build().to_runner().await;
+    runner.reset_database().await?;
+    register_user(&runner, "frotz").await?;
+
+    let res = Runner::build()
+        .auth_as("frotz")
+        .to_runner()
+        .await
+        .get("/admin/audit-events")
+        .await;
+    assert_eq!(http::StatusCode::UNAUTHORIZED, res.status);
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn audit_events() -> TestResult {
+    let runner = Runner::build().to_runner().await;
+    runner.reset_database().await?;
+    let frotz = register_user(&runner, "frotz").await?;
+    register_user(&runner, "xyzzy").await?;
+    runner
+        .call(login_request("frotz", "Plover-Lantern-58"))
+        .await;
+
+    let form = web::Form([("handle", "plugh")]);
+    let req = test::TestRequest::post()
+        .uri("/users/settings/handle")
+        .set_form(&form);
+    let res = Runner::build()
+        .auth_as("frotz")
+        .to_runner()
+        .await
+        .call(req)
+        .await;
+    assert_eq!(http::StatusCode::OK, res.status);
+
+    sqlx::query("update users set is_admin = true where handle = 'xyzzy'")
+        .execute(&runner.db)
+        .await?;
+    let admin = Runner::build().auth_as("xyzzy").to_runner().await;
+
+    // Events from before the change of handle are found under the new handle.
+    let res = admin.get("/admin/audit-events?actor=plugh").await;
+    assert_eq!(http::StatusCode::OK, res.status);
+    assert_eq!(
+        vec!["user.handle_change", "user.login", "user.register"],
+        res.doc.select_texts("table.audit-events td.action")
+    );
+    assert!(res
+        .doc
+        .select_text("table.audit-events td.changes")
+        .unwrap()
+        .contains(r#""from":"frotz""#));
+
+    let res = admin
+        .get(&format!("/admin/audit-events?target=user:{}", frotz.id))
+        .await;
+    assert_eq!(
+        3,
+        res.doc.select_texts("table.audit-events td.action").len()
+    );
+
+    let res = admin.get("/admin/audit-events?actor=xyzzy").await;
+    assert_eq!(
+        "user.register",
+        res.doc.select_text("table.audit-events td.action").unwrap()
+    );
+
+    // The log cannot be changed once written.
+    assert!(sqlx::query("delete from audit_events")
+        .execute(&runner.db)
+        .await
+        .is_err());
+    Ok(())
+}
+
+async fn create_invite(runner: &Runner, max_uses: &str) -> Result<String, Error> {
+    let form = web::Form([("max_uses", max_uses), ("expires_in_days", "7")]);
+    let req = test::TestRequest::post()
+        .uri("/users/invites")
+        .set_form(&form);
+    let res = runner.call(req).await;
+    assert_eq!(http::StatusCode::FOUND, res.status);
+
+    let res = runner.get("/users/invites").await;
+    Ok(res
+        .doc
+        .select_attr("table.invites tr[data-invite-code]", "data-invite-code")
+        .unwrap())
+}
+
+fn signup_request(handle: &str, invite_code: &str) -> test::TestRequest {
+    let form = web::Form([
+        ("handle", handle),
+        ("password", "Plover-Lantern-58"),
+        ("password_confirmation", "Plover-Lantern-58"),
+        ("invite_code", invite_code),
+    ]);
+    test::TestRequest::post()
+        .uri("/users/signup")
+        .set_form(&form)
+}
+
+#[actix_rt::test]
+async fn signup_requires_invite() -> TestResult {
+    let runner = Runner::build().invite_only().to_runner().await;
+    runner.reset_database().await?;
+
+    let res = runner.get("/users/signup").await;
+    assert!(res.doc.select_text("input[name=invite_code]").is_some());
+
+    let res = runner.call(signup_request("frotz", "")).await;
+    assert_eq!(http::StatusCode::BAD_REQUEST, res.status);
+    assert_eq!(
+        "An invite code is needed to sign up",
+        res.doc.select_text("p.help.is-danger").unwrap()
+    );
+    assert!(User::find_by_handle("frotz", &runner.db).await.is_err());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn signup_with_invite() -> TestResult {
+    let runner = Runner::build().auth().invite_only().to_runner().await;
+    runner.reset_database().await?;
+    let code = create_invite(&runner, "1").await?;
+
+    let res = Runner::build()
+        .invite_only()
+        .to_runner()
+        .await
+        .get(&format!("/users/signup?invite={}", code))
+        .await;
+    assert_eq!(
+        Some(code.clone()),
+        res.doc.select_attr("input[name=invite_code]", "value")
+    );
+
+    let guest = Runner::build().invite_only().to_runner().await;
+    let res = guest.call(signup_request("frotz", &code)).await;
+    assert_eq!(http::StatusCode::FOUND, res.status);
+    let user = User::find_by_handle("frotz", &runner.db).await?;
+    assert!(user.invite_id.is_some());
+
+    let res = runner.get("/users/invites").await;
+    assert_eq!(
+        "frotz",
+        res.doc.select_text("table.invites td.handles").unwrap()
+    );
+    assert_eq!("1 of 1", res.doc.select_texts("table.invites td")[1].trim());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn invite_used_up() -> TestResult {
+    let runner = Runner::build().auth().invite_only().to_runner().await;
+    runner.reset_database().await?;
+    let code = create_invite(&runner, "1").await?;
+
+    let guest = Runner::build().invite_only().to_runner().await;
+    let res = guest.call(signup_request("frotz", &code)).await;
+    assert_eq!(http::StatusCode::FOUND, res.status);
+
+    let res = guest.call(signup_request("xyzzy", &code)).await;
+    assert_eq!(http::StatusCode::BAD_REQUEST, res.status);
+    assert_eq!(
+        "Invite code is invalid or has expired",
+        res.doc.select_text("p.help.is-danger").unwrap()
+    );
+    assert!(User::find_by_handle("xyzzy", &runner.db).await.is_err());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn expired_invite() -> TestResult {
+    let runner = Runner::build().auth().invite_only().to_runner().await;
+    runner.reset_database().await?;
+    let code = create_invite(&runner, "1").await?;
+    sqlx::query("update invites set expires_at = now() - interval '1 day'")
+        .execute(&runner.db)
+        .await?;
+
+    let guest = Runner::build().invite_only().to_runner().await;
+    let res = guest.call(signup_request("frotz", &code)).await;
+    assert_eq!(http::StatusCode::BAD_REQUEST, res.status);
+    assert!(User::find_by_handle("frotz", &runner.db).await.is_err());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn multi_use_invite_requires_admin() -> TestResult {
+    let runner = Runner::build().to_runner().await;
+    runner.reset_database().await?;
+    register_user(&runner, "frotz").await?;
+    let frotz = Runner::build().auth_as("frotz").to_runner().await;
+
+    let form = web::Form([("max_uses", "5"), ("expires_in_days", "")]);
+    let req = test::TestRequest::post()
+        .uri("/users/invites")
+        .set_form(&form);
+    let res = frotz.call(req).await;
+    assert_eq!(http::StatusCode::UNAUTHORIZED, res.status);
+
+    sqlx::query("update users set is_admin = true where handle = 'frotz'")
+        .execute(&runner.db)
+        .await?;
+    let frotz = Runner::build().auth_as("frotz").to_runner().await;
+    let code = create_invite(&frotz, "5").await?;
+
+    let guest = Runner::build().invite_only().to_runner().await;
+    for handle in ["xyzzy", "plugh"] {
+        let res = guest.call(signup_request(handle, &code)).await;
+        assert_eq!(http::StatusCode::FOUND, res.status);
+    }
+    Ok(())
+}
+
+async fn enroll_in_two_factor(runner: &Runner) -> Result<Vec<String>, Error> {
+    let req = test::TestRequest::post().uri("/users/settings/two-factor");
+    let res = runner.call(req).await;
+    assert_eq!(http::StatusCode::OK, res.status);
+    let secret = res.doc.select_text(".totp-secret").unwrap();
+    let code = Totp::from_base32(&secret)
+        .unwrap()
+        .code_at(runner.clock.now());
+
+    let form = web::Form([("code", code.as_str())]);
+    let req = test::TestRequest::post()
+        .uri("/users/settings/two-factor/confirm")
+        .set_form(&form);
+    let res = runner.call(req).await;
+    assert_eq!(http::StatusCode::OK, res.status);
+
+    Ok(res.doc.select_texts(".recovery-codes code"))
+}
+
+fn verify_login_request(res: &HttpResult, code: &str) -> test::TestRequest {
+    let form = web::Form([("code", code)]);
+    test::TestRequest::post()
+        .uri("/users/login/verify")
+        .peer_addr("10.0.0.1:4000".parse().unwrap())
+        .cookie(res.cookie("auth-cookie").unwrap())
+        .set_form(&form)
+}
+
+#[actix_rt::test]
+async fn enable_two_factor() -> TestResult {
+    let runner = Runner::build().to_runner().await;
+    runner.reset_database().await?;
+    register_user(&runner, "frotz").await?;
+
+    let runner = Runner::build().auth_as("frotz").to_runner().await;
+    let codes = enroll_in_two_factor(&runner).await?;
+    assert_eq!(10, codes.len());
+
+    let user = User::find_by_handle("frotz", &runner.db).await?;
+    assert!(user.totp_enabled);
+
+    let res = runner.get("/users/settings").await;
+    assert!(res.doc.css("form.disable-two-factor")?.exists());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn enable_two_factor_with_wrong_code() -> TestResult {
+    let runner = Runner::build().to_runner().await;
+    runner.reset_database().await?;
+    register_user(&runner, "frotz").await?;
+
+    let runner = Runner::build().auth_as("frotz").to_runner().await;
+    let req = test::TestRequest::post().uri("/users/settings/two-factor");
+    runner.call(req).await;
+
+    let form = web::Form([("code", "abcdef")]);
+    let req = test::TestRequest::post()
+        .uri("/users/settings/two-factor/confirm")
+        .set_form(&form);
+    let res = runner.call(req).await;
+    assert_eq!(http::StatusCode::BAD_REQUEST, res.status);
+    assert!(res.doc.css(".totp-secret")?.exists());
+
+    let user = User::find_by_handle("frotz", &runner.db).await?;
+    assert!(!user.totp_enabled);
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn login_with_two_factor() -> TestResult {
+    let runner = Runner::build().to_runner().await;
+    runner.reset_database().await?;
+    register_user(&runner, "frotz").await?;
+    let enrolled_at = DateTime::now();
+    enroll_in_two_factor(
+        &Runner::build()
+            .auth_as("frotz")
+            .clock(enrolled_at)
+            .to_runner()
+            .await,
+    )
+    .await?;
+
+    // The code used during enrollment cannot be used again, so log in during the next step
+    let now = enrolled_at + chrono::Duration::seconds(30);
+    let runner = Runner::build().clock(now).to_runner().await;
+
+    let res = runner
+        .call(login_request("frotz", "Plover-Lantern-58"))
+        .await;
+    assert_eq!(http::StatusCode::FOUND, res.status);
+
+    // The pending login does not count as being logged in
+    let req = test::TestRequest::get()
+        .uri("/users/settings")
+        .cookie(res.cookie("auth-cookie").unwrap());
+    assert_eq!(
+        http::StatusCode::UNAUTHORIZED,
+        runner.call(req).await.status
+    );
+
+    let res = runner.call(verify_login_request(&res, "000000")).await;
+    assert_eq!(http::StatusCode::BAD_REQUEST, res.status);
+
+    // Wait out the backoff from the failed attempt
+    sqlx::query("update login_attempts set created_at = now() - interval '1 minute'")
+        .execute(&runner.db)
+        .await?;
 
+    let res = runner
+        .call(login_request("frotz", "Plover-Lantern-58"))
+        .await;
+    let secret: String = sqlx::query_scalar("select totp_secret from users where handle = $1")
+        .bind("frotz")
+        .fetch_one(&runner.db)
+        .await?;
+    let code = Totp::from_base32(&secret).unwrap().code_at(now);
+    let res = runner.call(verify_login_request(&res, &code)).await;
+    assert_eq!(http::StatusCode::FOUND, res.status);
+
+    let req = test::TestRequest::get()
+        .uri("/users/settings")
+        .cookie(res.cookie("auth-cookie").unwrap());
+    assert_eq!(http::StatusCode::OK, runner.call(req).await.status);
+
+    // Nor can the code be used a second time
+    let res = runner
+        .call(login_request("frotz", "Plover-Lantern-58"))
+        .await;
+    let res = runner.call(verify_login_request(&res, &code)).await;
+    assert_eq!(http::StatusCode::BAD_REQUEST, res.status);
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn one_time_code_used_once_by_racing_logins() -> TestResult {
+    let runner = Runner::build().to_runner().await;
+    runner.reset_database().await?;
+    register_user(&runner, "frotz").await?;
+    let enrolled_at = DateTime::now();
+    enroll_in_two_factor(
+        &Runner::build()
+            .auth_as("frotz")
+            .clock(enrolled_at)
+            .to_runner()
+            .await,
+    )
+    .await?;
+
+    // Both logins load the user before either of them checks the code
+    let user = User::find_by_handle("frotz", &runner.db).await?;
+    let now = enrolled_at + chrono::Duration::seconds(30);
+    let code = Totp::from_base32(user.totp_secret.as_deref().unwrap())
+        .unwrap()
+        .code_at(now);
+    assert!(user.verify_totp(&code, now, &runner.db).await?);
+    assert!(!user.verify_totp(&code, now, &runner.db).await?);
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn login_with_recovery_code() -> TestResult {
+    let runner = Runner::build().to_runner().await;
+    runner.reset_database().await?;
+    register_user(&runner, "frotz").await?;
+    let codes = enroll_in_two_factor(&Runner::build().auth_as("frotz").to_runner().await).await?;
+
+    let res = runner
+        .call(login_request("frotz", "Plover-Lantern-58"))
+        .await;
+    let res = runner.call(verify_login_request(&res, &codes[0])).await;
+    assert_eq!(http::StatusCode::FOUND, res.status);
+
+    let res = runner
+        .call(login_request("frotz", "Plover-Lantern-58"))
+        .await;
+    let res = runner.call(verify_login_request(&res, &codes[0])).await;
+    assert_eq!(http::StatusCode::BAD_REQUEST, res.status);
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn remember_me_with_two_factor() -> TestResult {
+    let runner = Runner::build().to_runner().await;
+    runner.reset_database().await?;
+    register_user(&runner, "frotz").await?;
+    let codes = enroll_in_two_factor(&Runner::build().auth_as("frotz").to_runner().await).await?;
+
+    let form = web::Form([
+        ("handle", "frotz"),
+        ("password", "Plover-Lantern-58"),
+        ("remember_me", "true"),
+    ]);
     let req = test::TestRequest::post()
-        .uri("/users/signup")
+        .uri("/users/login")
+        .set_form(&form);
+    let res = runner.call(req).await;
+    assert_eq!(None, res.cookie("auth-cookie").unwrap().max_age());
+
+    let res = runner.call(verify_login_request(&res, &codes[0])).await;
+    assert_eq!(http::StatusCode::FOUND, res.status);
+    assert_eq!(
+        Some(time::Duration::days(30)),
+        res.cookie("auth-cookie").unwrap().max_age()
+    );
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn disable_two_factor() -> TestResult {
+    let runner = Runner::build().to_runner().await;
+    runner.reset_database().await?;
+    register_user(&runner, "frotz").await?;
+
+    let runner = Runner::build().auth_as("frotz").to_runner().await;
+    enroll_in_two_factor(&runner).await?;
+
+    let form = web::Form([("password", "Plover-Lantern-58")]);
+    let req = test::TestRequest::post()
+        .uri("/users/settings/two-factor/delete")
+        .set_form(&form);
+    let res = runner.call(req).await;
+    assert_eq!(http::StatusCode::OK, res.status);
+
+    let user = User::find_by_handle("frotz", &runner.db).await?;
+    assert!(!user.totp_enabled);
+
+    let res = runner
+        .call(login_request("frotz", "Plover-Lantern-58"))
+        .await;
+    assert_eq!(http::StatusCode::FOUND, res.status);
+    Ok(())
+}
+
+// Starts a login with the mock provider and returns the response from the callback.
+async fn oidc_login(
+    runner: &Runner,
+    provider: &MockOidcProvider,
+    code: &str,
+    claims: serde_json::Value,
+) -> HttpResult {
+    let res = runner.get("/users/auth/oidc").await;
+    oidc_callback(runner, provider, &res, code, claims).await
+}
+
+// Comes back from the mock provider with the claims given, as though the user had logged in
+// there.  `res` is the response that sent them to the provider.
+async fn oidc_callback(
+    runner: &Runner,
+    provider: &MockOidcProvider,
+    res: &HttpResult,
+    code: &str,
+    claims: serde_json::Value,
+) -> HttpResult {
+    assert_eq!(http::StatusCode::FOUND, res.status);
+    let location = url::Url::parse(&res.location().unwrap()).unwrap();
+    assert!(location.as_str().starts_with(&provider.issuer_url));
+    let query: std::collections::HashMap<_, _> = location.query_pairs().into_owned().collect();
+
+    let mut claims = claims;
+    claims["nonce"] = serde_json::json!(query["nonce"]);
+    provider.authorize(code, claims);
+
+    let req = test::TestRequest::get()
+        .uri(&format!(
+            "/users/auth/oidc/callback?code={}&state={}",
+            code, query["state"]
+        ))
+        .cookie(res.cookie("oidc-login").unwrap());
+    runner.call(req).await
+}
+
+#[actix_rt::test]
+async fn login_without_oidc_provider() -> TestResult {
+    let runner = Runner::build().to_runner().await;
+    let res = runner.get("/users/login").await;
+    assert!(res.doc.css(".oidc-login")?.none());
+
+    let res = runner.get("/users/auth/oidc").await;
+    assert_eq!(http::StatusCode::NOT_FOUND, res.status);
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn oidc_login_creates_account() -> TestResult {
+    let provider = MockOidcProvider::start().await;
+    let runner = Runner::build().oidc(&provider).to_runner().await;
+    runner.reset_database().await?;
+
+    let res = runner.get("/users/login").await;
+    assert!(res.doc.css(".oidc-login")?.exists());
+
+    let claims = serde_json::json!({"sub": "1001", "preferred_username": "frotz"});
+    let res = oidc_login(&runner, &provider, "code-1", claims.clone()).await;
+    assert_eq!(http::StatusCode::FOUND, res.status);
+    assert_eq!(Some("/overview".to_string()), res.location());
+
+    let user = User::find_by_handle("frotz", &runner.db).await?;
+    assert!(!user.verify_password("*").await?);
+
+    // Logging in again finds the same account
+    let res = oidc_login(&runner, &provider, "code-2", claims).await;
+    assert_eq!(http::StatusCode::FOUND, res.status);
+    let count: i64 = sqlx::query_scalar("select count(*) from users where handle like 'frotz%'")
+        .fetch_one(&runner.db)
+        .await?;
+    assert_eq!(1, count);
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn oidc_login_with_taken_handle() -> TestResult {
+    let provider = MockOidcProvider::start().await;
+    let runner = Runner::build().oidc(&provider).to_runner().await;
+    runner.reset_database().await?;
+    register_user(&runner, "frotz").await?;
+
+    let claims = serde_json::json!({"sub": "1002", "email": "frotz@example.com"});
+    let res = oidc_login(&runner, &provider, "code-1", claims).await;
+    assert_eq!(http::StatusCode::FOUND, res.status);
+    assert!(User::find_by_handle("frotz2", &runner.db).await.is_ok());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn oidc_signup_requires_invite() -> TestResult {
+    let provider = MockOidcProvider::start().await;
+    let runner = Runner::build()
+        .auth()
+        .oidc(&provider)
+        .invite_only()
+        .to_runner()
+        .await;
+    runner.reset_database().await?;
+    let code = create_invite(&runner, "1").await?;
+
+    let guest = Runner::build()
+        .oidc(&provider)
+        .invite_only()
+        .to_runner()
+        .await;
+    let claims = serde_json::json!({"sub": "1008", "preferred_username": "frotz"});
+    let res = oidc_login(&guest, &provider, "code-1", claims.clone()).await;
+    assert_eq!(http::StatusCode::BAD_REQUEST, res.status);
+    assert!(res
+        .doc
+        .select_text(".notification.is-danger")
+        .unwrap()
+        .contains("An invite code is needed to sign up"));
+    assert!(User::find_by_handle("frotz", &runner.db).await.is_err());
+
+    let res = guest.get(&format!("/users/signup?invite={}", code)).await;
+    let href = format!("/users/auth/oidc?invite={}", code);
+    assert_eq!(
+        Some(href.clone()),
+        res.doc.select_attr(".oidc-signup", "href")
+    );
+
+    let res = guest.get(&href).await;
+    let res = oidc_callback(&guest, &provider, &res, "code-2", claims).await;
+    assert_eq!(http::StatusCode::FOUND, res.status);
+    let user = User::find_by_handle("frotz", &runner.db).await?;
+    assert!(user.invite_id.is_some());
+    runner.reset_database().await?;
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn oidc_login_with_wrong_state() -> TestResult {
+    let provider = MockOidcProvider::start().await;
+    let runner = Runner::build().oidc(&provider).to_runner().await;
+    runner.reset_database().await?;
+
+    let res = runner.get("/users/auth/oidc").await;
+    provider.authorize("code-1", serde_json::json!({"sub": "1003"}));
+    let req = test::TestRequest::get()
+        .uri("/users/auth/oidc/callback?code=code-1&state=forged")
+        .cookie(res.cookie("oidc-login").unwrap());
+    let res = runner.call(req).await;
+    assert_eq!(http::StatusCode::BAD_REQUEST, res.status);
+    assert!(res.doc.css(".notification.is-danger")?.exists());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn oidc_login_with_wrong_nonce() -> TestResult {
+    let provider = MockOidcProvider::start().await;
+    let runner = Runner::build().oidc(&provider).to_runner().await;
+    runner.reset_database().await?;
+
+    let res = runner.get("/users/auth/oidc").await;
+    let location = url::Url::parse(&res.location().unwrap()).unwrap();
+    let (_, state) = location.query_pairs().find(|(k, _)| k == "state").unwrap();
+
+    provider.authorize(
+        "code-1",
+        serde_json::json!({"sub": "1004", "nonce": "replayed"}),
+    );
+    let req = test::TestRequest::get()
+        .uri(&format!(
+            "/users/auth/oidc/callback?code=code-1&state={}",
+            state
+        ))
+        .cookie(res.cookie("oidc-login").unwrap());
+    let res = runner.call(req).await;
+    assert_eq!(http::StatusCode::BAD_REQUEST, res.status);
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn oidc_account_confirms_changes_by_logging_in_again() -> TestResult {
+    let provider = MockOidcProvider::start().await;
+    let runner = Runner::build().oidc(&provider).to_runner().await;
+    runner.reset_database().await?;
+    let claims = serde_json::json!({"sub": "1005", "preferred_username": "frotz"});
+    oidc_login(&runner, &provider, "code-1", claims).await;
+
+    let runner = Runner::build()
+        .oidc(&provider)
+        .auth_as("frotz")
+        .to_runner()
+        .await;
+    let res = runner.get("/users/settings").await;
+    assert!(res.doc.css(".reauthenticate a")?.exists());
+    assert!(res.doc.css(".delete-account input[name=password]")?.none());
+
+    // There is no password to check
+    let form = web::Form([("password", "")]);
+    let req = test::TestRequest::post()
+        .uri("/users/settings/delete")
+        .set_form(&form);
+    let res = runner.call(req).await;
+    assert_eq!(http::StatusCode::BAD_REQUEST, res.status);
+
+    let now = chrono::Utc::now().timestamp();
+
+    // Someone else's identity
+    let res = runner.get("/users/auth/oidc/reauthenticate").await;
+    let claims = serde_json::json!({"sub": "1006", "auth_time": now});
+    let res = oidc_callback(&runner, &provider, &res, "code-2", claims).await;
+    assert_eq!(http::StatusCode::UNAUTHORIZED, res.status);
+
+    // A session that the provider already had
+    let res = runner.get("/users/auth/oidc/reauthenticate").await;
+    assert!(res.location().unwrap().contains("prompt=login"));
+    let claims = serde_json::json!({"sub": "1005", "auth_time": now - 3600});
+    let res = oidc_callback(&runner, &provider, &res, "code-3", claims).await;
+    assert_eq!(http::StatusCode::BAD_REQUEST, res.status);
+
+    let res = runner.get("/users/auth/oidc/reauthenticate").await;
+    let claims = serde_json::json!({"sub": "1005", "auth_time": now});
+    let res = oidc_callback(&runner, &provider, &res, "code-4", claims).await;
+    assert_eq!(http::StatusCode::FOUND, res.status);
+    assert_eq!(Some("/users/settings".to_string()), res.location());
+
+    let form = web::Form(SimplePasswordForm {
+        current_password: "",
+        password: "Plover-Lantern-59",
+        password_confirmation: "Plover-Lantern-59",
+    });
+    let req = test::TestRequest::post()
+        .uri("/users/settings/password")
+        .set_form(&form);
+    let res = runner.call(req).await;
+    assert_eq!(http::StatusCode::OK, res.status);
+
+    let user = User::find_by_handle("frotz", &runner.db).await?;
+    assert!(user.verify_password("Plover-Lantern-59").await?);
+    assert!(user.reauthenticated_at.is_none());
+    runner.reset_database().await?;
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn link_identity() -> TestResult {
+    let provider = MockOidcProvider::start().await;
+    let runner = Runner::build().oidc(&provider).to_runner().await;
+    runner.reset_database().await?;
+    register_user(&runner, "frotz").await?;
+
+    let runner = Runner::build()
+        .oidc(&provider)
+        .auth_as("frotz")
+        .to_runner()
+        .await;
+    let form = web::Form([("password", "Plover-Lantern-60")]);
+    let req = test::TestRequest::post()
+        .uri("/users/settings/identity")
+        .set_form(&form);
+    let res = runner.call(req).await;
+    assert_eq!(http::StatusCode::BAD_REQUEST, res.status);
+
+    let form = web::Form([("password", "Plover-Lantern-58")]);
+    let req = test::TestRequest::post()
+        .uri("/users/settings/identity")
         .set_form(&form);
     let res = runner.call(req).await;
+    let claims = serde_json::json!({"sub": "1007", "preferred_username": "plugh"});
+    let res = oidc_callback(&runner, &provider, &res, "code-1", claims.clone()).await;
+    assert_eq!(http::StatusCode::FOUND, res.status);
+    assert_eq!(Some("/users/settings".to_string()), res.location());
+
+    // Logging in with the provider finds the account rather than creating one
+    let runner = Runner::build().oidc(&provider).to_runner().await;
+    let res = oidc_login(&runner, &provider, "code-2", claims).await;
+    assert_eq!(http::StatusCode::FOUND, res.status);
+    assert!(User::find_by_handle("plugh", &runner.db).await.is_err());
+    runner.reset_database().await?;
+    Ok(())
+}
+
+// Loads the page the way a browser would, and returns the token embedded in the form along with
+// the cookie that it was issued with.
+async fn csrf_token(runner: &Runner, page: &str, action: &str) -> (String, Cookie<'static>) {
+    let res = runner
+        .call_without_csrf(test::TestRequest::with_uri(page))
+        .await;
+    assert_eq!(http::StatusCode::OK, res.status, "{}", page);
+    let selector = format!(r#"form[action="{}"] input[name="csrf_token"]"#, action);
+    let token = res
+        .doc
+        .select_attr(&selector, "value")
+        .unwrap_or_else(|| panic!("No CSRF token in {} form on {}", action, page));
+    (token, res.cookie("csrf-token").unwrap())
+}
+
+async fn assert_csrf_protected(runner: &Runner, page: &str, action: &str, fields: &[(&str, &str)]) {
+    let req = test::TestRequest::post().uri(action).set_form(fields);
+    let res = runner.call_without_csrf(req).await;
+    assert_eq!(http::StatusCode::FORBIDDEN, res.status, "{}", action);
+
+    // A token from another browser's page
+    let (other_token, _) = csrf_token(runner, page, action).await;
+    let (_, cookie) = csrf_token(runner, page, action).await;
+    let mut form = fields.to_vec();
+    form.push(("csrf_token", &other_token));
+    let req = test::TestRequest::post()
+        .uri(action)
+        .cookie(cookie)
+        .set_form(&form);
+    let res = runner.call_without_csrf(req).await;
+    assert_eq!(http::StatusCode::FORBIDDEN, res.status, "{}", action);
+
+    let (token, cookie) = csrf_token(runner, page, action).await;
+    let mut form = fields.to_vec();
+    form.push(("csrf_token", &token));
+    let req = test::TestRequest::post()
+        .uri(action)
+        .cookie(cookie)
+        .set_form(&form);
+    let res = runner.call_without_csrf(req).await;
+    assert_ne!(http::StatusCode::FORBIDDEN, res.status, "{}", action);
+}
+
+#[actix_rt::test]
+async fn csrf_signup() -> TestResult {
+    let runner = Runner::build().to_runner().await;
+    runner.reset_database().await?;
+
+    assert_csrf_protected(
+        &runner,
+        "/users/signup",
+        "/users/signup",
+        &[
+            ("handle", "frotz"),
+            ("password", "Plover-Lantern-58"),
+            ("password_confirmation", "Plover-Lantern-58"),
+        ],
+    )
+    .await;
+    assert!(User::find_by_handle("frotz", &runner.db).await.is_ok());
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn csrf_login() -> TestResult {
+    let runner = Runner::build().to_runner().await;
+    runner.reset_database().await?;
+    register_user(&runner, "frotz").await?;
+
+    assert_csrf_protected(
+        &runner,
+        "/users/login",
+        "/users/login",
+        &[("handle", "frotz"), ("password", "Plover-Lantern-58")],
+    )
+    .await;
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn csrf_key_rotation() -> TestResult {
+    let old_key = "0123456789abcdef".repeat(4);
+    let new_key = "fedcba9876543210".repeat(4);
 
+    let runner = Runner::build().session_keys(&[&old_key]).to_runner().await;
+    runner.reset_database().await?;
+    register_user(&runner, "frotz").await?;
+    let (token, cookie) = csrf_token(&runner, "/users/login", "/users/login").await;
+
+    // A page loaded before the new key was added can still be submitted, and the cookie is
+    // encrypted again with the new key.
+    let runner = Runner::build()
+        .session_keys(&[&new_key, &old_key])
+        .to_runner()
+        .await;
+    let form = [
+        ("handle", "frotz"),
+        ("password", "Plover-Lantern-58"),
+        ("csrf_token", &token),
+    ];
+    let req = test::TestRequest::post()
+        .uri("/users/login")
+        .cookie(cookie)
+        .set_form(form);
+    let res = runner.call_without_csrf(req).await;
+    assert_eq!(http::StatusCode::FOUND, res.status);
+    let resigned = res.cookie("csrf-token").unwrap();
+
+    let runner = Runner::build().session_keys(&[&new_key]).to_runner().await;
+    let req = test::TestRequest::post()
+        .uri("/users/login")
+        .cookie(resigned)
+        .set_form(form);
+    let res = runner.call_without_csrf(req).await;
+    assert_ne!(http::StatusCode::FORBIDDEN, res.status);
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn csrf_create_question() -> TestResult {
+    let runner = Runner::build().auth().to_runner().await;
+    runner.reset_database().await?;
+
+    assert_csrf_protected(
+        &runner,
+        "/questions/new",
+        "/questions",
+        &[("title", "some-title"), ("link", "not-a-link")],
+    )
+    .await;
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn csrf_answer_question() -> TestResult {
+    let runner = Runner::build().auth().to_runner().await;
+    runner.reset_database().await?;
+
+    let question = Question::create(
+        CreateQuestion {
+            author_id: runner.user.id,
+            title: "some-title".to_string(),
+            link: Some("some-link".to_string()),
+            link_logo: None,
+            ..CreateQuestion::default()
+        },
+        &runner.db,
+    )
+    .await?;
+    let queue = Queue::find_or_create(
+        CreateQueue {
+            user_id: runner.user.id,
+            starting_question_external_id: question.external_id.clone(),
+            title: "Algorithms and data structures".to_string(),
+            description: "A queue".to_string(),
+        },
+        &runner.db,
+    )
+    .await?
+    .record;
+
+    let page = format!("/{}/queues/{}", runner.user.handle, queue.external_id);
+    let action = runner
+        .get(&page)
+        .await
+        .doc
+        .select_attr("form.next-question", "action")
+        .unwrap();
+    assert_csrf_protected(&runner, &page, &action, &[("state", "Correct")]).await;
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn csrf_logout() -> TestResult {
+    let runner = Runner::build().auth().to_runner().await;
+    assert_csrf_protected(&runner, "/overview", "/users/logout", &[]).await;
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn csrf_settings() -> TestResult {
+    let runner = Runner::build().to_runner().await;
+    runner.reset_database().await?;
+    register_user(&runner, "frotz").await?;
+
+    let runner = Runner::build().auth_as("frotz").to_runner().await;
+    assert_csrf_protected(
+        &runner,
+        "/users/settings",
+        "/users/settings/handle",
+        &[("handle", "xyzzy")],
+    )
+    .await;
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn csrf_header() -> TestResult {
+    let runner = Runner::build().auth().to_runner().await;
+    let (token, cookie) = csrf_token(&runner, "/overview", "/users/logout").await;
+
+    let req = test::TestRequest::post()
+        .uri("/users/logout")
+        .cookie(cookie)
+        .insert_header(("X-CSRF-Token", token));
+    let res = runner.call_without_csrf(req).await;
     assert_eq!(http::StatusCode::FOUND, res.status);
     Ok(())
 }
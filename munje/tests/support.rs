@@ -1,21 +1,28 @@
 #![allow(dead_code)]
 
-use actix_identity::{CookieIdentityPolicy, Identity, IdentityService};
+use actix_identity::{Identity, IdentityService};
 use actix_web::{
-    body::{BoxBody, EitherBody},
-    dev::ServiceResponse,
-    http, test, web, App, HttpRequest,
+    body::MessageBody, cookie::Cookie, dev::ServiceResponse, http, test, web, App, HttpRequest,
+    HttpServer,
 };
+use jsonwebtoken::{EncodingKey, Header};
 use munje::{
+    audit,
+    csrf::CsrfProtection,
     error::Error,
     prelude::*,
     questions, queues, routes,
-    types::{AppState, Config, Pool},
-    users,
+    session::{SessionDurations, SessionPolicy},
+    types::{AppState, Clock, Config, DateTime, Pool},
+    users::{self, handle, oidc, HashParams, LoadPreferences},
 };
 use scraper::{ElementRef, Html, Selector};
 use sqlx::postgres::PgPoolOptions;
-use std::str;
+use std::{
+    collections::HashMap,
+    fmt, str,
+    sync::{Arc, Mutex},
+};
 
 #[cfg(test)]
 #[ctor::ctor]
@@ -32,22 +39,43 @@ pub struct Document {
 }
 
 pub struct HttpResult {
+    pub cookies: Vec<Cookie<'static>>,
     pub doc: Document,
+    pub headers: http::header::HeaderMap,
     pub status: http::StatusCode,
 }
 
+impl HttpResult {
+    pub fn cookie(&self, name: &str) -> Option<Cookie<'static>> {
+        self.cookies
+            .iter()
+            .find(|cookie| cookie.name() == name)
+            .cloned()
+    }
+
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_str(&self.doc.source).expect("Failed to parse json")
+    }
+
+    pub fn location(&self) -> Option<String> {
+        self.headers
+            .get(http::header::LOCATION)
+            .map(|value| value.to_str().unwrap().to_string())
+    }
+}
+
 pub struct Matches<'a> {
     selector: Selector,
     matches: Vec<ElementRef<'a>>,
 }
 
 impl<'a> Matches<'a> {
-    pub fn first(&mut self) -> Option<&ElementRef> {
-        self.matches.iter().next()
+    pub fn first(&mut self) -> Option<&ElementRef<'a>> {
+        self.matches.first()
     }
 
     pub fn exists(&mut self) -> bool {
-        self.matches.iter().next() != None
+        !self.matches.is_empty()
     }
 
     pub fn none(&mut self) -> bool {
@@ -63,18 +91,30 @@ impl Document {
         }
     }
 
-    pub fn to_string(&self) -> String {
-        self.source.to_string()
+    pub fn select_text(&self, selector: &str) -> Option<String> {
+        self.css(selector)
+            .unwrap()
+            .first()
+            .map(|elem| elem.inner_html())
     }
 
-    pub fn select_text(&self, selector: &str) -> Option<String> {
-        match self.css(selector).unwrap().first() {
-            Some(elem) => Some(elem.inner_html()),
-            None => None,
-        }
+    pub fn select_attr(&self, selector: &str, attr: &str) -> Option<String> {
+        self.css(selector)
+            .unwrap()
+            .first()
+            .and_then(|elem| elem.value().attr(attr))
+            .map(str::to_string)
+    }
+
+    pub fn select_texts(&self, selector: &str) -> Vec<String> {
+        let selector = Selector::parse(selector).unwrap();
+        self.doc
+            .select(&selector)
+            .map(|elem| elem.inner_html())
+            .collect()
     }
 
-    pub fn css(&self, selector_str: &str) -> Result<Matches, Error> {
+    pub fn css(&self, selector_str: &str) -> Result<Matches<'_>, Error> {
         let selector = Selector::parse(selector_str).unwrap();
         Ok(Matches {
             selector: selector.clone(),
@@ -83,15 +123,30 @@ impl Document {
     }
 }
 
+impl fmt::Display for Document {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
 pub struct Runner {
+    pub clock: Clock,
     pub db: Pool,
     pub user: User,
     pub is_authenticated: bool,
     pub config: Config,
+    pub oidc: Option<oidc::Client>,
+    pub invite_only: bool,
 }
 
 pub struct RunnerBuilder {
+    clock: Clock,
+    handle: String,
     is_autheticated: bool,
+    oidc_issuer_url: Option<String>,
+    invite_only: bool,
+    session_keys: Option<Vec<String>>,
+    trusted_proxies: Vec<String>,
 }
 
 impl RunnerBuilder {
@@ -100,28 +155,81 @@ impl RunnerBuilder {
         self
     }
 
+    pub fn auth_as(&mut self, handle: &str) -> &mut Self {
+        self.handle = handle.to_string();
+        self.auth()
+    }
+
+    pub fn clock(&mut self, now: DateTime) -> &mut Self {
+        self.clock = Clock::Fixed(now);
+        self
+    }
+
+    pub fn oidc(&mut self, provider: &MockOidcProvider) -> &mut Self {
+        self.oidc_issuer_url = Some(provider.issuer_url.clone());
+        self
+    }
+
+    pub fn invite_only(&mut self) -> &mut Self {
+        self.invite_only = true;
+        self
+    }
+
+    pub fn session_keys(&mut self, keys: &[&str]) -> &mut Self {
+        self.session_keys = Some(keys.iter().map(|key| key.to_string()).collect());
+        self
+    }
+
+    pub fn trusted_proxies(&mut self, addresses: &[&str]) -> &mut Self {
+        self.trusted_proxies = addresses
+            .iter()
+            .map(|address| address.to_string())
+            .collect();
+        self
+    }
+
     pub async fn to_runner(&self) -> Runner {
-        let config = Config::test().expect("Failed to load test config");
+        let mut config = Config::test().expect("Failed to load test config");
+        if let Some(keys) = &self.session_keys {
+            config.session_keys = keys.clone();
+        }
+        config.trusted_proxies = self.trusted_proxies.clone();
         let db = Self::fetch_db(&config.database_url).await;
+        let oidc = self.oidc_issuer_url.as_ref().map(|issuer_url| {
+            oidc::Client::new(
+                issuer_url,
+                MockOidcProvider::CLIENT_ID,
+                MockOidcProvider::CLIENT_SECRET,
+                "http://localhost/users/auth/oidc/callback",
+                "Mock",
+                &config.session_keys,
+            )
+        });
 
         if !self.is_autheticated {
             return Runner {
+                clock: self.clock,
                 db,
                 config,
                 user: User::guest(),
                 is_authenticated: false,
+                oidc,
+                invite_only: self.invite_only,
             };
         }
 
-        let user = User::find_by_handle("gnusto", &db)
+        let user = User::find_by_handle(&self.handle, &db)
             .await
             .expect("Failed to fetch user");
 
         Runner {
+            clock: self.clock,
             db,
             config,
             user,
             is_authenticated: true,
+            oidc,
+            invite_only: self.invite_only,
         }
     }
 
@@ -131,9 +239,10 @@ impl RunnerBuilder {
             .connect(database_url)
             .await
             .expect("Failed to fetch database pool");
-        let _ = sqlx::migrate!("./migrations")
-            .run(&db)
-            .await;
+        let _ = sqlx::migrate!("./migrations").run(&db).await;
+        User::fill_handle_skeletons(&db)
+            .await
+            .expect("Failed to fill in handle skeletons");
 
         db
     }
@@ -142,34 +251,72 @@ impl RunnerBuilder {
 impl Runner {
     pub fn build() -> RunnerBuilder {
         RunnerBuilder {
+            clock: Clock::System,
+            handle: "gnusto".to_string(),
             is_autheticated: false,
+            oidc_issuer_url: None,
+            invite_only: false,
+            session_keys: None,
+            trusted_proxies: vec![],
         }
     }
 
     pub async fn reset_database(&self) -> Result<(), Error> {
+        sqlx::query("delete from asked_questions")
+            .execute(&self.db)
+            .await?;
         sqlx::query("delete from last_answers")
             .execute(&self.db)
             .await?;
         sqlx::query("delete from answers").execute(&self.db).await?;
+        sqlx::query(
+            "delete from queues
+             where user_id not in (select id from users where handle = 'gnusto')",
+        )
+        .execute(&self.db)
+        .await?;
+        sqlx::query(
+            "delete from questions
+             where author_id is null
+                or author_id not in (select id from users where handle = 'gnusto')",
+        )
+        .execute(&self.db)
+        .await?;
+        sqlx::query("delete from handle_redirects")
+            .execute(&self.db)
+            .await?;
+        sqlx::query("delete from login_attempts")
+            .execute(&self.db)
+            .await?;
+        sqlx::query("delete from invites").execute(&self.db).await?;
+        // Rows cannot be deleted from the audit log, but the table can be truncated.
+        sqlx::query("truncate audit_events")
+            .execute(&self.db)
+            .await?;
         sqlx::query("delete from users where handle <> 'gnusto'")
             .execute(&self.db)
             .await?;
         Ok(())
     }
 
-    async fn call_service(
-        &self,
-        mut req: test::TestRequest,
-    ) -> ServiceResponse<EitherBody<BoxBody>> {
-        let policy = CookieIdentityPolicy::new(self.config.session_key.as_bytes())
-            .name("auth-cookie")
-            .secure(true);
+    async fn call_service(&self, mut req: test::TestRequest) -> ServiceResponse<impl MessageBody> {
+        let policy = SessionPolicy::new(
+            &self.config.session_keys,
+            SessionDurations::from_config(&self.config),
+        );
 
         let app = App::new()
             .app_data(web::Data::new(AppState {
+                clock: self.clock,
                 db: self.db.clone(),
+                oidc: self.oidc.clone(),
+                invite_only: self.invite_only,
+                password_hashing: HashParams::from_config(&self.config),
+                trusted_proxies: self.config.trusted_proxies(),
             }))
+            .wrap(LoadPreferences::new(&self.config.session_keys))
             .wrap(IdentityService::new(policy))
+            .wrap(CsrfProtection::new(&self.config.session_keys))
             .service(web::resource("/login/{handle}").to(
                 |id: Identity, path: web::Path<String>, request: HttpRequest| async move {
                     let db = request.db().expect("Failed to fetch database handle");
@@ -178,36 +325,60 @@ impl Runner {
                         .expect("Unable to find user");
                     let string = serde_json::to_string(&user).expect("Failed to serialize user");
                     id.remember(string);
-                    HttpResponse::Ok()
+                    HttpResponse::Ok().finish()
                 },
             ))
             .configure(routes::register)
             .configure(users::routes::register)
             .configure(questions::routes::register)
-            .configure(queues::routes::register);
+            .configure(queues::routes::register)
+            .configure(audit::routes::register);
 
         let srv = test::init_service(app).await;
 
         if self.is_authenticated {
-            let login_path = format!("/login/{}", self.user.handle);
+            let login_path = format!("/login/{}", handle::encode(&self.user.handle));
             let auth_req = test::TestRequest::with_uri(&login_path).to_request();
             let res = test::call_service(&srv, auth_req).await;
             assert_eq!(http::StatusCode::OK, res.status());
-            let cookie = res.response().cookies().next().unwrap().to_owned();
+            let cookie = res
+                .response()
+                .cookies()
+                .find(|cookie| cookie.name() == "auth-cookie")
+                .unwrap()
+                .into_owned();
             req = req.cookie(cookie)
         }
 
         test::call_service(&srv, req.to_request()).await
     }
 
+    /// Sends the request with a valid CSRF token, as a browser would after loading the page
+    /// with the form on it.
     pub async fn call(&self, req: test::TestRequest) -> HttpResult {
+        let (token, cookie) = CsrfProtection::new(&self.config.session_keys).issue();
+        let req = req
+            .cookie(cookie)
+            .insert_header((CsrfProtection::HEADER_NAME, token));
+        self.call_without_csrf(req).await
+    }
+
+    pub async fn call_without_csrf(&self, req: test::TestRequest) -> HttpResult {
         let res = self.call_service(req).await;
         let status = res.status();
+        let headers = res.headers().clone();
+        let cookies = res
+            .response()
+            .cookies()
+            .map(|cookie| cookie.into_owned())
+            .collect();
         let body = test::read_body(res).await;
         let html = str::from_utf8(&body).expect("Failed to decode body");
 
         HttpResult {
+            cookies,
             doc: Document::from(html),
+            headers,
             status,
         }
     }
@@ -217,3 +388,109 @@ impl Runner {
         self.call(req).await
     }
 }
+
+/// A stand-in for an OpenID Connect identity provider.  Tests skip the provider's login page by
+/// calling `authorize` with the claims that the ID token should contain, and then pass the code
+/// to the callback.
+pub struct MockOidcProvider {
+    pub issuer_url: String,
+    codes: Arc<Mutex<HashMap<String, serde_json::Value>>>,
+}
+
+struct MockOidcState {
+    issuer_url: String,
+    codes: Arc<Mutex<HashMap<String, serde_json::Value>>>,
+}
+
+impl MockOidcProvider {
+    pub const CLIENT_ID: &'static str = "munje";
+    pub const CLIENT_SECRET: &'static str = "secret";
+    // "munje:secret"
+    const AUTHORIZATION: &'static str = "Basic bXVuamU6c2VjcmV0";
+    const KEY_ID: &'static str = "test-key";
+
+    pub async fn start() -> Self {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let issuer_url = format!("http://{}", listener.local_addr().unwrap());
+        let codes = Arc::new(Mutex::new(HashMap::new()));
+
+        let state = web::Data::new(MockOidcState {
+            issuer_url: issuer_url.clone(),
+            codes: codes.clone(),
+        });
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(state.clone())
+                .route(
+                    "/.well-known/openid-configuration",
+                    web::get().to(Self::discovery),
+                )
+                .route("/token", web::post().to(Self::token))
+                .route("/jwks", web::get().to(Self::jwks))
+        })
+        .workers(1)
+        .listen(listener)
+        .unwrap()
+        .run();
+        actix_rt::spawn(server);
+
+        Self { issuer_url, codes }
+    }
+
+    pub fn authorize(&self, code: &str, claims: serde_json::Value) {
+        self.codes.lock().unwrap().insert(code.to_string(), claims);
+    }
+
+    async fn discovery(state: web::Data<MockOidcState>) -> HttpResponse {
+        HttpResponse::Ok().json(serde_json::json!({
+            "issuer": state.issuer_url,
+            "authorization_endpoint": format!("{}/authorize", state.issuer_url),
+            "token_endpoint": format!("{}/token", state.issuer_url),
+            "jwks_uri": format!("{}/jwks", state.issuer_url),
+        }))
+    }
+
+    async fn jwks() -> HttpResponse {
+        HttpResponse::Ok()
+            .content_type("application/json")
+            .body(include_str!("fixtures/oidc-jwks.json"))
+    }
+
+    async fn token(
+        request: HttpRequest,
+        form: web::Form<HashMap<String, String>>,
+        state: web::Data<MockOidcState>,
+    ) -> HttpResponse {
+        let authorization = request
+            .headers()
+            .get(http::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok());
+        if authorization != Some(Self::AUTHORIZATION) {
+            return HttpResponse::Unauthorized().finish();
+        }
+
+        let code = form.get("code").cloned().unwrap_or_default();
+        let mut claims = match state.codes.lock().unwrap().remove(&code) {
+            Some(claims) => claims,
+            None => return HttpResponse::BadRequest().finish(),
+        };
+
+        let now = chrono::Utc::now().timestamp();
+        claims["iss"] = serde_json::json!(state.issuer_url);
+        claims["aud"] = serde_json::json!(Self::CLIENT_ID);
+        claims["iat"] = serde_json::json!(now);
+        claims["exp"] = serde_json::json!(now + 300);
+
+        let mut header = Header::new(jsonwebtoken::Algorithm::RS256);
+        header.kid = Some(Self::KEY_ID.to_string());
+        let key =
+            EncodingKey::from_rsa_pem(include_bytes!("fixtures/oidc-private-key.pem")).unwrap();
+        let id_token = jsonwebtoken::encode(&header, &claims, &key).unwrap();
+
+        HttpResponse::Ok().json(serde_json::json!({
+            "access_token": "access-token",
+            "token_type": "Bearer",
+            "id_token": id_token,
+        }))
+    }
+}